@@ -1 +1,5 @@
 #![no_std]
+
+/// Slots in each half of `USER_LIST`. Every slot of the half `ACTIVE` names
+/// is compared, and slots never written hold 0, i.e. root.
+pub const USER_LIST_MAX_ENTRIES: u32 = 1024;
@@ -6,8 +6,9 @@
 #![allow(non_camel_case_types)]
 #![allow(dead_code)]
 
-use aya_ebpf::{helpers::{bpf_get_current_uid_gid, bpf_probe_read_kernel, bpf_probe_read_kernel_str_bytes}, macros::{kprobe, map}, maps::PerCpuArray, programs::ProbeContext};
+use aya_ebpf::{helpers::{bpf_get_current_uid_gid, bpf_probe_read_kernel, bpf_probe_read_kernel_str_bytes}, macros::{kprobe, map}, maps::{Array, PerCpuArray}, programs::ProbeContext};
 use aya_log_ebpf::info;
+use array_common::USER_LIST_MAX_ENTRIES;
 use binding::{dentry, file, path, qstr};
 mod binding;
 struct Buffer {
@@ -17,8 +18,15 @@ struct Buffer {
 #[map]
 static mut BUFFER: PerCpuArray<Buffer> = PerCpuArray::with_max_entries(1, 0);
 
+// Two lists of uids, one after the other. A new list is written into the
+// half not in force and then `ACTIVE` switches to it, so no open is ever
+// judged by a list half written.
 #[map]
-static mut USER_LIST: PerCpuArray<u32> = PerCpuArray::with_max_entries(1024, 0);
+static mut USER_LIST: PerCpuArray<u32> = PerCpuArray::with_max_entries(2 * USER_LIST_MAX_ENTRIES, 0);
+
+// Which half of `USER_LIST` is in force, 0 or 1.
+#[map]
+static mut ACTIVE: Array<u32> = Array::with_max_entries(1, 0);
 
 #[kprobe]
 pub fn array(ctx: ProbeContext) -> u32 {
@@ -28,8 +36,12 @@ pub fn array(ctx: ProbeContext) -> u32 {
     }
 }
 fn check_valid_user(uid: u32) -> bool {
-    for i in 0..1024 {
-        match unsafe {USER_LIST.get(i) } {
+    let base = match unsafe { ACTIVE.get(0) } {
+        Some(&1) => USER_LIST_MAX_ENTRIES,
+        _ => 0,
+    };
+    for i in 0..USER_LIST_MAX_ENTRIES {
+        match unsafe {USER_LIST.get(base + i) } {
             Some(&user_id) => {
                 if user_id == uid {
                     return true;
@@ -0,0 +1,86 @@
+//! The users whose opens are traced, as a TOML file.
+//!
+//! ```toml
+//! uids = [0, 1000]
+//! ```
+//!
+//! Without a file only root is traced.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context as _};
+use array_common::USER_LIST_MAX_ENTRIES;
+use serde::Deserialize;
+
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub uids: BTreeSet<u32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config { uids: BTreeSet::from([0]) }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let text = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let config: Config = toml::from_str(&text).with_context(|| format!("bad config file {}", path.display()))?;
+        check(&config.uids).with_context(|| format!("bad config file {}", path.display()))?;
+        Ok(config)
+    }
+}
+
+/// Refuses a list `USER_LIST` cannot hold. An empty one would leave it as
+/// it is.
+pub fn check(uids: &BTreeSet<u32>) -> Result<(), anyhow::Error> {
+    if uids.is_empty() {
+        bail!("uids must not be empty");
+    }
+    if uids.len() > USER_LIST_MAX_ENTRIES as usize {
+        bail!("uids has {} entries, at most {} fit", uids.len(), USER_LIST_MAX_ENTRIES);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_configs() {
+        let config: Config = toml::from_str("uids = [1000, 0, 1000]").unwrap();
+        assert_eq!(config.uids, BTreeSet::from([0, 1000]));
+        assert_eq!(Config::default().uids, BTreeSet::from([0]));
+        for text in ["", "uids = 0", "uids = [-1]", "uids = [0]\nusers = [0]"] {
+            assert!(toml::from_str::<Config>(text).is_err(), "accepted {:?}", text);
+        }
+    }
+
+    #[test]
+    fn refuses_lists_user_list_cannot_hold() {
+        assert!(check(&BTreeSet::from([0])).is_ok());
+        assert!(check(&(0..USER_LIST_MAX_ENTRIES).collect()).is_ok());
+        assert_eq!(check(&BTreeSet::new()).unwrap_err().to_string(), "uids must not be empty");
+        assert_eq!(
+            check(&(0..=USER_LIST_MAX_ENTRIES).collect()).unwrap_err().to_string(),
+            "uids has 1025 entries, at most 1024 fit"
+        );
+    }
+
+    #[test]
+    fn loads_and_checks_a_file() {
+        let path = std::env::temp_dir().join(format!("array-config-{}.toml", std::process::id()));
+        fs::write(&path, "uids = []\n").unwrap();
+        let e = Config::load(&path).unwrap_err();
+        assert_eq!(format!("{:#}", e), format!("bad config file {}: uids must not be empty", path.display()));
+        fs::write(&path, "uids = [1000]\n").unwrap();
+        assert_eq!(Config::load(&path).unwrap().uids, BTreeSet::from([1000]));
+        fs::remove_file(&path).unwrap();
+        assert!(format!("{:#}", Config::load(&path).unwrap_err()).starts_with("failed to read"));
+    }
+}
@@ -1,14 +1,109 @@
-use aya::maps::{PerCpuArray, PerCpuValues};
+mod config;
+mod pin;
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use array_common::USER_LIST_MAX_ENTRIES;
+use aya::maps::{Array, Map, MapData, PerCpuArray, PerCpuValues};
+use aya::programs::links::FdLink;
 use aya::programs::KProbe;
 use aya::util::nr_cpus;
 use aya::{include_bytes_aligned, Bpf};
 use aya_log::BpfLogger;
+use clap::{Parser, Subcommand};
+use config::Config;
 use log::{info, warn, debug};
-use tokio::signal;
+use pin::Pins;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracer_support::control::{self, ControlSocket, Request, RpcError};
+use tracer_support::daemon::{self, Daemon, Signal, Signals};
+use tracer_support::features::{AttachPoint, Features, Need};
+use tracer_support::sandbox;
+use tracer_support::verifier;
+
+/// The eBPF source, to name the function of a line the verifier quotes.
+const EBPF_SOURCES: verifier::Sources = &[("main.rs", include_str!("../../array-ebpf/src/main.rs"))];
+
+#[derive(Debug, Parser)]
+struct Opt {
+    /// The uids to trace, in TOML; reread on SIGHUP
+    #[clap(long)]
+    config: Option<PathBuf>,
+    /// Serve the control API on this Unix socket
+    #[clap(long)]
+    control: Option<PathBuf>,
+    /// Pin the program and USER_LIST to this directory on a BPF filesystem,
+    /// so the program stays attached after exit, or reuse what an earlier
+    /// run pinned there
+    #[clap(long)]
+    pin: Option<PathBuf>,
+    /// Run as a system service: log JSON lines, notify systemd and feed its
+    /// watchdog while the self-check passes
+    #[clap(long)]
+    daemon: bool,
+    /// Run the self-check this often, in seconds
+    #[clap(long, default_value_t = 30, requires = "daemon")]
+    check_interval: u64,
+    /// Once attached, drop root and run as this user, under a seccomp filter
+    #[clap(long)]
+    user: Option<String>,
+    /// Write the verifier log here if a program fails to load
+    #[clap(long)]
+    verifier_log: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Send a request to the control socket of a running array and print the result
+    Ctl {
+        /// Path of the control socket
+        socket: PathBuf,
+        /// Method to call: programs, dump or set_users
+        method: String,
+        /// Params as a JSON object
+        params: Option<String>,
+    },
+    /// Remove the pins in a directory, which detaches the program and frees USER_LIST
+    Unpin {
+        /// The pin directory
+        dir: PathBuf,
+    },
+    /// Print what the kernel supports and where the probe would attach, without loading any eBPF
+    Features,
+}
+
+const ATTACH_POINT: AttachPoint = AttachPoint { targets: &["security_file_open"], programs: &["array"], untraced: "" };
+
+/// What a pin directory holds besides the link.
+const PINNED: [&str; 3] = ["USER_LIST", "ACTIVE", "array"];
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    env_logger::init();
+    let opt = Opt::parse();
+
+    daemon::init_logger(opt.daemon);
+
+    match &opt.command {
+        Some(Command::Ctl { socket, method, params }) => return control::cli(socket, method, params.as_deref()),
+        Some(Command::Unpin { dir }) => {
+            pin::unpin(dir, &PINNED)?;
+            info!("unpinned {}", dir.display());
+            return Ok(());
+        }
+        Some(Command::Features) => {
+            for line in Features::probe().report(&[ATTACH_POINT]) {
+                println!("{}", line);
+            }
+            return Ok(());
+        }
+        None => {}
+    }
 
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
@@ -21,6 +116,98 @@ async fn main() -> Result<(), anyhow::Error> {
         debug!("remove limit on locked memory failed, ret is: {}", ret);
     }
 
+    let features = Features::probe();
+    for line in features.report(&[ATTACH_POINT]) {
+        debug!("{}", line);
+    }
+    features.check("array", &[Need::Kprobes])?;
+
+    let config = match &opt.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let pins = opt.pin.as_deref().map(Pins::create).transpose()?;
+    // `bpf` holds the program while it is attached, unless the pins do.
+    let (bpf, mut user_list) = match &pins {
+        Some(pins) if pins.exist() => {
+            info!("Reusing the program and USER_LIST pinned at {}", pins.dir().display());
+            let mut user_list = UserList {
+                list: PerCpuArray::try_from(pins.map("USER_LIST", Map::PerCpuArray)?)?,
+                active: Array::try_from(pins.map("ACTIVE", Map::Array)?)?,
+            };
+            // The pinned list stays, unless there is a config file to replace it.
+            if opt.config.is_some() {
+                user_list.set(&config.uids)?;
+            }
+            (None, user_list)
+        }
+        _ => load(&config, &features, pins.as_ref(), opt.verifier_log.as_deref()).inspect_err(|_| {
+            // Leave nothing half pinned behind.
+            if let Some(pins) = &pins {
+                let _ = pin::unpin(pins.dir(), &PINNED);
+            }
+        })?,
+    };
+    let mut control = opt.control.as_deref().map(ControlSocket::bind).transpose()?;
+    let mut daemon = opt.daemon.then(|| Daemon::new(Duration::from_secs(opt.check_interval), None)).transpose()?;
+    if let Some(daemon) = &mut daemon {
+        match (&bpf, &pins) {
+            (Some(bpf), _) => daemon.watch(&daemon::program_ids(bpf, ["array"])?),
+            (None, Some(pins)) => daemon.watch(&[("array", pins.program_id("array")?)]),
+            (None, None) => unreachable!("the program is held by neither"),
+        }
+    }
+
+    let mut signals = Signals::new()?;
+    // USER_LIST is still read and written, from this thread.
+    if let Some(user) = &opt.user {
+        sandbox::enter(user, true)?;
+    }
+    if let Some(daemon) = &daemon {
+        daemon.ready();
+    }
+    info!("Waiting for Ctrl-C...");
+    loop {
+        tokio::select! {
+            request = ControlSocket::next(&mut control) => {
+                let result = handle_request(&request, &mut user_list);
+                request.reply(result);
+            }
+            _ = Daemon::tick(&mut daemon) => {
+                if let Some(daemon) = &mut daemon {
+                    // array counts no events, and has no --max-idle to check them against.
+                    daemon.check(0);
+                }
+            }
+            signal = signals.next() => match signal {
+                Signal::Shutdown => break,
+                Signal::Reload => {
+                    if let Some(daemon) = &daemon {
+                        daemon.reloading();
+                    }
+                    match &opt.config {
+                        Some(path) => reload(path, &mut user_list),
+                        None => info!("no --config to reload"),
+                    }
+                    if let Some(daemon) = &daemon {
+                        daemon.ready();
+                    }
+                }
+            },
+        }
+    }
+    if let Some(daemon) = &daemon {
+        daemon.stopping();
+    }
+    info!("Exiting...");
+
+    Ok(())
+}
+
+/// Loads and attaches the program with the configured uids in `USER_LIST`,
+/// and with `pins` pins both. Then the program is left to the pins and no
+/// `Bpf` is returned.
+fn load(config: &Config, features: &Features, pins: Option<&Pins>, verifier_log: Option<&Path>) -> Result<(Option<Bpf>, UserList), anyhow::Error> {
     // This will include your eBPF object file as raw bytes at compile-time and load it at
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
@@ -37,20 +224,110 @@ async fn main() -> Result<(), anyhow::Error> {
         // This can happen if you remove all log statements from your eBPF program.
         warn!("failed to initialize eBPF logger: {}", e);
     }
+    // Its one attach point is required, so resolving it never skips.
+    let function = features.resolve(&ATTACH_POINT)?.unwrap();
     let program: &mut KProbe = bpf.program_mut("array").unwrap().try_into()?;
-    program.load()?;
-    program.attach("security_file_open", 0)?;
+    program.load().map_err(|e| verifier::explain("array", e, verifier_log, EBPF_SOURCES))?;
+    let link = program.attach(function, 0).with_context(|| format!("failed to attach array to {}", function))?;
 
-    let mut user_list: PerCpuArray<_, u32> = bpf.map_mut("USER_LIST").unwrap().try_into()?;
+    if let Some(pins) = pins {
+        pins.pin_maps(&bpf, &["USER_LIST", "ACTIVE"])?;
+    }
+    let mut user_list = UserList {
+        list: PerCpuArray::try_from(bpf.take_map("USER_LIST").unwrap())?,
+        active: Array::try_from(bpf.take_map("ACTIVE").unwrap())?,
+    };
+    user_list.set(&config.uids)?;
+    let Some(pins) = pins else {
+        return Ok((Some(bpf), user_list));
+    };
+    pins.pin_programs(&mut bpf, &["array"])?;
+    let program: &mut KProbe = bpf.program_mut("array").unwrap().try_into()?;
+    let link = FdLink::try_from(program.take_link(link)?).context("this kernel cannot pin kprobe links, which needs 5.15 or later")?;
+    pins.pin_link(link)?;
+    info!("Pinned the program and USER_LIST to {}", pins.dir().display());
+    Ok((None, user_list))
+}
 
-    let user_ids: Vec<i32> = vec![0];
-    for (i, uid) in user_ids.iter().enumerate() {
-        let values = PerCpuValues::try_from(vec![*uid as u32; nr_cpus()?])?;
-        user_list.set(i as u32, values, 0)?;
+/// Rereads the config file and writes its uids into `USER_LIST`. If that
+/// fails, the list in force stays.
+fn reload(path: &Path, user_list: &mut UserList) {
+    let config = match Config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            warn!("keeping the previous users: {:#}", e);
+            return;
+        }
+    };
+    match user_list.set(&config.uids) {
+        Ok(()) => info!("reloaded {}", path.display()),
+        Err(e) => warn!("failed to update USER_LIST: {:#}", e),
     }
-    info!("Waiting for Ctrl-C...");
-    signal::ctrl_c().await?;
-    info!("Exiting...");
+}
 
-    Ok(())
+#[derive(Deserialize)]
+struct DumpParams {
+    map: String,
+}
+
+#[derive(Deserialize)]
+struct UsersParams {
+    uids: BTreeSet<u32>,
+}
+
+fn handle_request(request: &Request, user_list: &mut UserList) -> Result<Value, anyhow::Error> {
+    match request.method.as_str() {
+        "programs" => Ok(json!([{ "program": "array", "function": "security_file_open" }])),
+        "dump" => {
+            let DumpParams { map } = request.params()?;
+            if map != "USER_LIST" {
+                return Err(RpcError::invalid_params(format!("no map '{}' to dump", map)).into());
+            }
+            Ok(json!(user_list.users()?))
+        }
+        "set_users" => {
+            let UsersParams { uids } = request.params()?;
+            user_list.set(&uids)?;
+            info!("users changed over the control socket");
+            Ok(json!(user_list.users()?))
+        }
+        method => Err(RpcError::method_not_found(method).into()),
+    }
+}
+
+/// `USER_LIST` and `ACTIVE`, which says which of its two halves is in force.
+struct UserList {
+    list: PerCpuArray<MapData, u32>,
+    active: Array<MapData, u32>,
+}
+
+impl UserList {
+    /// The first slot of the half in force.
+    fn base(&self) -> Result<u32, anyhow::Error> {
+        Ok(if self.active.get(&0, 0)? == 1 { USER_LIST_MAX_ENTRIES } else { 0 })
+    }
+
+    /// The uids in force. Every CPU holds the same list.
+    fn users(&self) -> Result<BTreeSet<u32>, anyhow::Error> {
+        let base = self.base()?;
+        let mut uids = BTreeSet::new();
+        for i in base..base + USER_LIST_MAX_ENTRIES {
+            uids.insert(self.list.get(&i, 0)?[0]);
+        }
+        Ok(uids)
+    }
+
+    /// Makes `uids` the list. It is written into the half not in force, all
+    /// of its slots with the list repeated over them, since a slot left at 0
+    /// would let root through; then `ACTIVE` switches to that half.
+    fn set(&mut self, uids: &BTreeSet<u32>) -> Result<(), anyhow::Error> {
+        config::check(uids).map_err(RpcError::invalid_params)?;
+        let staged = USER_LIST_MAX_ENTRIES - self.base()?;
+        let nr_cpus = nr_cpus()?;
+        for (i, uid) in (staged..staged + USER_LIST_MAX_ENTRIES).zip(uids.iter().cycle()) {
+            self.list.set(i, PerCpuValues::try_from(vec![*uid; nr_cpus])?, 0)?;
+        }
+        self.active.set(0, staged / USER_LIST_MAX_ENTRIES, 0)?;
+        Ok(())
+    }
 }
@@ -3,6 +3,19 @@
 #[cfg(feature = "user")]
 use aya::Pod;
 
+pub const FILE_OPEN_COUNT_MAX_ENTRIES: u32 = 1024;
+pub const FILTER_MAX_ENTRIES: u32 = 1024;
+pub const TASK_NAME_LEN: usize = 16;
+/// Keys of `NAME_FILTER` are file names NUL padded to this length, so a
+/// name of `NAME_KEY_LEN` bytes or more never matches.
+pub const NAME_KEY_LEN: usize = 64;
+
+/// Bits of `FILTERS[0]`, one per filter map in force. An empty filter is not
+/// in force and lets every open through.
+pub const FILTER_UID: u32 = 1;
+pub const FILTER_COMM: u32 = 2;
+pub const FILTER_NAME: u32 = 4;
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 
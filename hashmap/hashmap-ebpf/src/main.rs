@@ -6,8 +6,9 @@
 #![allow(non_camel_case_types)]
 #![allow(dead_code)]
 
-use aya_ebpf::{helpers::bpf_get_current_uid_gid, macros::{kprobe, map}, maps::PerCpuHashMap, programs::ProbeContext};
-use hashmap_common::FileOpenInfo;
+use aya_ebpf::{helpers::{bpf_get_current_comm, bpf_get_current_uid_gid, bpf_probe_read_kernel, bpf_probe_read_kernel_str_bytes}, macros::{kprobe, map}, maps::{Array, HashMap, PerCpuArray, PerCpuHashMap}, programs::ProbeContext};
+use binding::file;
+use hashmap_common::{FileOpenInfo, FILE_OPEN_COUNT_MAX_ENTRIES, FILTER_COMM, FILTER_MAX_ENTRIES, FILTER_NAME, FILTER_UID, NAME_KEY_LEN, TASK_NAME_LEN};
 
 mod binding;
 
@@ -19,18 +20,56 @@ mod binding;
 // }
 
 #[map]
-static mut FILE_OPEN_COUNT: PerCpuHashMap<u32, FileOpenInfo> = PerCpuHashMap::with_max_entries(1024, 0);
+static mut FILE_OPEN_COUNT: PerCpuHashMap<u32, FileOpenInfo> = PerCpuHashMap::with_max_entries(FILE_OPEN_COUNT_MAX_ENTRIES, 0);
+
+// Opens that could not be counted because `FILE_OPEN_COUNT` was full.
+#[map]
+static mut LOST: PerCpuArray<u64> = PerCpuArray::with_max_entries(1, 0);
+
+// Only opens whose uid, task name and file name are in these maps are
+// counted, for the maps `FILTERS` says are in force.
+#[map]
+static mut UID_FILTER: HashMap<u32, u8> = HashMap::with_max_entries(FILTER_MAX_ENTRIES, 0);
+
+#[map]
+static mut COMM_FILTER: HashMap<[u8; TASK_NAME_LEN], u8> = HashMap::with_max_entries(FILTER_MAX_ENTRIES, 0);
+
+#[map]
+static mut NAME_FILTER: HashMap<[u8; NAME_KEY_LEN], u8> = HashMap::with_max_entries(FILTER_MAX_ENTRIES, 0);
+
+#[map]
+static mut FILTERS: Array<u32> = Array::with_max_entries(1, 0);
+
+// Which of `hashmap` (0) and `hashmap_standby` (1) counts. Moving to another
+// function attaches the idle one there before this flips, so there is always
+// exactly one program counting.
+#[map]
+static mut ACTIVE: Array<u32> = Array::with_max_entries(1, 0);
 
 #[kprobe]
 pub fn hashmap(ctx: ProbeContext) -> u32 {
-    match try_hashmap(ctx) {
+    match try_hashmap(ctx, 0) {
         Ok(ret) => ret,
         Err(_) => 1,
     }
 }
 
-fn try_hashmap(_ctx: ProbeContext) -> Result<u32, i64> {
+#[kprobe]
+pub fn hashmap_standby(ctx: ProbeContext) -> u32 {
+    match try_hashmap(ctx, 1) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+fn try_hashmap(ctx: ProbeContext, slot: u32) -> Result<u32, i64> {
+    if unsafe { ACTIVE.get(0) }.copied().unwrap_or(0) != slot {
+        return Ok(0);
+    }
     let uid = bpf_get_current_uid_gid() as u32;
+    if !unsafe { passes_filters(&ctx, uid)? } {
+        return Ok(0);
+    }
 
     unsafe {
         if let Some(info_ptr) = FILE_OPEN_COUNT.get_ptr_mut(&uid) {
@@ -38,12 +77,42 @@ fn try_hashmap(_ctx: ProbeContext) -> Result<u32, i64> {
             info.count += 1;
         } else {
             let info = FileOpenInfo { count: 1 };
-            FILE_OPEN_COUNT.insert(&uid, &info, 0)?;
+            if let Err(e) = FILE_OPEN_COUNT.insert(&uid, &info, 0) {
+                if let Some(lost) = LOST.get_ptr_mut(0) {
+                    *lost += 1;
+                }
+                return Err(e);
+            }
         }
     }
     Ok(0)
 }
 
+unsafe fn passes_filters(ctx: &ProbeContext, uid: u32) -> Result<bool, i64> {
+    let filters = FILTERS.get(0).copied().unwrap_or(0);
+    if filters & FILTER_UID != 0 && UID_FILTER.get(&uid).is_none() {
+        return Ok(false);
+    }
+    if filters & FILTER_COMM != 0 && COMM_FILTER.get(&bpf_get_current_comm()?).is_none() {
+        return Ok(false);
+    }
+    if filters & FILTER_NAME != 0 {
+        // The first argument is the file being opened.
+        let file: *const file = ctx.arg(0).ok_or(1i64)?;
+        let dentry = bpf_probe_read_kernel(&(*file).f_path.dentry)?;
+        let d_name = bpf_probe_read_kernel(&(*dentry).d_name)?;
+        if d_name.__bindgen_anon_1.__bindgen_anon_1.len as usize >= NAME_KEY_LEN {
+            return Ok(false);
+        }
+        let mut key = [0u8; NAME_KEY_LEN];
+        bpf_probe_read_kernel_str_bytes(d_name.name, &mut key)?;
+        if NAME_FILTER.get(&key).is_none() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     unsafe { core::hint::unreachable_unchecked() }
@@ -0,0 +1,512 @@
+//! The filter configuration and how it gets into the eBPF maps.
+//!
+//! ```toml
+//! target = "security_file_open"
+//!
+//! [filter]
+//! uids = [0, 1000]
+//! comms = ["nginx", "sshd"]
+//! names = ["shadow", "passwd"]
+//! ```
+//!
+//! Only opens whose uid, task name and file name are all in the filter are
+//! counted; a list that is empty or left out lets everything through. A new
+//! version of the file is diffed into the maps while the program stays
+//! attached, so opens are counted without a gap and `FILE_OPEN_COUNT` keeps
+//! its totals.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context as _};
+use aya::maps::{Array, HashMap, Map, MapData};
+use aya::Bpf;
+use hashmap_common::{FILTER_COMM, FILTER_MAX_ENTRIES, FILTER_NAME, FILTER_UID, NAME_KEY_LEN, TASK_NAME_LEN};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::pin::Pins;
+
+const DEFAULT_TARGET: &str = "security_file_open";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Kernel function the counting kprobe is attached to. With `names`, its
+    /// first argument must be the `struct file *` being opened.
+    #[serde(default = "default_target")]
+    pub target: String,
+    #[serde(default)]
+    pub filter: Filter,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Filter {
+    #[serde(default)]
+    pub uids: BTreeSet<u32>,
+    /// Task names, at most 15 bytes as the kernel keeps them.
+    #[serde(default)]
+    pub comms: BTreeSet<String>,
+    /// File names, without their directory.
+    #[serde(default)]
+    pub names: BTreeSet<String>,
+}
+
+fn default_target() -> String {
+    DEFAULT_TARGET.to_owned()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            target: default_target(),
+            filter: Filter::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let text = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let config: Config = toml::from_str(&text).with_context(|| format!("bad config file {}", path.display()))?;
+        config.filter.check().with_context(|| format!("bad config file {}", path.display()))?;
+        Ok(config)
+    }
+}
+
+impl Filter {
+    // Everything the maps could refuse is refused here, so applying a
+    // filter that passed does not stop halfway.
+    pub fn check(&self) -> Result<(), anyhow::Error> {
+        for (what, len) in [("uids", self.uids.len()), ("comms", self.comms.len()), ("names", self.names.len())] {
+            if len > FILTER_MAX_ENTRIES as usize {
+                bail!("{} has {} entries, at most {} fit", what, len, FILTER_MAX_ENTRIES);
+            }
+        }
+        if let Some(comm) = self.comms.iter().find(|comm| comm.len() >= TASK_NAME_LEN) {
+            bail!("comm '{}' is longer than {} bytes", comm, TASK_NAME_LEN - 1);
+        }
+        if let Some(name) = self.names.iter().find(|name| name.len() >= NAME_KEY_LEN || name.contains('/')) {
+            bail!("name '{}' is not a file name of at most {} bytes", name, NAME_KEY_LEN - 1);
+        }
+        Ok(())
+    }
+}
+
+/// `UID_FILTER`, `COMM_FILTER`, `NAME_FILTER` and the `FILTERS` bits that
+/// say which of them are in force.
+pub struct FilterMaps {
+    uids: HashMap<MapData, u32, u8>,
+    comms: HashMap<MapData, [u8; TASK_NAME_LEN], u8>,
+    names: HashMap<MapData, [u8; NAME_KEY_LEN], u8>,
+    filters: Array<MapData, u32>,
+}
+
+impl FilterMaps {
+    pub fn new(bpf: &mut Bpf) -> Result<Self, anyhow::Error> {
+        Ok(FilterMaps {
+            uids: HashMap::try_from(bpf.take_map("UID_FILTER").unwrap())?,
+            comms: HashMap::try_from(bpf.take_map("COMM_FILTER").unwrap())?,
+            names: HashMap::try_from(bpf.take_map("NAME_FILTER").unwrap())?,
+            filters: Array::try_from(bpf.take_map("FILTERS").unwrap())?,
+        })
+    }
+
+    /// The maps an earlier run pinned.
+    pub fn pinned(pins: &Pins) -> Result<Self, anyhow::Error> {
+        Ok(FilterMaps {
+            uids: HashMap::try_from(pins.map("UID_FILTER", Map::HashMap)?)?,
+            comms: HashMap::try_from(pins.map("COMM_FILTER", Map::HashMap)?)?,
+            names: HashMap::try_from(pins.map("NAME_FILTER", Map::HashMap)?)?,
+            filters: Array::try_from(pins.map("FILTERS", Map::Array)?)?,
+        })
+    }
+
+    /// The filter the maps hold, read back from the kernel.
+    pub fn current(&self) -> Result<Filter, anyhow::Error> {
+        Ok(Filter {
+            uids: self.uids.keys().collect::<Result<_, _>>()?,
+            comms: self.comms.keys().map(|comm| Ok(unpad(&comm?))).collect::<Result<_, anyhow::Error>>()?,
+            names: self.names.keys().map(|name| Ok(unpad(&name?))).collect::<Result<_, anyhow::Error>>()?,
+        })
+    }
+
+    /// Turns the maps from `current` into `new`, in the order `plan` gives.
+    /// If a step fails, the steps before it are undone, and `current`
+    /// becomes the filter the maps hold then, read back from the kernel, so
+    /// a later apply starts from what is really there.
+    pub fn apply(&mut self, current: &mut Filter, new: &Filter) -> Result<(), anyhow::Error> {
+        let result = run(&plan(current, new), |step| self.take(step));
+        match &result {
+            Ok(()) => *current = new.clone(),
+            Err(_) => match self.current() {
+                Ok(held) => *current = held,
+                Err(e) => warn!("cannot tell what the filter maps hold: {:#}", e),
+            },
+        }
+        result
+    }
+
+    fn take(&mut self, step: &Step) -> Result<(), anyhow::Error> {
+        match step {
+            Step::Insert(Key::Uid(uid)) => self.uids.insert(uid, 1, 0)?,
+            Step::Insert(Key::Comm(comm)) => self.comms.insert(comm, 1, 0)?,
+            Step::Insert(Key::Name(name)) => self.names.insert(name, 1, 0)?,
+            Step::Remove(Key::Uid(uid)) => self.uids.remove(uid)?,
+            Step::Remove(Key::Comm(comm)) => self.comms.remove(comm)?,
+            Step::Remove(Key::Name(name)) => self.names.remove(name)?,
+            Step::Bits { to, .. } => self.filters.set(0, to, 0)?,
+        }
+        Ok(())
+    }
+
+    /// The keys of the filter map called `name`, read back from the kernel,
+    /// or `None` if it is not one of them.
+    pub fn dump(&self, name: &str) -> Result<Option<Vec<String>>, anyhow::Error> {
+        let filter = self.current()?;
+        Ok(match name {
+            "UID_FILTER" => Some(filter.uids.iter().map(u32::to_string).collect()),
+            "COMM_FILTER" => Some(filter.comms.into_iter().collect()),
+            "NAME_FILTER" => Some(filter.names.into_iter().collect()),
+            _ => None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Key {
+    Uid(u32),
+    Comm([u8; TASK_NAME_LEN]),
+    Name([u8; NAME_KEY_LEN]),
+}
+
+/// One change to the filter maps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Step {
+    Insert(Key),
+    Remove(Key),
+    /// `FILTERS` switches from `from` to `to`.
+    Bits { from: u32, to: u32 },
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (verb, key) = match self {
+            Step::Insert(key) => ("insert", key),
+            Step::Remove(key) => ("remove", key),
+            Step::Bits { to, .. } => return write!(f, "set FILTERS to {:#x}", to),
+        };
+        match key {
+            Key::Uid(uid) => write!(f, "{} uid {}", verb, uid),
+            Key::Comm(comm) => write!(f, "{} comm '{}'", verb, unpad(comm)),
+            Key::Name(name) => write!(f, "{} name '{}'", verb, unpad(name)),
+        }
+    }
+}
+
+impl Step {
+    fn undo(&self) -> Step {
+        match self {
+            Step::Insert(key) => Step::Remove(key.clone()),
+            Step::Remove(key) => Step::Insert(key.clone()),
+            Step::Bits { from, to } => Step::Bits { from: *to, to: *from },
+        }
+    }
+}
+
+/// The steps from `old` to `new`. Entries are added first, then the
+/// `FILTERS` bits change, then entries are removed: an open racing with
+/// this is judged by the old filter, the new one or, for a moment, by their
+/// union, but never by an empty map in force. A list whose union does not
+/// fit in its map has its old entries removed first instead, so for that
+/// moment it is judged by what the two lists share.
+fn plan(old: &Filter, new: &Filter) -> Vec<Step> {
+    let mut first = Vec::new();
+    let mut last = Vec::new();
+    diff(&old.uids, &new.uids, Key::Uid, &mut first, &mut last);
+    diff(&keys(&old.comms), &keys(&new.comms), Key::Comm, &mut first, &mut last);
+    diff(&keys(&old.names), &keys(&new.names), Key::Name, &mut first, &mut last);
+    first.push(Step::Bits {
+        from: bits(old),
+        to: bits(new),
+    });
+    first.extend(last);
+    first
+}
+
+/// Adds the steps from `old` to `new` for one list to those taken before
+/// and after the `FILTERS` bits change.
+fn diff<K: Ord + Copy>(old: &BTreeSet<K>, new: &BTreeSet<K>, key: fn(K) -> Key, first: &mut Vec<Step>, last: &mut Vec<Step>) {
+    let added = new.difference(old).map(|k| Step::Insert(key(*k)));
+    let removed = old.difference(new).map(|k| Step::Remove(key(*k)));
+    if old.union(new).count() <= FILTER_MAX_ENTRIES as usize {
+        first.extend(added);
+        last.extend(removed);
+    } else {
+        first.extend(removed);
+        last.extend(added);
+    }
+}
+
+/// Takes `steps` in order. If one fails, those before it are undone, the
+/// last first.
+fn run(steps: &[Step], mut take: impl FnMut(&Step) -> Result<(), anyhow::Error>) -> Result<(), anyhow::Error> {
+    for (done, failed) in steps.iter().enumerate() {
+        let Err(e) = take(failed) else {
+            continue;
+        };
+        for step in steps[..done].iter().rev() {
+            if let Err(undo) = take(&step.undo()) {
+                return Err(e.context(format!("failed to {}, and to undo the steps before it: {:#}", failed, undo)));
+            }
+        }
+        return Err(e.context(format!("failed to {}; the filter is unchanged", failed)));
+    }
+    Ok(())
+}
+
+fn bits(filter: &Filter) -> u32 {
+    let mut bits = 0;
+    if !filter.uids.is_empty() {
+        bits |= FILTER_UID;
+    }
+    if !filter.comms.is_empty() {
+        bits |= FILTER_COMM;
+    }
+    if !filter.names.is_empty() {
+        bits |= FILTER_NAME;
+    }
+    bits
+}
+
+/// The strings NUL padded to `N` bytes, as the kernel side reads them.
+fn keys<const N: usize>(strings: &BTreeSet<String>) -> BTreeSet<[u8; N]> {
+    strings
+        .iter()
+        .map(|s| {
+            let mut key = [0u8; N];
+            key[..s.len()].copy_from_slice(s.as_bytes());
+            key
+        })
+        .collect()
+}
+
+fn unpad(key: &[u8]) -> String {
+    let len = key.iter().position(|&b| b == 0).unwrap_or(key.len());
+    String::from_utf8_lossy(&key[..len]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    /// The filter maps as sets, with a count of steps to take before one
+    /// fails.
+    #[derive(Debug, Default, PartialEq, Eq)]
+    struct Maps {
+        uids: BTreeSet<u32>,
+        comms: BTreeSet<[u8; TASK_NAME_LEN]>,
+        names: BTreeSet<[u8; NAME_KEY_LEN]>,
+        bits: u32,
+    }
+
+    impl Maps {
+        fn holding(filter: &Filter) -> Self {
+            Maps {
+                uids: filter.uids.clone(),
+                comms: keys(&filter.comms),
+                names: keys(&filter.names),
+                bits: bits(filter),
+            }
+        }
+
+        /// Takes `step` as the kernel would, refusing what does not fit.
+        fn take(&mut self, step: &Step) -> Result<(), anyhow::Error> {
+            fn insert<K: Ord>(set: &mut BTreeSet<K>, key: K) -> Result<(), anyhow::Error> {
+                if set.len() >= FILTER_MAX_ENTRIES as usize {
+                    return Err(anyhow!("E2BIG"));
+                }
+                set.insert(key);
+                Ok(())
+            }
+            fn remove<K: Ord>(set: &mut BTreeSet<K>, key: &K) -> Result<(), anyhow::Error> {
+                set.remove(key).then_some(()).ok_or_else(|| anyhow!("ENOENT"))
+            }
+            match step {
+                Step::Insert(Key::Uid(uid)) => insert(&mut self.uids, *uid),
+                Step::Insert(Key::Comm(comm)) => insert(&mut self.comms, *comm),
+                Step::Insert(Key::Name(name)) => insert(&mut self.names, *name),
+                Step::Remove(Key::Uid(uid)) => remove(&mut self.uids, uid),
+                Step::Remove(Key::Comm(comm)) => remove(&mut self.comms, comm),
+                Step::Remove(Key::Name(name)) => remove(&mut self.names, name),
+                Step::Bits { to, .. } => {
+                    self.bits = *to;
+                    Ok(())
+                }
+            }
+        }
+
+        /// Every list switched on by `FILTERS` has entries.
+        fn none_empty_in_force(&self) -> bool {
+            (self.bits & FILTER_UID == 0 || !self.uids.is_empty())
+                && (self.bits & FILTER_COMM == 0 || !self.comms.is_empty())
+                && (self.bits & FILTER_NAME == 0 || !self.names.is_empty())
+        }
+    }
+
+    fn filter(uids: impl IntoIterator<Item = u32>, comms: &[&str], names: &[&str]) -> Filter {
+        Filter {
+            uids: uids.into_iter().collect(),
+            comms: comms.iter().map(|comm| comm.to_string()).collect(),
+            names: names.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    /// Runs the plan from `old` to `new`, checking the maps after each step.
+    fn apply(old: &Filter, new: &Filter) -> Maps {
+        let mut maps = Maps::holding(old);
+        run(&plan(old, new), |step| {
+            maps.take(step)?;
+            assert!(maps.none_empty_in_force(), "after {}: {:?}", step, maps);
+            Ok(())
+        })
+        .unwrap();
+        maps
+    }
+
+    #[test]
+    fn applies_only_the_difference() {
+        let old = filter([0, 1000], &["nginx"], &[]);
+        let new = filter([1000, 1001], &[], &["shadow"]);
+        let steps = plan(&old, &new);
+        assert_eq!(
+            steps.iter().map(Step::to_string).collect::<Vec<_>>(),
+            [
+                "insert uid 1001",
+                "insert name 'shadow'",
+                "set FILTERS to 0x5",
+                "remove uid 0",
+                "remove comm 'nginx'",
+            ]
+        );
+        assert_eq!(apply(&old, &new), Maps::holding(&new));
+        assert_eq!(plan(&new, &new), [Step::Bits { from: 5, to: 5 }]);
+    }
+
+    #[test]
+    fn never_leaves_a_list_in_force_empty() {
+        for (old, new) in [
+            (filter([0], &["sshd"], &["passwd"]), filter([1], &["nginx"], &["shadow"])),
+            (filter([], &[], &[]), filter([1], &["nginx"], &["shadow"])),
+            (filter([1], &["nginx"], &["shadow"]), filter([], &[], &[])),
+        ] {
+            assert_eq!(apply(&old, &new), Maps::holding(&new));
+        }
+    }
+
+    #[test]
+    fn stays_within_the_maps_when_replacing_a_full_list() {
+        let max = FILTER_MAX_ENTRIES;
+        let old = filter(0..max, &[], &[]);
+        let new = filter(max / 2..max + max / 2, &[], &[]);
+        // Adding first would overflow `UID_FILTER`, and the fake refuses that.
+        assert_eq!(apply(&old, &new), Maps::holding(&new));
+        assert!(matches!(plan(&old, &new)[0], Step::Remove(Key::Uid(0))));
+        // One that fits still adds first.
+        let new = filter(1..max, &[], &[]);
+        let old = filter(0..max - 1, &[], &[]);
+        assert!(matches!(plan(&old, &new)[0], Step::Insert(Key::Uid(uid)) if uid == max - 1));
+    }
+
+    #[test]
+    fn undoes_the_steps_before_a_failure() {
+        let old = filter([0, 1000], &["nginx"], &[]);
+        let new = filter([1000, 1001], &["sshd"], &["shadow"]);
+        let steps = plan(&old, &new);
+        for fail_at in 0..steps.len() {
+            let mut maps = Maps::holding(&old);
+            let mut taken = 0;
+            let e = run(&steps, |step| {
+                taken += 1;
+                if taken == fail_at + 1 {
+                    return Err(anyhow!("EPERM"));
+                }
+                maps.take(step)
+            })
+            .unwrap_err();
+            assert_eq!(maps, Maps::holding(&old), "failing step {}", fail_at);
+            assert!(format!("{:#}", e).contains("the filter is unchanged"), "{:#}", e);
+        }
+    }
+
+    #[test]
+    fn reports_a_failed_undo() {
+        let old = filter([0], &[], &[]);
+        let new = filter([1], &[], &[]);
+        let mut calls = 0;
+        let e = run(&plan(&old, &new), |_| {
+            calls += 1;
+            // The insert goes through, the bits fail and so does the undo.
+            if calls > 1 {
+                return Err(anyhow!("EPERM"));
+            }
+            Ok(())
+        })
+        .unwrap_err();
+        assert_eq!(format!("{:#}", e), "failed to set FILTERS to 0x1, and to undo the steps before it: EPERM: EPERM");
+    }
+
+    #[test]
+    fn parses_configs() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.target, DEFAULT_TARGET);
+        assert_eq!(config.filter, Filter::default());
+
+        let config: Config = toml::from_str(
+            r#"
+            target = "vfs_open"
+            [filter]
+            uids = [0, 1000, 0]
+            comms = ["nginx"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.target, "vfs_open");
+        assert_eq!(config.filter, filter([0, 1000], &["nginx"], &[]));
+
+        for text in ["tagret = \"vfs_open\"", "[filter]\nuid = [0]", "[filter]\nuids = [-1]", "[filter]\nnames = \"shadow\""] {
+            assert!(toml::from_str::<Config>(text).is_err(), "accepted {:?}", text);
+        }
+    }
+
+    #[test]
+    fn refuses_filters_the_maps_cannot_hold() {
+        assert!(filter([0], &["123456789012345"], &["shadow"]).check().is_ok());
+        for (filter, error) in [
+            (filter(0..FILTER_MAX_ENTRIES + 1, &[], &[]), "uids has 1025 entries"),
+            (filter([], &["1234567890123456"], &[]), "comm '1234567890123456'"),
+            (filter([], &[], &["etc/shadow"]), "name 'etc/shadow'"),
+            (filter([], &[], &[&"x".repeat(NAME_KEY_LEN)]), "not a file name"),
+        ] {
+            let e = filter.check().unwrap_err();
+            assert!(e.to_string().contains(error), "{} does not mention {}", e, error);
+        }
+    }
+
+    #[test]
+    fn loads_and_checks_a_file() {
+        let path = std::env::temp_dir().join(format!("hashmap-config-{}.toml", std::process::id()));
+        fs::write(&path, "[filter]\ncomms = [\"a-task-name-too-long\"]\n").unwrap();
+        let e = Config::load(&path).unwrap_err();
+        assert!(format!("{:#}", e).contains("bad config file"), "{:#}", e);
+        fs::write(&path, "[filter]\ncomms = [\"sshd\"]\n").unwrap();
+        assert_eq!(Config::load(&path).unwrap().filter, filter([], &["sshd"], &[]));
+        fs::remove_file(&path).unwrap();
+        assert!(format!("{:#}", Config::load(&path).unwrap_err()).contains("failed to read"));
+    }
+}
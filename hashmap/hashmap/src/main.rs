@@ -1,13 +1,34 @@
-use std::collections::HashMap;
+mod config;
+mod metrics;
+mod pin;
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 
-use aya::maps::PerCpuHashMap;
+use anyhow::{bail, Context as _};
+use aya::maps::{Array, Map, MapData, PerCpuArray, PerCpuHashMap, PerCpuValues};
+use aya::programs::kprobe::KProbeLinkId;
+use aya::programs::links::FdLink;
 use aya::programs::KProbe;
+use aya::util::nr_cpus;
 use aya::{include_bytes_aligned, Bpf};
 use aya_log::BpfLogger;
+use clap::{Parser, Subcommand};
+use config::{Config, Filter, FilterMaps};
 use hashmap_common::FileOpenInfo;
 use log::{info, warn, debug};
-use tokio::signal;
+use metrics::{Attached, Metrics};
+use pin::Pins;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tracer_support::control::{self, ControlSocket, Emission, Request, RpcError};
+use tracer_support::daemon::{self, Daemon, Signal, Signals};
+use tracer_support::features::{Features, Need};
+use tracer_support::sandbox;
+use tracer_support::verifier;
 // use aya::Pod;
 
 
@@ -20,9 +41,112 @@ use tokio::signal;
 
 // unsafe impl Pod for FileOpenInfo {}
 
-#[tokio::main]
+static EMISSION: Emission = Emission::new();
+
+/// The eBPF source, to name the function of a line the verifier quotes.
+const EBPF_SOURCES: verifier::Sources = &[("main.rs", include_str!("../../hashmap-ebpf/src/main.rs"))];
+
+#[derive(Debug, Parser)]
+struct Opt {
+    /// Serve Prometheus metrics on this address, e.g. 127.0.0.1:9100
+    #[clap(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// Filters and attach target, in TOML; reread on SIGHUP
+    #[clap(long)]
+    config: Option<PathBuf>,
+    /// Serve the control API on this Unix socket
+    #[clap(long)]
+    control: Option<PathBuf>,
+    /// Pin the programs and maps to this directory on a BPF filesystem, so
+    /// counting goes on after exit, or reuse what an earlier run pinned there
+    #[clap(long)]
+    pin: Option<PathBuf>,
+    /// Run as a system service: log JSON lines, notify systemd and feed its
+    /// watchdog while the self-check passes
+    #[clap(long)]
+    daemon: bool,
+    /// Run the self-check this often, in seconds
+    #[clap(long, default_value_t = 30, requires = "daemon")]
+    check_interval: u64,
+    /// Fail the self-check once no open was counted for this many seconds
+    #[clap(long, requires = "daemon")]
+    max_idle: Option<u64>,
+    /// Once attached, drop root and run as this user, under a seccomp filter
+    #[clap(long)]
+    user: Option<String>,
+    /// Write the verifier log here if a program fails to load
+    #[clap(long)]
+    verifier_log: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Send a request to the control socket of a running hashmap and print the result
+    Ctl {
+        /// Path of the control socket
+        socket: PathBuf,
+        /// Method to call, e.g. stats, dump or set_filters
+        method: String,
+        /// Params as a JSON object
+        params: Option<String>,
+    },
+    /// Zero the counters pinned to a directory
+    Reset {
+        /// The pin directory
+        dir: PathBuf,
+    },
+    /// Remove the pins in a directory, which detaches the program and frees the maps
+    Unpin {
+        /// The pin directory
+        dir: PathBuf,
+    },
+    /// Print what the kernel supports and whether the configured target is
+    /// there to attach to, without loading any eBPF
+    Features,
+}
+
+/// The two copies of the counting program, by `ACTIVE` slot.
+const PROGRAMS: [&str; 2] = ["hashmap", "hashmap_standby"];
+/// The maps in a pin directory, next to the programs.
+const PINNED_MAPS: [&str; 7] = ["FILE_OPEN_COUNT", "LOST", "UID_FILTER", "COMM_FILTER", "NAME_FILTER", "FILTERS", "ACTIVE"];
+
+/// Everything in a pin directory besides the link.
+fn pinned() -> Vec<&'static str> {
+    [PINNED_MAPS.as_slice(), PROGRAMS.as_slice()].concat()
+}
+
+// With --user only the main thread, and the threads it starts, may keep
+// CAP_BPF for the maps, so every task runs there.
+#[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), anyhow::Error> {
-    env_logger::init();
+    let opt = Opt::parse();
+
+    daemon::init_logger(opt.daemon);
+
+    match &opt.command {
+        Some(Command::Ctl { socket, method, params }) => return control::cli(socket, method, params.as_deref()),
+        Some(Command::Reset { dir }) => return reset(&Pins::existing(dir)?),
+        Some(Command::Unpin { dir }) => {
+            pin::unpin(dir, &pinned())?;
+            info!("unpinned {}", dir.display());
+            return Ok(());
+        }
+        Some(Command::Features) | None => {}
+    }
+
+    let mut config = match &opt.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let features = Features::probe();
+    if let Some(Command::Features) = &opt.command {
+        for line in report(&features, &config.target) {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
 
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
@@ -35,6 +159,104 @@ async fn main() -> Result<(), anyhow::Error> {
         debug!("remove limit on locked memory failed, ret is: {}", ret);
     }
 
+    for line in report(&features, &config.target) {
+        debug!("{}", line);
+    }
+    features.check("hashmap", &[Need::Kprobes])?;
+
+    let pins = opt.pin.as_deref().map(Pins::create).transpose()?;
+    let (mut filters, metrics, mut probe) = match &pins {
+        Some(pins) if pins.exist() => {
+            info!("Reusing the program and maps pinned at {}", pins.dir().display());
+            let (filters, metrics) = reuse(pins, opt.config.is_some(), &mut config)?;
+            (filters, metrics, None)
+        }
+        _ => load(&config, features, pins.as_ref(), opt.verifier_log.as_deref()).inspect_err(|_| {
+            // Leave nothing half pinned behind.
+            if let Some(pins) = &pins {
+                let _ = pin::unpin(pins.dir(), &pinned());
+            }
+        })?,
+    };
+    let metrics = Arc::new(metrics);
+    if let Some(addr) = opt.metrics_addr {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Serving metrics on http://{}/metrics", addr);
+        let metrics = metrics.clone();
+        tokio::spawn(metrics::serve(listener, move || metrics.snapshot()));
+    }
+    let mut control = opt.control.as_deref().map(ControlSocket::bind).transpose()?;
+    let mut daemon = opt.daemon.then(|| Daemon::new(Duration::from_secs(opt.check_interval), opt.max_idle.map(Duration::from_secs))).transpose()?;
+    if let Some(daemon) = &mut daemon {
+        daemon.watch(&watched(probe.as_ref(), pins.as_ref())?);
+    }
+
+    let mut signals = Signals::new()?;
+    if let Some(user) = &opt.user {
+        sandbox::enter(user, true)?;
+    }
+    if let Some(daemon) = &daemon {
+        daemon.ready();
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(3));
+    info!("Waiting for Ctrl-C...");
+    loop {
+        tokio::select! {
+            request = ControlSocket::next(&mut control) => {
+                let result = handle_request(&request, &metrics, &mut config, &mut filters, probe.as_ref(), pins.as_ref());
+                request.reply(result);
+            }
+            _ = interval.tick() => {
+                if EMISSION.suppress() {
+                    continue;
+                }
+                for (uid, cnt) in metrics.open_counts()? {
+                    // info!("uid: {}, open count: {}", uid, cnt);
+                    println!("uid: {}, open count: {}", uid, cnt);
+                }
+            }
+            _ = Daemon::tick(&mut daemon) => {
+                if let Some(daemon) = &mut daemon {
+                    daemon.check(metrics.open_counts()?.values().sum::<u64>() + metrics.lost()?);
+                }
+            }
+            signal = signals.next() => match signal {
+                Signal::Shutdown => break,
+                Signal::Reload => {
+                    if let Some(daemon) = &daemon {
+                        daemon.reloading();
+                    }
+                    match &opt.config {
+                        Some(path) => reload(path, &mut config, &mut filters, probe.as_mut()),
+                        None => info!("no --config to reload"),
+                    }
+                    if let Some(probe) = &probe {
+                        metrics.attached.set(probe.links);
+                    }
+                    if let Some(daemon) = &mut daemon {
+                        // A new target is counted by the other copy. After
+                        // --user the pins may be out of reach.
+                        match watched(probe.as_ref(), pins.as_ref()) {
+                            Ok(programs) => daemon.watch(&programs),
+                            Err(e) => warn!("the self-check keeps watching the same programs: {:#}", e),
+                        }
+                        daemon.ready();
+                    }
+                }
+            },
+        }
+    }
+    if let Some(daemon) = &daemon {
+        daemon.stopping();
+    }
+    info!("Exiting...");
+
+    Ok(())
+}
+
+/// Loads the programs and attaches one of them, and with `pins` pins them
+/// and the maps. Then the probe is left to the pins.
+fn load(config: &Config, features: Features, pins: Option<&Pins>, verifier_log: Option<&Path>) -> Result<(FilterMaps, Metrics, Option<Probe>), anyhow::Error> {
     // This will include your eBPF object file as raw bytes at compile-time and load it at
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
@@ -51,36 +273,240 @@ async fn main() -> Result<(), anyhow::Error> {
         // This can happen if you remove all log statements from your eBPF program.
         warn!("failed to initialize eBPF logger: {}", e);
     }
-    let program: &mut KProbe = bpf.program_mut("hashmap").unwrap().try_into()?;
-    program.load()?;
-    program.attach("security_file_open", 0)?;
+    for name in PROGRAMS {
+        let program: &mut KProbe = bpf.program_mut(name).unwrap().try_into()?;
+        program.load().map_err(|e| verifier::explain(name, e, verifier_log, EBPF_SOURCES))?;
+    }
+    if let Some(pins) = pins {
+        pins.pin_maps(&bpf, &PINNED_MAPS)?;
+    }
+    // The filters are in place before anything is counted.
+    let mut filters = FilterMaps::new(&mut bpf)?;
+    filters.apply(&mut Filter::default(), &config.filter)?;
+    let metrics = Metrics {
+        file_open_count: PerCpuHashMap::try_from(bpf.take_map("FILE_OPEN_COUNT").unwrap())?,
+        lost: PerCpuArray::try_from(bpf.take_map("LOST").unwrap())?,
+        attached: match pins {
+            Some(pins) => Attached::Pinned(pins.link()),
+            None => Attached::Held(Default::default()),
+        },
+    };
+    let probe = Probe::attach(bpf, features, &config.target)?;
+    metrics.attached.set(probe.links);
+    match pins {
+        Some(pins) => {
+            probe.pin(pins)?;
+            info!("Pinned the program and maps to {}", pins.dir().display());
+            Ok((filters, metrics, None))
+        }
+        None => Ok((filters, metrics, Some(probe))),
+    }
+}
 
-    let mut interval = tokio::time::interval(Duration::from_secs(3));
-    info!("Waiting for Ctrl-C...");
-    loop {
-        tokio::select! {
-            _ = interval.tick() => {
-                let file_open_map: PerCpuHashMap<_, u32, FileOpenInfo> = PerCpuHashMap::try_from(bpf.map("FILE_OPEN_COUNT").unwrap())?;
-
-                let mut total_map: HashMap<u32, u64> = HashMap::new();
-                for ret in file_open_map.iter() {
-                    let (uid, cpu_data) = ret?;
-                    let total_cnt = cpu_data.iter().map(|info| info.count).sum::<u64>();
-                    let counter = total_map.entry(uid).or_insert(0);
-                    *counter += total_cnt;
-                }
+/// Opens what an earlier run pinned. The pinned filter stays, unless there
+/// is a config file to replace it; the pinned target always stays.
+fn reuse(pins: &Pins, configured: bool, config: &mut Config) -> Result<(FilterMaps, Metrics), anyhow::Error> {
+    let mut filters = FilterMaps::pinned(pins)?;
+    let mut pinned = filters.current()?;
+    if configured {
+        filters.apply(&mut pinned, &config.filter)?;
+    } else {
+        config.filter = pinned;
+    }
+    let metrics = Metrics {
+        file_open_count: PerCpuHashMap::try_from(pins.map("FILE_OPEN_COUNT", Map::PerCpuHashMap)?)?,
+        lost: PerCpuArray::try_from(pins.map("LOST", Map::PerCpuArray)?)?,
+        attached: Attached::Pinned(pins.link()),
+    };
+    Ok((filters, metrics))
+}
 
-                for (uid, cnt) in total_map {
-                    // info!("uid: {}, open count: {}", uid, cnt);
-                    println!("uid: {}, open count: {}", uid, cnt);
+/// Zeroes the pinned `FILE_OPEN_COUNT` and `LOST`. The program keeps
+/// counting meanwhile, so an open may be counted from scratch right away.
+fn reset(pins: &Pins) -> Result<(), anyhow::Error> {
+    let mut counts: PerCpuHashMap<_, u32, FileOpenInfo> = PerCpuHashMap::try_from(pins.map("FILE_OPEN_COUNT", Map::PerCpuHashMap)?)?;
+    let uids = counts.keys().collect::<Result<Vec<_>, _>>()?;
+    for uid in &uids {
+        counts.remove(uid)?;
+    }
+    let mut lost: PerCpuArray<_, u64> = PerCpuArray::try_from(pins.map("LOST", Map::PerCpuArray)?)?;
+    lost.set(0, PerCpuValues::try_from(vec![0; nr_cpus()?])?, 0)?;
+    info!("reset the counters of {} users", uids.len());
+    Ok(())
+}
+
+/// The copy of the program that counts, by name and id, for the self-check.
+fn watched(probe: Option<&Probe>, pins: Option<&Pins>) -> Result<Vec<(&'static str, u32)>, anyhow::Error> {
+    match (probe, pins) {
+        (Some(probe), _) => daemon::program_ids(&probe.bpf, [PROGRAMS[probe.slot as usize]]),
+        (None, Some(pins)) => {
+            let active: Array<_, u32> = Array::try_from(pins.map("ACTIVE", Map::Array)?)?;
+            let name = PROGRAMS[active.get(&0, 0)? as usize];
+            Ok(vec![(name, pins.program_id(name)?)])
+        }
+        (None, None) => Ok(Vec::new()),
+    }
+}
+
+/// What the kernel supports, then where the counting program would attach.
+fn report(features: &Features, target: &str) -> Vec<String> {
+    let mut lines = features.report(&[]);
+    lines.push(match features.attach_point(&[target]) {
+        Some(target) => format!("{} attach to {}", PROGRAMS.join(", "), target),
+        None => format!("{} is not in this kernel, so hashmap cannot run", target),
+    });
+    lines
+}
+
+/// Where the counting program is attached.
+struct Probe {
+    bpf: Bpf,
+    /// To check a new target before attaching to it.
+    features: Features,
+    slot: u32,
+    target: String,
+    link: KProbeLinkId,
+    /// Links attached through either copy; more than one while a retarget
+    /// could not detach the old one.
+    links: usize,
+    active: Array<MapData, u32>,
+}
+
+impl Probe {
+    fn attach(mut bpf: Bpf, features: Features, target: &str) -> Result<Self, anyhow::Error> {
+        let active = Array::try_from(bpf.take_map("ACTIVE").unwrap())?;
+        check_target(&features, target)?;
+        let program: &mut KProbe = bpf.program_mut(PROGRAMS[0]).unwrap().try_into()?;
+        let link = program.attach(target, 0).with_context(|| format!("failed to attach {} to {}", PROGRAMS[0], target))?;
+        Ok(Probe {
+            bpf,
+            features,
+            slot: 0,
+            target: target.to_owned(),
+            link,
+            links: 1,
+            active,
+        })
+    }
+
+    /// Attaches the idle copy of the program to `target`, hands counting over
+    /// to it and detaches the other one.
+    fn retarget(&mut self, target: &str) -> Result<(), anyhow::Error> {
+        let next = 1 - self.slot;
+        check_target(&self.features, target)?;
+        let program: &mut KProbe = self.bpf.program_mut(PROGRAMS[next as usize]).unwrap().try_into()?;
+        let link = program.attach(target, 0).with_context(|| format!("failed to attach {} to {}", PROGRAMS[next as usize], target))?;
+        self.links += 1;
+        if let Err(e) = self.active.set(0, next, 0) {
+            program.detach(link)?;
+            self.links -= 1;
+            return Err(e.into());
+        }
+        let old = std::mem::replace(&mut self.link, link);
+        let program: &mut KProbe = self.bpf.program_mut(PROGRAMS[self.slot as usize]).unwrap().try_into()?;
+        self.slot = next;
+        self.target = target.to_owned();
+        program.detach(old)?;
+        self.links -= 1;
+        Ok(())
+    }
+
+    /// Pins both programs and the link of the attached one. From then on
+    /// only unpinning detaches it.
+    fn pin(self, pins: &Pins) -> Result<(), anyhow::Error> {
+        let Probe { mut bpf, slot, link, .. } = self;
+        pins.pin_programs(&mut bpf, &PROGRAMS)?;
+        let program: &mut KProbe = bpf.program_mut(PROGRAMS[slot as usize]).unwrap().try_into()?;
+        let link = FdLink::try_from(program.take_link(link)?).context("this kernel cannot pin kprobe links, which needs 5.15 or later")?;
+        pins.pin_link(link)
+    }
+}
+
+/// Fails, naming `target`, if the kernel has no such function to attach to.
+fn check_target(features: &Features, target: &str) -> Result<(), anyhow::Error> {
+    match features.attach_point(&[target]) {
+        Some(_) => Ok(()),
+        None => bail!("cannot attach to {}: not in this kernel, or inlined or renamed in it", target),
+    }
+}
+
+/// Rereads the config file and moves the filters and the probe over to it.
+/// If that fails, what was in force stays in force.
+fn reload(path: &Path, config: &mut Config, filters: &mut FilterMaps, probe: Option<&mut Probe>) {
+    let new = match Config::load(path) {
+        Ok(new) => new,
+        Err(e) => {
+            warn!("keeping the previous config: {:#}", e);
+            return;
+        }
+    };
+    if let Err(e) = filters.apply(&mut config.filter, &new.filter) {
+        warn!("failed to update the filters: {:#}", e);
+        return;
+    }
+    match probe {
+        Some(probe) => {
+            if new.target != probe.target {
+                match probe.retarget(&new.target) {
+                    Ok(()) => info!("now counting opens at {}", probe.target),
+                    Err(e) => warn!("still counting opens at {}: {:#}", probe.target, e),
                 }
             }
-            _ = signal::ctrl_c() => {
-                break;
-            }
+            config.target = probe.target.clone();
         }
+        None if new.target != config.target => warn!("the pinned probe stays where it is; unpin it to move it to {}", new.target),
+        None => {}
     }
-    info!("Exiting...");
+    info!("reloaded {}", path.display());
+}
 
-    Ok(())
+#[derive(Deserialize)]
+struct DumpParams {
+    map: String,
+}
+
+fn handle_request(
+    request: &Request,
+    metrics: &Metrics,
+    config: &mut Config,
+    filters: &mut FilterMaps,
+    probe: Option<&Probe>,
+    pins: Option<&Pins>,
+) -> Result<Value, anyhow::Error> {
+    match request.method.as_str() {
+        "programs" => match probe {
+            Some(probe) => Ok(json!([{ "program": PROGRAMS[probe.slot as usize], "function": probe.target }])),
+            None => Ok(json!([{ "pinned": pins.map(Pins::dir) }])),
+        },
+        "stats" => Ok(json!({
+            "lost": metrics.lost()?,
+            "suppressed": EMISSION.suppressed(),
+            "paused": EMISSION.paused(),
+        })),
+        "pause" | "resume" => {
+            EMISSION.set_paused(request.method == "pause");
+            Ok(json!({ "paused": EMISSION.paused() }))
+        }
+        "dump" => {
+            let DumpParams { map } = request.params()?;
+            if map == "FILE_OPEN_COUNT" {
+                let mut counts: Vec<_> = metrics.open_counts()?.into_iter().collect();
+                counts.sort_unstable();
+                return Ok(counts.iter().map(|(uid, count)| json!({ "uid": uid, "count": count })).collect());
+            }
+            match filters.dump(&map)? {
+                Some(keys) => Ok(json!(keys)),
+                None => Err(RpcError::invalid_params(format!("no map '{}' to dump", map)).into()),
+            }
+        }
+        "filters" => Ok(json!(config.filter)),
+        "set_filters" => {
+            let filter: Filter = request.params()?;
+            filter.check().map_err(RpcError::invalid_params)?;
+            filters.apply(&mut config.filter, &filter)?;
+            info!("filters changed over the control socket");
+            Ok(json!(config.filter))
+        }
+        method => Err(RpcError::method_not_found(method).into()),
+    }
 }
@@ -0,0 +1,255 @@
+//! Prometheus exposition of the hashmap counters.
+//!
+//! This is a deliberately small HTTP/1.1 server: it answers `GET /metrics`
+//! with the text format and everything else with 404, one request per
+//! connection.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use aya::maps::{MapData, PerCpuArray, PerCpuHashMap};
+use aya::programs::links::PinnedLink;
+use hashmap_common::{FileOpenInfo, FILE_OPEN_COUNT_MAX_ENTRIES};
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracer_support::users::username;
+
+const MAX_REQUEST_LEN: usize = 8192;
+
+pub struct Metrics {
+    pub file_open_count: PerCpuHashMap<MapData, u32, FileOpenInfo>,
+    pub lost: PerCpuArray<MapData, u64>,
+    pub attached: Attached,
+}
+
+/// Where the links that keep the program attached are found.
+pub enum Attached {
+    /// Links this process holds; it updates the count as it attaches and
+    /// detaches.
+    Held(AtomicUsize),
+    /// The link pinned at this path, which is attached as long as the pin
+    /// can be opened.
+    Pinned(PathBuf),
+}
+
+impl Attached {
+    pub fn set(&self, links: usize) {
+        if let Attached::Held(count) = self {
+            count.store(links, Ordering::Relaxed);
+        }
+    }
+
+    fn count(&self) -> usize {
+        match self {
+            Attached::Held(count) => count.load(Ordering::Relaxed),
+            Attached::Pinned(path) => PinnedLink::from_pin(path).is_ok() as usize,
+        }
+    }
+}
+
+impl Metrics {
+    /// Open counts per uid, summed over all CPUs.
+    pub fn open_counts(&self) -> Result<HashMap<u32, u64>, anyhow::Error> {
+        let mut total_map: HashMap<u32, u64> = HashMap::new();
+        for ret in self.file_open_count.iter() {
+            let (uid, cpu_data) = ret?;
+            let total_cnt = cpu_data.iter().map(|info| info.count).sum::<u64>();
+            let counter = total_map.entry(uid).or_insert(0);
+            *counter += total_cnt;
+        }
+        Ok(total_map)
+    }
+
+    pub fn lost(&self) -> Result<u64, anyhow::Error> {
+        Ok(self.lost.get(&0, 0)?.iter().sum())
+    }
+
+    /// Reads the maps for one scrape.
+    pub fn snapshot(&self) -> Result<Snapshot, anyhow::Error> {
+        Ok(Snapshot {
+            open_counts: self.open_counts()?,
+            lost: self.lost()?,
+            programs_attached: self.attached.count(),
+        })
+    }
+}
+
+/// The counters as one scrape saw them.
+pub struct Snapshot {
+    pub open_counts: HashMap<u32, u64>,
+    pub lost: u64,
+    pub programs_attached: usize,
+}
+
+impl Snapshot {
+    pub fn render(&self) -> Result<String, anyhow::Error> {
+        let counts = &self.open_counts;
+        let mut out = String::new();
+
+        writeln!(out, "# HELP hashmap_file_opens_total Files opened, by user.")?;
+        writeln!(out, "# TYPE hashmap_file_opens_total counter")?;
+        let mut uids: Vec<_> = counts.keys().copied().collect();
+        uids.sort_unstable();
+        for uid in uids {
+            let user = username(uid).unwrap_or_else(|| uid.to_string());
+            writeln!(
+                out,
+                "hashmap_file_opens_total{{uid=\"{}\",user=\"{}\"}} {}",
+                uid,
+                escape_label(&user),
+                counts[&uid]
+            )?;
+        }
+
+        writeln!(out, "# HELP hashmap_programs_attached eBPF programs currently attached.")?;
+        writeln!(out, "# TYPE hashmap_programs_attached gauge")?;
+        writeln!(out, "hashmap_programs_attached {}", self.programs_attached)?;
+
+        writeln!(out, "# HELP hashmap_map_fill_ratio Used fraction of the map's max entries.")?;
+        writeln!(out, "# TYPE hashmap_map_fill_ratio gauge")?;
+        writeln!(
+            out,
+            "hashmap_map_fill_ratio{{map=\"FILE_OPEN_COUNT\"}} {}",
+            counts.len() as f64 / FILE_OPEN_COUNT_MAX_ENTRIES as f64
+        )?;
+
+        writeln!(out, "# HELP hashmap_events_lost_total Opens not counted because the map was full.")?;
+        writeln!(out, "# TYPE hashmap_events_lost_total counter")?;
+        writeln!(out, "hashmap_events_lost_total {}", self.lost)?;
+
+        Ok(out)
+    }
+}
+
+/// Answers scrapes with what `snapshot` reads.
+pub async fn serve<F>(listener: TcpListener, snapshot: F)
+where
+    F: Fn() -> Result<Snapshot, anyhow::Error> + Send + Sync + 'static,
+{
+    let snapshot = Arc::new(snapshot);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                debug!("failed to accept metrics connection: {}", e);
+                continue;
+            }
+        };
+        let snapshot = snapshot.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, snapshot).await {
+                debug!("metrics request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle<F>(mut stream: TcpStream, snapshot: Arc<F>) -> Result<(), anyhow::Error>
+where
+    F: Fn() -> Result<Snapshot, anyhow::Error> + Send + Sync + 'static,
+{
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request_line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|&b| b == b' ');
+    let (method, target) = (parts.next(), parts.next());
+
+    let response = if method == Some(b"GET") && target == Some(b"/metrics") {
+        // The maps are read here, on the runtime thread, which keeps CAP_BPF
+        // under --user. Resolving uids can hit NSS, so only that moves off it.
+        let snapshot = snapshot()?;
+        let body = tokio::task::spawn_blocking(move || snapshot.render()).await??;
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// Serves fake counts on a free port and returns its address.
+    async fn server() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, || {
+            Ok(Snapshot {
+                open_counts: HashMap::from([(0, 7), (4_000_000_000, 2)]),
+                lost: 3,
+                programs_attached: 1,
+            })
+        }));
+        addr
+    }
+
+    async fn get(addr: std::net::SocketAddr, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        // The server may close before reading all of an oversized request.
+        let _ = stream.write_all(request).await;
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_the_counters() {
+        let addr = server().await;
+        let response = get(addr, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("Content-Type: text/plain; version=0.0.4"));
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        let root = username(0).unwrap_or_else(|| "0".to_owned());
+        assert!(body.contains(&format!("hashmap_file_opens_total{{uid=\"0\",user=\"{}\"}} 7\n", root)), "{}", body);
+        // Users missing from the user database are named by uid.
+        assert!(body.contains("hashmap_file_opens_total{uid=\"4000000000\",user=\"4000000000\"} 2\n"));
+        assert!(body.contains("# TYPE hashmap_programs_attached gauge\nhashmap_programs_attached 1\n"));
+        assert!(body.contains(&format!(
+            "hashmap_map_fill_ratio{{map=\"FILE_OPEN_COUNT\"}} {}\n",
+            2.0 / FILE_OPEN_COUNT_MAX_ENTRIES as f64
+        )));
+        assert!(body.ends_with("# TYPE hashmap_events_lost_total counter\nhashmap_events_lost_total 3\n"));
+    }
+
+    #[tokio::test]
+    async fn answers_anything_else_with_404() {
+        let addr = server().await;
+        for request in [&b"GET / HTTP/1.1\r\n\r\n"[..], b"POST /metrics HTTP/1.1\r\n\r\n", b"nonsense\r\n\r\n"] {
+            assert_eq!(get(addr, request).await, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_oversized_requests() {
+        let addr = server().await;
+        let mut request = b"GET /metrics HTTP/1.1\r\nX-Padding: ".to_vec();
+        request.resize(MAX_REQUEST_LEN + 1, b'a');
+        request.extend_from_slice(b"\r\n\r\n");
+        assert_eq!(get(addr, &request).await, "");
+    }
+}
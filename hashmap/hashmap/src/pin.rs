@@ -0,0 +1,224 @@
+//! Pinning to a BPF filesystem, so the programs keep running and the maps
+//! keep their contents after the loader exits.
+//!
+//! A pin directory holds every map under its own name, every program under
+//! its own name, and the link that attaches the program, as `link`:
+//!
+//! ```text
+//! /sys/fs/bpf/hashmap/FILE_OPEN_COUNT
+//! /sys/fs/bpf/hashmap/hashmap
+//! /sys/fs/bpf/hashmap/link
+//! ```
+//!
+//! Removing the pins unloads all of it once no loader holds it open any more.
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context as _};
+use aya::maps::{Map, MapData};
+use aya::programs::links::FdLink;
+use aya::programs::ProgramInfo;
+use aya::Bpf;
+
+const LINK: &str = "link";
+
+pub struct Pins {
+    dir: PathBuf,
+}
+
+impl Pins {
+    /// The pin directory `dir`, which is created if need be.
+    pub fn create(dir: &Path) -> Result<Self, anyhow::Error> {
+        // Checked before anything is created where it does not belong.
+        let existing = dir.ancestors().find(|path| path.exists()).unwrap_or(Path::new("."));
+        if !is_bpf_fs(existing)? {
+            bail!("{} is not on a BPF filesystem, e.g. one mounted with `mount -t bpf bpf /sys/fs/bpf`", dir.display());
+        }
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        Ok(Pins { dir: dir.to_owned() })
+    }
+
+    /// The pin directory `dir`, which an earlier run must have pinned to.
+    pub fn existing(dir: &Path) -> Result<Self, anyhow::Error> {
+        let pins = Pins { dir: dir.to_owned() };
+        if !pins.exist() {
+            bail!("nothing is pinned at {}", dir.display());
+        }
+        Ok(pins)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Whether the programs are pinned here and still attached.
+    pub fn exist(&self) -> bool {
+        self.path(LINK).exists()
+    }
+
+    /// Where the link is pinned.
+    pub fn link(&self) -> PathBuf {
+        self.path(LINK)
+    }
+
+    /// The pinned map `name`, as the `Map` variant `kind`, e.g. `Map::HashMap`.
+    pub fn map(&self, name: &str, kind: fn(MapData) -> Map) -> Result<Map, anyhow::Error> {
+        let path = self.path(name);
+        let map = MapData::from_pin(&path).with_context(|| format!("failed to open pinned map {}", path.display()))?;
+        Ok(kind(map))
+    }
+
+    /// The id the kernel gave the pinned program `name`.
+    pub fn program_id(&self, name: &str) -> Result<u32, anyhow::Error> {
+        let path = self.path(name);
+        let info = ProgramInfo::from_pin(&path).with_context(|| format!("failed to open pinned program {}", path.display()))?;
+        Ok(info.id())
+    }
+
+    pub fn pin_maps(&self, bpf: &Bpf, names: &[&str]) -> Result<(), anyhow::Error> {
+        for name in names {
+            let path = self.path(name);
+            let map = bpf.map(name).with_context(|| format!("the object has no map {} to pin", name))?;
+            map.pin(&path).with_context(|| format!("failed to pin map {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    pub fn pin_programs(&self, bpf: &mut Bpf, names: &[&str]) -> Result<(), anyhow::Error> {
+        for name in names {
+            let path = self.path(name);
+            let program = bpf.program_mut(name).with_context(|| format!("the object has no program {} to pin", name))?;
+            program.pin(&path).with_context(|| format!("failed to pin program {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Where `name`, a map, a program or the link, is pinned.
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    pub fn pin_link(&self, link: FdLink) -> Result<(), anyhow::Error> {
+        let path = self.path(LINK);
+        link.pin(&path).with_context(|| format!("failed to pin link {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Removes the link, the pins called `names` and then `dir` itself. Pins that
+/// are missing, e.g. because pinning failed halfway, are skipped.
+pub fn unpin(dir: &Path, names: &[&str]) -> Result<(), anyhow::Error> {
+    if !dir.is_dir() {
+        bail!("nothing is pinned at {}", dir.display());
+    }
+    for name in [LINK].iter().chain(names) {
+        let path = dir.join(name);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("failed to unpin {}", path.display()));
+            }
+            _ => {}
+        }
+    }
+    fs::remove_dir(dir).with_context(|| format!("failed to remove {}", dir.display()))
+}
+
+fn is_bpf_fs(path: &Path) -> Result<bool, anyhow::Error> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let stat = unsafe { stat.assume_init() };
+    Ok(stat.f_type as u32 == libc::BPF_FS_MAGIC as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A scratch directory, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("hashmap-pin-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn touch(dir: &Path, names: &[&str]) {
+        for name in names {
+            fs::write(dir.join(name), "").unwrap();
+        }
+    }
+
+    #[test]
+    fn pins_everything_directly_in_the_directory() {
+        let pins = Pins {
+            dir: PathBuf::from("/sys/fs/bpf/hashmap"),
+        };
+        assert_eq!(pins.dir(), Path::new("/sys/fs/bpf/hashmap"));
+        assert_eq!(pins.path("FILE_OPEN_COUNT"), Path::new("/sys/fs/bpf/hashmap/FILE_OPEN_COUNT"));
+        assert_eq!(pins.path("hashmap"), Path::new("/sys/fs/bpf/hashmap/hashmap"));
+        assert_eq!(pins.link(), Path::new("/sys/fs/bpf/hashmap/link"));
+    }
+
+    #[test]
+    fn refuses_a_directory_off_the_bpf_filesystem() {
+        let tmp = TempDir::new("create");
+        let dir = tmp.0.join("hashmap");
+        let e = Pins::create(&dir).err().unwrap();
+        assert!(e.to_string().contains("is not on a BPF filesystem"), "{}", e);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn reuses_only_pins_with_a_link() {
+        let tmp = TempDir::new("reuse");
+        let pins = Pins { dir: tmp.0.clone() };
+        // Left by a run that failed before attaching.
+        touch(&tmp.0, &["FILE_OPEN_COUNT", "hashmap"]);
+        assert!(!pins.exist());
+        assert!(Pins::existing(&tmp.0).is_err());
+        touch(&tmp.0, &[LINK]);
+        assert!(pins.exist());
+        assert_eq!(Pins::existing(&tmp.0).unwrap().dir(), tmp.0);
+    }
+
+    #[test]
+    fn unpins_what_is_there_and_then_the_directory() {
+        let tmp = TempDir::new("unpin");
+        let dir = tmp.0.join("hashmap");
+        fs::create_dir(&dir).unwrap();
+        // Pinning stopped before the program and the link.
+        touch(&dir, &["FILE_OPEN_COUNT", "LOST"]);
+        unpin(&dir, &["FILE_OPEN_COUNT", "LOST", "hashmap"]).unwrap();
+        assert!(!dir.exists());
+        let e = unpin(&dir, &["FILE_OPEN_COUNT"]).unwrap_err();
+        assert!(e.to_string().contains("nothing is pinned"), "{}", e);
+    }
+
+    #[test]
+    fn leaves_pins_it_does_not_know_of() {
+        let tmp = TempDir::new("unpin-unknown");
+        touch(&tmp.0, &[LINK, "FILE_OPEN_COUNT", "STRAY"]);
+        let e = unpin(&tmp.0, &["FILE_OPEN_COUNT"]).unwrap_err();
+        assert!(e.to_string().contains("failed to remove"), "{}", e);
+        assert!(!tmp.0.join(LINK).exists() && !tmp.0.join("FILE_OPEN_COUNT").exists());
+        assert!(tmp.0.join("STRAY").exists());
+    }
+}
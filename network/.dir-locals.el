@@ -0,0 +1 @@
+((prog-mode . ((lsp-rust-analyzer-linked-projects . ["Cargo.toml" "network-ebpf/Cargo.toml"]))))
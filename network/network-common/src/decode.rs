@@ -0,0 +1,244 @@
+//! Validated, zero-copy decoding of `Event` records read from `EVENTS`.
+//!
+//! Records are fixed-size, so `header.size` must be exactly `Event`.
+//!
+//! The ring buffer hands us arbitrary bytes, so nothing here assumes the
+//! buffer is aligned or large enough, and kernel strings are never assumed to
+//! be UTF-8. Records carrying a different `EventHeader::version` or `kind`
+//! than this build knows about are rejected rather than misread.
+
+use core::mem::{offset_of, size_of};
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub use tracer_support::decode::{DecodeError, Escaped};
+use tracer_support::decode::{self, bytes, c_str, check_header, check_size, Decode};
+
+use crate::{Event, EventHeader, AF_INET, EVENT_KIND_ACCEPT, EVENT_KIND_CONNECT, EVENT_KIND_UDP_SEND, SCHEMA_VERSION};
+
+const _: () = assert!(
+    size_of::<EventHeader>() == decode::HEADER_LEN
+        && offset_of!(EventHeader, kind) == decode::KIND_OFFSET
+        && offset_of!(EventHeader, version) == decode::VERSION_OFFSET
+        && offset_of!(EventHeader, size) == decode::SIZE_OFFSET
+);
+
+/// A borrowed view of an `Event` whose size has been checked.
+#[derive(Debug, Clone, Copy)]
+pub struct EventRef<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> EventRef<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, DecodeError> {
+        let kind = check_header(buf, SCHEMA_VERSION)?;
+        if !matches!(kind, EVENT_KIND_CONNECT | EVENT_KIND_ACCEPT | EVENT_KIND_UDP_SEND) {
+            return Err(DecodeError::UnknownKind(kind));
+        }
+        check_size(buf, size_of::<Event>(), 0, || [])?;
+        Ok(EventRef { buf })
+    }
+
+    pub fn kind(&self) -> u16 {
+        u16::from_ne_bytes(self.bytes(offset_of!(Event, header.kind)))
+    }
+
+    pub fn version(&self) -> u16 {
+        u16::from_ne_bytes(self.bytes(offset_of!(Event, header.version)))
+    }
+
+    pub fn size(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, header.size)))
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        u64::from_ne_bytes(self.bytes(offset_of!(Event, header.timestamp)))
+    }
+
+    pub fn cpu(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, header.cpu)))
+    }
+
+    pub fn pid(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, pid)))
+    }
+
+    pub fn tid(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, tid)))
+    }
+
+    pub fn uid(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, uid)))
+    }
+
+    pub fn family(&self) -> u16 {
+        u16::from_ne_bytes(self.bytes(offset_of!(Event, family)))
+    }
+
+    pub fn protocol(&self) -> u16 {
+        u16::from_ne_bytes(self.bytes(offset_of!(Event, protocol)))
+    }
+
+    /// The local address.
+    pub fn saddr(&self) -> IpAddr {
+        self.addr(offset_of!(Event, saddr))
+    }
+
+    /// The remote address.
+    pub fn daddr(&self) -> IpAddr {
+        self.addr(offset_of!(Event, daddr))
+    }
+
+    pub fn sport(&self) -> u16 {
+        u16::from_ne_bytes(self.bytes(offset_of!(Event, sport)))
+    }
+
+    pub fn dport(&self) -> u16 {
+        u16::from_ne_bytes(self.bytes(offset_of!(Event, dport)))
+    }
+
+    /// The payload of a UDP send; always 0 for TCP events.
+    pub fn bytes_sent(&self) -> u64 {
+        u64::from_ne_bytes(self.bytes(offset_of!(Event, bytes)))
+    }
+
+    /// The task name up to its first NUL.
+    pub fn task_name(&self) -> &'a [u8] {
+        c_str(self.buf, offset_of!(Event, task_name), crate::TASK_NAME_LEN)
+    }
+
+    fn addr(&self, offset: usize) -> IpAddr {
+        if self.family() == AF_INET {
+            IpAddr::V4(Ipv4Addr::from(self.bytes::<4>(offset)))
+        } else {
+            IpAddr::V6(Ipv6Addr::from(self.bytes::<16>(offset)))
+        }
+    }
+
+    fn bytes<const N: usize>(&self, offset: usize) -> [u8; N] {
+        bytes(self.buf, offset)
+    }
+}
+
+/// The records in `EVENTS`.
+pub enum Events {}
+
+impl Decode for Events {
+    type Record<'a> = EventRef<'a>;
+
+    fn parse(buf: &[u8]) -> Result<EventRef<'_>, DecodeError> {
+        EventRef::parse(buf)
+    }
+
+    fn size(event: &EventRef<'_>) -> u32 {
+        event.size()
+    }
+}
+
+/// Counts decoded and rejected `EVENTS` records.
+pub type DecodeStats = decode::DecodeStats<Events>;
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::mem;
+    use std::format;
+    use std::vec::Vec;
+
+    use tracer_support::decode::testing::{as_bytes, assert_refuses_prefixes, set_u16, set_u32};
+
+    use super::*;
+    use crate::{ipv4_mapped, AF_INET6};
+
+    fn event(kind: u16, family: u16, saddr: &[u8], daddr: &[u8]) -> Vec<u8> {
+        let mut event: Event = unsafe { mem::zeroed() };
+        event.header = EventHeader::new(kind, size_of::<Event>() as u32, 1, 0);
+        event.pid = 42;
+        event.tid = 43;
+        event.uid = 1000;
+        event.family = family;
+        event.protocol = 17;
+        event.saddr[..saddr.len()].copy_from_slice(saddr);
+        event.daddr[..daddr.len()].copy_from_slice(daddr);
+        event.sport = 51234;
+        event.dport = 53;
+        event.bytes = 40;
+        event.task_name[..3].copy_from_slice(b"dig");
+        as_bytes(&event).to_vec()
+    }
+
+    #[test]
+    fn parses_ipv4_events() {
+        let buf = event(EVENT_KIND_UDP_SEND, AF_INET, &[10, 0, 0, 2], &[8, 8, 8, 8]);
+        let event = EventRef::parse(&buf).unwrap();
+        assert_eq!((event.pid(), event.tid(), event.uid()), (42, 43, 1000));
+        assert_eq!(event.saddr(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(event.daddr(), IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)));
+        assert_eq!((event.sport(), event.dport(), event.bytes_sent()), (51234, 53, 40));
+        assert_eq!((event.protocol(), event.task_name()), (17, &b"dig"[..]));
+    }
+
+    #[test]
+    fn parses_ipv6_events() {
+        let local = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        let remote = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 5);
+        for kind in [EVENT_KIND_CONNECT, EVENT_KIND_ACCEPT, EVENT_KIND_UDP_SEND] {
+            let buf = event(kind, AF_INET6, &local.octets(), &remote.octets());
+            let event = EventRef::parse(&buf).unwrap();
+            assert_eq!((event.kind(), event.saddr(), event.daddr()), (kind, IpAddr::V6(local), IpAddr::V6(remote)));
+        }
+    }
+
+    #[test]
+    fn finds_ipv4_in_mapped_addresses() {
+        assert_eq!(ipv4_mapped(&Ipv4Addr::new(8, 8, 4, 4).to_ipv6_mapped().octets()), Some([8, 8, 4, 4]));
+        assert_eq!(ipv4_mapped(&Ipv6Addr::UNSPECIFIED.octets()), None);
+        assert_eq!(ipv4_mapped(&Ipv6Addr::LOCALHOST.octets()), None);
+        // IPv4-compatible, `::a.b.c.d`, is not mapped.
+        assert_eq!(ipv4_mapped(&Ipv4Addr::new(8, 8, 4, 4).to_ipv6_compatible().octets()), None);
+        assert_eq!(ipv4_mapped(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0xffff, 0x0808, 0x0404).octets()), None);
+    }
+
+    #[test]
+    fn refuses_truncated_records() {
+        let buf = event(EVENT_KIND_CONNECT, AF_INET, &[10, 0, 0, 2], &[1, 1, 1, 1]);
+        assert_refuses_prefixes(&buf, EventRef::parse);
+    }
+
+    #[test]
+    fn refuses_any_size_but_the_event() {
+        let mut buf = event(EVENT_KIND_CONNECT, AF_INET, &[10, 0, 0, 2], &[1, 1, 1, 1]);
+        for size in [0, size_of::<Event>() as u32 - 1, size_of::<Event>() as u32 + 1, u32::MAX] {
+            set_u32(&mut buf, offset_of!(Event, header.size), size);
+            assert!(matches!(EventRef::parse(&buf), Err(DecodeError::SizeMismatch { .. })), "size {}", size);
+        }
+    }
+
+    #[test]
+    fn refuses_other_versions_and_kinds() {
+        let mut buf = event(EVENT_KIND_CONNECT, AF_INET, &[10, 0, 0, 2], &[1, 1, 1, 1]);
+        set_u16(&mut buf, offset_of!(EventHeader, version), SCHEMA_VERSION + 1);
+        let newer = DecodeError::UnsupportedVersion { version: SCHEMA_VERSION + 1, expected: SCHEMA_VERSION };
+        assert_eq!(EventRef::parse(&buf).unwrap_err(), newer);
+        let mut buf = event(EVENT_KIND_CONNECT, AF_INET, &[10, 0, 0, 2], &[1, 1, 1, 1]);
+        set_u16(&mut buf, offset_of!(EventHeader, kind), 999);
+        assert_eq!(EventRef::parse(&buf).unwrap_err(), DecodeError::UnknownKind(999));
+    }
+
+    #[test]
+    fn escapes_task_names_that_are_not_utf8() {
+        let mut buf = event(EVENT_KIND_CONNECT, AF_INET, &[10, 0, 0, 2], &[1, 1, 1, 1]);
+        buf[offset_of!(Event, task_name)..][..4].copy_from_slice(b"a\n\xff\0");
+        let event = EventRef::parse(&buf).unwrap();
+        assert_eq!(format!("{}", Escaped(event.task_name())), "a\\n\\xff");
+    }
+
+    #[test]
+    fn counts_decoded_and_malformed_records() {
+        let stats = DecodeStats::new();
+        let buf = event(EVENT_KIND_CONNECT, AF_INET, &[10, 0, 0, 2], &[1, 1, 1, 1]);
+        assert!(stats.decode(&buf).is_ok());
+        assert!(stats.decode(&buf[..8]).is_err());
+        assert_eq!((stats.decoded(), stats.malformed(), stats.bytes()), (1, 1, buf.len() as u64));
+    }
+}
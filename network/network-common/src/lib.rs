@@ -0,0 +1,94 @@
+#![no_std]
+
+#[cfg(feature = "user")]
+use aya::Pod;
+
+#[cfg(feature = "user")]
+pub mod decode;
+
+const TASK_NAME_LEN: usize = 16;
+
+pub const RINGBUF_SIZE: u32 = 128 * 4096;
+
+/// Bumped whenever the layout of `EventHeader` or any event changes.
+pub const SCHEMA_VERSION: u16 = 1;
+
+/// A TCP connection was initiated, from `tcp_connect`.
+pub const EVENT_KIND_CONNECT: u16 = 7;
+/// A TCP connection was accepted, from `inet_csk_accept` returning.
+pub const EVENT_KIND_ACCEPT: u16 = 8;
+/// A UDP datagram was sent, from `udp_sendmsg` or `udpv6_sendmsg`.
+pub const EVENT_KIND_UDP_SEND: u16 = 9;
+
+/// Values of `Event::family`, as in linux/socket.h.
+pub const AF_INET: u16 = 2;
+pub const AF_INET6: u16 = 10;
+
+/// The IPv4 address inside an IPv4-mapped IPv6 address, `::ffff:a.b.c.d`.
+pub fn ipv4_mapped(addr: &[u8; 16]) -> Option<[u8; 4]> {
+    if addr[..10].iter().any(|&b| b != 0) || addr[10] != 0xff || addr[11] != 0xff {
+        return None;
+    }
+    Some([addr[12], addr[13], addr[14], addr[15]])
+}
+
+/// Common prefix of every record sent to user space.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct EventHeader {
+    pub kind: u16,
+    pub version: u16,
+    /// Size of the whole record, header included.
+    pub size: u32,
+    /// `bpf_ktime_get_ns()` when the record was produced.
+    pub timestamp: u64,
+    pub cpu: u32,
+    pub _pad: u32,
+}
+
+impl EventHeader {
+    pub const fn new(kind: u16, size: u32, timestamp: u64, cpu: u32) -> Self {
+        EventHeader {
+            kind,
+            version: SCHEMA_VERSION,
+            size,
+            timestamp,
+            cpu,
+            _pad: 0,
+        }
+    }
+}
+
+/// A network event. Records are fixed-size: `header.size` is always
+/// `size_of::<Event>()`.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Event {
+    pub header: EventHeader,
+    pub pid: u32,
+    pub tid: u32,
+    pub uid: u32,
+    /// `AF_INET` or `AF_INET6`.
+    pub family: u16,
+    /// `IPPROTO_TCP` or `IPPROTO_UDP`.
+    pub protocol: u16,
+    /// Local and remote address in network byte order. IPv4 addresses take
+    /// the first 4 bytes and the rest is 0.
+    pub saddr: [u8; 16],
+    pub daddr: [u8; 16],
+    /// Local and remote port in host byte order.
+    pub sport: u16,
+    pub dport: u16,
+    pub _pad: u32,
+    /// UDP only: the payload passed to `udp_sendmsg` or `udpv6_sendmsg`. TCP
+    /// events are sent when a connection is made, before any data, and leave
+    /// it 0; that is not a count of what the connection carried.
+    pub bytes: u64,
+    pub task_name: [u8; TASK_NAME_LEN],
+}
+
+#[cfg(feature = "user")]
+unsafe impl Pod for EventHeader {}
+
+#[cfg(feature = "user")]
+unsafe impl Pod for Event {}
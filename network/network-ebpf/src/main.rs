@@ -0,0 +1,200 @@
+#![no_std]
+#![no_main]
+
+#![allow(non_upper_case_globals)]
+#![allow(non_snake_case)]
+#![allow(non_camel_case_types)]
+#![allow(dead_code)]
+
+use aya_ebpf::{
+    helpers::{bpf_get_current_comm, bpf_get_current_pid_tgid, bpf_get_current_uid_gid, bpf_get_smp_processor_id, bpf_ktime_get_ns, bpf_probe_read_kernel}, macros::{kprobe, kretprobe, map}, maps::RingBuf, programs::{ProbeContext, RetProbeContext}
+};
+use binding::{msghdr, sock};
+use network_common::{ipv4_mapped, Event, EventHeader, AF_INET, AF_INET6, EVENT_KIND_ACCEPT, EVENT_KIND_CONNECT, EVENT_KIND_UDP_SEND, RINGBUF_SIZE};
+
+mod binding;
+
+const IPPROTO_TCP: u16 = 6;
+const IPPROTO_UDP: u16 = 17;
+
+// `struct sockaddr_in` and `struct sockaddr_in6`, which the bindings lack.
+#[repr(C)]
+struct SockaddrIn {
+    sin_family: u16,
+    sin_port: u16,
+    sin_addr: [u8; 4],
+    sin_zero: [u8; 8],
+}
+
+#[repr(C)]
+struct SockaddrIn6 {
+    sin6_family: u16,
+    sin6_port: u16,
+    sin6_flowinfo: u32,
+    sin6_addr: [u8; 16],
+    sin6_scope_id: u32,
+}
+
+#[map]
+static EVENTS: RingBuf = RingBuf::with_byte_size(RINGBUF_SIZE, 0);
+
+#[kprobe]
+pub fn tcp_connect(ctx: ProbeContext) -> u32 {
+    match try_tcp_connect(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[kretprobe]
+pub fn inet_csk_accept(ctx: RetProbeContext) -> u32 {
+    match try_inet_csk_accept(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[kprobe]
+pub fn udp_sendmsg(ctx: ProbeContext) -> u32 {
+    match try_udp_sendmsg(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[kprobe]
+pub fn udpv6_sendmsg(ctx: ProbeContext) -> u32 {
+    match try_udpv6_sendmsg(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+fn try_tcp_connect(ctx: ProbeContext) -> Result<u32, i64> {
+    let sk: *const sock = ctx.arg(0).ok_or(1i64)?;
+    // The route and the local port have been chosen by now, so the 4-tuple
+    // is complete.
+    let mut event = new_event(EVENT_KIND_CONNECT, IPPROTO_TCP)?;
+    unsafe { read_sock(sk, &mut event)? };
+    EVENTS.output(&event, 0)?;
+    Ok(0)
+}
+
+fn try_inet_csk_accept(ctx: RetProbeContext) -> Result<u32, i64> {
+    // The new connection's socket, or NULL if there was none.
+    let sk: *const sock = ctx.ret().ok_or(1i64)?;
+    if sk.is_null() {
+        return Ok(0);
+    }
+    let mut event = new_event(EVENT_KIND_ACCEPT, IPPROTO_TCP)?;
+    unsafe { read_sock(sk, &mut event)? };
+    EVENTS.output(&event, 0)?;
+    Ok(0)
+}
+
+// Every IPv4 send, including those from an IPv6 socket: `udpv6_sendmsg`
+// hands a destination that is IPv4 or IPv4-mapped to `udp_sendmsg`, as a
+// `sockaddr_in` in `msg_name`.
+fn try_udp_sendmsg(ctx: ProbeContext) -> Result<u32, i64> {
+    let sk: *const sock = ctx.arg(0).ok_or(1i64)?;
+    let msg: *const msghdr = ctx.arg(1).ok_or(1i64)?;
+    let len: u64 = ctx.arg(2).ok_or(1i64)?;
+    let mut event = new_event(EVENT_KIND_UDP_SEND, IPPROTO_UDP)?;
+    event.bytes = len;
+    unsafe {
+        // The socket's own addresses; the remote ones only count for a
+        // connected socket.
+        read_sock(sk, &mut event)?;
+        // A destination given to sendto() or sendmsg() wins.
+        let name = bpf_probe_read_kernel(&(*msg).msg_name)?;
+        if !name.is_null() && bpf_probe_read_kernel(name as *const u16)? == AF_INET {
+            let addr = bpf_probe_read_kernel(name as *const SockaddrIn)?;
+            event.daddr = [0; 16];
+            event.daddr[..4].copy_from_slice(&addr.sin_addr);
+            event.dport = u16::from_be(addr.sin_port);
+            if event.family == AF_INET6 {
+                // The local address of an IPv6 socket is IPv4-mapped, or
+                // unspecified until the socket is bound.
+                let saddr = ipv4_mapped(&event.saddr).unwrap_or([0; 4]);
+                event.saddr = [0; 16];
+                event.saddr[..4].copy_from_slice(&saddr);
+                event.family = AF_INET;
+            }
+        }
+    }
+    EVENTS.output(&event, 0)?;
+    Ok(0)
+}
+
+// Native IPv6 sends. Those to an IPv4 or IPv4-mapped destination are left to
+// `udp_sendmsg`, which sees them next.
+fn try_udpv6_sendmsg(ctx: ProbeContext) -> Result<u32, i64> {
+    let sk: *const sock = ctx.arg(0).ok_or(1i64)?;
+    let msg: *const msghdr = ctx.arg(1).ok_or(1i64)?;
+    let len: u64 = ctx.arg(2).ok_or(1i64)?;
+    let mut event = new_event(EVENT_KIND_UDP_SEND, IPPROTO_UDP)?;
+    event.bytes = len;
+    unsafe {
+        read_sock(sk, &mut event)?;
+        let name = bpf_probe_read_kernel(&(*msg).msg_name)?;
+        if !name.is_null() {
+            match bpf_probe_read_kernel(name as *const u16)? {
+                AF_INET => return Ok(0),
+                AF_INET6 => {
+                    let addr = bpf_probe_read_kernel(name as *const SockaddrIn6)?;
+                    event.daddr = addr.sin6_addr;
+                    event.dport = u16::from_be(addr.sin6_port);
+                }
+                _ => {}
+            }
+        }
+    }
+    if ipv4_mapped(&event.daddr).is_some() {
+        return Ok(0);
+    }
+    EVENTS.output(&event, 0)?;
+    Ok(0)
+}
+
+fn new_event(kind: u16, protocol: u16) -> Result<Event, i64> {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    Ok(Event {
+        header: EventHeader::new(kind, core::mem::size_of::<Event>() as u32, unsafe { bpf_ktime_get_ns() }, unsafe { bpf_get_smp_processor_id() }),
+        pid: (pid_tgid >> 32) as u32,
+        tid: pid_tgid as u32,
+        uid: bpf_get_current_uid_gid() as u32,
+        family: 0,
+        protocol,
+        saddr: [0; 16],
+        daddr: [0; 16],
+        sport: 0,
+        dport: 0,
+        _pad: 0,
+        bytes: 0,
+        task_name: bpf_get_current_comm()?,
+    })
+}
+
+// Address family, addresses and ports of `sk`.
+unsafe fn read_sock(sk: *const sock, event: &mut Event) -> Result<(), i64> {
+    let common = core::ptr::addr_of!((*sk).__sk_common);
+    event.family = bpf_probe_read_kernel(&(*common).skc_family)?;
+    if event.family == AF_INET6 {
+        event.saddr = bpf_probe_read_kernel(&(*common).skc_v6_rcv_saddr)?.in6_u.u6_addr8;
+        event.daddr = bpf_probe_read_kernel(&(*common).skc_v6_daddr)?.in6_u.u6_addr8;
+    } else {
+        let addrs = bpf_probe_read_kernel(&(*common).__bindgen_anon_1.__bindgen_anon_1)?;
+        event.saddr[..4].copy_from_slice(&addrs.skc_rcv_saddr.to_ne_bytes());
+        event.daddr[..4].copy_from_slice(&addrs.skc_daddr.to_ne_bytes());
+    }
+    let ports = bpf_probe_read_kernel(&(*common).__bindgen_anon_3.__bindgen_anon_1)?;
+    // skc_num is already in host byte order, skc_dport is not.
+    event.sport = ports.skc_num;
+    event.dport = u16::from_be(ports.skc_dport);
+    Ok(())
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    unsafe { core::hint::unreachable_unchecked() }
+}
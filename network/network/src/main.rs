@@ -0,0 +1,304 @@
+mod capture;
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use aya::maps::RingBuf;
+use aya::programs::KProbe;
+use aya::{include_bytes_aligned, Bpf};
+use aya_log::BpfLogger;
+use capture::{CaptureReader, CaptureWriter};
+use clap::{Parser, Subcommand};
+use log::{info, warn, debug};
+use serde_json::{json, Value};
+use tokio::io::unix::AsyncFd;
+use tracer_support::control::{self, ControlSocket, Emission, Request, RpcError};
+use tracer_support::daemon::{self, Daemon, Signal, Signals};
+use tracer_support::enrich::{Enricher, Worker};
+use tracer_support::features::{AttachPoint, Features, Need};
+use tracer_support::sandbox;
+use tracer_support::verifier;
+use network_common::decode::{DecodeStats, Escaped, EventRef};
+use network_common::{EVENT_KIND_ACCEPT, EVENT_KIND_CONNECT};
+
+static DECODE_STATS: DecodeStats = DecodeStats::new();
+static EMISSION: Emission = Emission::new();
+const WORKER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Kernel functions the programs attach to. inet_csk_accept is a return
+/// probe: the accepted socket is its result. udpv6_sendmsg is in the ipv6
+/// module, which may not be loaded.
+const ATTACH_POINTS: [AttachPoint; 4] = [
+    AttachPoint { targets: &["tcp_connect"], programs: &["tcp_connect"], untraced: "" },
+    AttachPoint { targets: &["inet_csk_accept"], programs: &["inet_csk_accept"], untraced: "accepts" },
+    AttachPoint { targets: &["udp_sendmsg"], programs: &["udp_sendmsg"], untraced: "UDP sends" },
+    AttachPoint { targets: &["udpv6_sendmsg"], programs: &["udpv6_sendmsg"], untraced: "UDP sends over IPv6" },
+];
+
+/// The eBPF source, to name the function of a line the verifier quotes.
+const EBPF_SOURCES: verifier::Sources = &[("main.rs", include_str!("../../network-ebpf/src/main.rs"))];
+
+#[derive(Debug, Parser)]
+struct Opt {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// Print raw uids and pids instead of resolving them to names and executables
+    #[clap(long)]
+    no_enrich: bool,
+    /// How long resolved users, groups and processes are cached, in seconds
+    #[clap(long, default_value_t = 10)]
+    enrich_ttl: u64,
+    /// Serve the control API on this Unix socket
+    #[clap(long)]
+    control: Option<PathBuf>,
+    /// Run as a system service: log JSON lines, notify systemd and feed its
+    /// watchdog while the self-check passes
+    #[clap(long)]
+    daemon: bool,
+    /// Run the self-check this often, in seconds
+    #[clap(long, default_value_t = 30, requires = "daemon")]
+    check_interval: u64,
+    /// Fail the self-check once no event came in for this many seconds
+    #[clap(long, requires = "daemon")]
+    max_idle: Option<u64>,
+    /// Once attached, drop root and run as this user, under a seccomp filter
+    #[clap(long)]
+    user: Option<String>,
+    /// Write the verifier log here if a program fails to load
+    #[clap(long)]
+    verifier_log: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Trace connections and also write the raw events to a capture file
+    Record {
+        /// Path of the capture file to create
+        path: PathBuf,
+    },
+    /// Print the events of a capture file without loading any eBPF
+    Replay {
+        /// Path of the capture file to read
+        path: PathBuf,
+    },
+    /// Send a request to the control socket of a running network and print the result
+    Ctl {
+        /// Path of the control socket
+        socket: PathBuf,
+        /// Method to call: programs, stats, pause or resume
+        method: String,
+        /// Params as a JSON object
+        params: Option<String>,
+    },
+    /// Print what the kernel supports and where the probes would attach, without loading any eBPF
+    Features,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let opt = Opt::parse();
+
+    daemon::init_logger(opt.daemon);
+
+    match &opt.command {
+        Some(Command::Ctl { socket, method, params }) => return control::cli(socket, method, params.as_deref()),
+        Some(Command::Features) => {
+            for line in Features::probe().report(&ATTACH_POINTS) {
+                println!("{}", line);
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // A replay's pids belong to processes that are long gone, so it never enriches.
+    let mut capture = match &opt.command {
+        Some(Command::Replay { path }) => return replay(path),
+        Some(Command::Record { path }) => Some(CaptureWriter::create(path)?),
+        Some(Command::Features | Command::Ctl { .. }) | None => None,
+    };
+
+    // Bump the memlock rlimit. This is needed for older kernels that don't use the
+    // new memcg based accounting, see https://lwn.net/Articles/837122/
+    let rlim = libc::rlimit {
+        rlim_cur: libc::RLIM_INFINITY,
+        rlim_max: libc::RLIM_INFINITY,
+    };
+    let ret = unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlim) };
+    if ret != 0 {
+        debug!("remove limit on locked memory failed, ret is: {}", ret);
+    }
+
+    let features = Features::probe();
+    for line in features.report(&ATTACH_POINTS) {
+        debug!("{}", line);
+    }
+    features.check("network", &[Need::Kprobes, Need::RingBuffers])?;
+
+    // This will include your eBPF object file as raw bytes at compile-time and load it at
+    // runtime. This approach is recommended for most real-world use cases. If you would
+    // like to specify the eBPF program at runtime rather than at compile-time, you can
+    // reach for `Bpf::load_file` instead.
+    #[cfg(debug_assertions)]
+    let mut bpf = Bpf::load(include_bytes_aligned!(
+        "../../target/bpfel-unknown-none/debug/network"
+    ))?;
+    #[cfg(not(debug_assertions))]
+    let mut bpf = Bpf::load(include_bytes_aligned!(
+        "../../target/bpfel-unknown-none/release/network"
+    ))?;
+    if let Err(e) = BpfLogger::init(&mut bpf) {
+        // This can happen if you remove all log statements from your eBPF program.
+        warn!("failed to initialize eBPF logger: {}", e);
+    }
+    // Program and function of every probe, for the control API.
+    let mut attached = Vec::new();
+    for point in &ATTACH_POINTS {
+        let Some(function) = features.resolve(point)? else {
+            continue;
+        };
+        for name in point.programs {
+            let program: &mut KProbe = bpf.program_mut(name).unwrap().try_into()?;
+            program.load().map_err(|e| verifier::explain(name, e, opt.verifier_log.as_deref(), EBPF_SOURCES))?;
+            program.attach(function, 0).with_context(|| format!("failed to attach {} to {}", name, function))?;
+            attached.push((*name, function));
+        }
+    }
+    let mut daemon = opt.daemon.then(|| Daemon::new(Duration::from_secs(opt.check_interval), opt.max_idle.map(Duration::from_secs))).transpose()?;
+    if let Some(daemon) = &mut daemon {
+        daemon.watch(&daemon::program_ids(&bpf, attached.iter().map(|(program, _)| *program))?);
+    }
+
+    let ring_buf = RingBuf::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    let mut ring_buf = AsyncFd::new(ring_buf)?;
+    // Looking processes up blocks, so enriched events are handled on a
+    // thread of their own rather than in this loop.
+    let worker = (!opt.no_enrich).then(|| {
+        let enricher = Enricher::new(Duration::from_secs(opt.enrich_ttl));
+        Worker::spawn(move |buf: Vec<u8>| handle_event(&buf, Some(&enricher)))
+    });
+    let mut control = opt.control.as_deref().map(ControlSocket::bind).transpose()?;
+    let mut signals = Signals::new()?;
+    if let Some(user) = &opt.user {
+        sandbox::enter(user, false)?;
+    }
+    if let Some(daemon) = &daemon {
+        daemon.ready();
+    }
+
+    info!("Waiting for Ctrl-C...");
+    loop {
+        tokio::select! {
+            guard = ring_buf.readable_mut() => {
+                let mut guard = guard?;
+                let ring_buf = guard.get_inner_mut();
+                while let Some(item) = ring_buf.next() {
+                    let buf = &*item;
+                    if EMISSION.suppress() {
+                        continue;
+                    }
+                    if let Some(capture) = &mut capture {
+                        if let Err(e) = capture.write_record(buf) {
+                            warn!("failed to write capture record: {}", e);
+                        }
+                    }
+                    match &worker {
+                        Some(worker) => worker.submit(buf.to_vec()),
+                        None => handle_event(buf, None),
+                    }
+                }
+                guard.clear_ready();
+            }
+            request = ControlSocket::next(&mut control) => {
+                let result = handle_request(&request, &attached);
+                request.reply(result);
+            }
+            _ = Daemon::tick(&mut daemon) => {
+                if let Some(daemon) = &mut daemon {
+                    daemon.check(DECODE_STATS.decoded() + DECODE_STATS.malformed() + EMISSION.suppressed());
+                }
+            }
+            signal = signals.next() => match signal {
+                Signal::Shutdown => break,
+                Signal::Reload => info!("nothing to reload"),
+            },
+        }
+    }
+    if let Some(daemon) = &daemon {
+        daemon.stopping();
+    }
+    info!("Exiting...");
+
+    if let Some(capture) = &mut capture {
+        capture.flush()?;
+    }
+    if let Some(worker) = &worker {
+        worker.flush(WORKER_SHUTDOWN_TIMEOUT);
+    }
+    report_decode_stats();
+
+    Ok(())
+}
+
+fn handle_request(request: &Request, attached: &[(&str, &str)]) -> Result<Value, anyhow::Error> {
+    match request.method.as_str() {
+        "programs" => Ok(attached.iter().map(|(program, function)| json!({ "program": program, "function": function })).collect()),
+        "stats" => Ok(json!({
+            "decoded": DECODE_STATS.decoded(),
+            "malformed": DECODE_STATS.malformed(),
+            "suppressed": EMISSION.suppressed(),
+            "paused": EMISSION.paused(),
+        })),
+        "pause" | "resume" => {
+            EMISSION.set_paused(request.method == "pause");
+            Ok(json!({ "paused": EMISSION.paused() }))
+        }
+        method => Err(RpcError::method_not_found(method).into()),
+    }
+}
+
+fn replay(path: &Path) -> Result<(), anyhow::Error> {
+    for record in CaptureReader::open(path)? {
+        handle_event(&record?, None);
+    }
+    report_decode_stats();
+    Ok(())
+}
+
+fn handle_event(buf: &[u8], enricher: Option<&Enricher>) {
+    match DECODE_STATS.decode(buf) {
+        Ok(event) => {
+            let task = format!("{}[{}/{}]", Escaped(event.task_name()), event.pid(), event.tid());
+            match enricher.map(|enricher| enricher.lookup(event.uid(), event.pid())) {
+                Some(context) => println!("{}: {} {}, {}", context, task, Connection(event), context.details()),
+                None => println!("{} uid {}: {}", task, event.uid(), Connection(event)),
+            }
+        }
+        Err(e) => {
+            warn!("failed to parse event: {}", e);
+        }
+    }
+}
+
+/// Formats an event as e.g. `tcp connect 10.0.0.2:51234 -> 93.184.216.34:443`,
+/// with the local end first.
+struct Connection<'a>(EventRef<'a>);
+
+impl std::fmt::Display for Connection<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let event = &self.0;
+        let local = SocketAddr::new(event.saddr(), event.sport());
+        let remote = SocketAddr::new(event.daddr(), event.dport());
+        match event.kind() {
+            EVENT_KIND_CONNECT => write!(f, "tcp connect {} -> {}", local, remote),
+            EVENT_KIND_ACCEPT => write!(f, "tcp accept {} <- {}", local, remote),
+            _ => write!(f, "udp send {} -> {}, {} bytes", local, remote, event.bytes_sent()),
+        }
+    }
+}
+
+fn report_decode_stats() {
+    info!("decoded {} events, {} malformed", DECODE_STATS.decoded(), DECODE_STATS.malformed());
+}
@@ -0,0 +1,318 @@
+//! Validated, zero-copy decoding of `Event` records read from `EVENTS`.
+//!
+//! The perf buffer hands us arbitrary bytes, so nothing here assumes the
+//! buffer is aligned or large enough, and kernel strings are never assumed to
+//! be UTF-8. Records carrying a different `EventHeader::version` or `kind`
+//! than this build knows about are rejected rather than misread.
+
+use core::mem::{offset_of, size_of};
+
+pub use tracer_support::decode::{DecodeError, Escaped};
+use tracer_support::decode::{self, bytes, c_str, check_header, check_size, Decode};
+
+use crate::{
+    Event, EventHeader, EVENT_KIND_FILE_OPEN, EVENT_KIND_RENAME, EVENT_KIND_SETATTR, EVENT_KIND_UNLINK, EVENT_KIND_WRITE,
+    SCHEMA_VERSION,
+};
+
+// From asm-generic/fcntl.h, which every architecture we build for uses.
+const O_ACCMODE: u32 = 0o3;
+const O_WRONLY: u32 = 0o1;
+const O_RDWR: u32 = 0o2;
+const O_TRUNC: u32 = 0o1000;
+
+const _: () = assert!(
+    size_of::<EventHeader>() == decode::HEADER_LEN
+        && offset_of!(EventHeader, kind) == decode::KIND_OFFSET
+        && offset_of!(EventHeader, version) == decode::VERSION_OFFSET
+        && offset_of!(EventHeader, size) == decode::SIZE_OFFSET
+);
+
+/// A borrowed view of an `Event` whose size has been checked.
+#[derive(Debug, Clone, Copy)]
+pub struct EventRef<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> EventRef<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, DecodeError> {
+        let kind = check_header(buf, SCHEMA_VERSION)?;
+        if !matches!(
+            kind,
+            EVENT_KIND_FILE_OPEN | EVENT_KIND_WRITE | EVENT_KIND_RENAME | EVENT_KIND_UNLINK | EVENT_KIND_SETATTR
+        ) {
+            return Err(DecodeError::UnknownKind(kind));
+        }
+        // Perf pads samples, so what follows the event is left alone.
+        check_size(buf, size_of::<Event>(), 0, || [])?;
+        Ok(EventRef { buf })
+    }
+
+    pub fn kind(&self) -> u16 {
+        u16::from_ne_bytes(self.bytes(offset_of!(Event, header.kind)))
+    }
+
+    pub fn version(&self) -> u16 {
+        u16::from_ne_bytes(self.bytes(offset_of!(Event, header.version)))
+    }
+
+    pub fn size(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, header.size)))
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        u64::from_ne_bytes(self.bytes(offset_of!(Event, header.timestamp)))
+    }
+
+    pub fn cpu(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, header.cpu)))
+    }
+
+    pub fn uid(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, uid)))
+    }
+
+    pub fn pid(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, pid)))
+    }
+
+    pub fn gid(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, gid)))
+    }
+
+    pub fn euid(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, euid)))
+    }
+
+    pub fn fsuid(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, fsuid)))
+    }
+
+    pub fn cap_effective(&self) -> u64 {
+        u64::from_ne_bytes(self.bytes(offset_of!(Event, cap_effective)))
+    }
+
+    /// The open ran as root on behalf of an unprivileged user, e.g. through
+    /// a setuid binary.
+    pub fn elevated(&self) -> bool {
+        self.euid() == 0 && self.uid() != 0
+    }
+
+    pub fn ret(&self) -> i32 {
+        i32::from_ne_bytes(self.bytes(offset_of!(Event, ret)))
+    }
+
+    /// `security_file_open` refused the open.
+    pub fn denied(&self) -> bool {
+        self.ret() != 0
+    }
+
+    pub fn ino(&self) -> u64 {
+        u64::from_ne_bytes(self.bytes(offset_of!(Event, ino)))
+    }
+
+    pub fn dev(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, dev)))
+    }
+
+    /// Major number of `dev`; the kernel keeps 20 bits for the minor.
+    pub fn dev_major(&self) -> u32 {
+        self.dev() >> 20
+    }
+
+    pub fn dev_minor(&self) -> u32 {
+        self.dev() & ((1 << 20) - 1)
+    }
+
+    pub fn mode(&self) -> u16 {
+        u16::from_ne_bytes(self.bytes(offset_of!(Event, mode)))
+    }
+
+    /// The file type encoded in `mode`.
+    pub fn file_type(&self) -> &'static str {
+        match self.mode() & 0o170000 {
+            0o140000 => "socket",
+            0o120000 => "symlink",
+            0o100000 => "regular",
+            0o060000 => "block",
+            0o040000 => "directory",
+            0o020000 => "char",
+            0o010000 => "fifo",
+            _ => "unknown",
+        }
+    }
+
+    pub fn file_size(&self) -> i64 {
+        i64::from_ne_bytes(self.bytes(offset_of!(Event, file_size)))
+    }
+
+    /// The filesystem type name up to its first NUL.
+    pub fn fs_type(&self) -> &'a [u8] {
+        self.c_str(offset_of!(Event, fs_type), crate::FS_TYPE_LEN)
+    }
+
+    pub fn ppid(&self) -> i32 {
+        i32::from_ne_bytes(self.bytes(offset_of!(Event, ppid)))
+    }
+
+    pub fn f_flag(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, f_flag)))
+    }
+
+    /// The record is an open that can change the file: one with `O_WRONLY`
+    /// or `O_RDWR`, or with `O_TRUNC`, which truncates even a read-only open.
+    /// `security_file_open` runs before `do_dentry_open` clears `O_CREAT`,
+    /// `O_EXCL`, `O_NOCTTY` and `O_TRUNC` from `f_flags`, so they are still
+    /// set here.
+    pub fn opened_for_write(&self) -> bool {
+        self.kind() == EVENT_KIND_FILE_OPEN
+            && (matches!(self.f_flag() & O_ACCMODE, O_WRONLY | O_RDWR) || self.f_flag() & O_TRUNC != 0)
+    }
+
+    /// The task name up to its first NUL.
+    pub fn task_name(&self) -> &'a [u8] {
+        self.c_str(offset_of!(Event, task_name), crate::TASK_NAME_LEN)
+    }
+
+    /// The file name up to its first NUL.
+    pub fn file_path(&self) -> &'a [u8] {
+        self.c_str(offset_of!(Event, file_path), crate::MAX_PATH_LEN)
+    }
+
+    fn bytes<const N: usize>(&self, offset: usize) -> [u8; N] {
+        bytes(self.buf, offset)
+    }
+
+    fn c_str(&self, offset: usize, len: usize) -> &'a [u8] {
+        c_str(self.buf, offset, len)
+    }
+}
+
+/// The samples in `EVENTS`.
+pub enum Events {}
+
+impl Decode for Events {
+    type Record<'a> = EventRef<'a>;
+
+    fn parse(buf: &[u8]) -> Result<EventRef<'_>, DecodeError> {
+        EventRef::parse(buf)
+    }
+
+    fn size(event: &EventRef<'_>) -> u32 {
+        event.size()
+    }
+}
+
+/// Counts decoded and rejected `EVENTS` samples.
+pub type DecodeStats = decode::DecodeStats<Events>;
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::mem;
+    use std::format;
+    use std::vec::Vec;
+
+    use tracer_support::decode::testing::{as_bytes, assert_refuses_prefixes, corrupt_records, set_u16, set_u32};
+
+    use super::*;
+    use crate::MAX_PATH_LEN;
+
+    fn open(path: &[u8]) -> Vec<u8> {
+        let mut event: Event = unsafe { mem::zeroed() };
+        event.header = EventHeader::new(EVENT_KIND_FILE_OPEN, size_of::<Event>() as u32, 1, 0);
+        event.pid = 42;
+        event.ret = -13;
+        event.file_path[..path.len()].copy_from_slice(path);
+        as_bytes(&event).to_vec()
+    }
+
+    fn open_with(f_flag: u32) -> Vec<u8> {
+        let mut buf = open(b"passwd");
+        set_u32(&mut buf, offset_of!(Event, f_flag), f_flag);
+        buf
+    }
+
+    #[test]
+    fn parses_records() {
+        let buf = open(b"/etc/shadow");
+        let event = EventRef::parse(&buf).unwrap();
+        assert_eq!((event.pid(), event.file_path()), (42, &b"/etc/shadow"[..]));
+        assert!(event.denied());
+        // Perf pads samples; what follows the event is not part of it.
+        let padded = [buf.as_slice(), &[0xff; 4]].concat();
+        assert_eq!(EventRef::parse(&padded).unwrap().file_path(), b"/etc/shadow");
+    }
+
+    #[test]
+    fn refuses_truncated_records() {
+        let buf = open(b"/etc/shadow");
+        assert_refuses_prefixes(&buf, EventRef::parse);
+    }
+
+    #[test]
+    fn refuses_a_size_other_than_the_event() {
+        let mut buf = open(b"/etc/shadow");
+        for size in [0, size_of::<EventHeader>() as u32, buf.len() as u32 - 1, buf.len() as u32 + 1, u32::MAX] {
+            set_u32(&mut buf, offset_of!(Event, header.size), size);
+            assert!(matches!(EventRef::parse(&buf), Err(DecodeError::SizeMismatch { .. })), "size {}", size);
+        }
+    }
+
+    #[test]
+    fn keeps_names_without_a_nul_within_their_field() {
+        let buf = open(&[b'a'; MAX_PATH_LEN]);
+        let event = EventRef::parse(&buf).unwrap();
+        assert_eq!(event.file_path().len(), MAX_PATH_LEN);
+        let mut buf = open(b"");
+        buf[offset_of!(Event, task_name)..][..crate::TASK_NAME_LEN].fill(b'b');
+        assert_eq!(EventRef::parse(&buf).unwrap().task_name().len(), crate::TASK_NAME_LEN);
+    }
+
+    #[test]
+    fn refuses_other_versions_and_kinds() {
+        let mut buf = open(b"/etc/shadow");
+        set_u16(&mut buf, offset_of!(EventHeader, version), SCHEMA_VERSION + 1);
+        let newer = DecodeError::UnsupportedVersion { version: SCHEMA_VERSION + 1, expected: SCHEMA_VERSION };
+        assert_eq!(EventRef::parse(&buf).unwrap_err(), newer);
+        let mut buf = open(b"/etc/shadow");
+        set_u16(&mut buf, offset_of!(EventHeader, kind), 999);
+        assert_eq!(EventRef::parse(&buf).unwrap_err(), DecodeError::UnknownKind(999));
+    }
+
+    #[test]
+    fn escapes_names_that_are_not_utf8() {
+        let buf = open(b"/tmp/\xc3");
+        assert_eq!(format!("{}", Escaped(EventRef::parse(&buf).unwrap().file_path())), "/tmp/\\xc3");
+    }
+
+    /// Whatever the bytes, parsing never panics, and what it accepts can be
+    /// read in full.
+    #[test]
+    fn never_panics_on_corrupt_records() {
+        corrupt_records::<Events>(&[open(b"/etc/shadow")], |_, _| {}, |event| {
+            let _ = format!("{} {} {}", Escaped(event.file_path()), Escaped(event.task_name()), Escaped(event.fs_type()));
+        });
+    }
+
+    #[test]
+    fn tells_opens_that_can_change_the_file() {
+        for (f_flag, write) in [
+            (0o0, false),
+            // O_CREAT and O_EXCL alone do not touch an existing file.
+            (0o300, false),
+            (0o1, true),
+            (0o2, true),
+            (0o2101, true),
+            // O_RDONLY | O_TRUNC still truncates.
+            (0o1000, true),
+        ] {
+            let buf = open_with(f_flag);
+            assert_eq!(EventRef::parse(&buf).unwrap().opened_for_write(), write, "f_flags {:o}", f_flag);
+        }
+        let mut buf = open_with(0o1);
+        set_u16(&mut buf, offset_of!(EventHeader, kind), EVENT_KIND_WRITE);
+        assert!(!EventRef::parse(&buf).unwrap().opened_for_write());
+    }
+}
@@ -1,17 +1,105 @@
 #![no_std]
 
+#[cfg(feature = "user")]
+use aya::Pod;
+
+#[cfg(feature = "user")]
+pub mod decode;
 
 const MAX_PATH_LEN: usize = 256;
 const TASK_NAME_LEN: usize = 16;
+const FS_TYPE_LEN: usize = 16;
+
+/// Bumped whenever the layout of `EventHeader` or any event changes.
+pub const SCHEMA_VERSION: u16 = 5;
+
+pub const EVENT_KIND_FILE_OPEN: u16 = 1;
+/// Changes to a file on the watchlist. They share the layout of `Event`, with
+/// `f_flag` and `ret` left 0.
+pub const EVENT_KIND_WRITE: u16 = 10;
+pub const EVENT_KIND_RENAME: u16 = 11;
+pub const EVENT_KIND_UNLINK: u16 = 12;
+pub const EVENT_KIND_SETATTR: u16 = 15;
+
+/// Opens in flight at once between the entry and return probes.
+pub const PENDING_MAX_ENTRIES: u32 = 10240;
+
+/// Files on the watchlist.
+pub const WATCHED_MAX_ENTRIES: u32 = 4096;
+
+/// Common prefix of every record sent to user space.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct EventHeader {
+    pub kind: u16,
+    pub version: u16,
+    /// Size of the whole record, header included.
+    pub size: u32,
+    /// `bpf_ktime_get_ns()` when the record was produced.
+    pub timestamp: u64,
+    pub cpu: u32,
+    pub _pad: u32,
+}
+
+impl EventHeader {
+    pub const fn new(kind: u16, size: u32, timestamp: u64, cpu: u32) -> Self {
+        EventHeader {
+            kind,
+            version: SCHEMA_VERSION,
+            size,
+            timestamp,
+            cpu,
+            _pad: 0,
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct Event {
+    pub header: EventHeader,
     pub uid: u32,
     pub pid: u32,
+    /// `real_parent->tgid` of the current task.
     pub ppid: i32,
     pub f_flag: u32,
-    pub time: u64,
+    pub gid: u32,
+    /// Effective and filesystem uids, read from `task->cred`.
+    pub euid: u32,
+    pub fsuid: u32,
+    /// `s_dev` of the file's superblock, in the kernel's `MKDEV` encoding.
+    pub dev: u32,
+    /// `task->cred->cap_effective`, one bit per `CAP_*`.
+    pub cap_effective: u64,
+    pub ino: u64,
+    pub file_size: i64,
+    /// `i_mode`: file type and permission bits.
+    pub mode: u16,
+    pub _pad: u16,
+    /// Return value of `security_file_open`: 0, or a negative errno such as
+    /// `-EACCES` when the open was refused.
+    pub ret: i32,
+    /// `f_inode->i_sb->s_type->name`, NUL padded.
+    pub fs_type: [u8; FS_TYPE_LEN],
     pub task_name: [u8; TASK_NAME_LEN],
     pub file_path: [u8; MAX_PATH_LEN],
-}
\ No newline at end of file
+}
+
+/// Key of the `WATCHED` map: a file by device and inode number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct WatchKey {
+    /// `s_dev` of the file's superblock, in the kernel's `MKDEV` encoding.
+    pub dev: u32,
+    pub _pad: u32,
+    pub ino: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl Pod for EventHeader {}
+
+#[cfg(feature = "user")]
+unsafe impl Pod for Event {}
+
+#[cfg(feature = "user")]
+unsafe impl Pod for WatchKey {}
@@ -6,11 +6,25 @@
 #![allow(non_camel_case_types)]
 #![allow(dead_code)]
 
-use aya_ebpf::{bpf_printk, cty::c_void, helpers::{self, bpf_get_current_comm, bpf_get_current_pid_tgid, bpf_get_current_task, bpf_get_current_uid_gid, bpf_ktime_get_ns, bpf_probe_read_kernel, bpf_probe_read_kernel_str_bytes}, macros::{kprobe, map}, maps::{PerCpuArray, PerfEventArray}, programs::ProbeContext};
-use binding::{dentry, file, path, qstr, task_struct};
-use perf_common::Event;
+use aya_ebpf::{cty::c_void, helpers::{self, bpf_get_current_comm, bpf_get_current_pid_tgid, bpf_get_current_task, bpf_get_current_uid_gid, bpf_get_smp_processor_id, bpf_ktime_get_ns, bpf_probe_read_kernel, bpf_probe_read_kernel_str_bytes}, macros::{kprobe, kretprobe, map}, maps::{HashMap, LruHashMap, PerCpuArray, PerfEventArray}, programs::{ProbeContext, RetProbeContext}};
+use binding::{cred, dentry, file, inode, path, qstr, task_struct};
+use perf_common::{Event, EventHeader, WatchKey, EVENT_KIND_FILE_OPEN, EVENT_KIND_RENAME, EVENT_KIND_SETATTR, EVENT_KIND_UNLINK, EVENT_KIND_WRITE, PENDING_MAX_ENTRIES, WATCHED_MAX_ENTRIES};
 mod binding;
 
+// `struct renamedata` as Linux 5.12 lays it out, which the bindings lack.
+// User space only attaches the probes that read it from 5.12 on.
+#[repr(C)]
+struct RenameData {
+    old_mnt_userns: *const c_void,
+    old_dir: *const inode,
+    old_dentry: *const dentry,
+    new_mnt_userns: *const c_void,
+    new_dir: *const inode,
+    new_dentry: *const dentry,
+    delegated_inode: *const c_void,
+    flags: u32,
+}
+
 #[map]
 static mut EVENT_BUF: PerCpuArray<Event> = PerCpuArray::with_max_entries(1, 0);
 
@@ -20,6 +34,21 @@ static mut TASK_BUF: PerCpuArray<task_struct> = PerCpuArray::with_max_entries(1,
 #[map]
 static mut EVENTS: PerfEventArray<Event> = PerfEventArray::new(0);
 
+// Events whose `security_file_open` has not returned yet, by pid_tgid. LRU so
+// entries of calls whose return we never see cannot fill it up.
+#[map]
+static mut PENDING: LruHashMap<u64, Event> = LruHashMap::with_max_entries(PENDING_MAX_ENTRIES, 0);
+
+// Files on the integrity watchlist. Writes, renames, unlinks and attribute
+// changes are only sent for these. Filled by user space.
+#[map]
+static mut WATCHED: HashMap<WatchKey, u8> = HashMap::with_max_entries(WATCHED_MAX_ENTRIES, 0);
+
+// Only send opens that `security_file_open` refused. Set by user space at
+// load time.
+#[no_mangle]
+static DENIED_ONLY: u8 = 0;
+
 #[kprobe]
 pub fn perf(ctx: ProbeContext) -> u32 {
     match try_perf(ctx) {
@@ -28,9 +57,56 @@ pub fn perf(ctx: ProbeContext) -> u32 {
     }
 }
 
+/// Effective uid, filesystem uid and effective capabilities from `cred`.
+unsafe fn read_cred(cred: *const cred) -> Result<(u32, u32, u64), i64> {
+    let euid = bpf_probe_read_kernel(&(*cred).euid)?.val;
+    let fsuid = bpf_probe_read_kernel(&(*cred).fsuid)?.val;
+    // kernel_cap_t is two 32 bit words on this kernel.
+    let caps = bpf_probe_read_kernel(&(*cred).cap_effective)?;
+    Ok((euid, fsuid, caps.cap[0] as u64 | (caps.cap[1] as u64) << 32))
+}
+
+/// Inode number, size, mode, superblock device and filesystem type of `inode`.
+unsafe fn read_inode(inode: *const inode, event: &mut Event) -> Result<(), i64> {
+    event.ino = bpf_probe_read_kernel(&(*inode).i_ino)? as u64;
+    event.file_size = bpf_probe_read_kernel(&(*inode).i_size)?;
+    event.mode = bpf_probe_read_kernel(&(*inode).i_mode)?;
+    let sb = bpf_probe_read_kernel(&(*inode).i_sb)?;
+    event.dev = bpf_probe_read_kernel(&(*sb).s_dev)?;
+    let fs_type = bpf_probe_read_kernel(&(*sb).s_type)?;
+    let name = bpf_probe_read_kernel(&(*fs_type).name)?;
+    bpf_probe_read_kernel_str_bytes(name as *const u8, &mut event.fs_type)?;
+    Ok(())
+}
+
+/// Header and everything about the current task: ids, credentials and name.
+unsafe fn read_task(event: &mut Event, kind: u16) -> Result<(), i64> {
+    let task = bpf_get_current_task() as *const task_struct;
+    let p_task = TASK_BUF.get_ptr_mut(0).ok_or(1i64)?;
+    helpers::gen::bpf_probe_read_kernel(p_task as *mut c_void, core::mem::size_of::<task_struct>() as u32, task as *const c_void);
+    event.header = EventHeader::new(kind, core::mem::size_of::<Event>() as u32, bpf_ktime_get_ns(), bpf_get_smp_processor_id());
+    let uid_gid = bpf_get_current_uid_gid();
+    event.uid = uid_gid as u32;
+    event.gid = (uid_gid >> 32) as u32;
+    let pid_tgid = bpf_get_current_pid_tgid();
+    event.pid = (pid_tgid >> 32) as u32;
+    let parent = (*p_task).real_parent;
+    event.ppid = bpf_probe_read_kernel(&(*parent).tgid)?;
+    (event.euid, event.fsuid, event.cap_effective) = read_cred((*p_task).cred)?;
+    event.task_name = bpf_get_current_comm()?;
+    Ok(())
+}
+
+#[kretprobe]
+pub fn perf_ret(ctx: RetProbeContext) -> u32 {
+    match try_perf_ret(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
 fn try_perf(ctx: ProbeContext) -> Result<u32, i64> {
     let file:*mut file  = ctx.arg(0).ok_or(1i64)?;
-    let task = unsafe {bpf_get_current_task() as *const task_struct};
     let path = unsafe {
         bpf_probe_read_kernel(&(*file).f_path as *const path)
             .map_err(|e| e)?
@@ -52,30 +128,121 @@ fn try_perf(ctx: ProbeContext) -> Result<u32, i64> {
         &mut *ptr
     };
 
-    let p_task = unsafe {
-        TASK_BUF.get_ptr_mut(0).ok_or(1i64)?
-    };
-    unsafe {
-        helpers::gen::bpf_probe_read_kernel(p_task as *mut c_void, core::mem::size_of::<task_struct>() as u32, task as *const c_void);
-    }
-    event.uid = bpf_get_current_uid_gid() as u32;
-    event.pid = (bpf_get_current_pid_tgid() >> 32) as u32;
-    event.ppid = unsafe {(*p_task).pid};
-    // event.ppid = unsafe { bpf_probe_read_kernel((*p_task).pid as *const i32).map_err(|e| e)? };
+    let pid_tgid = bpf_get_current_pid_tgid();
+    unsafe { read_task(event, EVENT_KIND_FILE_OPEN)? };
     event.f_flag = unsafe { bpf_probe_read_kernel(&(*file).f_flags as *const u32).map_err(|e| e)?};
-    event.time = unsafe { bpf_ktime_get_ns()};
-    event.task_name = bpf_get_current_comm()?;
+    unsafe { read_inode(bpf_probe_read_kernel(&(*file).f_inode)?, event)? };
     unsafe {
         bpf_probe_read_kernel_str_bytes(dname.name, &mut event.file_path)?;
-        EVENTS.output(&ctx, event, 0);
+        // Sent by `perf_ret` once the result is known.
+        PENDING.insert(&pid_tgid, event, 0)?;
     }
+    // info!(&ctx, "function security_file_open called");
+    Ok(0)
+}
+
+fn try_perf_ret(ctx: RetProbeContext) -> Result<u32, i64> {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let event = unsafe { PENDING.get_ptr_mut(&pid_tgid).ok_or(1i64)? };
+    let ret: i32 = ctx.ret().ok_or(1i64)?;
     unsafe {
-        bpf_printk!(b"hi there! dec: %d, hex: 0x%08X", 42, 0x1234);
+        (*event).ret = ret;
+        if ret != 0 || core::ptr::read_volatile(&DENIED_ONLY) == 0 {
+            EVENTS.output(&ctx, &*event, 0);
+        }
+        PENDING.remove(&pid_tgid)?;
     }
-    // info!(&ctx, "function security_file_open called");
     Ok(0)
 }
 
+#[kprobe]
+pub fn vfs_write(ctx: ProbeContext) -> u32 {
+    match try_vfs_write(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[kprobe]
+pub fn vfs_rename(ctx: ProbeContext) -> u32 {
+    match try_vfs_rename(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[kprobe]
+pub fn vfs_unlink(ctx: ProbeContext) -> u32 {
+    match try_vfs_unlink(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[kprobe]
+pub fn notify_change(ctx: ProbeContext) -> u32 {
+    match try_notify_change(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+fn try_vfs_write(ctx: ProbeContext) -> Result<u32, i64> {
+    let file: *const file = ctx.arg(0).ok_or(1i64)?;
+    unsafe { report_watched(&ctx, EVENT_KIND_WRITE, bpf_probe_read_kernel(&(*file).f_path.dentry)?)? };
+    Ok(0)
+}
+
+fn try_vfs_rename(ctx: ProbeContext) -> Result<u32, i64> {
+    let rd: *const RenameData = ctx.arg(0).ok_or(1i64)?;
+    unsafe {
+        // A watched file can be moved away, or replaced by another one.
+        report_watched(&ctx, EVENT_KIND_RENAME, bpf_probe_read_kernel(&(*rd).old_dentry)?)?;
+        report_watched(&ctx, EVENT_KIND_RENAME, bpf_probe_read_kernel(&(*rd).new_dentry)?)?;
+    }
+    Ok(0)
+}
+
+fn try_vfs_unlink(ctx: ProbeContext) -> Result<u32, i64> {
+    let dentry: *const dentry = ctx.arg(2).ok_or(1i64)?;
+    unsafe { report_watched(&ctx, EVENT_KIND_UNLINK, dentry)? };
+    Ok(0)
+}
+
+fn try_notify_change(ctx: ProbeContext) -> Result<u32, i64> {
+    let dentry: *const dentry = ctx.arg(1).ok_or(1i64)?;
+    unsafe { report_watched(&ctx, EVENT_KIND_SETATTR, dentry)? };
+    Ok(0)
+}
+
+/// Sends a `kind` event about the file behind `dentry` if it is on the
+/// watchlist. The change has not happened yet; user space looks at the file
+/// once it has.
+unsafe fn report_watched(ctx: &ProbeContext, kind: u16, dentry: *const dentry) -> Result<(), i64> {
+    let inode = bpf_probe_read_kernel(&(*dentry).d_inode)?;
+    if inode.is_null() {
+        return Ok(());
+    }
+    let sb = bpf_probe_read_kernel(&(*inode).i_sb)?;
+    let key = WatchKey {
+        dev: bpf_probe_read_kernel(&(*sb).s_dev)?,
+        _pad: 0,
+        ino: bpf_probe_read_kernel(&(*inode).i_ino)? as u64,
+    };
+    if WATCHED.get(&key).is_none() {
+        return Ok(());
+    }
+    let event = &mut *EVENT_BUF.get_ptr_mut(0).ok_or(1i64)?;
+    read_task(event, kind)?;
+    read_inode(inode, event)?;
+    event.f_flag = 0;
+    event.ret = 0;
+    let name = bpf_probe_read_kernel(&(*dentry).d_name.name)?;
+    bpf_probe_read_kernel_str_bytes(name, &mut event.file_path)?;
+    EVENTS.output(ctx, event, 0);
+    Ok(())
+}
+
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     unsafe { core::hint::unreachable_unchecked() }
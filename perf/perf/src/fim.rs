@@ -0,0 +1,741 @@
+//! File-integrity monitoring: reports files on a watchlist that drift from
+//! their expected state.
+//!
+//! The watchlist is a set of paths and glob patterns such as `/usr/bin/*`.
+//! Every file they match is described by its device and inode, mode, owner,
+//! size, mtime and, optionally, a SHA-256 of its content; that description is
+//! the baseline, and it is kept in a state file so it survives restarts.
+//!
+//! The kernel side reports opens for writing of any file, and writes,
+//! renames, unlinks and attribute changes of the watched inodes. Each of
+//! those makes us look at the file again shortly after, and every difference
+//! from the baseline is reported as an alert and then becomes the new
+//! baseline, so a change is reported once. A periodic rescan, which also
+//! expands the patterns again, catches what the probes cannot see, such as
+//! files added under a watched directory. On startup the state file is
+//! compared with the files as they are, so changes made while nothing was
+//! watching are reported too.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Context as _;
+use aya::maps::{HashMap as BpfHashMap, MapData};
+use log::{info, warn};
+use perf_common::decode::{Escaped, EventRef};
+use perf_common::{WatchKey, EVENT_KIND_FILE_OPEN, EVENT_KIND_RENAME, EVENT_KIND_SETATTR, EVENT_KIND_UNLINK, EVENT_KIND_WRITE, WATCHED_MAX_ENTRIES};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use tracer_support::otlp::{self, AttrValue, Exporter, LogRecord};
+
+/// How long after a change is announced the file is looked at, so the write,
+/// rename or chmod the kernel side saw coming has happened by then.
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+/// Bumped whenever the state file changes incompatibly.
+const STATE_VERSION: u32 = 1;
+
+/// What a watched file looks like. Symlinks are followed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileState {
+    /// Device in the kernel's `MKDEV` encoding, as events carry it.
+    pub dev: u32,
+    pub ino: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    /// Modification time in nanoseconds since the epoch.
+    pub mtime: i64,
+    /// SHA-256 of the content of a regular file, when hashing is on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+impl FileState {
+    /// Describes the file at `path`, or returns `None` if there is none.
+    fn read(path: &Path, hash: bool) -> io::Result<Option<Self>> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let sha256 = if hash && metadata.is_file() { Some(sha256(path)?) } else { None };
+        Ok(Some(FileState {
+            dev: libc::major(metadata.dev()) << 20 | libc::minor(metadata.dev()),
+            ino: metadata.ino(),
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size(),
+            mtime: metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec(),
+            sha256,
+        }))
+    }
+
+    fn key(&self) -> WatchKey {
+        WatchKey {
+            dev: self.dev,
+            _pad: 0,
+            ino: self.ino,
+        }
+    }
+
+    /// `major:minor/ino`.
+    fn inode(&self) -> String {
+        format!("{}:{}/{}", self.dev >> 20, self.dev & ((1 << 20) - 1), self.ino)
+    }
+
+    /// The fields that differ in `after`, with their old and new values.
+    fn diff(&self, after: &FileState) -> Vec<Change> {
+        let mut changes = Vec::new();
+        let mut field = |field, before: String, after: String| {
+            if before != after {
+                changes.push(Change { field, before, after });
+            }
+        };
+        field("inode", self.inode(), after.inode());
+        field("mode", format!("{:06o}", self.mode), format!("{:06o}", after.mode));
+        field("uid", self.uid.to_string(), after.uid.to_string());
+        field("gid", self.gid.to_string(), after.gid.to_string());
+        field("size", self.size.to_string(), after.size.to_string());
+        field("mtime", Seconds(self.mtime).to_string(), Seconds(after.mtime).to_string());
+        // Only comparable when both sides were hashed.
+        if let (Some(before), Some(after)) = (&self.sha256, &after.sha256) {
+            field("sha256", before.clone(), after.clone());
+        }
+        changes
+    }
+}
+
+fn sha256(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Nanoseconds since the epoch, shown as seconds.
+struct Seconds(i64);
+
+impl fmt::Display for Seconds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:09}", self.0.div_euclid(1_000_000_000), self.0.rem_euclid(1_000_000_000))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StateFile {
+    version: u32,
+    patterns: Vec<String>,
+    hash: bool,
+    files: BTreeMap<String, FileState>,
+}
+
+/// What made us look at a file.
+#[derive(Debug, Clone)]
+pub enum Cause {
+    Startup,
+    Rescan,
+    Event {
+        op: &'static str,
+        pid: u32,
+        uid: u32,
+        task_name: String,
+        /// `bpf_ktime_get_ns()` of the event.
+        timestamp: u64,
+    },
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cause::Startup => write!(f, "found : at startup"),
+            Cause::Rescan => write!(f, "found : on rescan"),
+            Cause::Event { op, pid, uid, task_name, .. } => {
+                write!(f, "op : {}, pid : {}, uid : {}, task_name : {}", op, pid, uid, task_name)
+            }
+        }
+    }
+}
+
+/// One field of a file that is not what it was.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub field: &'static str,
+    pub before: String,
+    pub after: String,
+}
+
+/// A watched file that drifted from its baseline.
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub path: String,
+    pub before: Option<FileState>,
+    pub after: Option<FileState>,
+    /// Empty unless the file was there before and after.
+    pub changes: Vec<Change>,
+    pub cause: Cause,
+}
+
+impl Alert {
+    pub fn kind(&self) -> &'static str {
+        match (&self.before, &self.after) {
+            (None, _) => "added",
+            (_, None) => "removed",
+            _ => "modified",
+        }
+    }
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[drift] path : {}, change : {}", self.path, self.kind())?;
+        for change in &self.changes {
+            write!(f, ", {} : {} -> {}", change.field, change.before, change.after)?;
+        }
+        write!(f, ", {}", self.cause)
+    }
+}
+
+/// A look at a watched file the kernel side asked for.
+#[derive(Debug)]
+pub struct Trigger {
+    key: WatchKey,
+    cause: Cause,
+}
+
+impl Trigger {
+    /// The trigger in `event`, if it may have changed a file: an allowed
+    /// open for writing, or a write, rename, unlink or attribute change.
+    pub fn from_event(event: &EventRef) -> Option<Self> {
+        let op = match event.kind() {
+            EVENT_KIND_FILE_OPEN if event.opened_for_write() && !event.denied() => "open for write",
+            EVENT_KIND_WRITE => "write",
+            EVENT_KIND_RENAME => "rename",
+            EVENT_KIND_UNLINK => "unlink",
+            EVENT_KIND_SETATTR => "setattr",
+            _ => return None,
+        };
+        Some(Trigger {
+            key: WatchKey {
+                dev: event.dev(),
+                _pad: 0,
+                ino: event.ino(),
+            },
+            cause: Cause::Event {
+                op,
+                pid: event.pid(),
+                uid: event.uid(),
+                task_name: Escaped(event.task_name()).to_string(),
+                timestamp: event.timestamp(),
+            },
+        })
+    }
+}
+
+pub struct Watchlist {
+    patterns: Vec<String>,
+    hash: bool,
+    state_path: PathBuf,
+    /// The baseline of the files that exist, by path.
+    files: BTreeMap<String, FileState>,
+    by_key: HashMap<WatchKey, String>,
+}
+
+impl Watchlist {
+    /// Takes the baseline of the files `patterns` match and returns the
+    /// differences from the one in `state_path`. Without a usable state file
+    /// the files as they are now become the baseline, without alerts.
+    pub fn open(patterns: Vec<String>, hash: bool, state_path: PathBuf) -> Result<(Self, Vec<Alert>), anyhow::Error> {
+        for pattern in &patterns {
+            glob::Pattern::new(pattern).with_context(|| format!("invalid watch pattern {}", pattern))?;
+        }
+        let mut watchlist = Watchlist {
+            patterns,
+            hash,
+            state_path,
+            files: BTreeMap::new(),
+            by_key: HashMap::new(),
+        };
+        let previous = watchlist.load()?;
+        let baseline = previous.is_some();
+        if let Some(files) = previous {
+            watchlist.by_key = files.iter().map(|(path, state)| (state.key(), path.clone())).collect();
+            watchlist.files = files;
+        }
+        let alerts = watchlist.rescan(Cause::Startup);
+        watchlist.save()?;
+        Ok((watchlist, if baseline { alerts } else { Vec::new() }))
+    }
+
+    /// Number of watched files that exist.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// The path of the watched file with `key`.
+    pub fn path_of(&self, key: &WatchKey) -> Option<&str> {
+        self.by_key.get(key).map(String::as_str)
+    }
+
+    /// Looks at every file the patterns match now, and at every file in the
+    /// baseline.
+    pub fn rescan(&mut self, cause: Cause) -> Vec<Alert> {
+        let mut paths = self.expand();
+        paths.extend(self.files.keys().cloned());
+        paths.sort();
+        paths.dedup();
+        self.check(paths.into_iter().map(|path| (path, cause.clone())))
+    }
+
+    /// Compares the files at `paths` with their baseline, which they replace.
+    pub fn check(&mut self, paths: impl IntoIterator<Item = (String, Cause)>) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        let mut dirty = false;
+        for (path, cause) in paths {
+            let after = match FileState::read(Path::new(&path), self.hash) {
+                Ok(after) => after,
+                Err(e) => {
+                    warn!("failed to look at {}: {}", path, e);
+                    continue;
+                }
+            };
+            let before = self.files.get(&path).cloned();
+            if before == after {
+                continue;
+            }
+            dirty = true;
+            if let Some(before) = &before {
+                self.by_key.remove(&before.key());
+            }
+            match &after {
+                Some(after) => {
+                    self.by_key.insert(after.key(), path.clone());
+                    self.files.insert(path.clone(), after.clone());
+                }
+                None => {
+                    self.files.remove(&path);
+                }
+            }
+            let changes = match (&before, &after) {
+                (Some(before), Some(after)) => before.diff(after),
+                _ => Vec::new(),
+            };
+            // A hash filled in for the first time is not a change.
+            if before.is_some() && after.is_some() && changes.is_empty() {
+                continue;
+            }
+            alerts.push(Alert {
+                path,
+                before,
+                after,
+                changes,
+                cause,
+            });
+        }
+        if dirty {
+            if let Err(e) = self.save() {
+                warn!("failed to save {}: {:#}", self.state_path.display(), e);
+            }
+        }
+        alerts
+    }
+
+    /// The paths the patterns stand for now. Plain paths stand for
+    /// themselves even while they do not exist, so their creation is noticed.
+    fn expand(&self) -> Vec<String> {
+        let mut paths = Vec::new();
+        for pattern in &self.patterns {
+            if !pattern.contains(['*', '?', '[']) {
+                paths.push(pattern.clone());
+                continue;
+            }
+            match glob::glob(pattern) {
+                Ok(entries) => {
+                    paths.extend(entries.flatten().map(|path| path.to_string_lossy().into_owned()));
+                }
+                Err(e) => warn!("failed to expand {}: {}", pattern, e),
+            }
+        }
+        paths
+    }
+
+    /// The baseline in the state file, if it was taken for the same
+    /// patterns.
+    fn load(&self) -> Result<Option<BTreeMap<String, FileState>>, anyhow::Error> {
+        let data = match fs::read(&self.state_path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", self.state_path.display())),
+        };
+        let state: StateFile = serde_json::from_slice(&data)
+            .with_context(|| format!("invalid state file {}", self.state_path.display()))?;
+        if state.version != STATE_VERSION || state.patterns != self.patterns || state.hash != self.hash {
+            info!(
+                "{} was recorded for another watchlist, taking a new baseline",
+                self.state_path.display()
+            );
+            return Ok(None);
+        }
+        Ok(Some(state.files))
+    }
+
+    /// Writes the baseline to a temporary file and renames it over the state
+    /// file, so a crash leaves the old or the new state, never half of one.
+    fn save(&self) -> Result<(), anyhow::Error> {
+        let state = StateFile {
+            version: STATE_VERSION,
+            patterns: self.patterns.clone(),
+            hash: self.hash,
+            files: self.files.clone(),
+        };
+        let mut tmp = self.state_path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&state)?)
+            .with_context(|| format!("failed to write {}", PathBuf::from(&tmp).display()))?;
+        fs::rename(&tmp, &self.state_path)
+            .with_context(|| format!("failed to replace {}", self.state_path.display()))?;
+        Ok(())
+    }
+}
+
+/// Makes the `WATCHED` map hold exactly the inodes of the watched files.
+pub fn sync_watched(watched: &mut BpfHashMap<MapData, WatchKey, u8>, watchlist: &Watchlist) {
+    let stale: Vec<WatchKey> = watched
+        .keys()
+        .flatten()
+        .filter(|key| !watchlist.by_key.contains_key(key))
+        .collect();
+    for key in stale {
+        if let Err(e) = watched.remove(&key) {
+            warn!("failed to unwatch {}:{}: {}", key.dev, key.ino, e);
+        }
+    }
+    if watchlist.by_key.len() > WATCHED_MAX_ENTRIES as usize {
+        warn!(
+            "{} files are watched but only {} fit in WATCHED; the others are only checked on rescan",
+            watchlist.by_key.len(),
+            WATCHED_MAX_ENTRIES
+        );
+    }
+    for key in watchlist.by_key.keys() {
+        if let Err(e) = watched.insert(key, 0, 0) {
+            warn!("failed to watch {}: {}", watchlist.by_key[key], e);
+        }
+    }
+}
+
+/// Prints `alerts` and exports them as OTLP log records.
+pub fn report(alerts: &[Alert], exporter: Option<&Exporter>) {
+    for alert in alerts {
+        println!("{}", alert);
+        let Some(exporter) = exporter else {
+            continue;
+        };
+        let mut attributes = vec![
+            ("file.path", AttrValue::Str(alert.path.clone())),
+            ("fim.change", AttrValue::Str(alert.kind().to_owned())),
+            (
+                "fim.changed_fields",
+                AttrValue::Str(alert.changes.iter().map(|change| change.field).collect::<Vec<_>>().join(",")),
+            ),
+        ];
+        if let Some(after) = &alert.after {
+            attributes.extend([
+                ("file.inode", AttrValue::Int(after.ino as i64)),
+                ("file.mode", AttrValue::Str(format!("{:04o}", after.mode & 0o7777))),
+                ("file.owner.id", AttrValue::Int(after.uid as i64)),
+                ("file.group.id", AttrValue::Int(after.gid as i64)),
+                ("file.size", AttrValue::Int(after.size as i64)),
+            ]);
+            if let Some(sha256) = &after.sha256 {
+                attributes.push(("file.hash.sha256", AttrValue::Str(sha256.clone())));
+            }
+        }
+        let (timestamp, pid, comm) = match &alert.cause {
+            Cause::Event { op, pid, uid, task_name, timestamp } => {
+                attributes.push(("fim.cause", AttrValue::Str((*op).to_owned())));
+                attributes.push(("process.owner.id", AttrValue::Int(*uid as i64)));
+                (*timestamp, *pid, Some(task_name.clone()))
+            }
+            Cause::Startup | Cause::Rescan => (otlp::ktime_now(), 0, None),
+        };
+        exporter.export(LogRecord {
+            timestamp,
+            pid,
+            comm,
+            body: alert.to_string(),
+            attributes,
+        });
+    }
+}
+
+/// Runs the watchlist on its own thread and returns where to send triggers.
+/// Triggers for the same file within `SETTLE_DELAY` are looked at together,
+/// and credited to the first of them.
+pub fn spawn(
+    watchlist: Watchlist,
+    watched: BpfHashMap<MapData, WatchKey, u8>,
+    rescan_interval: Duration,
+    exporter: Option<Arc<Exporter>>,
+) -> Sender<Trigger> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || run(watchlist, watched, rescan_interval, exporter, rx));
+    tx
+}
+
+fn run(
+    mut watchlist: Watchlist,
+    mut watched: BpfHashMap<MapData, WatchKey, u8>,
+    rescan_interval: Duration,
+    exporter: Option<Arc<Exporter>>,
+    rx: Receiver<Trigger>,
+) {
+    let mut pending: BTreeMap<String, Cause> = BTreeMap::new();
+    let mut settle_at: Option<Instant> = None;
+    let mut rescan_at = Instant::now() + rescan_interval;
+    loop {
+        let wake_at = settle_at.map_or(rescan_at, |at| at.min(rescan_at));
+        match rx.recv_timeout(wake_at.saturating_duration_since(Instant::now())) {
+            Ok(trigger) => {
+                if let Some(path) = watchlist.path_of(&trigger.key) {
+                    pending.entry(path.to_owned()).or_insert(trigger.cause);
+                    settle_at.get_or_insert_with(|| Instant::now() + SETTLE_DELAY);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        let now = Instant::now();
+        let mut alerts = Vec::new();
+        if settle_at.is_some_and(|at| at <= now) {
+            settle_at = None;
+            alerts.extend(watchlist.check(std::mem::take(&mut pending)));
+        }
+        if rescan_at <= now {
+            rescan_at = now + rescan_interval;
+            alerts.extend(watchlist.rescan(Cause::Rescan));
+        }
+        if !alerts.is_empty() {
+            report(&alerts, exporter.as_deref());
+            // Replaced and new files have new inodes.
+            sync_watched(&mut watched, &watchlist);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    /// A scratch directory, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("fim-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("watched")).unwrap();
+            TempDir(dir)
+        }
+
+        fn file(&self, name: &str) -> PathBuf {
+            self.0.join("watched").join(name)
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.file(name).to_string_lossy().into_owned()
+        }
+
+        fn pattern(&self) -> Vec<String> {
+            vec![self.path("*")]
+        }
+
+        fn state(&self) -> PathBuf {
+            self.0.join("state.json")
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn summary(alerts: &[Alert]) -> Vec<(String, &'static str, Vec<&'static str>)> {
+        let mut summary: Vec<_> = alerts
+            .iter()
+            .map(|alert| (alert.path.clone(), alert.kind(), alert.changes.iter().map(|change| change.field).collect()))
+            .collect();
+        summary.sort();
+        summary
+    }
+
+    #[test]
+    fn takes_a_baseline_without_alerts() {
+        let dir = TempDir::new("baseline");
+        fs::write(dir.file("a"), "a").unwrap();
+        fs::write(dir.file("b"), "bb").unwrap();
+        let (watchlist, alerts) = Watchlist::open(dir.pattern(), false, dir.state()).unwrap();
+        assert!(alerts.is_empty());
+        assert_eq!(watchlist.len(), 2);
+
+        let a = FileState::read(&dir.file("a"), false).unwrap().unwrap();
+        assert_eq!(watchlist.path_of(&a.key()), Some(dir.path("a").as_str()));
+        assert_eq!(a.size, 1);
+        assert_eq!(a.sha256, None);
+
+        let state: StateFile = serde_json::from_slice(&fs::read(dir.state()).unwrap()).unwrap();
+        assert_eq!((state.version, state.patterns, state.hash), (STATE_VERSION, dir.pattern(), false));
+        assert_eq!(state.files.keys().cloned().collect::<Vec<_>>(), [dir.path("a"), dir.path("b")]);
+    }
+
+    #[test]
+    fn reports_drift_once() {
+        let dir = TempDir::new("drift");
+        fs::write(dir.file("grows"), "a").unwrap();
+        fs::write(dir.file("chmod"), "a").unwrap();
+        fs::write(dir.file("gone"), "a").unwrap();
+        let (mut watchlist, _) = Watchlist::open(dir.pattern(), false, dir.state()).unwrap();
+
+        fs::write(dir.file("grows"), "longer").unwrap();
+        fs::set_permissions(dir.file("chmod"), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::remove_file(dir.file("gone")).unwrap();
+        fs::write(dir.file("new"), "a").unwrap();
+
+        let alerts = watchlist.rescan(Cause::Rescan);
+        let summary = summary(&alerts);
+        assert_eq!(summary.len(), 4, "{:?}", summary);
+        assert_eq!(summary[0], (dir.path("chmod"), "modified", vec!["mode"]));
+        assert_eq!(summary[1], (dir.path("gone"), "removed", vec![]));
+        assert_eq!((summary[2].0.as_str(), summary[2].1), (dir.path("grows").as_str(), "modified"));
+        assert!(summary[2].2.contains(&"size"));
+        assert_eq!(summary[3], (dir.path("new"), "added", vec![]));
+        assert_eq!(watchlist.len(), 3);
+
+        // What was reported is the new baseline.
+        assert!(watchlist.rescan(Cause::Rescan).is_empty());
+    }
+
+    #[test]
+    fn notices_a_watched_path_being_created() {
+        let dir = TempDir::new("created");
+        let (mut watchlist, _) = Watchlist::open(vec![dir.path("later")], false, dir.state()).unwrap();
+        assert_eq!(watchlist.len(), 0);
+        fs::write(dir.file("later"), "a").unwrap();
+        let alerts = watchlist.rescan(Cause::Rescan);
+        assert_eq!(summary(&alerts), [(dir.path("later"), "added", vec![])]);
+    }
+
+    #[test]
+    fn hashes_content_when_asked() {
+        let dir = TempDir::new("hash");
+        fs::write(dir.file("a"), "same size").unwrap();
+        let (mut watchlist, _) = Watchlist::open(dir.pattern(), true, dir.state()).unwrap();
+        let before = FileState::read(&dir.file("a"), true).unwrap().unwrap();
+        assert_eq!(before.sha256.as_deref().map(str::len), Some(64));
+
+        fs::write(dir.file("a"), "SAME SIZE").unwrap();
+        let alerts = watchlist.check([(dir.path("a"), Cause::Rescan)]);
+        assert_eq!(alerts.len(), 1);
+        assert!(alerts[0].changes.iter().any(|change| change.field == "sha256"), "{}", alerts[0]);
+    }
+
+    #[test]
+    fn reports_what_changed_while_nothing_was_watching() {
+        let dir = TempDir::new("restart");
+        fs::write(dir.file("a"), "a").unwrap();
+        fs::write(dir.file("b"), "b").unwrap();
+        drop(Watchlist::open(dir.pattern(), false, dir.state()).unwrap());
+
+        fs::write(dir.file("a"), "changed").unwrap();
+        fs::remove_file(dir.file("b")).unwrap();
+        let (watchlist, alerts) = Watchlist::open(dir.pattern(), false, dir.state()).unwrap();
+        let summary = summary(&alerts);
+        assert_eq!(summary.len(), 2, "{:?}", summary);
+        assert_eq!((summary[0].0.as_str(), summary[0].1), (dir.path("a").as_str(), "modified"));
+        assert_eq!(summary[1], (dir.path("b"), "removed", vec![]));
+        assert!(alerts.iter().all(|alert| matches!(alert.cause, Cause::Startup)));
+        assert_eq!(watchlist.len(), 1);
+
+        // The state file was brought up to date.
+        let (_, alerts) = Watchlist::open(dir.pattern(), false, dir.state()).unwrap();
+        assert!(alerts.is_empty());
+    }
+
+    #[test]
+    fn takes_a_new_baseline_for_another_watchlist() {
+        let dir = TempDir::new("other");
+        fs::write(dir.file("a"), "a").unwrap();
+        drop(Watchlist::open(dir.pattern(), false, dir.state()).unwrap());
+        fs::write(dir.file("a"), "changed").unwrap();
+        // Turning hashing on changes what the baseline holds.
+        let (_, alerts) = Watchlist::open(dir.pattern(), true, dir.state()).unwrap();
+        assert!(alerts.is_empty());
+        let state: StateFile = serde_json::from_slice(&fs::read(dir.state()).unwrap()).unwrap();
+        assert!(state.hash);
+        assert!(state.files[&dir.path("a")].sha256.is_some());
+    }
+
+    #[test]
+    fn refuses_a_corrupt_state_file() {
+        let dir = TempDir::new("corrupt");
+        fs::write(dir.state(), "{ not json").unwrap();
+        assert!(Watchlist::open(dir.pattern(), false, dir.state()).is_err());
+        // And leaves it for someone to look at.
+        assert_eq!(fs::read_to_string(dir.state()).unwrap(), "{ not json");
+    }
+
+    #[test]
+    fn refuses_invalid_patterns() {
+        let dir = TempDir::new("pattern");
+        assert!(Watchlist::open(vec![dir.path("[")], false, dir.state()).is_err());
+    }
+
+    #[test]
+    fn shows_the_changes_in_an_alert() {
+        let before = FileState {
+            dev: 8 << 20 | 1,
+            ino: 100,
+            mode: 0o100644,
+            uid: 0,
+            gid: 0,
+            size: 10,
+            mtime: 1_500_000_000,
+            sha256: None,
+        };
+        let after = FileState {
+            ino: 101,
+            mode: 0o100600,
+            ..before.clone()
+        };
+        let alert = Alert {
+            path: "/etc/shadow".to_owned(),
+            changes: before.diff(&after),
+            before: Some(before),
+            after: Some(after),
+            cause: Cause::Event {
+                op: "rename",
+                pid: 42,
+                uid: 0,
+                task_name: "passwd".to_owned(),
+                timestamp: 1,
+            },
+        };
+        assert_eq!(
+            alert.to_string(),
+            "[drift] path : /etc/shadow, change : modified, inode : 8:1/100 -> 8:1/101, mode : 100644 -> 100600, \
+             op : rename, pid : 42, uid : 0, task_name : passwd"
+        );
+    }
+}
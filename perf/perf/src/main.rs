@@ -1,19 +1,196 @@
+mod capture;
+mod fim;
+mod rules;
 
-use std::ffi::CStr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use aya::maps::AsyncPerfEventArray;
+use anyhow::{bail, Context as _};
+use aya::maps::{AsyncPerfEventArray, HashMap};
 use aya::programs::KProbe;
 use aya::util::online_cpus;
-use aya::{include_bytes_aligned, Bpf};
+use aya::{include_bytes_aligned, Bpf, BpfLoader};
 use aya_log::BpfLogger;
 use bytes::BytesMut;
+use capture::{CaptureReader, CaptureWriter};
+use clap::{Parser, Subcommand};
+use fim::{Trigger, Watchlist};
 use log::{info, warn, debug};
-use perf_common::Event;
-use tokio::signal;
+use perf_common::decode::{DecodeStats, Escaped, EventRef};
+use serde_json::{json, Value};
+use tracer_support::control::{self, ControlSocket, Emission, Request, RpcError};
+use tracer_support::daemon::{self, Daemon, Signal, Signals};
+use tracer_support::enrich::{Enricher, Worker};
+use tracer_support::features::{AttachPoint, Features, Need};
+use tracer_support::otlp::{self, AttrValue, Exporter, LogRecord};
+use tracer_support::sandbox;
+use tracer_support::verifier;
+use rules::{Alerts, OpenFields, Rules};
+
+static DECODE_STATS: DecodeStats = DecodeStats::new();
+static EMISSION: Emission = Emission::new();
+const OTLP_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const OTLP_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(15);
+const WORKER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The functions the probes attach to: the first for opens, the rest only
+/// for `watch`, each named by its program. The last three read their
+/// arguments as Linux 5.12 lays them out, see `watch_points`.
+const ATTACH_POINTS: [AttachPoint; 5] = [
+    AttachPoint { targets: &["security_file_open"], programs: &["perf", "perf_ret"], untraced: "" },
+    AttachPoint { targets: &["vfs_write"], programs: &["vfs_write"], untraced: "writes to watched files" },
+    AttachPoint { targets: &["vfs_rename"], programs: &["vfs_rename"], untraced: "renames of watched files" },
+    AttachPoint { targets: &["vfs_unlink"], programs: &["vfs_unlink"], untraced: "unlinks of watched files" },
+    AttachPoint { targets: &["notify_change"], programs: &["notify_change"], untraced: "attribute changes of watched files" },
+];
+
+/// The eBPF source, to name the function of a line the verifier quotes.
+const EBPF_SOURCES: verifier::Sources = &[("main.rs", include_str!("../../perf-ebpf/src/main.rs"))];
+
+#[derive(Debug, Parser)]
+struct Opt {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// Also export every event as an OTLP log record to this collector URL,
+    /// e.g. http://localhost:4318/v1/logs
+    #[clap(long, global = true)]
+    otlp_endpoint: Option<String>,
+    /// Append batches the collector did not accept to this file
+    #[clap(long, global = true, requires = "otlp_endpoint")]
+    otlp_fallback: Option<PathBuf>,
+    /// Number of log records sent per export request
+    #[clap(long, global = true, default_value_t = 512)]
+    otlp_batch_size: usize,
+    /// Only report opens that were refused, e.g. by file permissions or an LSM
+    #[clap(long, global = true)]
+    denied_only: bool,
+    /// Raise alerts for the events that match the rules in this TOML file,
+    /// which is reloaded whenever it changes
+    #[clap(long, global = true)]
+    rules: Option<PathBuf>,
+    /// Only report events that match a rule
+    #[clap(long, global = true, requires = "rules")]
+    alerts_only: bool,
+    /// Print raw uids and pids instead of resolving them to names and executables
+    #[clap(long)]
+    no_enrich: bool,
+    /// How long resolved users, groups and processes are cached, in seconds
+    #[clap(long, default_value_t = 10)]
+    enrich_ttl: u64,
+    /// Serve the control API on this Unix socket
+    #[clap(long)]
+    control: Option<PathBuf>,
+    /// Run as a system service: log JSON lines, notify systemd and feed its
+    /// watchdog while the self-check passes
+    #[clap(long)]
+    daemon: bool,
+    /// Run the self-check this often, in seconds
+    #[clap(long, default_value_t = 30, requires = "daemon")]
+    check_interval: u64,
+    /// Fail the self-check once no event came in for this many seconds
+    #[clap(long, requires = "daemon")]
+    max_idle: Option<u64>,
+    /// Once attached, drop root and run as this user, under a seccomp filter
+    #[clap(long)]
+    user: Option<String>,
+    /// Write the verifier log here if a program fails to load
+    #[clap(long)]
+    verifier_log: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Trace file opens and also write the raw events to a capture file
+    Record {
+        /// Path of the capture file to create
+        path: PathBuf,
+    },
+    /// Print the events of a capture file without loading any eBPF
+    Replay {
+        /// Path of the capture file to read
+        path: PathBuf,
+    },
+    /// Watch files for drift from their recorded state instead of printing opens
+    Watch {
+        /// Files to watch; glob patterns such as '/usr/bin/*' are expanded
+        #[clap(required = true)]
+        paths: Vec<String>,
+        /// Also compare a SHA-256 of the content of regular files
+        #[clap(long)]
+        hash: bool,
+        /// File that keeps the expected state across restarts
+        #[clap(long, default_value = "perf-fim.json")]
+        state: PathBuf,
+        /// Look at every watched file again this often, in seconds
+        #[clap(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+        rescan_interval: u64,
+    },
+    /// Send a request to the control socket of a running perf and print the result
+    Ctl {
+        /// Path of the control socket
+        socket: PathBuf,
+        /// Method to call: programs, stats, pause or resume
+        method: String,
+        /// Params as a JSON object
+        params: Option<String>,
+    },
+    /// Print what the kernel supports and where the probes would attach, without loading any eBPF
+    Features,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    env_logger::init();
+    let opt = Opt::parse();
+
+    daemon::init_logger(opt.daemon);
+
+    match &opt.command {
+        Some(Command::Ctl { socket, method, params }) => return control::cli(socket, method, params.as_deref()),
+        Some(Command::Features) => {
+            for line in feature_report(&Features::probe()) {
+                println!("{}", line);
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let exporter = opt.otlp_endpoint.as_ref().map(|endpoint| {
+        Arc::new(Exporter::spawn(otlp::Config {
+            scope: "perf",
+            endpoint: endpoint.clone(),
+            fallback: opt.otlp_fallback.clone(),
+            batch_size: opt.otlp_batch_size.max(1),
+            flush_interval: OTLP_FLUSH_INTERVAL,
+        }))
+    });
+
+    let alerting = match &opt.rules {
+        Some(path) => Some(Arc::new(Alerting {
+            rules: Arc::new(Rules::load(path, rules::SUPPORTED)?),
+            alerts_only: opt.alerts_only,
+            live: !matches!(opt.command, Some(Command::Replay { .. })),
+        })),
+        None => None,
+    };
+
+    // A replay's pids belong to processes that are long gone, so it never enriches.
+    let capture = match &opt.command {
+        Some(Command::Replay { path }) => return replay(path, opt.denied_only, alerting.as_deref(), exporter.as_deref()),
+        Some(Command::Record { path }) => Some(Arc::new(Mutex::new(CaptureWriter::create(path)?))),
+        Some(Command::Watch { .. } | Command::Features | Command::Ctl { .. }) | None => None,
+    };
+    // Opens for writing are what the watchlist looks out for, so they must
+    // not be filtered away.
+    let watching = matches!(opt.command, Some(Command::Watch { .. }));
+    let denied_only = opt.denied_only && !watching;
+    // The watchlist hashes files only root may read and keeps WATCHED in
+    // step from its own thread, which has no CAP_BPF left to do so.
+    if watching && opt.user.is_some() {
+        bail!("watch mode cannot run with --user");
+    }
 
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
@@ -26,30 +203,73 @@ async fn main() -> Result<(), anyhow::Error> {
         debug!("remove limit on locked memory failed, ret is: {}", ret);
     }
 
+    let features = Features::probe();
+    for line in feature_report(&features) {
+        debug!("{}", line);
+    }
+    features.check("perf", &[Need::Kprobes, Need::GlobalData, Need::PerfArrays])?;
+
     // This will include your eBPF object file as raw bytes at compile-time and load it at
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
-    // reach for `Bpf::load_file` instead.
+    // reach for `BpfLoader::load_file` instead.
     #[cfg(debug_assertions)]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/debug/perf"
-    ))?;
+    let data = include_bytes_aligned!("../../target/bpfel-unknown-none/debug/perf");
     #[cfg(not(debug_assertions))]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/release/perf"
-    ))?;
+    let data = include_bytes_aligned!("../../target/bpfel-unknown-none/release/perf");
+    let mut bpf = BpfLoader::new()
+        .set_global("DENIED_ONLY", &(denied_only as u8), true)
+        .load(data)?;
     if let Err(e) = BpfLogger::init(&mut bpf) {
         // This can happen if you remove all log statements from your eBPF program.
         warn!("failed to initialize eBPF logger: {}", e);
     }
-    let program: &mut KProbe = bpf.program_mut("perf").unwrap().try_into()?;
-    program.load()?;
-    program.attach("security_file_open", 0)?;
+    // Program and function of every probe, for the control API.
+    let mut attached = Vec::new();
+    attach(&mut bpf, &features, &ATTACH_POINTS[..1], opt.verifier_log.as_deref(), &mut attached)?;
+
+    let fim = match &opt.command {
+        Some(Command::Watch { paths, hash, state, rescan_interval }) => {
+            let (watchlist, alerts) = Watchlist::open(paths.clone(), *hash, state.clone())?;
+            info!("watching {} files, baseline in {}", watchlist.len(), state.display());
+            fim::report(&alerts, exporter.as_deref());
+            let mut watched = HashMap::try_from(bpf.take_map("WATCHED").unwrap())?;
+            fim::sync_watched(&mut watched, &watchlist);
+            let points = watch_points(&features);
+            if points.len() < ATTACH_POINTS.len() - 1 {
+                warn!("this kernel is older than 5.12, so renames, unlinks and attribute changes of watched files are only found by the rescan");
+            }
+            attach(&mut bpf, &features, points, opt.verifier_log.as_deref(), &mut attached)?;
+            Some(fim::spawn(watchlist, watched, Duration::from_secs(*rescan_interval), exporter.clone()))
+        }
+        _ => None,
+    };
+    let mut daemon = opt.daemon.then(|| Daemon::new(Duration::from_secs(opt.check_interval), opt.max_idle.map(Duration::from_secs))).transpose()?;
+    if let Some(daemon) = &mut daemon {
+        daemon.watch(&daemon::program_ids(&bpf, attached.iter().map(|(program, _)| *program))?);
+    }
+
+    // Looking processes up blocks, so enriched events are handled on a
+    // thread of their own rather than on the tasks reading them.
+    let worker = (!opt.no_enrich).then(|| {
+        let enricher = Enricher::new(Duration::from_secs(opt.enrich_ttl));
+        let alerting = alerting.clone();
+        let exporter = exporter.clone();
+        Arc::new(Worker::spawn(move |buf: Vec<u8>| handle_event(&buf, Some(&enricher), alerting.as_deref(), exporter.as_deref())))
+    });
+    if let Some(alerting) = &alerting {
+        alerting.rules.watch();
+    }
 
     let mut perf_array = AsyncPerfEventArray::try_from(bpf.take_map("EVENTS").unwrap())?;
 
     for cpu_id in online_cpus()? {
         let mut buf = perf_array.open(cpu_id, None)?;
+        let capture = capture.clone();
+        let worker = worker.clone();
+        let exporter = exporter.clone();
+        let alerting = alerting.clone();
+        let fim = fim.clone();
         tokio::spawn(async move {
             let mut buffers = (0..10)
                 .map(|_| BytesMut::with_capacity(1024))
@@ -57,32 +277,248 @@ async fn main() -> Result<(), anyhow::Error> {
             loop {
                 let events = buf.read_events(&mut buffers).await.unwrap();
 
-                for i in 0..events.read{
-                    let buf = &mut buffers[i];
-                    if let Ok(event) = parse_event(buf) {
-                        let file_path = CStr::from_bytes_until_nul(&event.file_path).unwrap().to_str().unwrap();
-                        let task_name = CStr::from_bytes_until_nul(&event.task_name).unwrap().to_str().unwrap();
-                        println!("uid : {}, pid : {}, ppid : {}, task_name : {}, file_path : {}, f_flag : {}, time : {}", event.uid, event.pid, event.ppid, task_name, file_path, event.f_flag, event.time);
-                    } else {
-                        eprintln!("failed to parse event");
+                for buf in buffers.iter().take(events.read) {
+                    if let Some(fim) = &fim {
+                        handle_watch_event(buf, fim);
+                        continue;
+                    }
+                    if EMISSION.suppress() {
+                        continue;
+                    }
+                    if let Some(capture) = &capture {
+                        if let Err(e) = capture.lock().unwrap().write_record(buf) {
+                            warn!("failed to write capture record: {}", e);
+                        }
+                    }
+                    match &worker {
+                        Some(worker) => worker.submit(buf.to_vec()),
+                        None => handle_event(buf, None, alerting.as_deref(), exporter.as_deref()),
                     }
                 }
             }
         });
     }
+    let mut control = opt.control.as_deref().map(ControlSocket::bind).transpose()?;
+    let mut signals = Signals::new()?;
+    if let Some(user) = &opt.user {
+        sandbox::enter(user, false)?;
+    }
+    if let Some(daemon) = &daemon {
+        daemon.ready();
+    }
+
     info!("Waiting for Ctrl-C...");
-    signal::ctrl_c().await?;
+    loop {
+        tokio::select! {
+            request = ControlSocket::next(&mut control) => {
+                let result = handle_request(&request, &attached);
+                request.reply(result);
+            }
+            _ = Daemon::tick(&mut daemon) => {
+                if let Some(daemon) = &mut daemon {
+                    daemon.check(DECODE_STATS.decoded() + DECODE_STATS.malformed() + EMISSION.suppressed());
+                }
+            }
+            signal = signals.next() => match signal {
+                Signal::Shutdown => break,
+                Signal::Reload => {
+                    if let Some(daemon) = &daemon {
+                        daemon.reloading();
+                    }
+                    match &alerting {
+                        Some(alerting) => alerting.rules.reload(),
+                        None => info!("no --rules to reload"),
+                    }
+                    if let Some(daemon) = &daemon {
+                        daemon.ready();
+                    }
+                }
+            },
+        }
+    }
+    if let Some(daemon) = &daemon {
+        daemon.stopping();
+    }
     info!("Exiting...");
 
+    if let Some(capture) = &capture {
+        capture.lock().unwrap().flush()?;
+    }
+    if let Some(worker) = &worker {
+        worker.flush(WORKER_SHUTDOWN_TIMEOUT);
+    }
+    if let Some(exporter) = &exporter {
+        exporter.flush(OTLP_SHUTDOWN_TIMEOUT);
+    }
+    report_decode_stats();
+
     Ok(())
 }
 
-fn parse_event(buf: &[u8]) -> Result<Event, ()> {
-    if buf.len() < core::mem::size_of::<Event>() {
-        return Err(());
+/// What `--rules` asked for.
+struct Alerting {
+    rules: Arc<Rules>,
+    alerts_only: bool,
+    /// The events are happening now, so `/proc` can tell their ancestry.
+    live: bool,
+}
+
+fn replay(path: &Path, denied_only: bool, alerting: Option<&Alerting>, exporter: Option<&Exporter>) -> Result<(), anyhow::Error> {
+    for record in CaptureReader::open(path)? {
+        let record = record?;
+        // Live, the kernel side does this filtering.
+        if denied_only && EventRef::parse(&record).is_ok_and(|event| !event.denied()) {
+            continue;
+        }
+        handle_event(&record, None, alerting, exporter);
     }
-    let event = unsafe {
-        core::ptr::read_unaligned(buf.as_ptr() as *const Event)
-    };
-    Ok(event)
-}
\ No newline at end of file
+    if let Some(exporter) = exporter {
+        exporter.flush(OTLP_SHUTDOWN_TIMEOUT);
+    }
+    report_decode_stats();
+    Ok(())
+}
+
+fn handle_event(buf: &[u8], enricher: Option<&Enricher>, alerting: Option<&Alerting>, exporter: Option<&Exporter>) {
+    match DECODE_STATS.decode(buf) {
+        Ok(event) => {
+            let alerts = alerting.map_or_else(Alerts::default, |alerting| alerting.rules.current().evaluate(&OpenFields::new(event, alerting.live)));
+            if alerts.is_empty() && alerting.is_some_and(|alerting| alerting.alerts_only) {
+                return;
+            }
+            let mut line = format!("uid : {}, gid : {}, euid : {}, fsuid : {}, cap_effective : {:#x}, pid : {}, ppid : {}, task_name : {}, file_path : {}, f_flag : {}, ret : {}, ino : {}, dev : {}:{}, type : {}, mode : {:04o}, size : {}, fs : {}, time : {}", event.uid(), event.gid(), event.euid(), event.fsuid(), event.cap_effective(), event.pid(), event.ppid(), Escaped(event.task_name()), Escaped(event.file_path()), event.f_flag(), event.ret(), event.ino(), event.dev_major(), event.dev_minor(), event.file_type(), event.mode() & 0o7777, event.file_size(), Escaped(event.fs_type()), event.timestamp());
+            let context = enricher.map(|enricher| enricher.lookup(event.uid(), event.pid()));
+            if let Some(context) = &context {
+                line = format!("{}: {}, {}", context, line, context.details());
+            }
+            if event.elevated() {
+                line = format!("[elevated] {}", line);
+            }
+            if event.denied() {
+                line = format!("[denied] {}", line);
+            }
+            line = format!("{}{}", alerts, line);
+            println!("{}", line);
+            if let Some(exporter) = exporter {
+                let mut attributes = vec![
+                    ("process.owner.id", AttrValue::Int(event.uid() as i64)),
+                    ("process.group.id", AttrValue::Int(event.gid() as i64)),
+                    ("process.effective_user.id", AttrValue::Int(event.euid() as i64)),
+                    ("process.fs_user.id", AttrValue::Int(event.fsuid() as i64)),
+                    ("process.cap_effective", AttrValue::Int(event.cap_effective() as i64)),
+                    ("process.elevated", AttrValue::Bool(event.elevated())),
+                    ("process.parent_pid", AttrValue::Int(event.ppid() as i64)),
+                    ("file.path", AttrValue::Str(Escaped(event.file_path()).to_string())),
+                    ("file.open_flags", AttrValue::Int(event.f_flag() as i64)),
+                    ("file.open_result", AttrValue::Int(event.ret() as i64)),
+                    ("file.inode", AttrValue::Int(event.ino() as i64)),
+                    ("file.device", AttrValue::Str(format!("{}:{}", event.dev_major(), event.dev_minor()))),
+                    ("file.type", AttrValue::Str(event.file_type().to_owned())),
+                    ("file.mode", AttrValue::Str(format!("{:04o}", event.mode() & 0o7777))),
+                    ("file.size", AttrValue::Int(event.file_size())),
+                    ("file.system.type", AttrValue::Str(Escaped(event.fs_type()).to_string())),
+                ];
+                if let Some(context) = &context {
+                    attributes.extend(context.attributes());
+                }
+                attributes.extend(alert_attributes(&alerts));
+                exporter.export(LogRecord {
+                    timestamp: event.timestamp(),
+                    pid: event.pid(),
+                    comm: Some(Escaped(event.task_name()).to_string()),
+                    body: line,
+                    attributes,
+                });
+            }
+        }
+        Err(e) => {
+            warn!("failed to parse event: {}", e);
+        }
+    }
+}
+
+fn alert_attributes(alerts: &Alerts) -> Vec<(&'static str, AttrValue)> {
+    match alerts.highest() {
+        Some(highest) => vec![
+            ("alert.priority", AttrValue::Str(highest.priority.to_string())),
+            ("alert.rules", AttrValue::Str(alerts.iter().map(|alert| alert.rule.as_str()).collect::<Vec<_>>().join(", "))),
+        ],
+        None => Vec::new(),
+    }
+}
+
+/// Passes what may have changed a watched file on to the watchlist.
+fn handle_watch_event(buf: &[u8], fim: &Sender<Trigger>) {
+    match DECODE_STATS.decode(buf) {
+        Ok(event) => {
+            if let Some(trigger) = Trigger::from_event(&event) {
+                // Only fails once the watchlist thread is gone.
+                let _ = fim.send(trigger);
+            }
+        }
+        Err(e) => {
+            warn!("failed to parse event: {}", e);
+        }
+    }
+}
+
+/// The `watch` attach points whose probes can run here. Linux 5.12 put the
+/// mount's idmapping in front of the arguments of `vfs_unlink` and
+/// `notify_change` and in `struct renamedata`, and the probes read them at
+/// those places; on older kernels they would read the wrong ones.
+fn watch_points(features: &Features) -> &'static [AttachPoint] {
+    if features.at_least(5, 12) {
+        &ATTACH_POINTS[1..]
+    } else {
+        &ATTACH_POINTS[1..2]
+    }
+}
+
+/// What `Features::report` says about this kernel and the attach points the
+/// probes can use here, then those left out for their argument layout.
+fn feature_report(features: &Features) -> Vec<String> {
+    let points = watch_points(features);
+    let mut lines = features.report(&ATTACH_POINTS[..1 + points.len()]);
+    for point in &ATTACH_POINTS[1 + points.len()..] {
+        lines.push(format!("{} attach to nothing before Linux 5.12, so {} are not traced", point.programs.join(", "), point.untraced));
+    }
+    lines
+}
+
+/// Loads and attaches the programs of each point the kernel has, skipping
+/// with a warning those it may lack.
+fn attach(bpf: &mut Bpf, features: &Features, points: &[AttachPoint], verifier_log: Option<&Path>, attached: &mut Vec<(&'static str, &'static str)>) -> Result<(), anyhow::Error> {
+    for point in points {
+        let Some(function) = features.resolve(point)? else {
+            continue;
+        };
+        for name in point.programs {
+            let program: &mut KProbe = bpf.program_mut(name).unwrap().try_into()?;
+            program.load().map_err(|e| verifier::explain(name, e, verifier_log, EBPF_SOURCES))?;
+            program.attach(function, 0).with_context(|| format!("failed to attach {} to {}", name, function))?;
+            attached.push((*name, function));
+        }
+    }
+    Ok(())
+}
+
+fn handle_request(request: &Request, attached: &[(&str, &str)]) -> Result<Value, anyhow::Error> {
+    match request.method.as_str() {
+        "programs" => Ok(attached.iter().map(|(program, function)| json!({ "program": program, "function": function })).collect()),
+        "stats" => Ok(json!({
+            "decoded": DECODE_STATS.decoded(),
+            "malformed": DECODE_STATS.malformed(),
+            "suppressed": EMISSION.suppressed(),
+            "paused": EMISSION.paused(),
+        })),
+        "pause" | "resume" => {
+            EMISSION.set_paused(request.method == "pause");
+            Ok(json!({ "paused": EMISSION.paused() }))
+        }
+        method => Err(RpcError::method_not_found(method).into()),
+    }
+}
+
+fn report_decode_stats() {
+    info!("decoded {} events, {} malformed", DECODE_STATS.decoded(), DECODE_STATS.malformed());
+}
@@ -0,0 +1,125 @@
+//! The fields perf events offer to the alert rules in
+//! `tracer_support::rules`.
+
+use std::cell::OnceCell;
+
+use perf_common::decode::{Escaped, EventRef};
+use perf_common::{EVENT_KIND_FILE_OPEN, EVENT_KIND_RENAME, EVENT_KIND_SETATTR, EVENT_KIND_UNLINK, EVENT_KIND_WRITE};
+use tracer_support::rules::{proc_ancestors, Fields, Value};
+
+pub use tracer_support::rules::{Alerts, Rules};
+
+/// The fields `OpenFields` knows.
+pub const SUPPORTED: &[&str] = &[
+    "event", "comm", "pid", "ppid", "ancestor", "uid", "gid", "euid", "fsuid", "elevated", "name", "f_flag", "flags", "ret",
+    "denied", "type", "fs",
+];
+
+/// Names of the `f_flags` bits rules can test through `flags`. `O_CREAT`,
+/// `O_EXCL`, `O_NOCTTY` and `O_TRUNC` are still set when
+/// `security_file_open` runs. `O_SYNC` includes the `O_DSYNC` bit, so a
+/// name only matches when all of its bits are set.
+const OPEN_FLAGS: &[(u32, &str)] = &[
+    (libc::O_CREAT as u32, "O_CREAT"),
+    (libc::O_EXCL as u32, "O_EXCL"),
+    (libc::O_NOCTTY as u32, "O_NOCTTY"),
+    (libc::O_TRUNC as u32, "O_TRUNC"),
+    (libc::O_APPEND as u32, "O_APPEND"),
+    (libc::O_NONBLOCK as u32, "O_NONBLOCK"),
+    (libc::O_DSYNC as u32, "O_DSYNC"),
+    (libc::O_SYNC as u32, "O_SYNC"),
+    (libc::O_DIRECT as u32, "O_DIRECT"),
+    (libc::O_DIRECTORY as u32, "O_DIRECTORY"),
+    (libc::O_NOFOLLOW as u32, "O_NOFOLLOW"),
+    (libc::O_NOATIME as u32, "O_NOATIME"),
+    (libc::O_CLOEXEC as u32, "O_CLOEXEC"),
+    (libc::O_PATH as u32, "O_PATH"),
+];
+
+/// The fields of a perf event. `ppid` and `ancestor` come from `/proc` and
+/// are only there when `live` is set, since a replay's pids are long gone.
+pub struct OpenFields<'a> {
+    event: EventRef<'a>,
+    live: bool,
+    ancestors: OnceCell<Vec<(u32, String)>>,
+}
+
+impl<'a> OpenFields<'a> {
+    pub fn new(event: EventRef<'a>, live: bool) -> Self {
+        OpenFields {
+            event,
+            live,
+            ancestors: OnceCell::new(),
+        }
+    }
+
+    fn ancestors(&self) -> Option<&[(u32, String)]> {
+        self.live.then(|| self.ancestors.get_or_init(|| proc_ancestors(self.event.pid())).as_slice())
+    }
+}
+
+impl Fields for OpenFields<'_> {
+    fn get(&self, field: &str) -> Option<Value> {
+        let event = &self.event;
+        let open = event.kind() == EVENT_KIND_FILE_OPEN;
+        Some(match field {
+            "event" => Value::Str(
+                match event.kind() {
+                    EVENT_KIND_FILE_OPEN => "open",
+                    EVENT_KIND_WRITE => "write",
+                    EVENT_KIND_RENAME => "rename",
+                    EVENT_KIND_UNLINK => "unlink",
+                    EVENT_KIND_SETATTR => "setattr",
+                    _ => return None,
+                }
+                .to_owned(),
+            ),
+            "comm" => Value::Str(Escaped(event.task_name()).to_string()),
+            "pid" => Value::Int(event.pid() as i64),
+            "ppid" => Value::Int(self.ancestors()?.first()?.0 as i64),
+            "ancestor" => Value::List(self.ancestors()?.iter().map(|(_, task_name)| task_name.clone()).collect()),
+            "uid" => Value::Int(event.uid() as i64),
+            "gid" => Value::Int(event.gid() as i64),
+            "euid" => Value::Int(event.euid() as i64),
+            "fsuid" => Value::Int(event.fsuid() as i64),
+            "elevated" => Value::Bool(event.elevated()),
+            "name" => Value::Str(Escaped(event.file_path()).to_string()),
+            "f_flag" if open => Value::Int(event.f_flag() as i64),
+            "flags" if open => Value::List(flags(event.f_flag())),
+            "ret" if open => Value::Int(event.ret() as i64),
+            "denied" if open => Value::Bool(event.denied()),
+            "type" => Value::Str(event.file_type().to_owned()),
+            "fs" => Value::Str(Escaped(event.fs_type()).to_string()),
+            _ => return None,
+        })
+    }
+}
+
+/// The access mode of `f_flag` and the names in `OPEN_FLAGS` it has.
+fn flags(f_flag: u32) -> Vec<String> {
+    let access = match f_flag & libc::O_ACCMODE as u32 {
+        0 => "O_RDONLY",
+        1 => "O_WRONLY",
+        _ => "O_RDWR",
+    };
+    std::iter::once(access)
+        .chain(OPEN_FLAGS.iter().filter(|(bit, _)| f_flag & bit == *bit).map(|(_, name)| *name))
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_the_flags_of_an_open() {
+        let o = |flag: libc::c_int| flag as u32;
+        assert_eq!(flags(0), ["O_RDONLY"]);
+        assert_eq!(flags(o(libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC)), ["O_WRONLY", "O_CREAT", "O_TRUNC"]);
+        assert_eq!(flags(o(libc::O_RDWR | libc::O_CREAT | libc::O_EXCL | libc::O_CLOEXEC)), ["O_RDWR", "O_CREAT", "O_EXCL", "O_CLOEXEC"]);
+        // O_DSYNC is part of O_SYNC, not the other way round.
+        assert_eq!(flags(o(libc::O_WRONLY | libc::O_DSYNC)), ["O_WRONLY", "O_DSYNC"]);
+        assert_eq!(flags(o(libc::O_WRONLY | libc::O_SYNC)), ["O_WRONLY", "O_DSYNC", "O_SYNC"]);
+    }
+}
@@ -0,0 +1,485 @@
+//! Validated, zero-copy decoding of the records read from `RINGBUF`.
+//!
+//! `RINGBUF` carries file opens as `Event` and changes to files as
+//! `FileEvent`, told apart by `EventHeader::kind`. Records are
+//! variable-length: the fixed part is followed by the names it carries, and
+//! `header.size` covers both.
+//!
+//! The ring buffer hands us arbitrary bytes, so nothing here assumes the
+//! buffer is aligned or large enough, and kernel strings are never assumed to
+//! be UTF-8. Records carrying a different `EventHeader::version` or `kind`
+//! than this build knows about are rejected rather than misread.
+
+use core::mem::{offset_of, size_of};
+
+pub use tracer_support::decode::{DecodeError, Escaped};
+use tracer_support::decode::{self, bytes, c_str, check_header, check_size, Decode};
+
+use crate::{
+    Event, EventHeader, FileEvent, ATTR_GID, ATTR_MODE, ATTR_UID, EVENT_KIND_FILE_OPEN, EVENT_KIND_MKDIR,
+    EVENT_KIND_RENAME, EVENT_KIND_RMDIR, EVENT_KIND_SETATTR, EVENT_KIND_UNLINK, EVENT_KIND_WRITE, MAX_PATH_LEN,
+    SCHEMA_VERSION, TASK_NAME_LEN,
+};
+
+const _: () = assert!(
+    size_of::<EventHeader>() == decode::HEADER_LEN
+        && offset_of!(EventHeader, kind) == decode::KIND_OFFSET
+        && offset_of!(EventHeader, version) == decode::VERSION_OFFSET
+        && offset_of!(EventHeader, size) == decode::SIZE_OFFSET
+);
+
+/// Any record read from `RINGBUF`.
+#[derive(Debug, Clone, Copy)]
+pub enum Record<'a> {
+    Open(EventRef<'a>),
+    File(FileEventRef<'a>),
+}
+
+impl<'a> Record<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, DecodeError> {
+        match check_header(buf, SCHEMA_VERSION)? {
+            EVENT_KIND_FILE_OPEN => EventRef::parse(buf).map(Record::Open),
+            _ => FileEventRef::parse(buf).map(Record::File),
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        match self {
+            Record::Open(event) => event.size(),
+            Record::File(event) => event.size(),
+        }
+    }
+
+    /// The operation was refused.
+    pub fn denied(&self) -> bool {
+        match self {
+            Record::Open(event) => event.denied(),
+            Record::File(event) => event.denied(),
+        }
+    }
+}
+
+/// A borrowed view of an `Event` whose size has been checked.
+#[derive(Debug, Clone, Copy)]
+pub struct EventRef<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> EventRef<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, DecodeError> {
+        let kind = check_header(buf, SCHEMA_VERSION)?;
+        if kind != EVENT_KIND_FILE_OPEN {
+            return Err(DecodeError::UnknownKind(kind));
+        }
+        let event = EventRef { buf };
+        check_size(buf, size_of::<Event>(), MAX_PATH_LEN, || [event.file_path_len() as usize])?;
+        Ok(event)
+    }
+
+    pub fn kind(&self) -> u16 {
+        u16::from_ne_bytes(bytes(self.buf, offset_of!(Event, header.kind)))
+    }
+
+    pub fn version(&self) -> u16 {
+        u16::from_ne_bytes(bytes(self.buf, offset_of!(Event, header.version)))
+    }
+
+    pub fn size(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(Event, header.size)))
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        u64::from_ne_bytes(bytes(self.buf, offset_of!(Event, header.timestamp)))
+    }
+
+    pub fn cpu(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(Event, header.cpu)))
+    }
+
+    pub fn uid(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(Event, uid)))
+    }
+
+    pub fn pid(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(Event, pid)))
+    }
+
+    pub fn gid(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(Event, gid)))
+    }
+
+    pub fn euid(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(Event, euid)))
+    }
+
+    pub fn fsuid(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(Event, fsuid)))
+    }
+
+    pub fn cap_effective(&self) -> u64 {
+        u64::from_ne_bytes(bytes(self.buf, offset_of!(Event, cap_effective)))
+    }
+
+    /// The open ran as root on behalf of an unprivileged user, e.g. through
+    /// a setuid binary.
+    pub fn elevated(&self) -> bool {
+        self.euid() == 0 && self.uid() != 0
+    }
+
+    pub fn ret(&self) -> i32 {
+        i32::from_ne_bytes(bytes(self.buf, offset_of!(Event, ret)))
+    }
+
+    /// `security_file_open` refused the open.
+    pub fn denied(&self) -> bool {
+        self.ret() != 0
+    }
+
+    pub fn ino(&self) -> u64 {
+        u64::from_ne_bytes(bytes(self.buf, offset_of!(Event, ino)))
+    }
+
+    pub fn dev(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(Event, dev)))
+    }
+
+    /// Major number of `dev`; the kernel keeps 20 bits for the minor.
+    pub fn dev_major(&self) -> u32 {
+        self.dev() >> 20
+    }
+
+    pub fn dev_minor(&self) -> u32 {
+        self.dev() & ((1 << 20) - 1)
+    }
+
+    pub fn mode(&self) -> u16 {
+        u16::from_ne_bytes(bytes(self.buf, offset_of!(Event, mode)))
+    }
+
+    /// The file type encoded in `mode`.
+    pub fn file_type(&self) -> &'static str {
+        file_type(self.mode())
+    }
+
+    pub fn file_size(&self) -> i64 {
+        i64::from_ne_bytes(bytes(self.buf, offset_of!(Event, file_size)))
+    }
+
+    /// The filesystem type name up to its first NUL.
+    pub fn fs_type(&self) -> &'a [u8] {
+        c_str(self.buf, offset_of!(Event, fs_type), crate::FS_TYPE_LEN)
+    }
+
+    /// The task name up to its first NUL.
+    pub fn task_name(&self) -> &'a [u8] {
+        c_str(self.buf, offset_of!(Event, task_name), TASK_NAME_LEN)
+    }
+
+    pub fn file_path_len(&self) -> u16 {
+        u16::from_ne_bytes(bytes(self.buf, offset_of!(Event, file_path_len)))
+    }
+
+    /// The file name that follows the fixed part of the record.
+    pub fn file_path(&self) -> &'a [u8] {
+        &self.buf[size_of::<Event>()..self.size() as usize]
+    }
+}
+
+/// A borrowed view of a `FileEvent` whose size has been checked.
+#[derive(Debug, Clone, Copy)]
+pub struct FileEventRef<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> FileEventRef<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, DecodeError> {
+        let kind = check_header(buf, SCHEMA_VERSION)?;
+        if !matches!(
+            kind,
+            EVENT_KIND_WRITE | EVENT_KIND_RENAME | EVENT_KIND_UNLINK | EVENT_KIND_MKDIR | EVENT_KIND_RMDIR | EVENT_KIND_SETATTR
+        ) {
+            return Err(DecodeError::UnknownKind(kind));
+        }
+        let event = FileEventRef { buf };
+        check_size(buf, size_of::<FileEvent>(), MAX_PATH_LEN, || {
+            [event.name_len() as usize, event.new_name_len() as usize]
+        })?;
+        Ok(event)
+    }
+
+    pub fn kind(&self) -> u16 {
+        u16::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, header.kind)))
+    }
+
+    pub fn size(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, header.size)))
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        u64::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, header.timestamp)))
+    }
+
+    pub fn uid(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, uid)))
+    }
+
+    pub fn pid(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, pid)))
+    }
+
+    pub fn gid(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, gid)))
+    }
+
+    pub fn ret(&self) -> i32 {
+        i32::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, ret)))
+    }
+
+    /// The change was refused, e.g. with `-EACCES` or `-EPERM`.
+    pub fn denied(&self) -> bool {
+        self.ret() != 0
+    }
+
+    pub fn ino(&self) -> u64 {
+        u64::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, ino)))
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        u64::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, bytes)))
+    }
+
+    pub fn writes(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, writes)))
+    }
+
+    pub fn dev(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, dev)))
+    }
+
+    pub fn dev_major(&self) -> u32 {
+        self.dev() >> 20
+    }
+
+    pub fn dev_minor(&self) -> u32 {
+        self.dev() & ((1 << 20) - 1)
+    }
+
+    pub fn mode(&self) -> u16 {
+        u16::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, mode)))
+    }
+
+    pub fn file_type(&self) -> &'static str {
+        file_type(self.mode())
+    }
+
+    pub fn attr_valid(&self) -> u16 {
+        u16::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, attr_valid)))
+    }
+
+    /// The new permission bits of a chmod.
+    pub fn attr_mode(&self) -> Option<u16> {
+        (self.attr_valid() & ATTR_MODE != 0)
+            .then(|| u16::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, attr_mode))))
+    }
+
+    /// The new owner of a chown.
+    pub fn attr_uid(&self) -> Option<u32> {
+        (self.attr_valid() & ATTR_UID != 0)
+            .then(|| u32::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, attr_uid))))
+    }
+
+    /// The new group of a chown.
+    pub fn attr_gid(&self) -> Option<u32> {
+        (self.attr_valid() & ATTR_GID != 0)
+            .then(|| u32::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, attr_gid))))
+    }
+
+    /// What was done to the file, as the command that does it is called.
+    pub fn operation(&self) -> &'static str {
+        match self.kind() {
+            EVENT_KIND_WRITE => "write",
+            EVENT_KIND_RENAME => "rename",
+            EVENT_KIND_UNLINK => "unlink",
+            EVENT_KIND_MKDIR => "mkdir",
+            EVENT_KIND_RMDIR => "rmdir",
+            _ => match (self.attr_mode().is_some(), self.attr_valid() & (ATTR_UID | ATTR_GID) != 0) {
+                (true, true) => "chmod+chown",
+                (true, false) => "chmod",
+                _ => "chown",
+            },
+        }
+    }
+
+    /// The task name up to its first NUL.
+    pub fn task_name(&self) -> &'a [u8] {
+        c_str(self.buf, offset_of!(FileEvent, task_name), TASK_NAME_LEN)
+    }
+
+    pub fn name_len(&self) -> u16 {
+        u16::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, name_len)))
+    }
+
+    pub fn new_name_len(&self) -> u16 {
+        u16::from_ne_bytes(bytes(self.buf, offset_of!(FileEvent, new_name_len)))
+    }
+
+    /// The name of the file, for a rename the old one.
+    pub fn name(&self) -> &'a [u8] {
+        let start = size_of::<FileEvent>();
+        &self.buf[start..start + self.name_len() as usize]
+    }
+
+    /// The new name of a rename; empty for everything else.
+    pub fn new_name(&self) -> &'a [u8] {
+        let start = size_of::<FileEvent>() + self.name_len() as usize;
+        &self.buf[start..self.size() as usize]
+    }
+}
+
+/// The file type encoded in an `i_mode`.
+fn file_type(mode: u16) -> &'static str {
+    match mode & 0o170000 {
+        0o140000 => "socket",
+        0o120000 => "symlink",
+        0o100000 => "regular",
+        0o060000 => "block",
+        0o040000 => "directory",
+        0o020000 => "char",
+        0o010000 => "fifo",
+        _ => "unknown",
+    }
+}
+
+/// The records in `RINGBUF`.
+pub enum Records {}
+
+impl Decode for Records {
+    type Record<'a> = Record<'a>;
+
+    fn parse(buf: &[u8]) -> Result<Record<'_>, DecodeError> {
+        Record::parse(buf)
+    }
+
+    fn size(record: &Record<'_>) -> u32 {
+        record.size()
+    }
+}
+
+/// Counts decoded and rejected `RINGBUF` records.
+pub type DecodeStats = decode::DecodeStats<Records>;
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::mem;
+    use std::format;
+    use std::vec::Vec;
+
+    use tracer_support::decode::testing::{
+        as_bytes, assert_refuses_prefixes, corrupt_records, set_name_len, set_u16, set_u32, Rng,
+    };
+
+    use super::*;
+
+    fn open(path: &[u8]) -> Vec<u8> {
+        let mut event: Event = unsafe { mem::zeroed() };
+        let size = size_of::<Event>() + path.len();
+        event.header = EventHeader::new(EVENT_KIND_FILE_OPEN, size as u32, 1, 0);
+        event.pid = 42;
+        event.ret = -13;
+        event.file_path_len = path.len() as u16;
+        [as_bytes(&event), path].concat()
+    }
+
+    fn rename(from: &[u8], to: &[u8]) -> Vec<u8> {
+        let mut event: FileEvent = unsafe { mem::zeroed() };
+        let size = size_of::<FileEvent>() + from.len() + to.len();
+        event.header = EventHeader::new(EVENT_KIND_RENAME, size as u32, 1, 0);
+        event.name_len = from.len() as u16;
+        event.new_name_len = to.len() as u16;
+        [as_bytes(&event), from, to].concat()
+    }
+
+    #[test]
+    fn parses_records() {
+        let buf = open(b"/etc/shadow");
+        let Ok(Record::Open(event)) = Record::parse(&buf) else { panic!("not an open") };
+        assert_eq!((event.pid(), event.file_path()), (42, &b"/etc/shadow"[..]));
+        assert!(event.denied());
+        let buf = rename(b"old", b"new");
+        let Ok(Record::File(event)) = Record::parse(&buf) else { panic!("not a file event") };
+        assert_eq!((event.name(), event.new_name(), event.operation()), (&b"old"[..], &b"new"[..], "rename"));
+    }
+
+    #[test]
+    fn refuses_truncated_records() {
+        for buf in [open(b"/etc/shadow"), rename(b"old", b"new")] {
+            assert_refuses_prefixes(&buf, Record::parse);
+        }
+    }
+
+    #[test]
+    fn refuses_a_size_the_names_do_not_add_up_to() {
+        let mut buf = open(b"/etc/shadow");
+        for size in [0, size_of::<Event>() as u32, buf.len() as u32 - 1, buf.len() as u32 + 1, u32::MAX] {
+            set_u32(&mut buf, offset_of!(Event, header.size), size);
+            assert!(matches!(EventRef::parse(&buf), Err(DecodeError::SizeMismatch { .. })), "size {}", size);
+        }
+    }
+
+    #[test]
+    fn refuses_names_past_the_end() {
+        // Longer than the kernel side ever sends.
+        let mut buf = open(b"/etc/shadow");
+        set_u16(&mut buf, offset_of!(Event, file_path_len), MAX_PATH_LEN as u16 + 1);
+        assert!(matches!(EventRef::parse(&buf), Err(DecodeError::NameTooLong { .. })));
+        // Within the limit, and with a size to match, but past the buffer.
+        let mut buf = open(b"/etc/shadow");
+        set_u16(&mut buf, offset_of!(Event, file_path_len), 100);
+        set_u32(&mut buf, offset_of!(Event, header.size), size_of::<Event>() as u32 + 100);
+        assert!(matches!(EventRef::parse(&buf), Err(DecodeError::Truncated { .. })));
+        let mut buf = rename(b"old", b"new");
+        set_u16(&mut buf, offset_of!(FileEvent, new_name_len), 4);
+        assert!(matches!(FileEventRef::parse(&buf), Err(DecodeError::SizeMismatch { .. })));
+    }
+
+    #[test]
+    fn refuses_other_versions_and_kinds() {
+        let mut buf = open(b"/etc/shadow");
+        set_u16(&mut buf, offset_of!(EventHeader, version), SCHEMA_VERSION + 1);
+        let newer = DecodeError::UnsupportedVersion { version: SCHEMA_VERSION + 1, expected: SCHEMA_VERSION };
+        assert_eq!(Record::parse(&buf).unwrap_err(), newer);
+        let mut buf = open(b"/etc/shadow");
+        set_u16(&mut buf, offset_of!(EventHeader, kind), 999);
+        assert_eq!(Record::parse(&buf).unwrap_err(), DecodeError::UnknownKind(999));
+    }
+
+    #[test]
+    fn escapes_names_that_are_not_utf8() {
+        let buf = open(b"/tmp/\xc3");
+        let Ok(Record::Open(event)) = Record::parse(&buf) else { panic!("not an open") };
+        assert_eq!(format!("{}", Escaped(event.file_path())), "/tmp/\\xc3");
+    }
+
+    /// Whatever the bytes, parsing never panics, and what it accepts can be
+    /// read in full.
+    #[test]
+    fn never_panics_on_corrupt_records() {
+        let valid = [open(b"/etc/shadow"), rename(b"old", b"new")];
+        let tweak = |rng: &mut Rng, buf: &mut Vec<u8>| {
+            if rng.below(2) == 0 {
+                let len = rng.below(2 * MAX_PATH_LEN) as u16;
+                set_name_len(buf, offset_of!(Event, file_path_len), size_of::<Event>(), len);
+            }
+        };
+        corrupt_records::<Records>(&valid, tweak, |record| match record {
+            Record::Open(event) => {
+                let _ = format!("{} {}", Escaped(event.file_path()), Escaped(event.task_name()));
+            }
+            Record::File(event) => {
+                let _ = format!("{} {} {}", Escaped(event.name()), Escaped(event.new_name()), event.operation());
+            }
+        });
+    }
+}
@@ -1,13 +1,146 @@
 #![no_std]
 
-const MAX_PATH_LEN: usize = 256;
+#[cfg(feature = "user")]
+use aya::Pod;
+
+#[cfg(feature = "user")]
+pub mod decode;
+
+pub const MAX_PATH_LEN: usize = 256;
 const TASK_NAME_LEN: usize = 16;
+const FS_TYPE_LEN: usize = 16;
+
+/// Default size of `RINGBUF`; user space can resize it before load.
+pub const RINGBUF_SIZE: u32 = 128 * 4096;
+
+/// Opens in flight at once between the entry and return probes.
+pub const PENDING_MAX_ENTRIES: u32 = 10240;
+
+/// Files being written to whose byte counts have not been sent yet, one per
+/// process and file.
+pub const WRITES_MAX_ENTRIES: u32 = 4096;
+
+/// Indices into the per-CPU `STATS` array.
+pub const STAT_SUBMITTED: u32 = 0;
+pub const STAT_RESERVE_FAILED: u32 = 1;
+pub const STAT_FORCED_WAKEUP: u32 = 2;
+pub const STAT_COUNT: u32 = 3;
+
+/// Bumped whenever the layout of `EventHeader` or any event changes.
+pub const SCHEMA_VERSION: u16 = 5;
+
+pub const EVENT_KIND_FILE_OPEN: u16 = 1;
+pub const EVENT_KIND_WRITE: u16 = 10;
+pub const EVENT_KIND_RENAME: u16 = 11;
+pub const EVENT_KIND_UNLINK: u16 = 12;
+pub const EVENT_KIND_MKDIR: u16 = 13;
+pub const EVENT_KIND_RMDIR: u16 = 14;
+pub const EVENT_KIND_SETATTR: u16 = 15;
+
+/// `ia_valid` bits of `notify_change` that `FileEvent::attr_valid` keeps.
+pub const ATTR_MODE: u16 = 1 << 0;
+pub const ATTR_UID: u16 = 1 << 1;
+pub const ATTR_GID: u16 = 1 << 2;
+
+/// Common prefix of every record sent to user space.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct EventHeader {
+    pub kind: u16,
+    pub version: u16,
+    /// Size of the whole record, header included.
+    pub size: u32,
+    /// `bpf_ktime_get_ns()` when the record was produced.
+    pub timestamp: u64,
+    pub cpu: u32,
+    pub _pad: u32,
+}
+
+impl EventHeader {
+    pub const fn new(kind: u16, size: u32, timestamp: u64, cpu: u32) -> Self {
+        EventHeader {
+            kind,
+            version: SCHEMA_VERSION,
+            size,
+            timestamp,
+            cpu,
+            _pad: 0,
+        }
+    }
+}
 
+/// Fixed part of a file-open record. The file name follows it directly,
+/// `file_path_len` bytes long and without a trailing NUL, and
+/// `header.size` covers both.
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct Event {
+    pub header: EventHeader,
     pub uid: u32,
     pub pid: u32,
+    pub gid: u32,
+    /// Effective and filesystem uids, read from `task->cred`.
+    pub euid: u32,
+    pub fsuid: u32,
+    pub file_path_len: u16,
+    /// `i_mode`: file type and permission bits.
+    pub mode: u16,
+    /// `task->cred->cap_effective`, one bit per `CAP_*`.
+    pub cap_effective: u64,
+    pub ino: u64,
+    pub file_size: i64,
+    /// `s_dev` of the file's superblock, in the kernel's `MKDEV` encoding.
+    pub dev: u32,
+    /// Return value of `security_file_open`: 0, or a negative errno such as
+    /// `-EACCES` when the open was refused.
+    pub ret: i32,
+    /// `f_inode->i_sb->s_type->name`, NUL padded.
+    pub fs_type: [u8; FS_TYPE_LEN],
     pub task_name: [u8; TASK_NAME_LEN],
-    pub file_path: [u8; MAX_PATH_LEN],
-}
\ No newline at end of file
+}
+
+/// Fixed part of a record about a change to the filesystem: aggregated
+/// writes, renames, unlinks, mkdirs, rmdirs and chmod/chown, told apart by
+/// `header.kind`. The name of the file follows it directly, then for renames
+/// the new name, `name_len` and `new_name_len` bytes long and without
+/// trailing NULs, and `header.size` covers all of them.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct FileEvent {
+    pub header: EventHeader,
+    pub uid: u32,
+    pub pid: u32,
+    pub gid: u32,
+    /// Return value of the operation: 0, or a negative errno. Always 0 for
+    /// writes, which only count what was written.
+    pub ret: i32,
+    pub ino: u64,
+    /// Writes: bytes written since the previous record for this file.
+    pub bytes: u64,
+    /// `s_dev` of the file's superblock, in the kernel's `MKDEV` encoding.
+    pub dev: u32,
+    /// Writes: `vfs_write` calls that `bytes` adds up.
+    pub writes: u32,
+    /// `i_mode` of the file; for mkdir, the mode asked for.
+    pub mode: u16,
+    pub name_len: u16,
+    pub new_name_len: u16,
+    /// Setattr: which of `ATTR_MODE`, `ATTR_UID` and `ATTR_GID` change.
+    pub attr_valid: u16,
+    /// Setattr: the new permission bits, uid and gid, where `attr_valid`
+    /// says they change.
+    pub attr_mode: u16,
+    pub _pad: u16,
+    pub attr_uid: u32,
+    pub attr_gid: u32,
+    pub task_name: [u8; TASK_NAME_LEN],
+}
+
+#[cfg(feature = "user")]
+unsafe impl Pod for EventHeader {}
+
+#[cfg(feature = "user")]
+unsafe impl Pod for Event {}
+
+#[cfg(feature = "user")]
+unsafe impl Pod for FileEvent {}
@@ -6,67 +6,39 @@
 #![allow(non_camel_case_types)]
 #![allow(dead_code)]
 
-use aya_ebpf::{helpers::{bpf_get_current_comm, bpf_get_current_pid_tgid, bpf_get_current_uid_gid, bpf_probe_read_kernel, bpf_probe_read_kernel_str_bytes}, macros::{kprobe, map}, maps::RingBuf, programs::ProbeContext};
+use aya_ebpf::{bindings::{BPF_RB_AVAIL_DATA, BPF_RB_FORCE_WAKEUP, BPF_RB_NO_WAKEUP}, macros::map, maps::RingBuf, EbpfContext};
 use aya_log_ebpf::warn;
-use binding::{dentry, file, path, qstr};
-use ringbuf_common::Event;
+use probes::count;
+use ringbuf_common::{RINGBUF_SIZE, STAT_FORCED_WAKEUP, STAT_RESERVE_FAILED, STAT_SUBMITTED};
 
 mod binding;
-#[map]
-static RINGBUF: RingBuf = RingBuf::with_byte_size(128 * 4096, 0); // 128 pages = 256KB
-
-#[kprobe]
-pub fn ringbuf(ctx: ProbeContext) -> u32 {
-    match try_ringbuf(ctx) {
-        Ok(ret) => ret,
-        Err(_) => 1,
-    }
-}
+mod probes;
 
-fn try_ringbuf(ctx: ProbeContext) -> Result<u32, i64> {
-    let file:*mut file  = ctx.arg(0).ok_or(1i64)?;
-    let path = unsafe {
-        bpf_probe_read_kernel(&(*file).f_path as *const path)
-            .map_err(|e| e)?
-    };
-
-    let dentry = unsafe {
-        bpf_probe_read_kernel(path.dentry as *const dentry)
-            .map_err(|e| e)?
-    };
-    let dname = unsafe {
-        bpf_probe_read_kernel(&dentry.d_name as *const qstr)
-            .map_err(|e| e)?
-    };
-
-    if let Some(mut event_reserved) = RINGBUF.reserve::<Event>(0) {
-        unsafe {
-            (*event_reserved.as_mut_ptr()).uid = bpf_get_current_uid_gid() as u32;
-            (*event_reserved.as_mut_ptr()).pid = (bpf_get_current_pid_tgid() >> 32) as u32;
-            (*event_reserved.as_mut_ptr()).task_name = match bpf_get_current_comm() {
-                Ok(data) => data,
-                Err(_) => {
-                    event_reserved.discard(0);
-                    warn!(&ctx, "fail to reserve buffer");
-                    return Err(1i64);
-                }
-            };
-            match bpf_probe_read_kernel_str_bytes(dname.name, &mut (*event_reserved.as_mut_ptr()).file_path) {
-                Ok(_) => {}
-                Err(_) => {
-                    event_reserved.discard(0);
-                    warn!(&ctx, "fail to reserve buffer");
-                    return Err(1i64);
-                }
-            }
-        }
-        event_reserved.submit(0);
-    } else {
-        warn!(&ctx, "fail to reserve buffer");
+#[map]
+static RINGBUF: RingBuf = RingBuf::with_byte_size(RINGBUF_SIZE, 0); // 128 pages = 512KB, resized at load
+
+// Unconsumed bytes in `RINGBUF` at which the consumer is woken up. Below it
+// records are submitted without a wakeup and user space drains them on a
+// timer. Set by user space at load time.
+#[no_mangle]
+static WAKEUP_THRESHOLD: u64 = 0;
+
+fn output<C: EbpfContext>(ctx: &C, record: &[u8]) -> Result<(), i64> {
+    let size = record.len();
+    let threshold = unsafe { core::ptr::read_volatile(&WAKEUP_THRESHOLD) };
+    let wakeup = RINGBUF.query(BPF_RB_AVAIL_DATA as u64) + size as u64 >= threshold;
+    let flags = if wakeup { BPF_RB_FORCE_WAKEUP } else { BPF_RB_NO_WAKEUP };
+    if RINGBUF.output(record, flags as u64).is_err() {
+        count(STAT_RESERVE_FAILED);
+        warn!(ctx, "fail to reserve buffer");
         return Err(1i64);
     }
-    
-    Ok(0)
+    // Only a record that went in woke the reader.
+    if wakeup {
+        count(STAT_FORCED_WAKEUP);
+    }
+    count(STAT_SUBMITTED);
+    Ok(())
 }
 
 #[panic_handler]
@@ -0,0 +1,33 @@
+//! The same probes for kernels without BPF ring buffers, before 5.8: records
+//! go through a perf event array, one buffer per CPU, instead of `RINGBUF`.
+
+#![no_std]
+#![no_main]
+
+#![allow(non_upper_case_globals)]
+#![allow(non_snake_case)]
+#![allow(non_camel_case_types)]
+#![allow(dead_code)]
+
+use aya_ebpf::{macros::map, maps::PerfEventByteArray, EbpfContext};
+use probes::count;
+use ringbuf_common::STAT_SUBMITTED;
+
+mod binding;
+mod probes;
+
+#[map]
+static EVENTS: PerfEventByteArray = PerfEventByteArray::new(0);
+
+// A full buffer drops the record without telling us; user space counts what
+// was lost as it reads.
+fn output<C: EbpfContext>(ctx: &C, record: &[u8]) -> Result<(), i64> {
+    EVENTS.output(ctx, record, 0);
+    count(STAT_SUBMITTED);
+    Ok(())
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    unsafe { core::hint::unreachable_unchecked() }
+}
@@ -0,0 +1,466 @@
+//! The probes both objects share. Each sends its records with the `output`
+//! of its crate root, through a ring buffer or a perf event array.
+
+use aya_ebpf::{cty::c_void, helpers::{bpf_get_current_comm, bpf_get_current_pid_tgid, bpf_get_current_task, bpf_get_current_uid_gid, bpf_get_smp_processor_id, bpf_ktime_get_ns, bpf_probe_read_kernel, bpf_probe_read_kernel_str_bytes}, macros::{kprobe, kretprobe, map}, maps::{LruHashMap, PerCpuArray}, programs::{ProbeContext, RetProbeContext}};
+use ringbuf_common::{Event, EventHeader, FileEvent, ATTR_GID, ATTR_MODE, ATTR_UID, EVENT_KIND_FILE_OPEN, EVENT_KIND_MKDIR, EVENT_KIND_RENAME, EVENT_KIND_RMDIR, EVENT_KIND_SETATTR, EVENT_KIND_UNLINK, EVENT_KIND_WRITE, MAX_PATH_LEN, PENDING_MAX_ENTRIES, STAT_COUNT, WRITES_MAX_ENTRIES};
+
+use crate::binding::{cred, dentry, file, iattr, inode, path, qstr, task_struct};
+use crate::output;
+
+const S_IFMT: u16 = 0o170000;
+const S_IFREG: u16 = 0o100000;
+const S_IFDIR: u16 = 0o040000;
+
+// `Event` followed by room for the longest name we copy. Only the used part
+// of `file_path` is sent to user space.
+#[repr(C)]
+struct EventBuf {
+    event: Event,
+    file_path: [u8; MAX_PATH_LEN],
+}
+
+// `FileEvent` followed by room for its name and, for renames, the new name
+// right after it. Only the used part of `names` is sent to user space.
+#[repr(C)]
+struct FileEventBuf {
+    event: FileEvent,
+    names: [u8; 2 * MAX_PATH_LEN],
+}
+
+// A process and a file it writes to.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct WriteKey {
+    pid: u32,
+    dev: u32,
+    ino: u64,
+}
+
+// `struct renamedata` in its 5.12+ layout, which the bindings lack. User
+// space only attaches the probes that read it, and the other 5.12+ argument
+// layouts, from 5.12 on.
+#[repr(C)]
+struct RenameData {
+    old_mnt_userns: *const c_void,
+    old_dir: *const inode,
+    old_dentry: *const dentry,
+    new_mnt_userns: *const c_void,
+    new_dir: *const inode,
+    new_dentry: *const dentry,
+    delegated_inode: *const c_void,
+    flags: u32,
+}
+
+#[map]
+static mut EVENT_BUF: PerCpuArray<EventBuf> = PerCpuArray::with_max_entries(1, 0);
+
+#[map]
+static mut FILE_EVENT_BUF: PerCpuArray<FileEventBuf> = PerCpuArray::with_max_entries(1, 0);
+
+// Events whose `security_file_open` has not returned yet, by pid_tgid. LRU so
+// entries of calls whose return we never see cannot fill it up.
+#[map]
+static mut PENDING: LruHashMap<u64, EventBuf> = LruHashMap::with_max_entries(PENDING_MAX_ENTRIES, 0);
+
+// Renames, unlinks, mkdirs, rmdirs and setattrs whose function has not
+// returned yet, by pid_tgid.
+#[map]
+static mut FILE_PENDING: LruHashMap<u64, FileEventBuf> = LruHashMap::with_max_entries(PENDING_MAX_ENTRIES, 0);
+
+// The file each `vfs_write` in flight writes to, by pid_tgid.
+#[map]
+static mut WRITE_PENDING: LruHashMap<u64, u64> = LruHashMap::with_max_entries(PENDING_MAX_ENTRIES, 0);
+
+// Bytes written per process and file and not sent yet. A record is sent when
+// the file is closed or, at its next write, once it is `WRITE_INTERVAL_NS`
+// old. LRU so files that are never closed cannot fill it up; their counts are
+// lost when they are evicted.
+#[map]
+static mut WRITES: LruHashMap<WriteKey, FileEventBuf> = LruHashMap::with_max_entries(WRITES_MAX_ENTRIES, 0);
+
+#[map]
+static mut STATS: PerCpuArray<u64> = PerCpuArray::with_max_entries(STAT_COUNT, 0);
+
+// Only send opens that `security_file_open` refused. Set by user space at
+// load time.
+#[no_mangle]
+static DENIED_ONLY: u8 = 0;
+
+// How long writes to a file are added up before they are sent, in
+// nanoseconds. Set by user space at load time.
+#[no_mangle]
+static WRITE_INTERVAL_NS: u64 = 0;
+
+#[kprobe]
+pub fn ringbuf(ctx: ProbeContext) -> u32 {
+    match try_ringbuf(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[kretprobe]
+pub fn ringbuf_ret(ctx: RetProbeContext) -> u32 {
+    match try_ringbuf_ret(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[kprobe]
+pub fn vfs_write(ctx: ProbeContext) -> u32 {
+    match try_vfs_write(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[kretprobe]
+pub fn vfs_write_ret(ctx: RetProbeContext) -> u32 {
+    match try_vfs_write_ret(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[kprobe]
+pub fn filp_close(ctx: ProbeContext) -> u32 {
+    match try_filp_close(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[kprobe]
+pub fn vfs_rename(ctx: ProbeContext) -> u32 {
+    match try_vfs_rename(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[kprobe]
+pub fn vfs_unlink(ctx: ProbeContext) -> u32 {
+    match try_vfs_remove(ctx, EVENT_KIND_UNLINK) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[kprobe]
+pub fn vfs_rmdir(ctx: ProbeContext) -> u32 {
+    match try_vfs_remove(ctx, EVENT_KIND_RMDIR) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[kprobe]
+pub fn vfs_mkdir(ctx: ProbeContext) -> u32 {
+    match try_vfs_mkdir(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[kprobe]
+pub fn notify_change(ctx: ProbeContext) -> u32 {
+    match try_notify_change(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+// Attached to the return of vfs_rename, vfs_unlink, vfs_rmdir, vfs_mkdir and
+// notify_change.
+#[kretprobe]
+pub fn file_change_ret(ctx: RetProbeContext) -> u32 {
+    match try_file_change_ret(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+pub fn count(stat: u32) {
+    if let Some(counter) = unsafe { STATS.get_ptr_mut(stat) } {
+        unsafe { *counter += 1 };
+    }
+}
+
+/// Effective uid, filesystem uid and effective capabilities from `cred`.
+unsafe fn read_cred(cred: *const cred) -> Result<(u32, u32, u64), i64> {
+    let euid = bpf_probe_read_kernel(&(*cred).euid)?.val;
+    let fsuid = bpf_probe_read_kernel(&(*cred).fsuid)?.val;
+    // kernel_cap_t is two 32 bit words on this kernel.
+    let caps = bpf_probe_read_kernel(&(*cred).cap_effective)?;
+    Ok((euid, fsuid, caps.cap[0] as u64 | (caps.cap[1] as u64) << 32))
+}
+
+/// Inode number, size, mode, superblock device and filesystem type of `file`.
+unsafe fn read_inode(file: *const file, event: &mut Event) -> Result<(), i64> {
+    let inode = bpf_probe_read_kernel(&(*file).f_inode)?;
+    event.ino = bpf_probe_read_kernel(&(*inode).i_ino)? as u64;
+    event.file_size = bpf_probe_read_kernel(&(*inode).i_size)?;
+    event.mode = bpf_probe_read_kernel(&(*inode).i_mode)?;
+    let sb = bpf_probe_read_kernel(&(*inode).i_sb)?;
+    event.dev = bpf_probe_read_kernel(&(*sb).s_dev)?;
+    let fs_type = bpf_probe_read_kernel(&(*sb).s_type)?;
+    let name = bpf_probe_read_kernel(&(*fs_type).name)?;
+    bpf_probe_read_kernel_str_bytes(name as *const u8, &mut event.fs_type)?;
+    Ok(())
+}
+
+fn try_ringbuf(ctx: ProbeContext) -> Result<u32, i64> {
+    let file:*mut file  = ctx.arg(0).ok_or(1i64)?;
+    let path = unsafe {
+        bpf_probe_read_kernel(&(*file).f_path as *const path)
+            .map_err(|e| e)?
+    };
+
+    let dentry = unsafe {
+        bpf_probe_read_kernel(path.dentry as *const dentry)
+            .map_err(|e| e)?
+    };
+    let dname = unsafe {
+        bpf_probe_read_kernel(&dentry.d_name as *const qstr)
+            .map_err(|e| e)?
+    };
+
+    let buf = unsafe {
+        let ptr = EVENT_BUF.get_ptr_mut(0).ok_or(1i64)?;
+        &mut *ptr
+    };
+    // The mask keeps the length provably in bounds for the verifier; the
+    // helper never returns more than MAX_PATH_LEN - 1 bytes anyway.
+    let path_len = unsafe {
+        bpf_probe_read_kernel_str_bytes(dname.name, &mut buf.file_path)?.len() & (MAX_PATH_LEN - 1)
+    };
+    let size = core::mem::size_of::<Event>() + path_len;
+
+    buf.event.header = unsafe {
+        EventHeader::new(EVENT_KIND_FILE_OPEN, size as u32, bpf_ktime_get_ns(), bpf_get_smp_processor_id())
+    };
+    let uid_gid = bpf_get_current_uid_gid();
+    buf.event.uid = uid_gid as u32;
+    buf.event.gid = (uid_gid >> 32) as u32;
+    let pid_tgid = bpf_get_current_pid_tgid();
+    buf.event.pid = (pid_tgid >> 32) as u32;
+    (buf.event.euid, buf.event.fsuid, buf.event.cap_effective) = unsafe {
+        let task = bpf_get_current_task() as *const task_struct;
+        read_cred(bpf_probe_read_kernel(&(*task).cred)?)?
+    };
+    buf.event.task_name = bpf_get_current_comm()?;
+    unsafe { read_inode(file, &mut buf.event)? };
+    buf.event.file_path_len = path_len as u16;
+
+    // Sent by `ringbuf_ret` once the result is known.
+    unsafe { PENDING.insert(&pid_tgid, buf, 0)? };
+
+    Ok(0)
+}
+
+fn try_ringbuf_ret(ctx: RetProbeContext) -> Result<u32, i64> {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let buf = unsafe { &mut *PENDING.get_ptr_mut(&pid_tgid).ok_or(1i64)? };
+    let ret: i32 = ctx.ret().ok_or(1i64)?;
+    buf.event.ret = ret;
+    let submit = ret != 0 || !denied_only();
+    let size = core::mem::size_of::<Event>() + (buf.event.file_path_len as usize & (MAX_PATH_LEN - 1));
+    let submitted = if submit { output(&ctx, record(buf, size)) } else { Ok(()) };
+    unsafe { PENDING.remove(&pid_tgid)? };
+    submitted.map(|_| 0)
+}
+
+fn try_vfs_write(ctx: ProbeContext) -> Result<u32, i64> {
+    // Writes never fail a permission check here, so there is nothing to
+    // report when only refusals are wanted.
+    if denied_only() {
+        return Ok(0);
+    }
+    let file: *const file = ctx.arg(0).ok_or(1i64)?;
+    // Only regular files; pipes, sockets and terminals are written to far
+    // too often to be worth it.
+    let mode = unsafe {
+        let inode = bpf_probe_read_kernel(&(*file).f_inode)?;
+        bpf_probe_read_kernel(&(*inode).i_mode)?
+    };
+    if mode & S_IFMT != S_IFREG {
+        return Ok(0);
+    }
+    unsafe { WRITE_PENDING.insert(&bpf_get_current_pid_tgid(), &(file as u64), 0)? };
+    Ok(0)
+}
+
+fn try_vfs_write_ret(ctx: RetProbeContext) -> Result<u32, i64> {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let file = unsafe { *WRITE_PENDING.get(&pid_tgid).ok_or(1i64)? } as *const file;
+    unsafe { WRITE_PENDING.remove(&pid_tgid)? };
+    let written: i64 = ctx.ret().ok_or(1i64)?;
+    if written <= 0 {
+        return Ok(0);
+    }
+    let key = unsafe { write_key(file)? };
+    if let Some(writes) = unsafe { WRITES.get_ptr_mut(&key) } {
+        let writes = unsafe { &mut *writes };
+        writes.event.bytes += written as u64;
+        writes.event.writes += 1;
+        let interval = unsafe { core::ptr::read_volatile(&WRITE_INTERVAL_NS) };
+        if unsafe { bpf_ktime_get_ns() } - writes.event.header.timestamp >= interval {
+            let sent = output(&ctx, file_record(writes));
+            unsafe { WRITES.remove(&key)? };
+            sent?;
+        }
+        return Ok(0);
+    }
+    // The first write since the last record: the record's timestamp is the
+    // time of this write.
+    let buf = unsafe { new_file_event(EVENT_KIND_WRITE, bpf_probe_read_kernel(&(*file).f_path.dentry)?)? };
+    buf.event.bytes = written as u64;
+    buf.event.writes = 1;
+    unsafe { WRITES.insert(&key, buf, 0)? };
+    Ok(0)
+}
+
+fn try_filp_close(ctx: ProbeContext) -> Result<u32, i64> {
+    let file: *const file = ctx.arg(0).ok_or(1i64)?;
+    let key = unsafe { write_key(file)? };
+    if let Some(writes) = unsafe { WRITES.get_ptr_mut(&key) } {
+        let sent = output(&ctx, file_record(unsafe { &mut *writes }));
+        unsafe { WRITES.remove(&key)? };
+        sent?;
+    }
+    Ok(0)
+}
+
+fn try_vfs_rename(ctx: ProbeContext) -> Result<u32, i64> {
+    let rd: *const RenameData = ctx.arg(0).ok_or(1i64)?;
+    unsafe {
+        let buf = new_file_event(EVENT_KIND_RENAME, bpf_probe_read_kernel(&(*rd).old_dentry)?)?;
+        // The new name goes right after the old one.
+        let offset = buf.event.name_len as usize & (MAX_PATH_LEN - 1);
+        let new_dentry = bpf_probe_read_kernel(&(*rd).new_dentry)?;
+        buf.event.new_name_len = read_name(new_dentry, &mut buf.names[offset..offset + MAX_PATH_LEN])? as u16;
+        park(buf)
+    }
+}
+
+// vfs_unlink and vfs_rmdir, which both take the dentry to remove third.
+fn try_vfs_remove(ctx: ProbeContext, kind: u16) -> Result<u32, i64> {
+    let dentry: *const dentry = ctx.arg(2).ok_or(1i64)?;
+    let buf = unsafe { new_file_event(kind, dentry)? };
+    park(buf)
+}
+
+fn try_vfs_mkdir(ctx: ProbeContext) -> Result<u32, i64> {
+    let dentry: *const dentry = ctx.arg(2).ok_or(1i64)?;
+    let mode: u16 = ctx.arg(3).ok_or(1i64)?;
+    let buf = unsafe { new_file_event(EVENT_KIND_MKDIR, dentry)? };
+    // The directory does not exist yet, so there is no inode to read the
+    // mode from.
+    buf.event.mode = S_IFDIR | (mode & 0o7777);
+    park(buf)
+}
+
+fn try_notify_change(ctx: ProbeContext) -> Result<u32, i64> {
+    let dentry: *const dentry = ctx.arg(1).ok_or(1i64)?;
+    let attr: *const iattr = ctx.arg(2).ok_or(1i64)?;
+    let attr = unsafe { bpf_probe_read_kernel(attr)? };
+    // Truncates, timestamp updates and the like are not chmod or chown.
+    let valid = attr.ia_valid as u16 & (ATTR_MODE | ATTR_UID | ATTR_GID);
+    if valid == 0 {
+        return Ok(0);
+    }
+    let buf = unsafe { new_file_event(EVENT_KIND_SETATTR, dentry)? };
+    buf.event.attr_valid = valid;
+    buf.event.attr_mode = attr.ia_mode & 0o7777;
+    buf.event.attr_uid = attr.ia_uid.val;
+    buf.event.attr_gid = attr.ia_gid.val;
+    park(buf)
+}
+
+fn try_file_change_ret(ctx: RetProbeContext) -> Result<u32, i64> {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let buf = unsafe { &mut *FILE_PENDING.get_ptr_mut(&pid_tgid).ok_or(1i64)? };
+    let ret: i32 = ctx.ret().ok_or(1i64)?;
+    buf.event.ret = ret;
+    let submit = ret != 0 || !denied_only();
+    let submitted = if submit { output(&ctx, file_record(buf)) } else { Ok(()) };
+    unsafe { FILE_PENDING.remove(&pid_tgid)? };
+    submitted.map(|_| 0)
+}
+
+fn denied_only() -> bool {
+    unsafe { core::ptr::read_volatile(&DENIED_ONLY) } != 0
+}
+
+/// Fills the per-CPU `FileEvent` buffer with what every kind of file change
+/// carries: who made it and to which file, named by `dentry`.
+unsafe fn new_file_event(kind: u16, dentry: *const dentry) -> Result<&'static mut FileEventBuf, i64> {
+    let buf = &mut *FILE_EVENT_BUF.get_ptr_mut(0).ok_or(1i64)?;
+    let event = &mut buf.event;
+    event.header = EventHeader::new(kind, 0, bpf_ktime_get_ns(), bpf_get_smp_processor_id());
+    let uid_gid = bpf_get_current_uid_gid();
+    event.uid = uid_gid as u32;
+    event.gid = (uid_gid >> 32) as u32;
+    event.pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    event.ret = 0;
+    event.bytes = 0;
+    event.writes = 0;
+    event.new_name_len = 0;
+    event.attr_valid = 0;
+    event.attr_mode = 0;
+    event.attr_uid = 0;
+    event.attr_gid = 0;
+    event.task_name = bpf_get_current_comm()?;
+    // A mkdir's dentry has no inode yet.
+    let inode = bpf_probe_read_kernel(&(*dentry).d_inode)?;
+    if inode.is_null() {
+        event.ino = 0;
+        event.mode = 0;
+    } else {
+        event.ino = bpf_probe_read_kernel(&(*inode).i_ino)? as u64;
+        event.mode = bpf_probe_read_kernel(&(*inode).i_mode)?;
+    }
+    let sb = bpf_probe_read_kernel(&(*dentry).d_sb)?;
+    event.dev = bpf_probe_read_kernel(&(*sb).s_dev)?;
+    event.name_len = read_name(dentry, &mut buf.names[..MAX_PATH_LEN])? as u16;
+    Ok(buf)
+}
+
+/// Copies the name of `dentry` into `dest` and returns its length.
+unsafe fn read_name(dentry: *const dentry, dest: &mut [u8]) -> Result<usize, i64> {
+    let name = bpf_probe_read_kernel(&(*dentry).d_name.name)?;
+    // The mask keeps the length provably in bounds for the verifier.
+    Ok(bpf_probe_read_kernel_str_bytes(name, dest)?.len() & (MAX_PATH_LEN - 1))
+}
+
+/// The `WRITES` key of the current process and `file`.
+unsafe fn write_key(file: *const file) -> Result<WriteKey, i64> {
+    let inode = bpf_probe_read_kernel(&(*file).f_inode)?;
+    let sb = bpf_probe_read_kernel(&(*inode).i_sb)?;
+    Ok(WriteKey {
+        pid: (bpf_get_current_pid_tgid() >> 32) as u32,
+        dev: bpf_probe_read_kernel(&(*sb).s_dev)?,
+        ino: bpf_probe_read_kernel(&(*inode).i_ino)? as u64,
+    })
+}
+
+// Sent by `file_change_ret` once the result is known.
+fn park(buf: &FileEventBuf) -> Result<u32, i64> {
+    unsafe { FILE_PENDING.insert(&bpf_get_current_pid_tgid(), buf, 0)? };
+    Ok(0)
+}
+
+/// The first `size` bytes of `buf`.
+fn record<T>(buf: &T, size: usize) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(buf as *const T as *const u8, size) }
+}
+
+/// The used part of `buf`, with `header.size` set to match.
+fn file_record(buf: &mut FileEventBuf) -> &[u8] {
+    let size = core::mem::size_of::<FileEvent>()
+        + (buf.event.name_len as usize & (MAX_PATH_LEN - 1))
+        + (buf.event.new_name_len as usize & (MAX_PATH_LEN - 1));
+    buf.event.header.size = size as u32;
+    record(buf, size)
+}
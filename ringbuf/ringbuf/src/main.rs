@@ -1,15 +1,184 @@
-use std::ffi::CStr;
+mod capture;
+mod rules;
 
-use aya::maps::RingBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context as _};
+use aya::maps::{MapData, PerCpuArray};
 use aya::programs::KProbe;
-use aya::{include_bytes_aligned, Bpf};
+use aya::{include_bytes_aligned, BpfLoader};
 use aya_log::BpfLogger;
-use log::{warn, debug};
-use ringbuf_common::Event;
+use capture::{CaptureReader, CaptureWriter};
+use clap::{Parser, Subcommand};
+use log::{info, warn, debug};
+use ringbuf_common::decode::{DecodeStats, Escaped, EventRef, FileEventRef, Record};
+use rules::{Alerts, RecordFields, Rules};
+use serde_json::{json, Value};
+use ringbuf_common::{Event, EventHeader, EVENT_KIND_MKDIR, EVENT_KIND_RENAME, EVENT_KIND_SETATTR, EVENT_KIND_WRITE, MAX_PATH_LEN, RINGBUF_SIZE, STAT_COUNT, STAT_FORCED_WAKEUP, STAT_RESERVE_FAILED, STAT_SUBMITTED};
+use tracer_support::control::{self, ControlSocket, Emission, Request, RpcError};
+use tracer_support::daemon::{self, Daemon, Signal, Signals};
+use tracer_support::enrich::{Enricher, Worker};
+use tracer_support::features::{AttachPoint, Features, Need, Transport};
+use tracer_support::sandbox;
+use tracer_support::transport::Reader;
+use tracer_support::verifier;
+
+static DECODE_STATS: DecodeStats = DecodeStats::new();
+static EMISSION: Emission = Emission::new();
+const WORKER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PROGRAMS: [&str; 11] = [
+    "ringbuf",
+    "ringbuf_ret",
+    "vfs_write",
+    "vfs_write_ret",
+    "filp_close",
+    "vfs_rename",
+    "vfs_unlink",
+    "vfs_rmdir",
+    "vfs_mkdir",
+    "notify_change",
+    "file_change_ret",
+];
+
+/// Kernel functions some programs attach to, with fallbacks in order. The
+/// file change ones, from `vfs_rename` on, read the 5.12+ layout of their
+/// arguments, see `attach_points`.
+const ATTACH_POINTS: [AttachPoint; 8] = [
+    // `do_dentry_open` calls `security_file_open`, takes the file first too,
+    // and returns its refusal.
+    AttachPoint { targets: &["security_file_open", "do_dentry_open"], programs: &["ringbuf", "ringbuf_ret"], untraced: "" },
+    AttachPoint { targets: &["vfs_write"], programs: &["vfs_write", "vfs_write_ret"], untraced: "writes" },
+    AttachPoint { targets: &["filp_close"], programs: &["filp_close"], untraced: "closes" },
+    AttachPoint { targets: &["vfs_rename"], programs: &["vfs_rename", "file_change_ret"], untraced: "renames" },
+    AttachPoint { targets: &["vfs_unlink"], programs: &["vfs_unlink", "file_change_ret"], untraced: "unlinks" },
+    AttachPoint { targets: &["vfs_rmdir"], programs: &["vfs_rmdir", "file_change_ret"], untraced: "rmdirs" },
+    AttachPoint { targets: &["vfs_mkdir"], programs: &["vfs_mkdir", "file_change_ret"], untraced: "mkdirs" },
+    AttachPoint { targets: &["notify_change"], programs: &["notify_change", "file_change_ret"], untraced: "attribute changes" },
+];
+
+/// The eBPF sources, to name the function of a line the verifier quotes.
+const EBPF_SOURCES: verifier::Sources = &[
+    ("main.rs", include_str!("../../ringbuf-ebpf/src/main.rs")),
+    ("perf.rs", include_str!("../../ringbuf-ebpf/src/perf.rs")),
+    ("probes.rs", include_str!("../../ringbuf-ebpf/src/probes.rs")),
+];
+
+#[derive(Debug, Parser)]
+struct Opt {
+    /// Size of the ring buffer in bytes, a power of two multiple of the page size
+    #[clap(long, default_value_t = RINGBUF_SIZE)]
+    ringbuf_size: u32,
+    /// Wake the reader once this percentage of the ring buffer is filled
+    #[clap(long, default_value_t = 25, value_parser = clap::value_parser!(u8).range(0..=100))]
+    wakeup_fill: u8,
+    /// Drain the ring buffer at least this often, in milliseconds
+    #[clap(long, default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..))]
+    wakeup_timeout: u64,
+    /// Read records through this instead of the best the kernel supports
+    #[clap(long, value_enum)]
+    transport: Option<Transport>,
+    /// Only report opens and file changes that were refused, e.g. by file permissions or an LSM
+    #[clap(long, global = true)]
+    denied_only: bool,
+    /// Raise alerts for the events that match the rules in this TOML file,
+    /// which is reloaded whenever it changes
+    #[clap(long, global = true)]
+    rules: Option<PathBuf>,
+    /// Only report events that match a rule
+    #[clap(long, global = true, requires = "rules")]
+    alerts_only: bool,
+    /// Report writes to a file that stays open at most this often, in seconds
+    #[clap(long, default_value_t = 5)]
+    write_interval: u64,
+    /// Print raw uids and pids instead of resolving them to names and executables
+    #[clap(long)]
+    no_enrich: bool,
+    /// How long resolved users, groups and processes are cached, in seconds
+    #[clap(long, default_value_t = 10)]
+    enrich_ttl: u64,
+    /// Serve the control API on this Unix socket
+    #[clap(long)]
+    control: Option<PathBuf>,
+    /// Run as a system service: log JSON lines, notify systemd and feed its
+    /// watchdog while the self-check passes
+    #[clap(long)]
+    daemon: bool,
+    /// Run the self-check this often, in seconds
+    #[clap(long, default_value_t = 30, requires = "daemon")]
+    check_interval: u64,
+    /// Fail the self-check once no event came in for this many seconds
+    #[clap(long, requires = "daemon")]
+    max_idle: Option<u64>,
+    /// Once attached, drop root and run as this user, under a seccomp filter
+    #[clap(long)]
+    user: Option<String>,
+    /// Write the verifier log here if a program fails to load
+    #[clap(long)]
+    verifier_log: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Trace file opens and changes and also write the raw events to a capture file
+    Record {
+        /// Path of the capture file to create
+        path: PathBuf,
+    },
+    /// Print the events of a capture file without loading any eBPF
+    Replay {
+        /// Path of the capture file to read
+        path: PathBuf,
+    },
+    /// Print what the kernel supports and where the probes would attach, without loading any eBPF
+    Features,
+    /// Send a request to the control socket of a running ringbuf and print the result
+    Ctl {
+        /// Path of the control socket
+        socket: PathBuf,
+        /// Method to call: programs, stats, pause or resume
+        method: String,
+        /// Params as a JSON object
+        params: Option<String>,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    env_logger::init();
+    let opt = Opt::parse();
+
+    daemon::init_logger(opt.daemon);
+
+    match &opt.command {
+        Some(Command::Ctl { socket, method, params }) => return control::cli(socket, method, params.as_deref()),
+        Some(Command::Features) => {
+            for line in feature_report(&Features::probe(), opt.transport) {
+                println!("{}", line);
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let alerting = match &opt.rules {
+        Some(path) => Some(Arc::new(Alerting {
+            rules: Arc::new(Rules::load(path, rules::SUPPORTED)?),
+            alerts_only: opt.alerts_only,
+            live: !matches!(opt.command, Some(Command::Replay { .. })),
+        })),
+        None => None,
+    };
+
+    // A replay's pids belong to processes that are long gone, so it never enriches.
+    let mut capture = match &opt.command {
+        Some(Command::Replay { path }) => return replay(path, opt.denied_only, alerting.as_deref()),
+        Some(Command::Record { path }) => Some(CaptureWriter::create(path)?),
+        Some(Command::Features | Command::Ctl { .. }) | None => None,
+    };
 
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
@@ -22,49 +191,355 @@ async fn main() -> Result<(), anyhow::Error> {
         debug!("remove limit on locked memory failed, ret is: {}", ret);
     }
 
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+    if !opt.ringbuf_size.is_power_of_two() || opt.ringbuf_size % page_size != 0 {
+        bail!("--ringbuf-size must be a power of two multiple of the page size ({})", page_size);
+    }
+    let wakeup_threshold = opt.ringbuf_size as u64 * opt.wakeup_fill as u64 / 100;
+
+    let features = Features::probe();
+    for line in feature_report(&features, opt.transport) {
+        debug!("{}", line);
+    }
+    features.check("ringbuf", &[Need::Kprobes, Need::GlobalData, Need::AnyTransport])?;
+    let transport = features.transport(opt.transport)?;
+    info!("reading records through a {}", transport);
+
     // This will include your eBPF object file as raw bytes at compile-time and load it at
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
-    // reach for `Bpf::load_file` instead.
+    // reach for `BpfLoader::load_file` instead. The perf object has the same probes,
+    // writing to a perf event array instead of RINGBUF.
     #[cfg(debug_assertions)]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/debug/ringbuf"
-    ))?;
+    let data: &[u8] = match transport {
+        Transport::Ringbuf => include_bytes_aligned!("../../target/bpfel-unknown-none/debug/ringbuf"),
+        Transport::Perf => include_bytes_aligned!("../../target/bpfel-unknown-none/debug/ringbuf-perf"),
+    };
     #[cfg(not(debug_assertions))]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/release/ringbuf"
-    ))?;
+    let data: &[u8] = match transport {
+        Transport::Ringbuf => include_bytes_aligned!("../../target/bpfel-unknown-none/release/ringbuf"),
+        Transport::Perf => include_bytes_aligned!("../../target/bpfel-unknown-none/release/ringbuf-perf"),
+    };
+    let denied_only = opt.denied_only as u8;
+    let write_interval_ns = opt.write_interval * 1_000_000_000;
+    let mut loader = BpfLoader::new();
+    loader
+        .set_global("DENIED_ONLY", &denied_only, true)
+        .set_global("WRITE_INTERVAL_NS", &write_interval_ns, true);
+    if transport == Transport::Ringbuf {
+        loader
+            .set_max_entries("RINGBUF", opt.ringbuf_size)
+            .set_global("WAKEUP_THRESHOLD", &wakeup_threshold, true);
+    }
+    let mut bpf = loader.load(data)?;
     if let Err(e) = BpfLogger::init(&mut bpf) {
         // This can happen if you remove all log statements from your eBPF program.
         warn!("failed to initialize eBPF logger: {}", e);
     }
-    let program: &mut KProbe = bpf.program_mut("ringbuf").unwrap().try_into()?;
-    program.load()?;
-    program.attach("security_file_open", 0)?;
+    for name in PROGRAMS {
+        let program: &mut KProbe = bpf.program_mut(name).unwrap().try_into()?;
+        program.load().map_err(|e| verifier::explain(name, e, opt.verifier_log.as_deref(), EBPF_SOURCES))?;
+    }
+    // Program and function of every probe, for the control API.
+    let mut attached = Vec::new();
+    let points = attach_points(&features);
+    if points.len() < ATTACH_POINTS.len() {
+        warn!("this kernel is older than 5.12, so renames, unlinks, rmdirs, mkdirs and attribute changes are not traced");
+    }
+    for point in points {
+        let Some(function) = features.resolve(point)? else {
+            continue;
+        };
+        for name in point.programs {
+            let program: &mut KProbe = bpf.program_mut(name).unwrap().try_into()?;
+            program.attach(function, 0).with_context(|| format!("failed to attach {} to {}", name, function))?;
+            attached.push((*name, function));
+        }
+    }
+    let mut daemon = opt.daemon.then(|| Daemon::new(Duration::from_secs(opt.check_interval), opt.max_idle.map(Duration::from_secs))).transpose()?;
+    if let Some(daemon) = &mut daemon {
+        daemon.watch(&daemon::program_ids(&bpf, attached.iter().map(|(program, _)| *program))?);
+    }
 
-    let mut ring_buf = RingBuf::try_from(bpf.map_mut("RINGBUF").unwrap()).unwrap();
+    let mut reader = Reader::open(&mut bpf, transport, opt.ringbuf_size, record_len)?;
+    let stats: PerCpuArray<_, u64> = PerCpuArray::try_from(bpf.take_map("STATS").unwrap())?;
+    // Looking processes up blocks, so enriched events are handled on a
+    // thread of their own rather than in this loop.
+    let worker = (!opt.no_enrich).then(|| {
+        let enricher = Enricher::new(Duration::from_secs(opt.enrich_ttl));
+        let alerting = alerting.clone();
+        Worker::spawn(move |buf: Vec<u8>| handle_event(&buf, Some(&enricher), alerting.as_deref()))
+    });
+    if let Some(alerting) = &alerting {
+        alerting.rules.watch();
+    }
+
+    // Most records are submitted without waking us up, so the timer bounds
+    // how long they can sit in the buffer.
+    let mut timeout = tokio::time::interval(Duration::from_millis(opt.wakeup_timeout));
+    let mut reserve_failed = 0;
+    let mut control = opt.control.as_deref().map(ControlSocket::bind).transpose()?;
+    let mut signals = Signals::new()?;
+    // STATS is still read, from this thread.
+    if let Some(user) = &opt.user {
+        sandbox::enter(user, true)?;
+    }
+    if let Some(daemon) = &daemon {
+        daemon.ready();
+    }
 
+    info!("Waiting for Ctrl-C...");
     loop {
-        if let Some(item) = ring_buf.next() {
-            let buf = &*item;
-            if let Ok(event) = parse_event(buf) {
-                let file_path = CStr::from_bytes_until_nul(&event.file_path).unwrap().to_str().unwrap();
-                let task_name = CStr::from_bytes_until_nul(&event.task_name).unwrap().to_str().unwrap();
-                println!("uid : {}, pid : {}, task_name : {}, file_path : {}", event.uid, event.pid, task_name, file_path);
-            } else {
-                eprintln!("fail to parse event!");
+        tokio::select! {
+            read = reader.read(|buf| handle_record(buf, &mut capture, worker.as_ref(), alerting.as_deref())) => read?,
+            _ = timeout.tick() => {
+                reader.drain(|buf| handle_record(buf, &mut capture, worker.as_ref(), alerting.as_deref()));
+                let failed = dropped(&reader, &stats)?;
+                if failed > reserve_failed {
+                    warn!("{} records dropped because the {} was full", failed - reserve_failed, transport);
+                    reserve_failed = failed;
+                }
+            }
+            request = ControlSocket::next(&mut control) => {
+                let result = handle_request(&request, &attached, &reader, &stats);
+                request.reply(result);
+            }
+            _ = Daemon::tick(&mut daemon) => {
+                if let Some(daemon) = &mut daemon {
+                    daemon.check(DECODE_STATS.decoded() + DECODE_STATS.malformed() + EMISSION.suppressed());
+                }
             }
+            signal = signals.next() => match signal {
+                Signal::Shutdown => break,
+                Signal::Reload => {
+                    if let Some(daemon) = &daemon {
+                        daemon.reloading();
+                    }
+                    match &alerting {
+                        Some(alerting) => alerting.rules.reload(),
+                        None => info!("no --rules to reload"),
+                    }
+                    if let Some(daemon) = &daemon {
+                        daemon.ready();
+                    }
+                }
+            },
+        }
+    }
+    if let Some(daemon) = &daemon {
+        daemon.stopping();
+    }
+    info!("Exiting...");
+
+    if let Some(capture) = &mut capture {
+        capture.flush()?;
+    }
+    if let Some(worker) = &worker {
+        worker.flush(WORKER_SHUTDOWN_TIMEOUT);
+    }
+    let totals = read_stats(&stats)?;
+    info!(
+        "submitted {} records, {} dropped, {} forced wakeups",
+        totals[STAT_SUBMITTED as usize],
+        dropped(&reader, &stats)?,
+        totals[STAT_FORCED_WAKEUP as usize]
+    );
+    report_decode_stats((transport == Transport::Ringbuf).then_some(opt.ringbuf_size));
+
+    Ok(())
+}
+
+fn handle_record(buf: &[u8], capture: &mut Option<CaptureWriter>, worker: Option<&Worker>, alerting: Option<&Alerting>) {
+    if EMISSION.suppress() {
+        return;
+    }
+    if let Some(capture) = capture {
+        if let Err(e) = capture.write_record(buf) {
+            warn!("failed to write capture record: {}", e);
+        }
+    }
+    match worker {
+        Some(worker) => worker.submit(buf.to_vec()),
+        None => handle_event(buf, None, alerting),
+    }
+}
+
+/// Sums the per-CPU `STATS` counters.
+fn read_stats(stats: &PerCpuArray<MapData, u64>) -> Result<[u64; STAT_COUNT as usize], anyhow::Error> {
+    let mut totals = [0; STAT_COUNT as usize];
+    for (i, total) in totals.iter_mut().enumerate() {
+        *total = stats.get(&(i as u32), 0)?.iter().sum();
+    }
+    Ok(totals)
+}
+
+/// The attach points whose probes can run here. Linux 5.12 put the mount's
+/// idmapping in `struct renamedata` and in front of the arguments of the
+/// other file change functions, and the probes read them at those places;
+/// on older kernels they would read the wrong ones.
+fn attach_points(features: &Features) -> &'static [AttachPoint] {
+    if features.at_least(5, 12) {
+        &ATTACH_POINTS
+    } else {
+        &ATTACH_POINTS[..3]
+    }
+}
+
+/// What `Features::report` says about this kernel and the attach points the
+/// probes can use here, then those left out for their argument layout, then
+/// the transport it gets.
+fn feature_report(features: &Features, wanted: Option<Transport>) -> Vec<String> {
+    let points = attach_points(features);
+    let mut lines = features.report(points);
+    for point in &ATTACH_POINTS[points.len()..] {
+        lines.push(format!("{} attach to nothing before Linux 5.12, so {} are not traced", point.programs.join(", "), point.untraced));
+    }
+    match features.transport(wanted) {
+        Ok(transport) => lines.push(format!("transport: {}", transport)),
+        Err(e) => lines.push(format!("transport: none, {}", e)),
+    }
+    lines
+}
+
+/// The size `EventHeader` gives a record, to cut perf samples to.
+fn record_len(buf: &[u8]) -> Option<usize> {
+    let offset = std::mem::offset_of!(EventHeader, size);
+    Some(u32::from_ne_bytes(buf.get(offset..offset + 4)?.try_into().unwrap()) as usize)
+}
+
+/// Records lost so far because the ring buffer or a perf buffer was full.
+fn dropped(reader: &Reader, stats: &PerCpuArray<MapData, u64>) -> Result<u64, anyhow::Error> {
+    match reader.lost() {
+        Some(lost) => Ok(lost),
+        None => Ok(read_stats(stats)?[STAT_RESERVE_FAILED as usize]),
+    }
+}
+
+fn handle_request(request: &Request, attached: &[(&str, &str)], reader: &Reader, stats: &PerCpuArray<MapData, u64>) -> Result<Value, anyhow::Error> {
+    match request.method.as_str() {
+        "programs" => Ok(attached.iter().map(|(program, function)| json!({ "program": program, "function": function })).collect()),
+        "stats" => {
+            let totals = read_stats(stats)?;
+            Ok(json!({
+                "submitted": totals[STAT_SUBMITTED as usize],
+                "reserve_failed": dropped(reader, stats)?,
+                "forced_wakeup": totals[STAT_FORCED_WAKEUP as usize],
+                "decoded": DECODE_STATS.decoded(),
+                "malformed": DECODE_STATS.malformed(),
+                "suppressed": EMISSION.suppressed(),
+                "paused": EMISSION.paused(),
+            }))
+        }
+        "pause" | "resume" => {
+            EMISSION.set_paused(request.method == "pause");
+            Ok(json!({ "paused": EMISSION.paused() }))
         }
+        method => Err(RpcError::method_not_found(method).into()),
     }
+}
 
+/// What `--rules` asked for.
+struct Alerting {
+    rules: Arc<Rules>,
+    alerts_only: bool,
+    /// The events are happening now, so `/proc` can tell their ancestry.
+    live: bool,
 }
 
-fn parse_event(buf: &[u8]) -> Result<Event, ()> {
-    if buf.len() < core::mem::size_of::<Event>() {
-        return Err(());
+fn replay(path: &Path, denied_only: bool, alerting: Option<&Alerting>) -> Result<(), anyhow::Error> {
+    for record in CaptureReader::open(path)? {
+        let record = record?;
+        // Live, the kernel side does this filtering.
+        if denied_only && Record::parse(&record).is_ok_and(|record| !record.denied()) {
+            continue;
+        }
+        handle_event(&record, None, alerting);
     }
-    let event = unsafe {
-        core::ptr::read_unaligned(buf.as_ptr() as *const Event)
+    report_decode_stats(Some(RINGBUF_SIZE));
+    Ok(())
+}
+
+fn handle_event(buf: &[u8], enricher: Option<&Enricher>, alerting: Option<&Alerting>) {
+    let record = match DECODE_STATS.decode(buf) {
+        Ok(record) => record,
+        Err(e) => {
+            warn!("fail to parse event: {}", e);
+            return;
+        }
     };
-    Ok(event)
-}
\ No newline at end of file
+    let alerts = alerting.map_or_else(Alerts::default, |alerting| alerting.rules.current().evaluate(&RecordFields::new(record, alerting.live)));
+    if alerts.is_empty() && alerting.is_some_and(|alerting| alerting.alerts_only) {
+        return;
+    }
+    match record {
+        Record::Open(event) => handle_open(event, enricher, &alerts),
+        Record::File(event) => handle_file_event(event, enricher, &alerts),
+    }
+}
+
+fn handle_open(event: EventRef, enricher: Option<&Enricher>, alerts: &Alerts) {
+    let mut line = format!("uid : {}, gid : {}, euid : {}, fsuid : {}, cap_effective : {:#x}, pid : {}, task_name : {}, file_path : {}, ret : {}, ino : {}, dev : {}:{}, type : {}, mode : {:04o}, size : {}, fs : {}", event.uid(), event.gid(), event.euid(), event.fsuid(), event.cap_effective(), event.pid(), Escaped(event.task_name()), Escaped(event.file_path()), event.ret(), event.ino(), event.dev_major(), event.dev_minor(), event.file_type(), event.mode() & 0o7777, event.file_size(), Escaped(event.fs_type()));
+    if let Some(context) = enricher.map(|enricher| enricher.lookup(event.uid(), event.pid())) {
+        line = format!("{}: {}, {}", context, line, context.details());
+    }
+    if event.elevated() {
+        line = format!("[elevated] {}", line);
+    }
+    if event.denied() {
+        line = format!("[denied] {}", line);
+    }
+    println!("{}{}", alerts, line);
+}
+
+fn handle_file_event(event: FileEventRef, enricher: Option<&Enricher>, alerts: &Alerts) {
+    let details = match event.kind() {
+        EVENT_KIND_WRITE => format!(", bytes : {}, writes : {}", event.bytes_written(), event.writes()),
+        EVENT_KIND_RENAME => format!(", new_path : {}", Escaped(event.new_name())),
+        EVENT_KIND_MKDIR => format!(", mode : {:04o}", event.mode() & 0o7777),
+        EVENT_KIND_SETATTR => {
+            let mut details = String::new();
+            if let Some(mode) = event.attr_mode() {
+                details.push_str(&format!(", new_mode : {:04o}", mode));
+            }
+            if let Some(uid) = event.attr_uid() {
+                details.push_str(&format!(", new_uid : {}", uid));
+            }
+            if let Some(gid) = event.attr_gid() {
+                details.push_str(&format!(", new_gid : {}", gid));
+            }
+            details
+        }
+        _ => String::new(),
+    };
+    let mut line = format!("uid : {}, gid : {}, pid : {}, task_name : {}, op : {}, file_path : {}{}, ret : {}, ino : {}, dev : {}:{}, type : {}", event.uid(), event.gid(), event.pid(), Escaped(event.task_name()), event.operation(), Escaped(event.name()), details, event.ret(), event.ino(), event.dev_major(), event.dev_minor(), event.file_type());
+    if let Some(context) = enricher.map(|enricher| enricher.lookup(event.uid(), event.pid())) {
+        line = format!("{}: {}, {}", context, line, context.details());
+    }
+    if event.denied() {
+        line = format!("[denied] {}", line);
+    }
+    println!("{}{}", alerts, line);
+}
+
+fn report_decode_stats(ringbuf_size: Option<u32>) {
+    let decoded = DECODE_STATS.decoded();
+    info!("decoded {} events, {} malformed", decoded, DECODE_STATS.malformed());
+    let (Some(ringbuf_size), Some(average)) = (ringbuf_size, DECODE_STATS.bytes().checked_div(decoded)) else {
+        return;
+    };
+    // What every record cost when `file_path` was a fixed array.
+    let fixed = (core::mem::size_of::<Event>() + MAX_PATH_LEN) as u64;
+    info!(
+        "average record size {} bytes: RINGBUF holds {} such records, against {} fixed {} byte records",
+        average,
+        ringbuf_size as u64 / ringbuf_slot(average),
+        ringbuf_size as u64 / ringbuf_slot(fixed),
+        fixed
+    );
+}
+
+/// Space a record takes in a BPF ring buffer: an 8 byte header, rounded up to
+/// a multiple of 8.
+fn ringbuf_slot(size: u64) -> u64 {
+    (size + 8).div_ceil(8) * 8
+}
@@ -0,0 +1,80 @@
+//! The fields `RINGBUF` records offer to the alert rules in
+//! `tracer_support::rules`.
+
+use std::cell::OnceCell;
+
+use ringbuf_common::decode::{Escaped, Record};
+use ringbuf_common::EVENT_KIND_SETATTR;
+use tracer_support::rules::{proc_ancestors, Fields, Value};
+
+pub use tracer_support::rules::{Alerts, Rules};
+
+/// The fields `RecordFields` knows.
+pub const SUPPORTED: &[&str] = &[
+    "event", "comm", "pid", "ppid", "ancestor", "uid", "gid", "euid", "fsuid", "elevated", "name", "new_name", "ret", "denied",
+    "type", "fs",
+];
+
+/// The fields of a `RINGBUF` record. `ppid` and `ancestor` come from `/proc`
+/// and are only there when `live` is set, since a replay's pids are long
+/// gone.
+pub struct RecordFields<'a> {
+    record: Record<'a>,
+    live: bool,
+    ancestors: OnceCell<Vec<(u32, String)>>,
+}
+
+impl<'a> RecordFields<'a> {
+    pub fn new(record: Record<'a>, live: bool) -> Self {
+        RecordFields {
+            record,
+            live,
+            ancestors: OnceCell::new(),
+        }
+    }
+
+    fn ancestors(&self, pid: u32) -> Option<&[(u32, String)]> {
+        self.live.then(|| self.ancestors.get_or_init(|| proc_ancestors(pid)).as_slice())
+    }
+}
+
+impl Fields for RecordFields<'_> {
+    fn get(&self, field: &str) -> Option<Value> {
+        let (pid, uid, gid, task_name) = match &self.record {
+            Record::Open(event) => (event.pid(), event.uid(), event.gid(), event.task_name()),
+            Record::File(event) => (event.pid(), event.uid(), event.gid(), event.task_name()),
+        };
+        Some(match field {
+            "comm" => Value::Str(Escaped(task_name).to_string()),
+            "pid" => Value::Int(pid as i64),
+            "ppid" => Value::Int(self.ancestors(pid)?.first()?.0 as i64),
+            "ancestor" => Value::List(self.ancestors(pid)?.iter().map(|(_, task_name)| task_name.clone()).collect()),
+            "uid" => Value::Int(uid as i64),
+            "gid" => Value::Int(gid as i64),
+            "denied" => Value::Bool(self.record.denied()),
+            _ => match &self.record {
+                Record::Open(event) => match field {
+                    "event" => Value::Str("open".to_owned()),
+                    "euid" => Value::Int(event.euid() as i64),
+                    "fsuid" => Value::Int(event.fsuid() as i64),
+                    "elevated" => Value::Bool(event.elevated()),
+                    "name" => Value::Str(Escaped(event.file_path()).to_string()),
+                    "ret" => Value::Int(event.ret() as i64),
+                    "type" => Value::Str(event.file_type().to_owned()),
+                    "fs" => Value::Str(Escaped(event.fs_type()).to_string()),
+                    _ => return None,
+                },
+                Record::File(event) => match field {
+                    // One name for chmod, chown and both at once.
+                    "event" if event.kind() == EVENT_KIND_SETATTR => Value::Str("setattr".to_owned()),
+                    "event" => Value::Str(event.operation().to_owned()),
+                    "name" => Value::Str(Escaped(event.name()).to_string()),
+                    "new_name" if event.new_name_len() > 0 => Value::Str(Escaped(event.new_name()).to_string()),
+                    "ret" => Value::Int(event.ret() as i64),
+                    "type" => Value::Str(event.file_type().to_owned()),
+                    _ => return None,
+                },
+            },
+        })
+    }
+}
@@ -0,0 +1,397 @@
+//! Validated, zero-copy decoding of the records read from `EVENTS`.
+//!
+//! `EVENTS` carries exec syscalls as `Event` and process lifecycle steps as
+//! `ProcessEvent`, told apart by `EventHeader::kind`. Records are
+//! variable-length: the fixed part is followed by the names it carries, and
+//! `header.size` covers both.
+//!
+//! The ring buffer hands us arbitrary bytes, so nothing here assumes the
+//! buffer is aligned or large enough, and kernel strings are never assumed to
+//! be UTF-8. Records carrying a different `EventHeader::version` or `kind`
+//! than this build knows about are rejected rather than misread.
+
+use core::fmt;
+use core::mem::{offset_of, size_of};
+
+pub use tracer_support::decode::{DecodeError, Escaped};
+use tracer_support::decode::{self, bytes, c_str, check_header, check_size, Decode};
+
+use crate::{
+    Event, EventHeader, ProcessEvent, EVENT_KIND_EXEC, EVENT_KIND_EXIT, EVENT_KIND_FORK, EVENT_KIND_PROCESS_EXEC,
+    EXEC_EXE_TRUNCATED, EXEC_INTERPRETED, MAX_FILENAME_LEN, SCHEMA_VERSION, TASK_NAME_LEN,
+};
+
+const _: () = assert!(
+    size_of::<EventHeader>() == decode::HEADER_LEN
+        && offset_of!(EventHeader, kind) == decode::KIND_OFFSET
+        && offset_of!(EventHeader, version) == decode::VERSION_OFFSET
+        && offset_of!(EventHeader, size) == decode::SIZE_OFFSET
+);
+
+/// Any record read from `EVENTS`.
+#[derive(Debug, Clone, Copy)]
+pub enum Record<'a> {
+    Exec(EventRef<'a>),
+    Process(ProcessEventRef<'a>),
+}
+
+impl<'a> Record<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, DecodeError> {
+        match check_header(buf, SCHEMA_VERSION)? {
+            EVENT_KIND_EXEC => EventRef::parse(buf).map(Record::Exec),
+            EVENT_KIND_FORK | EVENT_KIND_PROCESS_EXEC | EVENT_KIND_EXIT => {
+                ProcessEventRef::parse(buf).map(Record::Process)
+            }
+            kind => Err(DecodeError::UnknownKind(kind)),
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        match self {
+            Record::Exec(event) => event.size(),
+            Record::Process(event) => event.size(),
+        }
+    }
+}
+
+/// A borrowed view of an `Event` whose size has been checked.
+#[derive(Debug, Clone, Copy)]
+pub struct EventRef<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> EventRef<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, DecodeError> {
+        let kind = check_header(buf, SCHEMA_VERSION)?;
+        if kind != EVENT_KIND_EXEC {
+            return Err(DecodeError::UnknownKind(kind));
+        }
+        let event = EventRef { buf };
+        check_size(buf, size_of::<Event>(), MAX_FILENAME_LEN, || [event.filename_len() as usize])?;
+        Ok(event)
+    }
+
+    pub fn kind(&self) -> u16 {
+        u16::from_ne_bytes(bytes(self.buf, offset_of!(Event, header.kind)))
+    }
+
+    pub fn version(&self) -> u16 {
+        u16::from_ne_bytes(bytes(self.buf, offset_of!(Event, header.version)))
+    }
+
+    pub fn size(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(Event, header.size)))
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        u64::from_ne_bytes(bytes(self.buf, offset_of!(Event, header.timestamp)))
+    }
+
+    pub fn cpu(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(Event, header.cpu)))
+    }
+
+    pub fn pid(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(Event, pid)))
+    }
+
+    pub fn uid(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(Event, uid)))
+    }
+
+    pub fn filename_len(&self) -> u16 {
+        u16::from_ne_bytes(bytes(self.buf, offset_of!(Event, filename_len)))
+    }
+
+    /// The filename argument that follows the fixed part of the record.
+    pub fn filename(&self) -> &'a [u8] {
+        &self.buf[size_of::<Event>()..self.size() as usize]
+    }
+}
+
+/// A borrowed view of a `ProcessEvent` whose size has been checked.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessEventRef<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> ProcessEventRef<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, DecodeError> {
+        let kind = check_header(buf, SCHEMA_VERSION)?;
+        if !matches!(kind, EVENT_KIND_FORK | EVENT_KIND_PROCESS_EXEC | EVENT_KIND_EXIT) {
+            return Err(DecodeError::UnknownKind(kind));
+        }
+        let event = ProcessEventRef { buf };
+        check_size(buf, size_of::<ProcessEvent>(), MAX_FILENAME_LEN, || {
+            [event.filename_len() as usize, event.exe_len() as usize]
+        })?;
+        Ok(event)
+    }
+
+    pub fn kind(&self) -> u16 {
+        u16::from_ne_bytes(bytes(self.buf, offset_of!(ProcessEvent, header.kind)))
+    }
+
+    pub fn size(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(ProcessEvent, header.size)))
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        u64::from_ne_bytes(bytes(self.buf, offset_of!(ProcessEvent, header.timestamp)))
+    }
+
+    pub fn pid(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(ProcessEvent, pid)))
+    }
+
+    pub fn ppid(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(ProcessEvent, ppid)))
+    }
+
+    pub fn uid(&self) -> u32 {
+        u32::from_ne_bytes(bytes(self.buf, offset_of!(ProcessEvent, uid)))
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        i32::from_ne_bytes(bytes(self.buf, offset_of!(ProcessEvent, exit_code)))
+    }
+
+    pub fn start_time(&self) -> u64 {
+        u64::from_ne_bytes(bytes(self.buf, offset_of!(ProcessEvent, start_time)))
+    }
+
+    pub fn user_time(&self) -> u64 {
+        u64::from_ne_bytes(bytes(self.buf, offset_of!(ProcessEvent, user_time)))
+    }
+
+    pub fn system_time(&self) -> u64 {
+        u64::from_ne_bytes(bytes(self.buf, offset_of!(ProcessEvent, system_time)))
+    }
+
+    /// The task name up to its first NUL.
+    pub fn task_name(&self) -> &'a [u8] {
+        c_str(self.buf, offset_of!(ProcessEvent, task_name), TASK_NAME_LEN)
+    }
+
+    pub fn filename_len(&self) -> u16 {
+        u16::from_ne_bytes(bytes(self.buf, offset_of!(ProcessEvent, filename_len)))
+    }
+
+    pub fn exe_len(&self) -> u16 {
+        u16::from_ne_bytes(bytes(self.buf, offset_of!(ProcessEvent, exe_len)))
+    }
+
+    pub fn exec_flags(&self) -> u8 {
+        self.buf[offset_of!(ProcessEvent, exec_flags)]
+    }
+
+    /// The program an exec ran, as it was passed to exec; empty for forks and
+    /// exits.
+    pub fn filename(&self) -> &'a [u8] {
+        let start = size_of::<ProcessEvent>();
+        &self.buf[start..start + self.filename_len() as usize]
+    }
+
+    /// Absolute path of the file an exec loaded: `filename` resolved, or the
+    /// interpreter that took over. Empty for forks and exits.
+    pub fn exe(&self) -> &'a [u8] {
+        let start = size_of::<ProcessEvent>() + self.filename_len() as usize;
+        &self.buf[start..self.size() as usize]
+    }
+
+    /// Whether a `#!` line or binfmt_misc handed the program to `exe`.
+    pub fn interpreted(&self) -> bool {
+        self.exec_flags() & EXEC_INTERPRETED != 0
+    }
+
+    /// Whether `exe` is missing its leading components.
+    pub fn exe_truncated(&self) -> bool {
+        self.exec_flags() & EXEC_EXE_TRUNCATED != 0
+    }
+
+    /// How the process ended, decoded from `exit_code`.
+    pub fn exit_status(&self) -> ExitStatus {
+        let code = self.exit_code();
+        match code & 0x7f {
+            0 => ExitStatus::Exited((code >> 8) & 0xff),
+            signal => ExitStatus::Signaled {
+                signal,
+                core_dumped: code & 0x80 != 0,
+            },
+        }
+    }
+}
+
+/// The `exit_code` of an exit record, as `WIFEXITED`/`WIFSIGNALED` see it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Exited(i32),
+    Signaled { signal: i32, core_dumped: bool },
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitStatus::Exited(code) => write!(f, "exited {}", code),
+            ExitStatus::Signaled { signal, core_dumped } => {
+                write!(f, "killed by signal {}", signal)?;
+                if *core_dumped {
+                    write!(f, " (core dumped)")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The records in `EVENTS`.
+pub enum Records {}
+
+impl Decode for Records {
+    type Record<'a> = Record<'a>;
+
+    fn parse(buf: &[u8]) -> Result<Record<'_>, DecodeError> {
+        Record::parse(buf)
+    }
+
+    fn size(record: &Record<'_>) -> u32 {
+        record.size()
+    }
+}
+
+/// Counts decoded and rejected `EVENTS` records.
+pub type DecodeStats = decode::DecodeStats<Records>;
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::mem;
+    use std::format;
+    use std::vec::Vec;
+
+    use tracer_support::decode::testing::{
+        as_bytes, assert_refuses_prefixes, corrupt_records, set_name_len, set_u16, set_u32, Rng,
+    };
+
+    use super::*;
+
+    fn exec(filename: &[u8]) -> Vec<u8> {
+        let mut event: Event = unsafe { mem::zeroed() };
+        let size = size_of::<Event>() + filename.len();
+        event.header = EventHeader::new(EVENT_KIND_EXEC, size as u32, 1, 0);
+        event.pid = 42;
+        event.filename_len = filename.len() as u16;
+        [as_bytes(&event), filename].concat()
+    }
+
+    fn process_exec(filename: &[u8], exe: &[u8]) -> Vec<u8> {
+        let mut event: ProcessEvent = unsafe { mem::zeroed() };
+        let size = size_of::<ProcessEvent>() + filename.len() + exe.len();
+        event.header = EventHeader::new(EVENT_KIND_PROCESS_EXEC, size as u32, 1, 0);
+        event.pid = 42;
+        event.ppid = 1;
+        event.task_name[..2].copy_from_slice(b"sh");
+        event.filename_len = filename.len() as u16;
+        event.exe_len = exe.len() as u16;
+        [as_bytes(&event), filename, exe].concat()
+    }
+
+    #[test]
+    fn parses_records() {
+        let buf = exec(b"/bin/ls");
+        let Ok(Record::Exec(event)) = Record::parse(&buf) else { panic!("not an exec") };
+        assert_eq!((event.pid(), event.filename()), (42, &b"/bin/ls"[..]));
+        let buf = process_exec(b"./run", b"/usr/bin/bash");
+        let Ok(Record::Process(event)) = Record::parse(&buf) else { panic!("not a process event") };
+        assert_eq!(
+            (event.task_name(), event.filename(), event.exe()),
+            (&b"sh"[..], &b"./run"[..], &b"/usr/bin/bash"[..])
+        );
+    }
+
+    #[test]
+    fn refuses_truncated_records() {
+        for buf in [exec(b"/bin/ls"), process_exec(b"./run", b"/usr/bin/bash")] {
+            assert_refuses_prefixes(&buf, Record::parse);
+        }
+    }
+
+    #[test]
+    fn refuses_a_size_the_names_do_not_add_up_to() {
+        let mut buf = exec(b"/bin/ls");
+        let len = buf.len() as u32;
+        for size in [0, size_of::<Event>() as u32, len - 1, len + 1, u32::MAX] {
+            set_u32(&mut buf, offset_of!(EventHeader, size), size);
+            assert!(matches!(Record::parse(&buf), Err(DecodeError::SizeMismatch { .. })), "size {}", size);
+        }
+    }
+
+    #[test]
+    fn refuses_names_past_the_end() {
+        // Longer than the kernel side ever sends.
+        let mut buf = exec(b"/bin/ls");
+        set_u16(&mut buf, offset_of!(Event, filename_len), MAX_FILENAME_LEN as u16 + 1);
+        assert!(matches!(Record::parse(&buf), Err(DecodeError::NameTooLong { .. })));
+        let mut buf = process_exec(b"./run", b"/usr/bin/bash");
+        set_u16(&mut buf, offset_of!(ProcessEvent, exe_len), u16::MAX);
+        assert!(matches!(Record::parse(&buf), Err(DecodeError::NameTooLong { .. })));
+        // Within the limit, and with a size to match, but past the buffer.
+        let mut buf = exec(b"/bin/ls");
+        set_u16(&mut buf, offset_of!(Event, filename_len), 100);
+        set_u32(&mut buf, offset_of!(EventHeader, size), size_of::<Event>() as u32 + 100);
+        assert!(matches!(Record::parse(&buf), Err(DecodeError::Truncated { .. })));
+        // One name running into the other changes what they add up to.
+        let mut buf = process_exec(b"./run", b"/usr/bin/bash");
+        set_u16(&mut buf, offset_of!(ProcessEvent, filename_len), 6);
+        assert!(matches!(Record::parse(&buf), Err(DecodeError::SizeMismatch { .. })));
+    }
+
+    #[test]
+    fn refuses_other_versions_and_kinds() {
+        let mut buf = exec(b"/bin/ls");
+        set_u16(&mut buf, offset_of!(EventHeader, version), SCHEMA_VERSION + 1);
+        let newer = DecodeError::UnsupportedVersion { version: SCHEMA_VERSION + 1, expected: SCHEMA_VERSION };
+        assert_eq!(Record::parse(&buf).unwrap_err(), newer);
+        let mut buf = exec(b"/bin/ls");
+        set_u16(&mut buf, offset_of!(EventHeader, kind), 999);
+        assert_eq!(Record::parse(&buf).unwrap_err(), DecodeError::UnknownKind(999));
+        // Each view only takes its own kinds.
+        let buf = exec(b"/bin/ls");
+        assert_eq!(ProcessEventRef::parse(&buf).unwrap_err(), DecodeError::UnknownKind(EVENT_KIND_EXEC));
+    }
+
+    #[test]
+    fn escapes_names_that_are_not_utf8() {
+        let buf = exec(b"/tmp/\xc3");
+        let Ok(Record::Exec(event)) = Record::parse(&buf) else { panic!("not an exec") };
+        assert_eq!(format!("{}", Escaped(event.filename())), "/tmp/\\xc3");
+    }
+
+    /// Whatever the bytes, parsing never panics, and what it accepts can be
+    /// read in full.
+    #[test]
+    fn never_panics_on_corrupt_records() {
+        let valid = [exec(b"/bin/ls"), process_exec(b"./run", b"/usr/bin/bash")];
+        let tweak = |rng: &mut Rng, buf: &mut Vec<u8>| {
+            if rng.below(2) == 0 {
+                let len = rng.below(2 * MAX_FILENAME_LEN) as u16;
+                set_name_len(buf, offset_of!(Event, filename_len), size_of::<Event>(), len);
+            }
+        };
+        corrupt_records::<Records>(&valid, tweak, |record| match record {
+            Record::Exec(event) => {
+                let _ = format!("{}", Escaped(event.filename()));
+            }
+            Record::Process(event) => {
+                let _ = format!(
+                    "{} {} {} {}",
+                    Escaped(event.task_name()),
+                    Escaped(event.filename()),
+                    Escaped(event.exe()),
+                    event.exit_status()
+                );
+            }
+        });
+    }
+}
@@ -1,8 +1,108 @@
 #![no_std]
 
+#[cfg(feature = "user")]
+use aya::Pod;
+
+#[cfg(feature = "user")]
+pub mod decode;
+
+pub const MAX_FILENAME_LEN: usize = 4096;
+
+pub const TASK_NAME_LEN: usize = 16;
+
+pub const RINGBUF_SIZE: u32 = 128 * 4096;
+
+/// Bumped whenever the layout of `EventHeader` or any event changes.
+pub const SCHEMA_VERSION: u16 = 3;
+
+pub const EVENT_KIND_EXEC: u16 = 2;
+/// A new process, not a new thread, was created.
+pub const EVENT_KIND_FORK: u16 = 4;
+/// A process finished loading a new program.
+pub const EVENT_KIND_PROCESS_EXEC: u16 = 5;
+/// The last thread of a process exited.
+pub const EVENT_KIND_EXIT: u16 = 6;
+
+/// `ProcessEvent::exec_flags`: the program was handed to an interpreter, by
+/// a `#!` line or binfmt_misc, and `exe` is that interpreter.
+pub const EXEC_INTERPRETED: u8 = 1;
+/// `ProcessEvent::exec_flags`: `exe` lost its leading components because the
+/// path was too deep or too long.
+pub const EXEC_EXE_TRUNCATED: u8 = 2;
+
+/// Common prefix of every record sent to user space.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct EventHeader {
+    pub kind: u16,
+    pub version: u16,
+    /// Size of the whole record, header included.
+    pub size: u32,
+    /// `bpf_ktime_get_ns()` when the record was produced.
+    pub timestamp: u64,
+    pub cpu: u32,
+    pub _pad: u32,
+}
+
+impl EventHeader {
+    pub const fn new(kind: u16, size: u32, timestamp: u64, cpu: u32) -> Self {
+        EventHeader {
+            kind,
+            version: SCHEMA_VERSION,
+            size,
+            timestamp,
+            cpu,
+            _pad: 0,
+        }
+    }
+}
+
+/// Fixed part of an exec record. The filename argument follows it directly,
+/// `filename_len` bytes long and without a trailing NUL, and `header.size`
+/// covers both.
+#[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct Event {
+    pub header: EventHeader,
     pub pid: u32,
     pub uid: u32,
-    pub filename: [u8; 4096],
-}
\ No newline at end of file
+    pub filename_len: u16,
+    pub _pad: [u8; 6],
+}
+
+/// A step in the life of a process: `EVENT_KIND_FORK`, `EVENT_KIND_PROCESS_EXEC`
+/// or `EVENT_KIND_EXIT`. Exec records are followed by the program path as it
+/// was passed to exec, `filename_len` bytes long, and then by the absolute
+/// path of the file that was loaded, `exe_len` bytes long, both without a
+/// trailing NUL. The others carry neither and both lengths are 0.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ProcessEvent {
+    pub header: EventHeader,
+    pub pid: u32,
+    /// pid of the real parent; for a fork, the process that forked.
+    pub ppid: u32,
+    pub uid: u32,
+    /// Exit status as `wait` reports it. Only set on exit.
+    pub exit_code: i32,
+    /// `bpf_ktime_get_ns()` clock value when the process was created.
+    pub start_time: u64,
+    /// CPU time spent in user and kernel mode, in ns. Only set on exit.
+    pub user_time: u64,
+    pub system_time: u64,
+    pub task_name: [u8; TASK_NAME_LEN],
+    pub filename_len: u16,
+    pub exe_len: u16,
+    /// `EXEC_*` flags. Only set on exec.
+    pub exec_flags: u8,
+    pub _pad: [u8; 3],
+}
+
+#[cfg(feature = "user")]
+unsafe impl Pod for EventHeader {}
+
+#[cfg(feature = "user")]
+unsafe impl Pod for Event {}
+
+#[cfg(feature = "user")]
+unsafe impl Pod for ProcessEvent {}
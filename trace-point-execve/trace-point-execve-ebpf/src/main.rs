@@ -7,13 +7,15 @@
 #![allow(dead_code)]
 
 use core::ffi::c_char;
+use core::mem::offset_of;
 
 use aya_ebpf::{
-    helpers::{bpf_get_current_pid_tgid, bpf_get_current_uid_gid, bpf_probe_read_kernel, bpf_probe_read_user_str_bytes}, macros::{map, tracepoint}, maps::{PerCpuArray, PerfEventArray}, programs::TracePointContext
+    cty::c_void, helpers::{gen, bpf_get_current_comm, bpf_get_current_pid_tgid, bpf_get_current_task, bpf_get_current_uid_gid, bpf_get_smp_processor_id, bpf_ktime_get_ns, bpf_probe_read_kernel, bpf_probe_read_kernel_str_bytes, bpf_probe_read_user_str_bytes}, macros::{btf_tracepoint, map, tracepoint}, maps::{PerCpuArray, RingBuf}, programs::{BtfTracePointContext, TracePointContext}
 };
 use aya_log_ebpf::info;
 // use aya_log_ebpf::info;
-use trace_point_execve_common::Event;
+use trace_point_execve_common::{Event, EventHeader, ProcessEvent, EVENT_KIND_EXEC, EVENT_KIND_EXIT, EVENT_KIND_FORK, EVENT_KIND_PROCESS_EXEC, EXEC_EXE_TRUNCATED, EXEC_INTERPRETED, MAX_FILENAME_LEN, RINGBUF_SIZE};
+use binding::{linux_binprm, mount, path, task_struct};
 mod binding;
 
 // #[repr(C)]
@@ -34,11 +36,42 @@ pub struct SysEnterExecve {
 }
 
 
+// `Event` followed by room for the longest filename we copy. Only the used
+// part of `filename` is sent to user space.
+#[repr(C)]
+struct EventBuf {
+    event: Event,
+    filename: [u8; MAX_FILENAME_LEN],
+}
+
+#[map]
+static mut BUFFER: PerCpuArray<EventBuf> = PerCpuArray::with_max_entries(1, 0);
+
+// Same for `ProcessEvent`, with room for the filename and the exe path.
+#[repr(C)]
+struct ProcessEventBuf {
+    event: ProcessEvent,
+    names: [u8; 2 * MAX_FILENAME_LEN],
+}
+
 #[map]
-static mut BUFFER: PerCpuArray<Event> = PerCpuArray::with_max_entries(1, 0);
+static mut PROCESS_BUFFER: PerCpuArray<ProcessEventBuf> = PerCpuArray::with_max_entries(1, 0);
+
+// Where `resolve_path` assembles a path, from the last component back. Twice
+// the longest path, so that every write the verifier can imagine fits.
+#[repr(C)]
+struct PathBuf {
+    buf: [u8; 2 * MAX_FILENAME_LEN],
+}
+
+#[map]
+static mut PATH_BUFFER: PerCpuArray<PathBuf> = PerCpuArray::with_max_entries(1, 0);
+
+// Path components followed up to the root before giving up.
+const MAX_PATH_DEPTH: usize = 32;
 
 #[map]
-static mut EVENTS : PerfEventArray<Event> = PerfEventArray::new(0);
+static EVENTS: RingBuf = RingBuf::with_byte_size(RINGBUF_SIZE, 0);
 
 #[tracepoint]
 pub fn trace_point_execveat(ctx: TracePointContext) -> u32 {
@@ -66,21 +99,218 @@ fn try_trace_point_execve_common(ctx: TracePointContext, filename_offset: usize)
         let filename_ptr = ctx.read_at::<*const c_char>(filename_offset).map_err(|e|e)? as *const u8;
 
         if !filename_ptr.is_null() {
-            buffer.filename.fill(0);
             // let args = bpf_probe_read_kernel(args_ptr).map_err(|e| e)?;
-            bpf_probe_read_user_str_bytes(filename_ptr, &mut buffer.filename)?;
+            // The mask keeps the length provably in bounds for the verifier.
+            let filename_len = bpf_probe_read_user_str_bytes(filename_ptr, &mut buffer.filename)?.len() & (MAX_FILENAME_LEN - 1);
             // info!(&ctx, "tracepoint sys_enter_execve called : {}", file_name_len);
             // info!(&ctx, "tracepoint sys_enter_execve called : {}", file_name_str);
             // bpf_printk!(b"tracepoint sys_enter_execve called : %s", args.filename);
-            buffer.uid = bpf_get_current_uid_gid() as u32;
-            buffer.pid = (bpf_get_current_pid_tgid() >> 32) as u32;
-            EVENTS.output(&ctx, buffer, 0)
+            let size = core::mem::size_of::<Event>() + filename_len;
+            buffer.event.header = EventHeader::new(EVENT_KIND_EXEC, size as u32, bpf_ktime_get_ns(), bpf_get_smp_processor_id());
+            buffer.event.uid = bpf_get_current_uid_gid() as u32;
+            buffer.event.pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+            buffer.event.filename_len = filename_len as u16;
+            let record = core::slice::from_raw_parts(buffer as *const EventBuf as *const u8, size);
+            EVENTS.output(record, 0)?;
         }
     }
     // info!(&ctx, "tracepoint sys_enter_execve called");
     Ok(0)
 }
 
+// A BTF tracepoint, because the plain sched_process_fork record only has the
+// child's thread id and cannot tell a new process from a new thread.
+#[btf_tracepoint(function = "sched_process_fork")]
+pub fn sched_process_fork(ctx: BtfTracePointContext) -> i32 {
+    match try_sched_process_fork(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+// A BTF tracepoint for `bprm`, which the plain record does not carry.
+#[btf_tracepoint(function = "sched_process_exec")]
+pub fn sched_process_exec(ctx: BtfTracePointContext) -> i32 {
+    match try_sched_process_exec(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[tracepoint]
+pub fn sched_process_exit(ctx: TracePointContext) -> u32 {
+    match try_sched_process_exit(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+fn try_sched_process_fork(ctx: BtfTracePointContext) -> Result<i32, i64> {
+    let child: *const task_struct = unsafe { ctx.arg(1) };
+    unsafe {
+        let pid = bpf_probe_read_kernel(&(*child).tgid)?;
+        // Threads share the tgid of the process they are created in.
+        if pid != bpf_probe_read_kernel(&(*child).pid)? {
+            return Ok(0);
+        }
+        let buffer = process_buffer()?;
+        let event = &mut buffer.event;
+        event.pid = pid as u32;
+        event.ppid = parent_pid(child)?;
+        event.start_time = bpf_probe_read_kernel(&(*child).start_time)?;
+        event.task_name = bpf_probe_read_kernel(&(*child).comm)?.map(|c| c as u8);
+        output_process_event(buffer, EVENT_KIND_FORK, 0, 0)?;
+    }
+    Ok(0)
+}
+
+fn try_sched_process_exec(ctx: BtfTracePointContext) -> Result<i32, i64> {
+    let task = bpf_get_current_task() as *const task_struct;
+    let bprm: *const linux_binprm = unsafe { ctx.arg(2) };
+    unsafe {
+        let buffer = process_buffer()?;
+        let filename = bpf_probe_read_kernel(&(*bprm).filename)?;
+        let filename_len = bpf_probe_read_kernel_str_bytes(filename as *const u8, &mut buffer.names[..MAX_FILENAME_LEN])?.len();
+        // By now `file` is what was actually loaded, the interpreter if one
+        // took over. `interp` only differs from `filename` in that case.
+        let mut exec_flags = 0;
+        if bpf_probe_read_kernel(&(*bprm).interp)? != filename {
+            exec_flags |= EXEC_INTERPRETED;
+        }
+        let file = bpf_probe_read_kernel(&(*bprm).file)?;
+        let paths = &mut *PATH_BUFFER.get_ptr_mut(0).ok_or(1i64)?;
+        let (exe_len, truncated) = resolve_path(&(*file).f_path as *const path, paths)?;
+        if truncated {
+            exec_flags |= EXEC_EXE_TRUNCATED;
+        }
+        // The masks keep the offsets and length provably in bounds for the
+        // verifier.
+        let exe_len = exe_len & (MAX_FILENAME_LEN - 1);
+        gen::bpf_probe_read_kernel(
+            buffer.names.as_mut_ptr().add(filename_len & (MAX_FILENAME_LEN - 1)) as *mut c_void,
+            exe_len as u32,
+            paths.buf.as_ptr().add((MAX_FILENAME_LEN - exe_len) & (MAX_FILENAME_LEN - 1)) as *const c_void,
+        );
+        let event = &mut buffer.event;
+        event.pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+        event.ppid = parent_pid(task)?;
+        event.start_time = bpf_probe_read_kernel(&(*task).start_time)?;
+        event.task_name = bpf_get_current_comm()?;
+        event.exec_flags = exec_flags;
+        output_process_event(buffer, EVENT_KIND_PROCESS_EXEC, filename_len, exe_len)?;
+    }
+    Ok(0)
+}
+
+// Writes the absolute path of `path` to `paths.buf`, ending just before
+// `MAX_FILENAME_LEN`, and returns its length and whether leading components
+// had to be left out. Mount points are crossed up to the root of the mount
+// namespace; a chroot does not stop the walk.
+unsafe fn resolve_path(path: *const path, paths: &mut PathBuf) -> Result<(usize, bool), i64> {
+    let mut dentry = bpf_probe_read_kernel(&(*path).dentry)?;
+    let vfsmnt = bpf_probe_read_kernel(&(*path).mnt)?;
+    // A `vfsmount` is embedded in the `mount` that knows where it is mounted.
+    let mut mnt = (vfsmnt as *mut u8).sub(offset_of!(mount, mnt)) as *mut mount;
+    let mut mnt_root = bpf_probe_read_kernel(&(*vfsmnt).mnt_root)?;
+    let mut len = 0;
+    for _ in 0..MAX_PATH_DEPTH {
+        if dentry == mnt_root {
+            let parent = bpf_probe_read_kernel(&(*mnt).mnt_parent)?;
+            if parent == mnt {
+                return Ok((len, false));
+            }
+            dentry = bpf_probe_read_kernel(&(*mnt).mnt_mountpoint)?;
+            mnt = parent;
+            mnt_root = bpf_probe_read_kernel(&(*mnt).mnt.mnt_root)?;
+            continue;
+        }
+        let parent = bpf_probe_read_kernel(&(*dentry).d_parent)?;
+        if parent == dentry {
+            return Ok((len, false));
+        }
+        let name = bpf_probe_read_kernel(&(*dentry).d_name)?;
+        let name_len = (name.__bindgen_anon_1.hash_len >> 32) as usize;
+        if len + name_len + 1 > MAX_FILENAME_LEN - 1 {
+            return Ok((len, true));
+        }
+        len += name_len + 1;
+        let offset = (MAX_FILENAME_LEN - len) & (MAX_FILENAME_LEN - 1);
+        paths.buf[offset] = b'/';
+        gen::bpf_probe_read_kernel(
+            paths.buf.as_mut_ptr().add(offset + 1) as *mut c_void,
+            (name_len & (MAX_FILENAME_LEN - 1)) as u32,
+            name.name as *const c_void,
+        );
+        dentry = parent;
+    }
+    Ok((len, true))
+}
+
+fn try_sched_process_exit(_ctx: TracePointContext) -> Result<u32, i64> {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let task = bpf_get_current_task() as *const task_struct;
+    unsafe {
+        // `do_exit` has counted this thread out of `signal->live` by now, so
+        // the process ends with the thread that takes it to 0, leader or not.
+        // Two threads exiting at once can both see 0, and both report it.
+        let signal = bpf_probe_read_kernel(&(*task).signal)?;
+        if bpf_probe_read_kernel(&(*signal).live.counter)? != 0 {
+            return Ok(0);
+        }
+        let leader = bpf_probe_read_kernel(&(*task).group_leader)?;
+        let buffer = process_buffer()?;
+        let event = &mut buffer.event;
+        event.pid = (pid_tgid >> 32) as u32;
+        event.ppid = parent_pid(task)?;
+        event.exit_code = bpf_probe_read_kernel(&(*task).exit_code)?;
+        event.start_time = bpf_probe_read_kernel(&(*leader).start_time)?;
+        // Threads that exited before this one are folded into the signal
+        // struct, except the leader, which is not until the process is reaped.
+        let mut user_time = bpf_probe_read_kernel(&(*task).utime)? + bpf_probe_read_kernel(&(*signal).utime)?;
+        let mut system_time = bpf_probe_read_kernel(&(*task).stime)? + bpf_probe_read_kernel(&(*signal).stime)?;
+        if leader as *const task_struct != task {
+            user_time += bpf_probe_read_kernel(&(*leader).utime)?;
+            system_time += bpf_probe_read_kernel(&(*leader).stime)?;
+        }
+        event.user_time = user_time;
+        event.system_time = system_time;
+        event.task_name = bpf_probe_read_kernel(&(*leader).comm)?.map(|c| c as u8);
+        output_process_event(buffer, EVENT_KIND_EXIT, 0, 0)?;
+    }
+    Ok(0)
+}
+
+// This CPU's `ProcessEventBuf`, with the fields only some kinds set cleared.
+unsafe fn process_buffer() -> Result<&'static mut ProcessEventBuf, i64> {
+    let buffer = &mut *PROCESS_BUFFER.get_ptr_mut(0).ok_or(1i64)?;
+    let event = &mut buffer.event;
+    event.uid = bpf_get_current_uid_gid() as u32;
+    event.exit_code = 0;
+    event.user_time = 0;
+    event.system_time = 0;
+    event.exec_flags = 0;
+    Ok(buffer)
+}
+
+unsafe fn parent_pid(task: *const task_struct) -> Result<u32, i64> {
+    let parent = bpf_probe_read_kernel(&(*task).real_parent)?;
+    Ok(bpf_probe_read_kernel(&(*parent).tgid)? as u32)
+}
+
+// Sends the fixed part of `buffer` and the filename and exe path that
+// `names` starts with.
+unsafe fn output_process_event(buffer: &mut ProcessEventBuf, kind: u16, filename_len: usize, exe_len: usize) -> Result<(), i64> {
+    // The masks keep the lengths provably in bounds for the verifier.
+    let filename_len = filename_len & (MAX_FILENAME_LEN - 1);
+    let exe_len = exe_len & (MAX_FILENAME_LEN - 1);
+    let size = core::mem::size_of::<ProcessEvent>() + filename_len + exe_len;
+    buffer.event.header = EventHeader::new(kind, size as u32, bpf_ktime_get_ns(), bpf_get_smp_processor_id());
+    buffer.event.filename_len = filename_len as u16;
+    buffer.event.exe_len = exe_len as u16;
+    let record = core::slice::from_raw_parts(buffer as *const ProcessEventBuf as *const u8, size);
+    EVENTS.output(record, 0)
+}
+
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     unsafe { core::hint::unreachable_unchecked() }
@@ -1,18 +1,167 @@
-use std::ffi::CStr;
+mod capture;
+mod proctable;
+mod rules;
 
-use aya::maps::AsyncPerfEventArray;
-use aya::programs::TracePoint;
-use aya::util::online_cpus;
-use aya::{include_bytes_aligned, Bpf};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use aya::maps::RingBuf;
+use aya::programs::{BtfTracePoint, TracePoint};
+use aya::{include_bytes_aligned, Bpf, Btf};
 use aya_log::BpfLogger;
-use bytes::BytesMut;
+use capture::{CaptureReader, CaptureWriter};
+use clap::{Parser, Subcommand};
 use log::{info, warn, debug};
-use tokio::signal;
-use trace_point_execve_common::Event;
+use proctable::ProcessTable;
+use rules::{Alerts, RecordFields, Rules};
+use serde_json::{json, Value};
+use tracer_support::control::{self, ControlSocket, Emission, Request, RpcError};
+use tracer_support::daemon::{self, Daemon, Signal, Signals};
+use tracer_support::enrich::{Context, Enricher, Worker};
+use tracer_support::features::{AttachPoint, Features, Need};
+use tracer_support::otlp::{self, AttrValue, Exporter, LogRecord};
+use tracer_support::sandbox;
+use tracer_support::verifier;
+use tokio::io::unix::AsyncFd;
+use trace_point_execve_common::decode::{DecodeStats, Escaped, EventRef, ExitStatus, ProcessEventRef, Record};
+use trace_point_execve_common::{Event, EVENT_KIND_EXIT, EVENT_KIND_FORK, MAX_FILENAME_LEN, RINGBUF_SIZE};
+
+static DECODE_STATS: DecodeStats = DecodeStats::new();
+static EMISSION: Emission = Emission::new();
+const OTLP_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const OTLP_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(15);
+const WORKER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The tracepoints the programs attach to. The fork and exec ones are BTF
+/// tracepoints, which attach by the name of their program.
+const ATTACH_POINTS: [AttachPoint; 5] = [
+    AttachPoint { targets: &["syscalls/sys_enter_execve"], programs: &["trace_point_execve"], untraced: "" },
+    AttachPoint { targets: &["syscalls/sys_enter_execveat"], programs: &["trace_point_execveat"], untraced: "execveat calls" },
+    AttachPoint { targets: &["sched/sched_process_fork"], programs: &["sched_process_fork"], untraced: "" },
+    AttachPoint { targets: &["sched/sched_process_exec"], programs: &["sched_process_exec"], untraced: "" },
+    AttachPoint { targets: &["sched/sched_process_exit"], programs: &["sched_process_exit"], untraced: "" },
+];
+const BTF_PROGRAMS: [&str; 2] = ["sched_process_fork", "sched_process_exec"];
+
+/// The eBPF source, to name the function of a line the verifier quotes.
+const EBPF_SOURCES: verifier::Sources = &[("main.rs", include_str!("../../trace-point-execve-ebpf/src/main.rs"))];
+
+#[derive(Debug, Parser)]
+struct Opt {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// Also export every event as an OTLP log record to this collector URL,
+    /// e.g. http://localhost:4318/v1/logs
+    #[clap(long, global = true)]
+    otlp_endpoint: Option<String>,
+    /// Append batches the collector did not accept to this file
+    #[clap(long, global = true, requires = "otlp_endpoint")]
+    otlp_fallback: Option<PathBuf>,
+    /// Number of log records sent per export request
+    #[clap(long, global = true, default_value_t = 512)]
+    otlp_batch_size: usize,
+    /// Raise alerts for the events that match the rules in this TOML file,
+    /// which is reloaded whenever it changes
+    #[clap(long, global = true)]
+    rules: Option<PathBuf>,
+    /// Only report events that match a rule
+    #[clap(long, global = true, requires = "rules")]
+    alerts_only: bool,
+    /// Print raw uids and pids instead of resolving them to names and executables
+    #[clap(long)]
+    no_enrich: bool,
+    /// How long resolved users, groups and processes are cached, in seconds
+    #[clap(long, default_value_t = 10)]
+    enrich_ttl: u64,
+    /// Serve the control API on this Unix socket
+    #[clap(long)]
+    control: Option<PathBuf>,
+    /// Run as a system service: log JSON lines, notify systemd and feed its
+    /// watchdog while the self-check passes
+    #[clap(long)]
+    daemon: bool,
+    /// Run the self-check this often, in seconds
+    #[clap(long, default_value_t = 30, requires = "daemon")]
+    check_interval: u64,
+    /// Fail the self-check once no event came in for this many seconds
+    #[clap(long, requires = "daemon")]
+    max_idle: Option<u64>,
+    /// Once attached, drop root and run as this user, under a seccomp filter
+    #[clap(long)]
+    user: Option<String>,
+    /// Write the verifier log here if a program fails to load
+    #[clap(long)]
+    verifier_log: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Trace execs and process lifecycles and also write the raw events to a capture file
+    Record {
+        /// Path of the capture file to create
+        path: PathBuf,
+    },
+    /// Print the events of a capture file without loading any eBPF
+    Replay {
+        /// Path of the capture file to read
+        path: PathBuf,
+    },
+    /// Send a request to the control socket of a running trace-point-execve and print the result
+    Ctl {
+        /// Path of the control socket
+        socket: PathBuf,
+        /// Method to call: programs, stats, processes, pause or resume
+        method: String,
+        /// Params as a JSON object
+        params: Option<String>,
+    },
+    /// Print what the kernel supports and where the programs would attach, without loading any eBPF
+    Features,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    env_logger::init();
+    let opt = Opt::parse();
+
+    daemon::init_logger(opt.daemon);
+
+    match &opt.command {
+        Some(Command::Ctl { socket, method, params }) => return control::cli(socket, method, params.as_deref()),
+        Some(Command::Features) => {
+            for line in Features::probe().report(&ATTACH_POINTS) {
+                println!("{}", line);
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let exporter = opt.otlp_endpoint.as_ref().map(|endpoint| {
+        Arc::new(Exporter::spawn(otlp::Config {
+            scope: "trace-point-execve",
+            endpoint: endpoint.clone(),
+            fallback: opt.otlp_fallback.clone(),
+            batch_size: opt.otlp_batch_size.max(1),
+            flush_interval: OTLP_FLUSH_INTERVAL,
+        }))
+    });
+
+    let alerting = match &opt.rules {
+        Some(path) => Some(Arc::new(Alerting {
+            rules: Arc::new(Rules::load(path, rules::SUPPORTED)?),
+            alerts_only: opt.alerts_only,
+        })),
+        None => None,
+    };
+
+    // A replay's pids belong to processes that are long gone, so it never enriches.
+    let mut capture = match &opt.command {
+        Some(Command::Replay { path }) => return replay(path, alerting.as_deref(), exporter.as_deref()),
+        Some(Command::Record { path }) => Some(CaptureWriter::create(path)?),
+        Some(Command::Features | Command::Ctl { .. }) | None => None,
+    };
 
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
@@ -25,6 +174,12 @@ async fn main() -> Result<(), anyhow::Error> {
         debug!("remove limit on locked memory failed, ret is: {}", ret);
     }
 
+    let features = Features::probe();
+    for line in features.report(&ATTACH_POINTS) {
+        debug!("{}", line);
+    }
+    features.check("trace-point-execve", &[Need::Tracepoints, Need::BtfTracepoints, Need::RingBuffers])?;
+
     // This will include your eBPF object file as raw bytes at compile-time and load it at
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
@@ -41,53 +196,382 @@ async fn main() -> Result<(), anyhow::Error> {
         // This can happen if you remove all log statements from your eBPF program.
         warn!("failed to initialize eBPF logger: {}", e);
     }
-    let program_execve: &mut TracePoint = bpf.program_mut("trace_point_execve").unwrap().try_into()?;
-
-    program_execve.load()?;
-    program_execve.attach("syscalls", "sys_enter_execve")?;
-
-    let program_execveat: &mut TracePoint = bpf.program_mut("trace_point_execveat").unwrap().try_into()?;
-    program_execveat.load()?;
-    program_execveat.attach("syscalls", "sys_enter_execveat")?;
-
-    let mut perf_array = AsyncPerfEventArray::try_from(bpf.take_map("EVENTS").unwrap())?;
-
-    for cpu_id in online_cpus()? {
-        let mut buf = perf_array.open(cpu_id, None)?;
-        tokio::spawn(async move {
-            let mut buffers = (0..10)
-                .map(|_| BytesMut::with_capacity(1024))
-                .collect::<Vec<_>>();
-            loop {
-                let events = buf.read_events(&mut buffers).await.unwrap();
-
-                for i in 0..events.read{
-                    let buf = &mut buffers[i];
-                    if let Ok(event) = parse_event(buf) {
-                        print!("pid: {}, uid: {}", event.pid, event.uid);
-                        // let file_path = CStr::from_bytes_until_nul(&event.filename).unwrap().to_str().unwrap();
-                        let file_path = std::str::from_utf8(&event.filename).unwrap();
-                        println!(" file_path : {}", file_path);
-                    } else {
-                        eprintln!("failed to parse event");
+    let btf = Btf::from_sys_fs()?;
+    // Program and tracepoint of every attachment, for the control API.
+    let mut attached = Vec::new();
+    for point in &ATTACH_POINTS {
+        let Some(tracepoint) = features.resolve(point)? else {
+            continue;
+        };
+        let name = point.programs[0];
+        if BTF_PROGRAMS.contains(&name) {
+            let program: &mut BtfTracePoint = bpf.program_mut(name).unwrap().try_into()?;
+            program.load(name, &btf).map_err(|e| verifier::explain(name, e, opt.verifier_log.as_deref(), EBPF_SOURCES))?;
+            program.attach().with_context(|| format!("failed to attach {} to {}", name, tracepoint))?;
+        } else {
+            let program: &mut TracePoint = bpf.program_mut(name).unwrap().try_into()?;
+            program.load().map_err(|e| verifier::explain(name, e, opt.verifier_log.as_deref(), EBPF_SOURCES))?;
+            let (category, event) = tracepoint.split_once('/').unwrap();
+            program.attach(category, event).with_context(|| format!("failed to attach {} to {}", name, tracepoint))?;
+        }
+        attached.push((name, tracepoint));
+    }
+    let mut daemon = opt.daemon.then(|| Daemon::new(Duration::from_secs(opt.check_interval), opt.max_idle.map(Duration::from_secs))).transpose()?;
+    if let Some(daemon) = &mut daemon {
+        daemon.watch(&daemon::program_ids(&bpf, attached.iter().map(|(program, _)| *program))?);
+    }
+
+    // Seeded after attaching, so a process forked in between is not missed.
+    // Its events are applied on top of what /proc said.
+    let mut processes = ProcessTable::new();
+    processes.seed();
+    let processes = Arc::new(Mutex::new(processes));
+
+    let ring_buf = RingBuf::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    let mut ring_buf = AsyncFd::new(ring_buf)?;
+    // Looking processes up blocks, so enriched records are handled on a
+    // thread of their own rather than in this loop. The table is kept up to
+    // date there too, in the order the records came in.
+    let worker = (!opt.no_enrich).then(|| {
+        let enricher = Enricher::new(Duration::from_secs(opt.enrich_ttl));
+        let processes = processes.clone();
+        let alerting = alerting.clone();
+        let exporter = exporter.clone();
+        Worker::spawn(move |job| match job {
+            Job::Track(buf) => track(&buf, &mut processes.lock().unwrap()),
+            Job::Handle(buf) => handle_event(&buf, &processes, Some(&enricher), alerting.as_deref(), exporter.as_deref()),
+        })
+    });
+    if let Some(alerting) = &alerting {
+        alerting.rules.watch();
+    }
+    let mut control = opt.control.as_deref().map(ControlSocket::bind).transpose()?;
+    let mut signals = Signals::new()?;
+    if let Some(user) = &opt.user {
+        sandbox::enter(user, false)?;
+    }
+    if let Some(daemon) = &daemon {
+        daemon.ready();
+    }
+
+    info!("Waiting for Ctrl-C...");
+    loop {
+        tokio::select! {
+            guard = ring_buf.readable_mut() => {
+                let mut guard = guard?;
+                let ring_buf = guard.get_inner_mut();
+                while let Some(item) = ring_buf.next() {
+                    let buf = &*item;
+                    if EMISSION.suppress() {
+                        // The table must keep up, or it is stale once
+                        // emission resumes.
+                        match &worker {
+                            Some(worker) => worker.submit(Job::Track(buf.to_vec())),
+                            None => track(buf, &mut processes.lock().unwrap()),
+                        }
+                        continue;
+                    }
+                    if let Some(capture) = &mut capture {
+                        if let Err(e) = capture.write_record(buf) {
+                            warn!("failed to write capture record: {}", e);
+                        }
+                    }
+                    match &worker {
+                        Some(worker) => worker.submit(Job::Handle(buf.to_vec())),
+                        None => handle_event(buf, &processes, None, alerting.as_deref(), exporter.as_deref()),
                     }
                 }
+                guard.clear_ready();
             }
-        });
+            request = ControlSocket::next(&mut control) => {
+                let result = handle_request(&request, &attached, &processes.lock().unwrap());
+                request.reply(result);
+            }
+            _ = Daemon::tick(&mut daemon) => {
+                if let Some(daemon) = &mut daemon {
+                    daemon.check(DECODE_STATS.decoded() + DECODE_STATS.malformed() + EMISSION.suppressed());
+                }
+            }
+            signal = signals.next() => match signal {
+                Signal::Shutdown => break,
+                Signal::Reload => {
+                    if let Some(daemon) = &daemon {
+                        daemon.reloading();
+                    }
+                    match &alerting {
+                        Some(alerting) => alerting.rules.reload(),
+                        None => info!("no --rules to reload"),
+                    }
+                    if let Some(daemon) = &daemon {
+                        daemon.ready();
+                    }
+                }
+            },
+        }
+    }
+    if let Some(daemon) = &daemon {
+        daemon.stopping();
     }
-
-    info!("Waiting for Ctrl-C...");
-    signal::ctrl_c().await?;
     info!("Exiting...");
 
+    if let Some(capture) = &mut capture {
+        capture.flush()?;
+    }
+    if let Some(worker) = &worker {
+        worker.flush(WORKER_SHUTDOWN_TIMEOUT);
+    }
+    if let Some(exporter) = &exporter {
+        exporter.flush(OTLP_SHUTDOWN_TIMEOUT);
+    }
+    let processes = processes.lock().unwrap();
+    info!("{} live processes", processes.len());
+    for (pid, process) in processes.iter() {
+        debug!("pid : {}, ppid : {}, uid : {}, task_name : {}, exe : {}", pid, process.ppid, process.uid, process.task_name, process.exe.as_deref().unwrap_or("?"));
+    }
+    report_decode_stats();
+
+    Ok(())
+}
+
+fn handle_request(request: &Request, attached: &[(&str, &str)], processes: &ProcessTable) -> Result<Value, anyhow::Error> {
+    match request.method.as_str() {
+        "programs" => Ok(attached.iter().map(|(program, tracepoint)| json!({ "program": program, "tracepoint": tracepoint })).collect()),
+        "stats" => Ok(json!({
+            "decoded": DECODE_STATS.decoded(),
+            "malformed": DECODE_STATS.malformed(),
+            "suppressed": EMISSION.suppressed(),
+            "paused": EMISSION.paused(),
+            "processes": processes.len(),
+        })),
+        "processes" => {
+            let mut pids: Vec<_> = processes.iter().collect();
+            pids.sort_unstable_by_key(|(pid, _)| *pid);
+            Ok(pids
+                .into_iter()
+                .map(|(pid, process)| json!({ "pid": pid, "ppid": process.ppid, "uid": process.uid, "task_name": process.task_name, "exe": process.exe }))
+                .collect())
+        }
+        "pause" | "resume" => {
+            EMISSION.set_paused(request.method == "pause");
+            Ok(json!({ "paused": EMISSION.paused() }))
+        }
+        method => Err(RpcError::method_not_found(method).into()),
+    }
+}
+
+/// What `--rules` asked for.
+struct Alerting {
+    rules: Arc<Rules>,
+    alerts_only: bool,
+}
+
+fn replay(path: &Path, alerting: Option<&Alerting>, exporter: Option<&Exporter>) -> Result<(), anyhow::Error> {
+    let processes = Mutex::new(ProcessTable::new());
+    for record in CaptureReader::open(path)? {
+        handle_event(&record?, &processes, None, alerting, exporter);
+    }
+    if let Some(exporter) = exporter {
+        exporter.flush(OTLP_SHUTDOWN_TIMEOUT);
+    }
+    report_decode_stats();
     Ok(())
 }
-fn parse_event(buf: &[u8]) -> Result<Event, ()> {
-    if buf.len() < core::mem::size_of::<Event>() {
-        return Err(());
+
+/// What the worker does with a record.
+enum Job {
+    /// Only keep the process table up to date, while emission is suppressed.
+    Track(Vec<u8>),
+    Handle(Vec<u8>),
+}
+
+fn track(buf: &[u8], processes: &mut ProcessTable) {
+    if let Ok(Record::Process(event)) = DECODE_STATS.decode(buf) {
+        processes.apply(&event);
     }
-    let event = unsafe {
-        core::ptr::read_unaligned(buf.as_ptr() as *const Event)
+}
+
+fn handle_event(buf: &[u8], processes: &Mutex<ProcessTable>, enricher: Option<&Enricher>, alerting: Option<&Alerting>, exporter: Option<&Exporter>) {
+    let record = match DECODE_STATS.decode(buf) {
+        Ok(record) => record,
+        Err(e) => {
+            warn!("failed to parse event: {}", e);
+            return;
+        }
     };
-    Ok(event)
+    // Looked up before taking the table, which the control socket reads too.
+    let context = enricher.map(|enricher| lookup(enricher, record));
+    let mut processes = processes.lock().unwrap();
+    match record {
+        Record::Exec(event) => handle_exec(event, &processes, context, alerting, exporter),
+        Record::Process(event) => handle_process_event(event, &mut processes, context, alerting, exporter),
+    }
+}
+
+/// Resolves the process behind `record`, and forgets what it made stale.
+fn lookup(enricher: &Enricher, record: Record) -> Context {
+    match record {
+        Record::Exec(event) => {
+            // The event fires on entry to execve, so this is still the image
+            // doing the exec; the next lookup of the pid must see the new one.
+            let context = enricher.lookup(event.uid(), event.pid());
+            enricher.forget(event.pid());
+            context
+        }
+        Record::Process(event) => {
+            let context = enricher.lookup(event.uid(), event.pid());
+            // The pid is free for reuse now.
+            if event.kind() == EVENT_KIND_EXIT {
+                enricher.forget(event.pid());
+            }
+            context
+        }
+    }
+}
+
+/// The rules `fields` match, or `None` when `--alerts-only` drops the event.
+fn evaluate(alerting: Option<&Alerting>, fields: &RecordFields) -> Option<Alerts> {
+    let Some(alerting) = alerting else {
+        return Some(Alerts::default());
+    };
+    let alerts = alerting.rules.current().evaluate(fields);
+    (!alerts.is_empty() || !alerting.alerts_only).then_some(alerts)
+}
+
+fn alert_attributes(alerts: &Alerts) -> Vec<(&'static str, AttrValue)> {
+    match alerts.highest() {
+        Some(highest) => vec![
+            ("alert.priority", AttrValue::Str(highest.priority.to_string())),
+            ("alert.rules", AttrValue::Str(alerts.iter().map(|alert| alert.rule.as_str()).collect::<Vec<_>>().join(", "))),
+        ],
+        None => Vec::new(),
+    }
+}
+
+fn handle_exec(event: EventRef, processes: &ProcessTable, context: Option<Context>, alerting: Option<&Alerting>, exporter: Option<&Exporter>) {
+    let mut line = format!("pid: {}, uid: {} file_path : {}", event.pid(), event.uid(), Escaped(event.filename()));
+    let context = context.map(|context| context.with_known_exe(processes.get(event.pid()).and_then(|process| process.exe.as_deref())));
+    let Some(alerts) = evaluate(alerting, &RecordFields::new(Record::Exec(event), processes.get(event.pid()), processes)) else {
+        return;
+    };
+    if let Some(context) = &context {
+        line = format!("{}: {}, {}", context, line, context.details());
+    }
+    line = format!("{}{}", alerts, line);
+    println!("{}", line);
+    if let Some(exporter) = exporter {
+        let mut attributes = vec![
+            ("process.owner.id", AttrValue::Int(event.uid() as i64)),
+            ("process.exec.path", AttrValue::Str(Escaped(event.filename()).to_string())),
+        ];
+        if let Some(context) = &context {
+            attributes.extend(context.attributes());
+        }
+        attributes.extend(alert_attributes(&alerts));
+        exporter.export(LogRecord {
+            timestamp: event.timestamp(),
+            pid: event.pid(),
+            comm: None,
+            body: line,
+            attributes,
+        });
+    }
+}
+
+fn handle_process_event(event: ProcessEventRef, processes: &mut ProcessTable, context: Option<Context>, alerting: Option<&Alerting>, exporter: Option<&Exporter>) {
+    // After an exit this is the last word on the process, since /proc no
+    // longer has it.
+    let process = processes.apply(&event);
+    let exe = process.as_ref().and_then(|process| process.exe.as_deref());
+    let (name, mut line) = match event.kind() {
+        EVENT_KIND_FORK => (
+            "fork",
+            format!("fork pid : {}, ppid : {}, uid : {}, task_name : {}, exe : {}", event.pid(), event.ppid(), event.uid(), Escaped(event.task_name()), exe.unwrap_or("?")),
+        ),
+        EVENT_KIND_EXIT => (
+            "exit",
+            format!("exit pid : {}, ppid : {}, uid : {}, task_name : {}, exe : {}, status : {}, lifetime : {}, user : {}, system : {}", event.pid(), event.ppid(), event.uid(), Escaped(event.task_name()), exe.unwrap_or("?"), event.exit_status(), Millis(event.timestamp().saturating_sub(event.start_time())), Millis(event.user_time()), Millis(event.system_time())),
+        ),
+        _ => (
+            "exec",
+            format!("exec pid : {}, ppid : {}, uid : {}, task_name : {}, file_path : {}, exe : {}{}", event.pid(), event.ppid(), event.uid(), Escaped(event.task_name()), Escaped(event.filename()), if event.exe_truncated() { "..." } else { "" }, Escaped(event.exe())),
+        ),
+    };
+    if event.interpreted() {
+        line.push_str(", interpreted : yes");
+    }
+    let context = context.map(|context| context.with_known_exe(exe));
+    let Some(alerts) = evaluate(alerting, &RecordFields::new(Record::Process(event), process.as_ref(), processes)) else {
+        return;
+    };
+    if let Some(context) = &context {
+        line = format!("{}: {}, {}", context, line, context.details());
+    }
+    line = format!("{}{}", alerts, line);
+    println!("{}", line);
+    if let Some(exporter) = exporter {
+        let mut attributes = vec![
+            ("event.name", AttrValue::Str(format!("process.{}", name))),
+            ("process.owner.id", AttrValue::Int(event.uid() as i64)),
+            ("process.parent_pid", AttrValue::Int(event.ppid() as i64)),
+        ];
+        match event.kind() {
+            EVENT_KIND_FORK => {}
+            EVENT_KIND_EXIT => {
+                match event.exit_status() {
+                    ExitStatus::Exited(code) => attributes.push(("process.exit.code", AttrValue::Int(code as i64))),
+                    ExitStatus::Signaled { signal, .. } => attributes.push(("process.exit.signal", AttrValue::Int(signal as i64))),
+                }
+                attributes.push(("process.lifetime_ns", AttrValue::Int(event.timestamp().saturating_sub(event.start_time()) as i64)));
+                attributes.push(("process.cpu.user_time_ns", AttrValue::Int(event.user_time() as i64)));
+                attributes.push(("process.cpu.system_time_ns", AttrValue::Int(event.system_time() as i64)));
+            }
+            _ => {
+                attributes.push(("process.exec.path", AttrValue::Str(Escaped(event.filename()).to_string())));
+                attributes.push(("process.exec.resolved_path", AttrValue::Str(Escaped(event.exe()).to_string())));
+                attributes.push(("process.exec.interpreted", AttrValue::Bool(event.interpreted())));
+            }
+        }
+        if let Some(context) = &context {
+            attributes.extend(context.attributes());
+        }
+        attributes.extend(alert_attributes(&alerts));
+        exporter.export(LogRecord {
+            timestamp: event.timestamp(),
+            pid: event.pid(),
+            comm: Some(Escaped(event.task_name()).to_string()),
+            body: line,
+            attributes,
+        });
+    }
+}
+
+/// Formats nanoseconds as milliseconds, e.g. `12.345ms`.
+struct Millis(u64);
+
+impl std::fmt::Display for Millis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{:03}ms", self.0 / 1_000_000, self.0 / 1_000 % 1_000)
+    }
+}
+
+fn report_decode_stats() {
+    let decoded = DECODE_STATS.decoded();
+    info!("decoded {} events, {} malformed", decoded, DECODE_STATS.malformed());
+    if let Some(average) = DECODE_STATS.bytes().checked_div(decoded) {
+        // What every record cost when `filename` was a fixed array.
+        let fixed = (core::mem::offset_of!(Event, filename_len) + MAX_FILENAME_LEN) as u64;
+        info!(
+            "average record size {} bytes: EVENTS holds {} such records, against {} fixed {} byte records",
+            average,
+            RINGBUF_SIZE as u64 / ringbuf_slot(average),
+            RINGBUF_SIZE as u64 / ringbuf_slot(fixed),
+            fixed
+        );
+    }
+}
+
+/// Space a record takes in a BPF ring buffer: an 8 byte header, rounded up to
+/// a multiple of 8.
+fn ringbuf_slot(size: u64) -> u64 {
+    (size + 8).div_ceil(8) * 8
 }
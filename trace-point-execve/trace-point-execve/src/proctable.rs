@@ -0,0 +1,293 @@
+//! The live processes, rebuilt from fork, exec and exit events.
+//!
+//! The table is seeded from `/proc` when tracing starts and from then on only
+//! follows the event stream, so it still knows a process after it has exited
+//! and vanished from `/proc`, which is how most short-lived processes are
+//! seen by the time their events are printed. A replay starts with an empty
+//! table and learns the processes forked during the capture.
+//!
+//! A process whose exit was lost, e.g. because `EVENTS` was full, stays in
+//! the table until its pid is reused.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use trace_point_execve_common::decode::{Escaped, ProcessEventRef};
+use trace_point_execve_common::{EVENT_KIND_EXIT, EVENT_KIND_FORK, EVENT_KIND_PROCESS_EXEC};
+
+#[derive(Debug, Clone)]
+pub struct Process {
+    pub ppid: u32,
+    pub uid: u32,
+    pub task_name: String,
+    /// Absolute path of the program the process runs, or of its interpreter.
+    /// Inherited from the parent until the process execs itself.
+    pub exe: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct ProcessTable {
+    processes: HashMap<u32, Process>,
+}
+
+impl ProcessTable {
+    pub fn new() -> Self {
+        ProcessTable::default()
+    }
+
+    /// Adds the processes that are running now. Processes we may not look at
+    /// closely are still added, with what could be read.
+    pub fn seed(&mut self) {
+        self.seed_from(Path::new("/proc"));
+    }
+
+    fn seed_from(&mut self, proc: &Path) {
+        let Ok(entries) = fs::read_dir(proc) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse().ok()) else {
+                continue;
+            };
+            if let Some(process) = read_process(&entry.path()) {
+                self.processes.insert(pid, process);
+            }
+        }
+    }
+
+    pub fn get(&self, pid: u32) -> Option<&Process> {
+        self.processes.get(&pid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.processes.len()
+    }
+
+    /// The processes by pid, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &Process)> {
+        self.processes.iter().map(|(&pid, process)| (pid, process))
+    }
+
+    /// Updates the table for `event` and returns the process it is about, as
+    /// it is after a fork or exec and as it was before an exit.
+    pub fn apply(&mut self, event: &ProcessEventRef) -> Option<Process> {
+        let task_name = Escaped(event.task_name()).to_string();
+        match event.kind() {
+            EVENT_KIND_FORK => {
+                let exe = self.get(event.ppid()).and_then(|parent| parent.exe.clone());
+                let process = Process {
+                    ppid: event.ppid(),
+                    uid: event.uid(),
+                    task_name,
+                    exe,
+                };
+                self.processes.insert(event.pid(), process.clone());
+                Some(process)
+            }
+            EVENT_KIND_PROCESS_EXEC => {
+                // Without a resolved path, what was passed to exec is the
+                // best we have.
+                let exe = match event.exe() {
+                    exe if exe.is_empty() || event.exe_truncated() => event.filename(),
+                    exe => exe,
+                };
+                let process = Process {
+                    ppid: event.ppid(),
+                    uid: event.uid(),
+                    task_name,
+                    exe: Some(Escaped(exe).to_string()),
+                };
+                self.processes.insert(event.pid(), process.clone());
+                Some(process)
+            }
+            EVENT_KIND_EXIT => self.processes.remove(&event.pid()),
+            _ => None,
+        }
+    }
+}
+
+/// The process behind `dir`, a `/proc/<pid>` directory.
+fn read_process(dir: &Path) -> Option<Process> {
+    // `pid (comm) state ppid ...`; comm may itself contain spaces and parens.
+    let stat = fs::read_to_string(dir.join("stat")).ok()?;
+    let (head, tail) = stat.rsplit_once(')')?;
+    let task_name = head.split_once('(')?.1.to_owned();
+    let ppid = tail.split_whitespace().nth(1)?.parse().ok()?;
+    let uid = fs::read_to_string(dir.join("status")).ok().and_then(|status| {
+        let line = status.lines().find(|line| line.starts_with("Uid:"))?;
+        line.split_whitespace().nth(1)?.parse().ok()
+    })?;
+    let exe = fs::read_link(dir.join("exe"))
+        .ok()
+        .map(|path| path.to_string_lossy().into_owned());
+    Some(Process {
+        ppid,
+        uid,
+        task_name,
+        exe,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::{mem, slice};
+
+    use trace_point_execve_common::{EventHeader, ProcessEvent};
+
+    use super::*;
+
+    fn as_bytes<T>(value: &T) -> &[u8] {
+        unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+    }
+
+    fn event(kind: u16, pid: u32, ppid: u32, task_name: &[u8], filename: &[u8], exe: &[u8]) -> Vec<u8> {
+        let mut event: ProcessEvent = unsafe { mem::zeroed() };
+        let size = mem::size_of::<ProcessEvent>() + filename.len() + exe.len();
+        event.header = EventHeader::new(kind, size as u32, 1, 0);
+        event.pid = pid;
+        event.ppid = ppid;
+        event.uid = 1000;
+        event.task_name[..task_name.len()].copy_from_slice(task_name);
+        event.filename_len = filename.len() as u16;
+        event.exe_len = exe.len() as u16;
+        [as_bytes(&event), filename, exe].concat()
+    }
+
+    fn fork(pid: u32, ppid: u32, task_name: &[u8]) -> Vec<u8> {
+        event(EVENT_KIND_FORK, pid, ppid, task_name, b"", b"")
+    }
+
+    fn exec(pid: u32, ppid: u32, task_name: &[u8], filename: &[u8], exe: &[u8]) -> Vec<u8> {
+        event(EVENT_KIND_PROCESS_EXEC, pid, ppid, task_name, filename, exe)
+    }
+
+    fn exit(pid: u32, ppid: u32, task_name: &[u8]) -> Vec<u8> {
+        event(EVENT_KIND_EXIT, pid, ppid, task_name, b"", b"")
+    }
+
+    fn apply(table: &mut ProcessTable, buf: &[u8]) -> Option<Process> {
+        table.apply(&ProcessEventRef::parse(buf).unwrap())
+    }
+
+    /// A scratch directory laid out like `/proc`, removed on drop.
+    struct FakeProc(PathBuf);
+
+    impl FakeProc {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("proctable-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            FakeProc(dir)
+        }
+
+        fn add(&self, pid: u32, stat: &str, uid: u32, exe: Option<&str>) {
+            let dir = self.0.join(pid.to_string());
+            fs::create_dir(&dir).unwrap();
+            fs::write(dir.join("stat"), stat).unwrap();
+            fs::write(dir.join("status"), format!("Name:\tx\nUid:\t{}\t{}\t{}\t{}\n", uid, uid, uid, uid)).unwrap();
+            if let Some(exe) = exe {
+                std::os::unix::fs::symlink(exe, dir.join("exe")).unwrap();
+            }
+        }
+    }
+
+    impl Drop for FakeProc {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn follows_a_process_from_fork_through_exec_to_exit() {
+        let mut table = ProcessTable::new();
+        apply(&mut table, &exec(100, 1, b"bash", b"/bin/bash", b"/usr/bin/bash"));
+
+        // Until it execs, the child runs what its parent runs.
+        let child = apply(&mut table, &fork(200, 100, b"bash")).unwrap();
+        assert_eq!((child.ppid, child.task_name.as_str(), child.exe.as_deref()), (100, "bash", Some("/usr/bin/bash")));
+
+        let child = apply(&mut table, &exec(200, 100, b"ls", b"ls", b"/usr/bin/ls")).unwrap();
+        assert_eq!((child.task_name.as_str(), child.exe.as_deref()), ("ls", Some("/usr/bin/ls")));
+        assert_eq!(table.get(200).unwrap().exe.as_deref(), Some("/usr/bin/ls"));
+        assert_eq!(table.len(), 2);
+
+        // The exit reports the process as it was.
+        let gone = apply(&mut table, &exit(200, 100, b"ls")).unwrap();
+        assert_eq!(gone.exe.as_deref(), Some("/usr/bin/ls"));
+        assert!(table.get(200).is_none());
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn falls_back_to_the_exec_argument_without_a_resolved_path() {
+        let mut table = ProcessTable::new();
+        let process = apply(&mut table, &exec(300, 1, b"sh", b"./run.sh", b"")).unwrap();
+        assert_eq!(process.exe.as_deref(), Some("./run.sh"));
+    }
+
+    #[test]
+    fn a_fork_of_an_unknown_parent_has_no_exe() {
+        let mut table = ProcessTable::new();
+        let process = apply(&mut table, &fork(400, 399, b"worker")).unwrap();
+        assert_eq!(process.exe, None);
+    }
+
+    /// The kernel side reports one exit per process, from whichever thread is
+    /// the last to go, under the process's pid. Two threads exiting at once
+    /// can both report it; the second finds nothing to remove.
+    #[test]
+    fn a_process_exits_once_whichever_thread_reports_it() {
+        let mut table = ProcessTable::new();
+        apply(&mut table, &exec(500, 1, b"server", b"/usr/sbin/server", b"/usr/sbin/server"));
+        apply(&mut table, &fork(600, 1, b"other"));
+        assert!(apply(&mut table, &exit(500, 1, b"server")).is_some());
+        assert!(apply(&mut table, &exit(500, 1, b"server")).is_none());
+        assert!(table.get(500).is_none());
+        assert!(table.get(600).is_some());
+    }
+
+    #[test]
+    fn a_reused_pid_replaces_the_process_whose_exit_was_lost() {
+        let mut table = ProcessTable::new();
+        apply(&mut table, &exec(700, 1, b"old", b"/bin/old", b"/bin/old"));
+        apply(&mut table, &fork(700, 2, b"new"));
+        let process = table.get(700).unwrap();
+        assert_eq!((process.ppid, process.task_name.as_str()), (2, "new"));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn seeds_from_proc() {
+        let proc = FakeProc::new("seed");
+        proc.add(1, "1 (systemd) S 0 1 1 0 -1", 0, Some("/usr/lib/systemd/systemd"));
+        // comm may hold spaces and parens.
+        proc.add(42, "42 (my (odd) name) R 1 42 42 0 -1", 1000, None);
+        // Unreadable or malformed entries are skipped, as is anything that
+        // is not a pid.
+        proc.add(43, "garbage", 1000, None);
+        fs::create_dir(proc.0.join("self")).unwrap();
+
+        let mut table = ProcessTable::new();
+        table.seed_from(&proc.0);
+        assert_eq!(table.len(), 2);
+        let init = table.get(1).unwrap();
+        assert_eq!((init.ppid, init.uid, init.task_name.as_str()), (0, 0, "systemd"));
+        assert_eq!(init.exe.as_deref(), Some("/usr/lib/systemd/systemd"));
+        let odd = table.get(42).unwrap();
+        assert_eq!((odd.ppid, odd.uid, odd.task_name.as_str(), odd.exe.as_deref()), (1, 1000, "my (odd) name", None));
+
+        // Events then build on what was seeded.
+        let child = apply(&mut table, &fork(44, 1, b"systemd")).unwrap();
+        assert_eq!(child.exe.as_deref(), Some("/usr/lib/systemd/systemd"));
+    }
+
+    #[test]
+    fn seeds_this_process_from_the_real_proc() {
+        let mut table = ProcessTable::new();
+        table.seed();
+        let me = table.get(std::process::id()).unwrap();
+        assert_eq!(me.exe, std::env::current_exe().ok().map(|path| path.to_string_lossy().into_owned()));
+    }
+}
@@ -0,0 +1,99 @@
+//! The fields `EVENTS` records offer to the alert rules in
+//! `tracer_support::rules`.
+
+use trace_point_execve_common::decode::{Escaped, Record};
+use trace_point_execve_common::{EVENT_KIND_EXIT, EVENT_KIND_FORK};
+use tracer_support::rules::{Fields, Value, MAX_ANCESTORS};
+
+use crate::proctable::{Process, ProcessTable};
+
+pub use tracer_support::rules::{Alerts, Rules};
+
+/// The fields `RecordFields` knows.
+pub const SUPPORTED: &[&str] = &["event", "comm", "pid", "ppid", "ancestor", "uid", "path", "name", "exe", "interpreted"];
+
+/// The fields of an `EVENTS` record. `process` is the one the record is
+/// about, as `ProcessTable::apply` returned it, and the process tree above
+/// it comes from `processes`.
+pub struct RecordFields<'a> {
+    record: Record<'a>,
+    process: Option<&'a Process>,
+    processes: &'a ProcessTable,
+}
+
+impl<'a> RecordFields<'a> {
+    pub fn new(record: Record<'a>, process: Option<&'a Process>, processes: &'a ProcessTable) -> Self {
+        RecordFields {
+            record,
+            process,
+            processes,
+        }
+    }
+
+    /// Task names of `ppid` and the processes above it, nearest first.
+    fn ancestors(&self, ppid: u32) -> Vec<String> {
+        let mut ancestors = Vec::new();
+        let mut ppid = ppid;
+        while ancestors.len() < MAX_ANCESTORS {
+            let Some(process) = self.processes.get(ppid) else {
+                break;
+            };
+            ancestors.push(process.task_name.clone());
+            ppid = process.ppid;
+        }
+        ancestors
+    }
+}
+
+impl Fields for RecordFields<'_> {
+    fn get(&self, field: &str) -> Option<Value> {
+        match &self.record {
+            // On entry to execve, so the process is still the one doing the
+            // exec.
+            Record::Exec(event) => {
+                let process = self.process;
+                Some(match field {
+                    "event" => Value::Str("execve".to_owned()),
+                    "comm" => Value::Str(process?.task_name.clone()),
+                    "pid" => Value::Int(event.pid() as i64),
+                    "ppid" => Value::Int(process?.ppid as i64),
+                    "ancestor" => Value::List(self.ancestors(process?.ppid)),
+                    "uid" => Value::Int(event.uid() as i64),
+                    "path" => Value::Str(Escaped(event.filename()).to_string()),
+                    "name" => Value::Str(Escaped(file_name(event.filename())).to_string()),
+                    "exe" => Value::Str(process?.exe.clone()?),
+                    _ => return None,
+                })
+            }
+            Record::Process(event) => {
+                let exec = !matches!(event.kind(), EVENT_KIND_FORK | EVENT_KIND_EXIT);
+                Some(match field {
+                    "event" => Value::Str(
+                        match event.kind() {
+                            EVENT_KIND_FORK => "fork",
+                            EVENT_KIND_EXIT => "exit",
+                            _ => "exec",
+                        }
+                        .to_owned(),
+                    ),
+                    "comm" => Value::Str(Escaped(event.task_name()).to_string()),
+                    "pid" => Value::Int(event.pid() as i64),
+                    "ppid" => Value::Int(event.ppid() as i64),
+                    "ancestor" => Value::List(self.ancestors(event.ppid())),
+                    "uid" => Value::Int(event.uid() as i64),
+                    "path" if exec => Value::Str(Escaped(event.filename()).to_string()),
+                    "name" if exec => Value::Str(Escaped(file_name(event.filename())).to_string()),
+                    // After an exec, the resolved path when there is one.
+                    "exe" => Value::Str(self.process?.exe.clone()?),
+                    "interpreted" if exec => Value::Bool(event.interpreted()),
+                    _ => return None,
+                })
+            }
+        }
+    }
+}
+
+/// The last component of `path`.
+fn file_name(path: &[u8]) -> &[u8] {
+    path.rsplit(|&b| b == b'/').next().unwrap_or(path)
+}
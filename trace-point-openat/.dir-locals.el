@@ -0,0 +1 @@
+((prog-mode . ((lsp-rust-analyzer-linked-projects . ["Cargo.toml" "trace-point-openat-ebpf/Cargo.toml"]))))
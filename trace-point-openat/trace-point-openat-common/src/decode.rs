@@ -0,0 +1,247 @@
+//! Validated, zero-copy decoding of `Event` records read from `EVENTS`.
+//!
+//! Records are variable-length: the fixed `Event` is followed by
+//! `path_len` bytes of path, and `header.size` covers both.
+//!
+//! The ring buffer hands us arbitrary bytes, so nothing here assumes the
+//! buffer is aligned or large enough, and kernel strings are never assumed to
+//! be UTF-8. Records carrying a different `EventHeader::version` or `kind`
+//! than this build knows about are rejected rather than misread.
+
+use core::mem::{offset_of, size_of};
+
+pub use tracer_support::decode::{DecodeError, Escaped};
+use tracer_support::decode::{self, bytes, c_str, check_header, check_size, Decode};
+
+use crate::{Event, EventHeader, EVENT_KIND_OPEN, MAX_PATH_LEN, SCHEMA_VERSION};
+
+const _: () = assert!(
+    size_of::<EventHeader>() == decode::HEADER_LEN
+        && offset_of!(EventHeader, kind) == decode::KIND_OFFSET
+        && offset_of!(EventHeader, version) == decode::VERSION_OFFSET
+        && offset_of!(EventHeader, size) == decode::SIZE_OFFSET
+);
+
+/// A borrowed view of an `Event` whose size has been checked.
+#[derive(Debug, Clone, Copy)]
+pub struct EventRef<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> EventRef<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, DecodeError> {
+        let kind = check_header(buf, SCHEMA_VERSION)?;
+        if kind != EVENT_KIND_OPEN {
+            return Err(DecodeError::UnknownKind(kind));
+        }
+        let event = EventRef { buf };
+        check_size(buf, size_of::<Event>(), MAX_PATH_LEN, || [event.path_len() as usize])?;
+        Ok(event)
+    }
+
+    pub fn kind(&self) -> u16 {
+        u16::from_ne_bytes(self.bytes(offset_of!(Event, header.kind)))
+    }
+
+    pub fn version(&self) -> u16 {
+        u16::from_ne_bytes(self.bytes(offset_of!(Event, header.version)))
+    }
+
+    pub fn size(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, header.size)))
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        u64::from_ne_bytes(self.bytes(offset_of!(Event, header.timestamp)))
+    }
+
+    pub fn cpu(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, header.cpu)))
+    }
+
+    pub fn pid(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, pid)))
+    }
+
+    pub fn tid(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, tid)))
+    }
+
+    pub fn uid(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, uid)))
+    }
+
+    pub fn dirfd(&self) -> i32 {
+        i32::from_ne_bytes(self.bytes(offset_of!(Event, dirfd)))
+    }
+
+    pub fn flags(&self) -> u64 {
+        u64::from_ne_bytes(self.bytes(offset_of!(Event, flags)))
+    }
+
+    pub fn resolve(&self) -> u64 {
+        u64::from_ne_bytes(self.bytes(offset_of!(Event, resolve)))
+    }
+
+    pub fn ret(&self) -> i64 {
+        i64::from_ne_bytes(self.bytes(offset_of!(Event, ret)))
+    }
+
+    pub fn mode(&self) -> u32 {
+        u32::from_ne_bytes(self.bytes(offset_of!(Event, mode)))
+    }
+
+    pub fn syscall(&self) -> u16 {
+        u16::from_ne_bytes(self.bytes(offset_of!(Event, syscall)))
+    }
+
+    pub fn path_len(&self) -> u16 {
+        u16::from_ne_bytes(self.bytes(offset_of!(Event, path_len)))
+    }
+
+    /// The task name up to its first NUL.
+    pub fn task_name(&self) -> &'a [u8] {
+        c_str(self.buf, offset_of!(Event, task_name), crate::TASK_NAME_LEN)
+    }
+
+    /// The path argument, as the caller passed it, that follows the fixed
+    /// part of the record.
+    pub fn path(&self) -> &'a [u8] {
+        &self.buf[size_of::<Event>()..self.size() as usize]
+    }
+
+    fn bytes<const N: usize>(&self, offset: usize) -> [u8; N] {
+        bytes(self.buf, offset)
+    }
+}
+
+/// The records in `EVENTS`.
+pub enum Events {}
+
+impl Decode for Events {
+    type Record<'a> = EventRef<'a>;
+
+    fn parse(buf: &[u8]) -> Result<EventRef<'_>, DecodeError> {
+        EventRef::parse(buf)
+    }
+
+    fn size(event: &EventRef<'_>) -> u32 {
+        event.size()
+    }
+}
+
+/// Counts decoded and rejected `EVENTS` records.
+pub type DecodeStats = decode::DecodeStats<Events>;
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::mem;
+    use std::format;
+    use std::vec::Vec;
+
+    use tracer_support::decode::testing::{as_bytes, assert_refuses_prefixes, set_u16, set_u32};
+
+    use super::*;
+    use crate::{SYSCALL_OPENAT, SYSCALL_OPENAT2};
+
+    /// A record as the exit tracepoint sends it: the arguments saved on
+    /// enter, with the result filled in on exit.
+    fn open(syscall: u16, path: &[u8], ret: i64) -> Vec<u8> {
+        let mut event: Event = unsafe { mem::zeroed() };
+        let size = size_of::<Event>() + path.len();
+        event.header = EventHeader::new(EVENT_KIND_OPEN, size as u32, 1, 0);
+        event.pid = 42;
+        event.tid = 43;
+        event.dirfd = -100;
+        event.flags = 0o101;
+        event.mode = 0o644;
+        event.resolve = if syscall == SYSCALL_OPENAT2 { 0x0c } else { 0 };
+        event.syscall = syscall;
+        event.ret = ret;
+        event.path_len = path.len() as u16;
+        event.task_name[..3].copy_from_slice(b"cat");
+        [as_bytes(&event), path].concat()
+    }
+
+    #[test]
+    fn parses_the_enter_arguments_with_the_exit_result() {
+        let buf = open(SYSCALL_OPENAT, b"/etc/passwd", 3);
+        let event = EventRef::parse(&buf).unwrap();
+        assert_eq!((event.pid(), event.tid(), event.dirfd()), (42, 43, -100));
+        assert_eq!((event.flags(), event.mode(), event.resolve()), (0o101, 0o644, 0));
+        assert_eq!((event.syscall(), event.ret()), (SYSCALL_OPENAT, 3));
+        assert_eq!((event.path(), event.task_name()), (&b"/etc/passwd"[..], &b"cat"[..]));
+    }
+
+    #[test]
+    fn parses_openat2_how() {
+        let buf = open(SYSCALL_OPENAT2, b"lib", -2);
+        let event = EventRef::parse(&buf).unwrap();
+        assert_eq!(event.syscall(), SYSCALL_OPENAT2);
+        assert_eq!((event.flags(), event.mode(), event.resolve()), (0o101, 0o644, 0x0c));
+        assert_eq!(event.ret(), -2);
+    }
+
+    #[test]
+    fn parses_an_empty_path() {
+        // What the enter tracepoint sends when the path pointer is bad.
+        let buf = open(SYSCALL_OPENAT, b"", -14);
+        let event = EventRef::parse(&buf).unwrap();
+        assert_eq!((event.path(), event.ret()), (&b""[..], -14));
+    }
+
+    #[test]
+    fn refuses_truncated_records() {
+        let buf = open(SYSCALL_OPENAT, b"/etc/passwd", 3);
+        assert_refuses_prefixes(&buf, EventRef::parse);
+    }
+
+    #[test]
+    fn refuses_a_size_the_path_does_not_add_up_to() {
+        let mut buf = open(SYSCALL_OPENAT, b"/etc/passwd", 3);
+        for size in [0, size_of::<Event>() as u32, buf.len() as u32 - 1, buf.len() as u32 + 1, u32::MAX] {
+            set_u32(&mut buf, offset_of!(Event, header.size), size);
+            assert!(matches!(EventRef::parse(&buf), Err(DecodeError::SizeMismatch { .. })), "size {}", size);
+        }
+    }
+
+    #[test]
+    fn refuses_paths_past_the_end() {
+        let mut buf = open(SYSCALL_OPENAT, b"/etc/passwd", 3);
+        set_u16(&mut buf, offset_of!(Event, path_len), MAX_PATH_LEN as u16 + 1);
+        assert!(matches!(EventRef::parse(&buf), Err(DecodeError::NameTooLong { .. })));
+        let mut buf = open(SYSCALL_OPENAT, b"/etc/passwd", 3);
+        set_u16(&mut buf, offset_of!(Event, path_len), 100);
+        set_u32(&mut buf, offset_of!(Event, header.size), size_of::<Event>() as u32 + 100);
+        assert!(matches!(EventRef::parse(&buf), Err(DecodeError::Truncated { .. })));
+    }
+
+    #[test]
+    fn refuses_other_versions_and_kinds() {
+        let mut buf = open(SYSCALL_OPENAT, b"/etc/passwd", 3);
+        set_u16(&mut buf, offset_of!(EventHeader, version), SCHEMA_VERSION + 1);
+        let newer = DecodeError::UnsupportedVersion { version: SCHEMA_VERSION + 1, expected: SCHEMA_VERSION };
+        assert_eq!(EventRef::parse(&buf).unwrap_err(), newer);
+        let mut buf = open(SYSCALL_OPENAT, b"/etc/passwd", 3);
+        set_u16(&mut buf, offset_of!(EventHeader, kind), 999);
+        assert_eq!(EventRef::parse(&buf).unwrap_err(), DecodeError::UnknownKind(999));
+    }
+
+    #[test]
+    fn escapes_paths_that_are_not_utf8() {
+        let buf = open(SYSCALL_OPENAT, b"/tmp/\xff\n", 3);
+        let event = EventRef::parse(&buf).unwrap();
+        assert_eq!(format!("{}", Escaped(event.path())), "/tmp/\\xff\\n");
+    }
+
+    #[test]
+    fn counts_decoded_and_malformed_records() {
+        let stats = DecodeStats::new();
+        let buf = open(SYSCALL_OPENAT, b"/etc/passwd", 3);
+        assert!(stats.decode(&buf).is_ok());
+        assert!(stats.decode(&buf[..10]).is_err());
+        assert_eq!((stats.decoded(), stats.malformed(), stats.bytes()), (1, 1, buf.len() as u64));
+    }
+}
@@ -0,0 +1,83 @@
+#![no_std]
+
+#[cfg(feature = "user")]
+use aya::Pod;
+
+#[cfg(feature = "user")]
+pub mod decode;
+
+/// Longest path copied from user memory, `PATH_MAX`.
+pub const MAX_PATH_LEN: usize = 4096;
+const TASK_NAME_LEN: usize = 16;
+
+pub const RINGBUF_SIZE: u32 = 128 * 4096;
+
+/// Opens in flight at once between the enter and exit tracepoints. Each entry
+/// holds a whole `MAX_PATH_LEN` path, so this is kept small.
+pub const PENDING_MAX_ENTRIES: u32 = 2048;
+
+/// Bumped whenever the layout of `EventHeader` or any event changes.
+pub const SCHEMA_VERSION: u16 = 1;
+
+pub const EVENT_KIND_OPEN: u16 = 3;
+
+/// Values of `Event::syscall`.
+pub const SYSCALL_OPENAT: u16 = 1;
+pub const SYSCALL_OPENAT2: u16 = 2;
+
+/// Common prefix of every record sent to user space.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct EventHeader {
+    pub kind: u16,
+    pub version: u16,
+    /// Size of the whole record, header included.
+    pub size: u32,
+    /// `bpf_ktime_get_ns()` when the record was produced.
+    pub timestamp: u64,
+    pub cpu: u32,
+    pub _pad: u32,
+}
+
+impl EventHeader {
+    pub const fn new(kind: u16, size: u32, timestamp: u64, cpu: u32) -> Self {
+        EventHeader {
+            kind,
+            version: SCHEMA_VERSION,
+            size,
+            timestamp,
+            cpu,
+            _pad: 0,
+        }
+    }
+}
+
+/// Fixed part of an open record, sent once the syscall has returned. The path
+/// argument follows it directly, `path_len` bytes long and without a trailing
+/// NUL, and `header.size` covers both.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Event {
+    pub header: EventHeader,
+    pub pid: u32,
+    pub tid: u32,
+    pub uid: u32,
+    pub dirfd: i32,
+    /// `O_*` flags; `open_how.flags` for openat2.
+    pub flags: u64,
+    /// `open_how.resolve` for openat2, 0 for openat.
+    pub resolve: u64,
+    /// The new fd, or a negative errno.
+    pub ret: i64,
+    pub mode: u32,
+    /// `SYSCALL_OPENAT` or `SYSCALL_OPENAT2`.
+    pub syscall: u16,
+    pub path_len: u16,
+    pub task_name: [u8; TASK_NAME_LEN],
+}
+
+#[cfg(feature = "user")]
+unsafe impl Pod for EventHeader {}
+
+#[cfg(feature = "user")]
+unsafe impl Pod for Event {}
@@ -0,0 +1,132 @@
+#![no_std]
+#![no_main]
+
+use aya_ebpf::{
+    helpers::{bpf_get_current_comm, bpf_get_current_pid_tgid, bpf_get_current_uid_gid, bpf_get_smp_processor_id, bpf_ktime_get_ns, bpf_probe_read_user, bpf_probe_read_user_str_bytes}, macros::{map, tracepoint}, maps::{LruHashMap, PerCpuArray, RingBuf}, programs::TracePointContext
+};
+use trace_point_openat_common::{Event, EventHeader, EVENT_KIND_OPEN, MAX_PATH_LEN, PENDING_MAX_ENTRIES, RINGBUF_SIZE, SYSCALL_OPENAT, SYSCALL_OPENAT2};
+
+// Field offsets in the syscalls:sys_{enter,exit}_openat{,2} records, see
+// /sys/kernel/tracing/events/syscalls/sys_enter_openat/format.
+const ENTER_DFD: usize = 16;
+const ENTER_FILENAME: usize = 24;
+// `flags` for openat, `how` for openat2.
+const ENTER_FLAGS: usize = 32;
+// `mode` for openat.
+const ENTER_MODE: usize = 40;
+const EXIT_RET: usize = 16;
+
+// `struct open_how` as passed to openat2.
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+// `Event` followed by room for the longest path we copy. Only the used part
+// of `path` is sent to user space.
+#[repr(C)]
+struct EventBuf {
+    event: Event,
+    path: [u8; MAX_PATH_LEN],
+}
+
+#[map]
+static mut BUFFER: PerCpuArray<EventBuf> = PerCpuArray::with_max_entries(1, 0);
+
+// Opens whose syscall has not returned yet, by pid_tgid. LRU so entries of
+// calls whose exit we never see cannot fill it up.
+#[map]
+static mut PENDING: LruHashMap<u64, EventBuf> = LruHashMap::with_max_entries(PENDING_MAX_ENTRIES, 0);
+
+#[map]
+static EVENTS: RingBuf = RingBuf::with_byte_size(RINGBUF_SIZE, 0);
+
+#[tracepoint]
+pub fn trace_point_openat(ctx: TracePointContext) -> u32 {
+    match try_trace_point_openat_enter(ctx, SYSCALL_OPENAT) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+#[tracepoint]
+pub fn trace_point_openat2(ctx: TracePointContext) -> u32 {
+    match try_trace_point_openat_enter(ctx, SYSCALL_OPENAT2) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+// Attached to both sys_exit_openat and sys_exit_openat2.
+#[tracepoint]
+pub fn trace_point_openat_exit(ctx: TracePointContext) -> u32 {
+    match try_trace_point_openat_exit(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+
+fn try_trace_point_openat_enter(ctx: TracePointContext, syscall: u16) -> Result<u32, i64> {
+    let buffer = unsafe {
+        let ptr = BUFFER.get_ptr_mut(0).ok_or(1i64)?;
+        &mut *ptr
+    };
+    let pid_tgid = bpf_get_current_pid_tgid();
+    unsafe {
+        let filename = ctx.read_at::<*const u8>(ENTER_FILENAME)?;
+        // A bad pointer makes the syscall fail with EFAULT; that is still
+        // worth reporting, with an empty path. The mask keeps the length
+        // provably in bounds for the verifier.
+        let path_len = match bpf_probe_read_user_str_bytes(filename, &mut buffer.path) {
+            Ok(path) => path.len() & (MAX_PATH_LEN - 1),
+            Err(_) => 0,
+        };
+        let event = &mut buffer.event;
+        let size = core::mem::size_of::<Event>() + path_len;
+        event.header = EventHeader::new(EVENT_KIND_OPEN, size as u32, bpf_ktime_get_ns(), bpf_get_smp_processor_id());
+        event.pid = (pid_tgid >> 32) as u32;
+        event.tid = pid_tgid as u32;
+        event.uid = bpf_get_current_uid_gid() as u32;
+        event.dirfd = ctx.read_at::<i64>(ENTER_DFD)? as i32;
+        if syscall == SYSCALL_OPENAT2 {
+            let how = ctx.read_at::<*const OpenHow>(ENTER_FLAGS)?;
+            (event.flags, event.mode, event.resolve) = match bpf_probe_read_user(how) {
+                Ok(how) => (how.flags, how.mode as u32, how.resolve),
+                Err(_) => (0, 0, 0),
+            };
+        } else {
+            event.flags = ctx.read_at::<u64>(ENTER_FLAGS)?;
+            event.mode = ctx.read_at::<u64>(ENTER_MODE)? as u32;
+            event.resolve = 0;
+        }
+        event.ret = 0;
+        event.syscall = syscall;
+        event.path_len = path_len as u16;
+        event.task_name = bpf_get_current_comm()?;
+
+        // Sent by `trace_point_openat_exit` once the result is known.
+        PENDING.insert(&pid_tgid, buffer, 0)?;
+    }
+    Ok(0)
+}
+
+fn try_trace_point_openat_exit(ctx: TracePointContext) -> Result<u32, i64> {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    unsafe {
+        let buffer = &mut *PENDING.get_ptr_mut(&pid_tgid).ok_or(1i64)?;
+        buffer.event.ret = ctx.read_at::<i64>(EXIT_RET)?;
+        let size = core::mem::size_of::<Event>() + (buffer.event.path_len as usize & (MAX_PATH_LEN - 1));
+        let record = core::slice::from_raw_parts(buffer as *const EventBuf as *const u8, size);
+        let sent = EVENTS.output(record, 0);
+        PENDING.remove(&pid_tgid)?;
+        sent?;
+    }
+    Ok(0)
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    unsafe { core::hint::unreachable_unchecked() }
+}
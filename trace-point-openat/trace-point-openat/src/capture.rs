@@ -0,0 +1,274 @@
+//! Binary capture files for offline analysis.
+//!
+//! A capture starts with a header that describes the layout of `Event`, so a
+//! replay can refuse files written by an incompatible build. The header is
+//! followed by the raw records exactly as they were read from `EVENTS`, each
+//! prefixed with its length. All integers are little-endian.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem::{offset_of, size_of};
+use std::path::Path;
+
+use anyhow::{bail, Context as _};
+use trace_point_openat_common::{Event, MAX_PATH_LEN};
+
+const MAGIC: [u8; 8] = *b"BPFTCAP\0";
+const FORMAT_VERSION: u16 = 1;
+
+/// Longest record a replay accepts: an `Event` with the longest path.
+const MAX_RECORD_SIZE: usize = size_of::<Event>() + MAX_PATH_LEN;
+
+macro_rules! field {
+    ($($name:ident).+: $ty:ty) => {
+        (stringify!($($name).+), offset_of!(Event, $($name).+), size_of::<$ty>())
+    };
+}
+
+/// Name, offset and size of every `Event` field, in declaration order. A size
+/// of 0 marks the variable-length tail that follows the fixed part.
+const LAYOUT: &[(&str, usize, usize)] = &[
+    field!(header.kind: u16),
+    field!(header.version: u16),
+    field!(header.size: u32),
+    field!(header.timestamp: u64),
+    field!(header.cpu: u32),
+    field!(pid: u32),
+    field!(tid: u32),
+    field!(uid: u32),
+    field!(dirfd: i32),
+    field!(flags: u64),
+    field!(resolve: u64),
+    field!(ret: i64),
+    field!(mode: u32),
+    field!(syscall: u16),
+    field!(path_len: u16),
+    field!(task_name: [u8; 16]),
+    ("path", size_of::<Event>(), 0),
+];
+
+#[derive(Debug, PartialEq, Eq)]
+struct Layout {
+    record_size: u32,
+    fields: Vec<(String, u32, u32)>,
+}
+
+impl Layout {
+    fn current() -> Self {
+        Layout {
+            record_size: size_of::<Event>() as u32,
+            fields: LAYOUT
+                .iter()
+                .map(|&(name, offset, size)| (name.to_owned(), offset as u32, size as u32))
+                .collect(),
+        }
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&MAGIC)?;
+        out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        out.write_all(&(self.fields.len() as u16).to_le_bytes())?;
+        out.write_all(&self.record_size.to_le_bytes())?;
+        for (name, offset, size) in &self.fields {
+            out.write_all(&[name.len() as u8])?;
+            out.write_all(name.as_bytes())?;
+            out.write_all(&offset.to_le_bytes())?;
+            out.write_all(&size.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn read_from(input: &mut impl Read) -> Result<Self, anyhow::Error> {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            bail!("not a capture file");
+        }
+        let version = read_u16(input)?;
+        if version != FORMAT_VERSION {
+            bail!("unsupported capture format version {} (expected {})", version, FORMAT_VERSION);
+        }
+        let field_count = read_u16(input)?;
+        let record_size = read_u32(input)?;
+        let mut fields = Vec::with_capacity(field_count as usize);
+        for _ in 0..field_count {
+            let mut name_len = [0u8; 1];
+            input.read_exact(&mut name_len)?;
+            let mut name = vec![0u8; name_len[0] as usize];
+            input.read_exact(&mut name)?;
+            let name = String::from_utf8(name).context("field name is not valid UTF-8")?;
+            fields.push((name, read_u32(input)?, read_u32(input)?));
+        }
+        Ok(Layout { record_size, fields })
+    }
+}
+
+fn read_u16(input: &mut impl Read) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    input.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub struct CaptureWriter<W = BufWriter<File>> {
+    out: W,
+}
+
+impl CaptureWriter {
+    pub fn create(path: &Path) -> Result<Self, anyhow::Error> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create capture file {}", path.display()))?;
+        Ok(CaptureWriter::new(BufWriter::new(file))?)
+    }
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Starts a capture in `out` with the header.
+    pub fn new(mut out: W) -> io::Result<Self> {
+        Layout::current().write_to(&mut out)?;
+        Ok(CaptureWriter { out })
+    }
+
+    pub fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        self.out.write_all(&(record.len() as u32).to_le_bytes())?;
+        self.out.write_all(record)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Iterates over the raw records of a capture file.
+pub struct CaptureReader<R = BufReader<File>> {
+    input: R,
+}
+
+impl CaptureReader {
+    pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open capture file {}", path.display()))?;
+        CaptureReader::new(BufReader::new(file)).with_context(|| format!("cannot replay {}", path.display()))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Reads the header from `input` and checks this build can read the
+    /// records that follow.
+    pub fn new(mut input: R) -> Result<Self, anyhow::Error> {
+        let layout = Layout::read_from(&mut input).context("invalid capture header")?;
+        if layout != Layout::current() {
+            bail!(
+                "recorded with a different event layout ({} byte records, this build uses {})",
+                layout.record_size,
+                size_of::<Event>()
+            );
+        }
+        Ok(CaptureReader { input })
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut len = [0u8; 4];
+        match self.input.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e)),
+        }
+        // Checked before allocating, so a corrupt length cannot ask for 4 GiB.
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_RECORD_SIZE {
+            return Some(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("record of {} bytes, larger than any event ({} bytes)", len, MAX_RECORD_SIZE),
+            )));
+        }
+        let mut record = vec![0u8; len];
+        Some(self.input.read_exact(&mut record).map(|_| record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{mem, slice};
+
+    use trace_point_openat_common::decode::EventRef;
+    use trace_point_openat_common::{EventHeader, EVENT_KIND_OPEN};
+
+    use super::*;
+
+    fn as_bytes<T>(value: &T) -> &[u8] {
+        unsafe { slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+    }
+
+    fn open(pid: u32, path: &[u8]) -> Vec<u8> {
+        let mut event: Event = unsafe { mem::zeroed() };
+        let size = size_of::<Event>() + path.len();
+        event.header = EventHeader::new(EVENT_KIND_OPEN, size as u32, 1, 0);
+        event.pid = pid;
+        event.path_len = path.len() as u16;
+        [as_bytes(&event), path].concat()
+    }
+
+    fn record(records: &[Vec<u8>]) -> Vec<u8> {
+        let mut capture = Vec::new();
+        let mut writer = CaptureWriter::new(&mut capture).unwrap();
+        for record in records {
+            writer.write_record(record).unwrap();
+        }
+        writer.flush().unwrap();
+        capture
+    }
+
+    fn header_len() -> usize {
+        record(&[]).len()
+    }
+
+    #[test]
+    fn replays_what_was_recorded() {
+        let records = [open(1, b"/etc/passwd"), open(2, b"/tmp/x")];
+        let capture = record(&records);
+        let replayed = CaptureReader::new(capture.as_slice()).unwrap().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(replayed, records);
+        let event = EventRef::parse(&replayed[1]).unwrap();
+        assert_eq!(event.pid(), 2);
+        assert_eq!(event.path(), b"/tmp/x");
+    }
+
+    #[test]
+    fn refuses_other_files_and_layouts() {
+        assert!(CaptureReader::new(&b"not a capture at all"[..]).is_err());
+        let mut capture = record(&[]);
+        // `record_size`, after the magic, the version and the field count.
+        capture[12] ^= 1;
+        assert!(CaptureReader::new(capture.as_slice()).is_err());
+    }
+
+    #[test]
+    fn refuses_records_longer_than_any_event() {
+        let mut capture = record(&[]);
+        capture.extend_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = CaptureReader::new(capture.as_slice()).unwrap();
+        let error = reader.next().unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn stops_at_a_truncated_record() {
+        let mut capture = record(&[open(1, b"/etc/passwd")]);
+        capture.truncate(capture.len() - 1);
+        let mut reader = CaptureReader::new(capture.as_slice()).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        // A capture cut off between records is only shorter.
+        let capture = record(&[open(1, b"/etc/passwd")]);
+        assert_eq!(CaptureReader::new(&capture[..header_len()]).unwrap().count(), 0);
+    }
+}
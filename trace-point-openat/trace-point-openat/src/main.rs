@@ -0,0 +1,313 @@
+mod capture;
+mod syscall;
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use aya::maps::RingBuf;
+use aya::programs::TracePoint;
+use aya::{include_bytes_aligned, Bpf};
+use aya_log::BpfLogger;
+use capture::{CaptureReader, CaptureWriter};
+use clap::{Parser, Subcommand};
+use log::{info, warn, debug};
+use serde_json::{json, Value};
+use syscall::Call;
+use tokio::io::unix::AsyncFd;
+use tracer_support::control::{self, ControlSocket, Emission, Request, RpcError};
+use tracer_support::daemon::{self, Daemon, Signal, Signals};
+use tracer_support::enrich::{Enricher, Worker};
+use tracer_support::features::{AttachPoint, Features, Need};
+use tracer_support::sandbox;
+use tracer_support::verifier;
+use trace_point_openat_common::decode::{DecodeStats, Escaped};
+use trace_point_openat_common::{Event, MAX_PATH_LEN, RINGBUF_SIZE};
+
+static DECODE_STATS: DecodeStats = DecodeStats::new();
+static EMISSION: Emission = Emission::new();
+const WORKER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PROGRAMS: [&str; 3] = ["trace_point_openat", "trace_point_openat2", "trace_point_openat_exit"];
+
+/// The tracepoints the programs attach to; one program serves both exits.
+/// openat2 came in Linux 5.6.
+const ATTACH_POINTS: [AttachPoint; 4] = [
+    AttachPoint { targets: &["syscalls/sys_enter_openat"], programs: &["trace_point_openat"], untraced: "" },
+    AttachPoint { targets: &["syscalls/sys_enter_openat2"], programs: &["trace_point_openat2"], untraced: "openat2 calls" },
+    AttachPoint { targets: &["syscalls/sys_exit_openat"], programs: &["trace_point_openat_exit"], untraced: "" },
+    AttachPoint { targets: &["syscalls/sys_exit_openat2"], programs: &["trace_point_openat_exit"], untraced: "openat2 results" },
+];
+
+/// The eBPF source, to name the function of a line the verifier quotes.
+const EBPF_SOURCES: verifier::Sources = &[("main.rs", include_str!("../../trace-point-openat-ebpf/src/main.rs"))];
+
+#[derive(Debug, Parser)]
+struct Opt {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// Print raw uids and pids instead of resolving them to names and executables
+    #[clap(long)]
+    no_enrich: bool,
+    /// How long resolved users, groups and processes are cached, in seconds
+    #[clap(long, default_value_t = 10)]
+    enrich_ttl: u64,
+    /// Serve the control API on this Unix socket
+    #[clap(long)]
+    control: Option<PathBuf>,
+    /// Run as a system service: log JSON lines, notify systemd and feed its
+    /// watchdog while the self-check passes
+    #[clap(long)]
+    daemon: bool,
+    /// Run the self-check this often, in seconds
+    #[clap(long, default_value_t = 30, requires = "daemon")]
+    check_interval: u64,
+    /// Fail the self-check once no event came in for this many seconds
+    #[clap(long, requires = "daemon")]
+    max_idle: Option<u64>,
+    /// Once attached, drop root and run as this user, under a seccomp filter
+    #[clap(long)]
+    user: Option<String>,
+    /// Write the verifier log here if a program fails to load
+    #[clap(long)]
+    verifier_log: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Trace opens and also write the raw events to a capture file
+    Record {
+        /// Path of the capture file to create
+        path: PathBuf,
+    },
+    /// Print the events of a capture file without loading any eBPF
+    Replay {
+        /// Path of the capture file to read
+        path: PathBuf,
+    },
+    /// Send a request to the control socket of a running trace-point-openat and print the result
+    Ctl {
+        /// Path of the control socket
+        socket: PathBuf,
+        /// Method to call: programs, stats, pause or resume
+        method: String,
+        /// Params as a JSON object
+        params: Option<String>,
+    },
+    /// Print what the kernel supports and where the programs would attach, without loading any eBPF
+    Features,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let opt = Opt::parse();
+
+    daemon::init_logger(opt.daemon);
+
+    match &opt.command {
+        Some(Command::Ctl { socket, method, params }) => return control::cli(socket, method, params.as_deref()),
+        Some(Command::Features) => {
+            for line in Features::probe().report(&ATTACH_POINTS) {
+                println!("{}", line);
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // A replay's pids belong to processes that are long gone, so it never enriches.
+    let mut capture = match &opt.command {
+        Some(Command::Replay { path }) => return replay(path),
+        Some(Command::Record { path }) => Some(CaptureWriter::create(path)?),
+        Some(Command::Features | Command::Ctl { .. }) | None => None,
+    };
+
+    // Bump the memlock rlimit. This is needed for older kernels that don't use the
+    // new memcg based accounting, see https://lwn.net/Articles/837122/
+    let rlim = libc::rlimit {
+        rlim_cur: libc::RLIM_INFINITY,
+        rlim_max: libc::RLIM_INFINITY,
+    };
+    let ret = unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlim) };
+    if ret != 0 {
+        debug!("remove limit on locked memory failed, ret is: {}", ret);
+    }
+
+    let features = Features::probe();
+    for line in features.report(&ATTACH_POINTS) {
+        debug!("{}", line);
+    }
+    features.check("trace-point-openat", &[Need::Tracepoints, Need::RingBuffers])?;
+
+    // This will include your eBPF object file as raw bytes at compile-time and load it at
+    // runtime. This approach is recommended for most real-world use cases. If you would
+    // like to specify the eBPF program at runtime rather than at compile-time, you can
+    // reach for `Bpf::load_file` instead.
+    #[cfg(debug_assertions)]
+    let mut bpf = Bpf::load(include_bytes_aligned!(
+        "../../target/bpfel-unknown-none/debug/trace-point-openat"
+    ))?;
+    #[cfg(not(debug_assertions))]
+    let mut bpf = Bpf::load(include_bytes_aligned!(
+        "../../target/bpfel-unknown-none/release/trace-point-openat"
+    ))?;
+    if let Err(e) = BpfLogger::init(&mut bpf) {
+        // This can happen if you remove all log statements from your eBPF program.
+        warn!("failed to initialize eBPF logger: {}", e);
+    }
+    for name in PROGRAMS {
+        let program: &mut TracePoint = bpf.program_mut(name).unwrap().try_into()?;
+        program.load().map_err(|e| verifier::explain(name, e, opt.verifier_log.as_deref(), EBPF_SOURCES))?;
+    }
+    // Program and tracepoint of every attachment, for the control API.
+    let mut attached = Vec::new();
+    for point in &ATTACH_POINTS {
+        let Some(tracepoint) = features.resolve(point)? else {
+            continue;
+        };
+        let (category, name) = tracepoint.split_once('/').unwrap();
+        for program_name in point.programs {
+            let program: &mut TracePoint = bpf.program_mut(program_name).unwrap().try_into()?;
+            program.attach(category, name).with_context(|| format!("failed to attach {} to {}", program_name, tracepoint))?;
+            attached.push((*program_name, tracepoint));
+        }
+    }
+    let mut daemon = opt.daemon.then(|| Daemon::new(Duration::from_secs(opt.check_interval), opt.max_idle.map(Duration::from_secs))).transpose()?;
+    if let Some(daemon) = &mut daemon {
+        daemon.watch(&daemon::program_ids(&bpf, attached.iter().map(|(program, _)| *program))?);
+    }
+
+    let ring_buf = RingBuf::try_from(bpf.map_mut("EVENTS").unwrap())?;
+    let mut ring_buf = AsyncFd::new(ring_buf)?;
+    // Looking processes up blocks, so enriched events are handled on a
+    // thread of their own rather than in this loop.
+    let worker = (!opt.no_enrich).then(|| {
+        let enricher = Enricher::new(Duration::from_secs(opt.enrich_ttl));
+        Worker::spawn(move |buf: Vec<u8>| handle_event(&buf, Some(&enricher)))
+    });
+    let mut control = opt.control.as_deref().map(ControlSocket::bind).transpose()?;
+    let mut signals = Signals::new()?;
+    if let Some(user) = &opt.user {
+        sandbox::enter(user, false)?;
+    }
+    if let Some(daemon) = &daemon {
+        daemon.ready();
+    }
+
+    info!("Waiting for Ctrl-C...");
+    loop {
+        tokio::select! {
+            guard = ring_buf.readable_mut() => {
+                let mut guard = guard?;
+                let ring_buf = guard.get_inner_mut();
+                while let Some(item) = ring_buf.next() {
+                    let buf = &*item;
+                    if EMISSION.suppress() {
+                        continue;
+                    }
+                    if let Some(capture) = &mut capture {
+                        if let Err(e) = capture.write_record(buf) {
+                            warn!("failed to write capture record: {}", e);
+                        }
+                    }
+                    match &worker {
+                        Some(worker) => worker.submit(buf.to_vec()),
+                        None => handle_event(buf, None),
+                    }
+                }
+                guard.clear_ready();
+            }
+            request = ControlSocket::next(&mut control) => {
+                let result = handle_request(&request, &attached);
+                request.reply(result);
+            }
+            _ = Daemon::tick(&mut daemon) => {
+                if let Some(daemon) = &mut daemon {
+                    daemon.check(DECODE_STATS.decoded() + DECODE_STATS.malformed() + EMISSION.suppressed());
+                }
+            }
+            signal = signals.next() => match signal {
+                Signal::Shutdown => break,
+                Signal::Reload => info!("nothing to reload"),
+            },
+        }
+    }
+    if let Some(daemon) = &daemon {
+        daemon.stopping();
+    }
+    info!("Exiting...");
+
+    if let Some(capture) = &mut capture {
+        capture.flush()?;
+    }
+    if let Some(worker) = &worker {
+        worker.flush(WORKER_SHUTDOWN_TIMEOUT);
+    }
+    report_decode_stats();
+
+    Ok(())
+}
+
+fn handle_request(request: &Request, attached: &[(&str, &str)]) -> Result<Value, anyhow::Error> {
+    match request.method.as_str() {
+        "programs" => Ok(attached.iter().map(|(program, tracepoint)| json!({ "program": program, "tracepoint": tracepoint })).collect()),
+        "stats" => Ok(json!({
+            "decoded": DECODE_STATS.decoded(),
+            "malformed": DECODE_STATS.malformed(),
+            "suppressed": EMISSION.suppressed(),
+            "paused": EMISSION.paused(),
+        })),
+        "pause" | "resume" => {
+            EMISSION.set_paused(request.method == "pause");
+            Ok(json!({ "paused": EMISSION.paused() }))
+        }
+        method => Err(RpcError::method_not_found(method).into()),
+    }
+}
+
+fn replay(path: &Path) -> Result<(), anyhow::Error> {
+    for record in CaptureReader::open(path)? {
+        handle_event(&record?, None);
+    }
+    report_decode_stats();
+    Ok(())
+}
+
+fn handle_event(buf: &[u8], enricher: Option<&Enricher>) {
+    match DECODE_STATS.decode(buf) {
+        Ok(event) => {
+            let task = format!("{}[{}/{}]", Escaped(event.task_name()), event.pid(), event.tid());
+            // The cwd in the details is what relative paths resolve against,
+            // unless the process changed it since.
+            match enricher.map(|enricher| enricher.lookup(event.uid(), event.pid())) {
+                Some(context) => println!("{}: {} {}, {}", context, task, Call(event), context.details()),
+                None => println!("{} uid {}: {}", task, event.uid(), Call(event)),
+            }
+        }
+        Err(e) => {
+            warn!("failed to parse event: {}", e);
+        }
+    }
+}
+
+fn report_decode_stats() {
+    let decoded = DECODE_STATS.decoded();
+    info!("decoded {} events, {} malformed", decoded, DECODE_STATS.malformed());
+    if let Some(average) = DECODE_STATS.bytes().checked_div(decoded) {
+        // What every record would cost with `path` as a fixed array.
+        let fixed = (core::mem::size_of::<Event>() + MAX_PATH_LEN) as u64;
+        info!(
+            "average record size {} bytes: EVENTS holds {} such records, against {} fixed {} byte records",
+            average,
+            RINGBUF_SIZE as u64 / ringbuf_slot(average),
+            RINGBUF_SIZE as u64 / ringbuf_slot(fixed),
+            fixed
+        );
+    }
+}
+
+/// Space a record takes in a BPF ring buffer: an 8 byte header, rounded up to
+/// a multiple of 8.
+fn ringbuf_slot(size: u64) -> u64 {
+    (size + 8).div_ceil(8) * 8
+}
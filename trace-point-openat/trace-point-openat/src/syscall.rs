@@ -0,0 +1,265 @@
+//! strace-style rendering of the arguments and results of an open.
+
+use std::fmt;
+
+use trace_point_openat_common::decode::{Escaped, EventRef};
+use trace_point_openat_common::SYSCALL_OPENAT2;
+
+/// `O_*` flags by name. `O_SYNC` and `O_TMPFILE` include the bits of
+/// `O_DSYNC` and `O_DIRECTORY`, so they come first and take those bits. Flags
+/// that are 0 on this architecture, like `O_LARGEFILE` on x86_64, are skipped.
+const OPEN_FLAGS: &[(libc::c_int, &str)] = &[
+    (libc::O_CREAT, "O_CREAT"),
+    (libc::O_EXCL, "O_EXCL"),
+    (libc::O_NOCTTY, "O_NOCTTY"),
+    (libc::O_TRUNC, "O_TRUNC"),
+    (libc::O_APPEND, "O_APPEND"),
+    (libc::O_NONBLOCK, "O_NONBLOCK"),
+    (libc::O_SYNC, "O_SYNC"),
+    (libc::O_DSYNC, "O_DSYNC"),
+    (libc::O_DIRECT, "O_DIRECT"),
+    (libc::O_LARGEFILE, "O_LARGEFILE"),
+    (libc::O_TMPFILE, "O_TMPFILE"),
+    (libc::O_DIRECTORY, "O_DIRECTORY"),
+    (libc::O_NOFOLLOW, "O_NOFOLLOW"),
+    (libc::O_NOATIME, "O_NOATIME"),
+    (libc::O_CLOEXEC, "O_CLOEXEC"),
+    (libc::O_PATH, "O_PATH"),
+];
+
+/// `RESOLVE_*` flags of openat2, from linux/openat2.h.
+const RESOLVE_FLAGS: &[(u64, &str)] = &[
+    (0x01, "RESOLVE_NO_XDEV"),
+    (0x02, "RESOLVE_NO_MAGICLINKS"),
+    (0x04, "RESOLVE_NO_SYMLINKS"),
+    (0x08, "RESOLVE_BENEATH"),
+    (0x10, "RESOLVE_IN_ROOT"),
+    (0x20, "RESOLVE_CACHED"),
+];
+
+/// Formats an open as strace would, e.g.
+/// `openat(AT_FDCWD, "/etc/passwd", O_RDONLY|O_CLOEXEC) = 3`.
+pub struct Call<'a>(pub EventRef<'a>);
+
+impl fmt::Display for Call<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let event = &self.0;
+        let openat2 = event.syscall() == SYSCALL_OPENAT2;
+        write!(f, "{}(", if openat2 { "openat2" } else { "openat" })?;
+        match event.dirfd() {
+            libc::AT_FDCWD => write!(f, "AT_FDCWD")?,
+            fd => write!(f, "{}", fd)?,
+        }
+        write!(f, ", \"{}\", ", Escaped(event.path()))?;
+        if openat2 {
+            write!(f, "{{flags={}", OpenFlags(event.flags()))?;
+            write!(f, ", mode={}", Mode(event.mode()))?;
+            write!(f, ", resolve={}}}", ResolveFlags(event.resolve()))?;
+        } else {
+            write!(f, "{}", OpenFlags(event.flags()))?;
+            // Like strace, only show the mode when the open can create a file.
+            let flags = event.flags() as libc::c_int;
+            if flags & libc::O_CREAT != 0 || flags & libc::O_TMPFILE == libc::O_TMPFILE {
+                write!(f, ", {}", Mode(event.mode()))?;
+            }
+        }
+        write!(f, ") = ")?;
+        match event.ret() {
+            ret if ret < 0 => write!(f, "-1 {}", Errno(-ret as i32)),
+            fd => write!(f, "{}", fd),
+        }
+    }
+}
+
+struct OpenFlags(u64);
+
+impl fmt::Display for OpenFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut flags = self.0 as libc::c_int;
+        let access = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => "O_RDONLY",
+            libc::O_WRONLY => "O_WRONLY",
+            libc::O_RDWR => "O_RDWR",
+            _ => "O_ACCMODE",
+        };
+        write!(f, "{}", access)?;
+        flags &= !libc::O_ACCMODE;
+        for &(flag, name) in OPEN_FLAGS {
+            if flag != 0 && flags & flag == flag {
+                write!(f, "|{}", name)?;
+                flags &= !flag;
+            }
+        }
+        if flags != 0 {
+            write!(f, "|{:#x}", flags)?;
+        }
+        Ok(())
+    }
+}
+
+/// Octal with a leading 0, like C.
+struct Mode(u32);
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            0 => write!(f, "0"),
+            mode => write!(f, "0{:o}", mode),
+        }
+    }
+}
+
+struct ResolveFlags(u64);
+
+impl fmt::Display for ResolveFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut flags = self.0;
+        if flags == 0 {
+            return write!(f, "0");
+        }
+        let mut sep = "";
+        for &(flag, name) in RESOLVE_FLAGS {
+            if flags & flag != 0 {
+                write!(f, "{}{}", sep, name)?;
+                flags &= !flag;
+                sep = "|";
+            }
+        }
+        if flags != 0 {
+            write!(f, "{}{:#x}", sep, flags)?;
+        }
+        Ok(())
+    }
+}
+
+/// `ENOENT (No such file or directory)`.
+struct Errno(i32);
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.0 {
+            libc::EPERM => "EPERM",
+            libc::ENOENT => "ENOENT",
+            libc::EINTR => "EINTR",
+            libc::ENXIO => "ENXIO",
+            libc::EBADF => "EBADF",
+            libc::EAGAIN => "EAGAIN",
+            libc::ENOMEM => "ENOMEM",
+            libc::EACCES => "EACCES",
+            libc::EFAULT => "EFAULT",
+            libc::EBUSY => "EBUSY",
+            libc::EEXIST => "EEXIST",
+            libc::EXDEV => "EXDEV",
+            libc::ENODEV => "ENODEV",
+            libc::ENOTDIR => "ENOTDIR",
+            libc::EISDIR => "EISDIR",
+            libc::EINVAL => "EINVAL",
+            libc::ENFILE => "ENFILE",
+            libc::EMFILE => "EMFILE",
+            libc::ETXTBSY => "ETXTBSY",
+            libc::EFBIG => "EFBIG",
+            libc::ENOSPC => "ENOSPC",
+            libc::EROFS => "EROFS",
+            libc::ENAMETOOLONG => "ENAMETOOLONG",
+            libc::ELOOP => "ELOOP",
+            libc::EOVERFLOW => "EOVERFLOW",
+            libc::EOPNOTSUPP => "EOPNOTSUPP",
+            libc::E2BIG => "E2BIG",
+            _ => return write!(f, "errno {}", self.0),
+        };
+        let description = std::io::Error::from_raw_os_error(self.0).to_string();
+        // Drop the " (os error N)" std appends.
+        let description = description.split(" (os error").next().unwrap_or_default();
+        write!(f, "{} ({})", name, description)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::{self, size_of};
+    use std::slice;
+
+    use trace_point_openat_common::{Event, EventHeader, EVENT_KIND_OPEN, SYSCALL_OPENAT};
+
+    use super::*;
+
+    fn as_bytes<T>(value: &T) -> &[u8] {
+        unsafe { slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+    }
+
+    fn open(syscall: u16, dirfd: i32, path: &[u8], flags: libc::c_int, mode: u32, resolve: u64, ret: i64) -> Vec<u8> {
+        let mut event: Event = unsafe { mem::zeroed() };
+        let size = size_of::<Event>() + path.len();
+        event.header = EventHeader::new(EVENT_KIND_OPEN, size as u32, 1, 0);
+        event.dirfd = dirfd;
+        event.flags = flags as u64;
+        event.mode = mode;
+        event.resolve = resolve;
+        event.syscall = syscall;
+        event.ret = ret;
+        event.path_len = path.len() as u16;
+        [as_bytes(&event), path].concat()
+    }
+
+    fn call(buf: &[u8]) -> String {
+        Call(EventRef::parse(buf).unwrap()).to_string()
+    }
+
+    #[test]
+    fn formats_openat() {
+        let buf = open(SYSCALL_OPENAT, libc::AT_FDCWD, b"/etc/passwd", libc::O_RDONLY | libc::O_CLOEXEC, 0, 0, 3);
+        assert_eq!(call(&buf), "openat(AT_FDCWD, \"/etc/passwd\", O_RDONLY|O_CLOEXEC) = 3");
+    }
+
+    #[test]
+    fn shows_the_mode_only_when_a_file_can_be_created() {
+        let buf = open(SYSCALL_OPENAT, 5, b"log", libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC, 0o644, 0, 4);
+        assert_eq!(call(&buf), "openat(5, \"log\", O_WRONLY|O_CREAT|O_TRUNC, 0644) = 4");
+        let buf = open(SYSCALL_OPENAT, 5, b"log", libc::O_WRONLY | libc::O_APPEND, 0o644, 0, 4);
+        assert_eq!(call(&buf), "openat(5, \"log\", O_WRONLY|O_APPEND) = 4");
+        let buf = open(SYSCALL_OPENAT, libc::AT_FDCWD, b"/tmp", libc::O_RDWR | libc::O_TMPFILE, 0o600, 0, 3);
+        assert_eq!(call(&buf), "openat(AT_FDCWD, \"/tmp\", O_RDWR|O_TMPFILE, 0600) = 3");
+    }
+
+    #[test]
+    fn names_composite_flags_once() {
+        let buf = open(SYSCALL_OPENAT, libc::AT_FDCWD, b"db", libc::O_RDWR | libc::O_SYNC, 0, 0, 3);
+        assert_eq!(call(&buf), "openat(AT_FDCWD, \"db\", O_RDWR|O_SYNC) = 3");
+        let buf = open(SYSCALL_OPENAT, libc::AT_FDCWD, b"db", libc::O_RDWR | libc::O_DSYNC, 0, 0, 3);
+        assert_eq!(call(&buf), "openat(AT_FDCWD, \"db\", O_RDWR|O_DSYNC) = 3");
+        let buf = open(SYSCALL_OPENAT, libc::AT_FDCWD, b"/", libc::O_RDONLY | libc::O_DIRECTORY, 0, 0, 3);
+        assert_eq!(call(&buf), "openat(AT_FDCWD, \"/\", O_RDONLY|O_DIRECTORY) = 3");
+    }
+
+    #[test]
+    fn shows_unknown_bits_in_hex() {
+        let buf = open(SYSCALL_OPENAT, libc::AT_FDCWD, b"x", libc::O_ACCMODE | 0x4000_0000, 0, 0, 3);
+        assert_eq!(call(&buf), "openat(AT_FDCWD, \"x\", O_ACCMODE|0x40000000) = 3");
+    }
+
+    #[test]
+    fn formats_openat2_how() {
+        let buf = open(SYSCALL_OPENAT2, libc::AT_FDCWD, b"lib", libc::O_RDONLY, 0, 0x08 | 0x10, 5);
+        assert_eq!(call(&buf), "openat2(AT_FDCWD, \"lib\", {flags=O_RDONLY, mode=0, resolve=RESOLVE_BENEATH|RESOLVE_IN_ROOT}) = 5");
+        let buf = open(SYSCALL_OPENAT2, 3, b"new", libc::O_WRONLY | libc::O_CREAT, 0o640, 0x40 | 0x01, 6);
+        assert_eq!(call(&buf), "openat2(3, \"new\", {flags=O_WRONLY|O_CREAT, mode=0640, resolve=RESOLVE_NO_XDEV|0x40}) = 6");
+        let buf = open(SYSCALL_OPENAT2, 3, b"new", libc::O_RDONLY, 0, 0, 6);
+        assert_eq!(call(&buf), "openat2(3, \"new\", {flags=O_RDONLY, mode=0, resolve=0}) = 6");
+    }
+
+    #[test]
+    fn formats_errors_like_strace() {
+        let buf = open(SYSCALL_OPENAT, libc::AT_FDCWD, b"/nope", libc::O_RDONLY, 0, 0, -libc::ENOENT as i64);
+        assert_eq!(call(&buf), "openat(AT_FDCWD, \"/nope\", O_RDONLY) = -1 ENOENT (No such file or directory)");
+        let buf = open(SYSCALL_OPENAT, libc::AT_FDCWD, b"/root/x", libc::O_RDONLY, 0, 0, -libc::EACCES as i64);
+        assert_eq!(call(&buf), "openat(AT_FDCWD, \"/root/x\", O_RDONLY) = -1 EACCES (Permission denied)");
+        let buf = open(SYSCALL_OPENAT, libc::AT_FDCWD, b"x", libc::O_RDONLY, 0, 0, -200);
+        assert_eq!(call(&buf), "openat(AT_FDCWD, \"x\", O_RDONLY) = -1 errno 200");
+    }
+
+    #[test]
+    fn escapes_the_path() {
+        let buf = open(SYSCALL_OPENAT, libc::AT_FDCWD, b"a\"\n\xff", libc::O_RDONLY, 0, 0, 3);
+        assert_eq!(call(&buf), "openat(AT_FDCWD, \"a\"\\n\\xff\", O_RDONLY) = 3");
+    }
+}
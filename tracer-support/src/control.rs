@@ -0,0 +1,386 @@
+//! The control socket: a JSON-RPC 2.0 API on a Unix domain socket, one
+//! request or response per line, and the `ctl` client for it.
+//!
+//! ```text
+//! -> {"jsonrpc": "2.0", "id": 1, "method": "pause"}
+//! <- {"jsonrpc": "2.0", "id": 1, "result": {"paused": true}}
+//! ```
+//!
+//! Connections are served on their own tasks, but every request is handed
+//! to the tracer's main loop, which owns the maps and answers them one at a
+//! time; the methods are the tracer's own. The socket is created with mode
+//! 0600, so only the user the tracer runs as, normally root, can connect.
+
+use std::fmt;
+use std::fs;
+use std::future;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use anyhow::{bail, Context as _};
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+/// An error with its own JSON-RPC code. Every other error a request fails
+/// with is reported as an internal error.
+#[derive(Debug)]
+pub struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    pub fn method_not_found(method: &str) -> Self {
+        RpcError {
+            code: METHOD_NOT_FOUND,
+            message: format!("unknown method '{}'", method),
+        }
+    }
+
+    pub fn invalid_params(message: impl fmt::Display) -> Self {
+        RpcError {
+            code: INVALID_PARAMS,
+            message: format!("invalid params: {}", message),
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// A request waiting for the main loop's answer.
+pub struct Request {
+    pub method: String,
+    params: Value,
+    reply: oneshot::Sender<Result<Value, anyhow::Error>>,
+}
+
+impl Request {
+    /// The params as a `T`. Requests without params read as `null`.
+    pub fn params<T: DeserializeOwned>(&self) -> Result<T, anyhow::Error> {
+        serde_json::from_value(self.params.clone()).map_err(|e| RpcError::invalid_params(e).into())
+    }
+
+    pub fn reply(self, result: Result<Value, anyhow::Error>) {
+        // The client may have gone away in the meantime.
+        let _ = self.reply.send(result);
+    }
+}
+
+/// Whether events are emitted, i.e. printed, exported and recorded. While
+/// paused, events are still read so the kernel side never backs up, but are
+/// only counted.
+pub struct Emission {
+    paused: AtomicBool,
+    suppressed: AtomicU64,
+}
+
+impl Emission {
+    pub const fn new() -> Self {
+        Emission {
+            paused: AtomicBool::new(false),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        if self.paused.swap(paused, Ordering::Relaxed) != paused {
+            info!("{} emission", if paused { "paused" } else { "resumed" });
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// True if an event should be dropped, in which case it is counted.
+    pub fn suppress(&self) -> bool {
+        let paused = self.paused();
+        if paused {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+        }
+        paused
+    }
+
+    /// Events dropped while paused.
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Emission {
+    fn default() -> Self {
+        Emission::new()
+    }
+}
+
+pub struct ControlSocket {
+    path: PathBuf,
+    requests: mpsc::Receiver<Request>,
+}
+
+impl ControlSocket {
+    pub fn bind(path: &Path) -> Result<Self, anyhow::Error> {
+        if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            if StdUnixStream::connect(path).is_ok() {
+                bail!("{} is in use by another tracer", path.display());
+            }
+            // Left behind by a tracer that did not exit cleanly.
+            fs::remove_file(path)?;
+        }
+        // No moment in which others may connect.
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(path);
+        unsafe { libc::umask(umask) };
+        let listener = listener.with_context(|| format!("failed to bind control socket {}", path.display()))?;
+        info!("Serving the control API on {}", path.display());
+        let (sender, requests) = mpsc::channel(16);
+        tokio::spawn(accept(listener, sender));
+        Ok(ControlSocket {
+            path: path.to_owned(),
+            requests,
+        })
+    }
+
+    /// The next request. Without a socket this never returns, so it can sit
+    /// in a `select!` either way.
+    pub async fn next(socket: &mut Option<ControlSocket>) -> Request {
+        match socket {
+            Some(socket) => match socket.requests.recv().await {
+                Some(request) => request,
+                None => future::pending().await,
+            },
+            None => future::pending().await,
+        }
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+async fn accept(listener: UnixListener, sender: mpsc::Sender<Request>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(serve(stream, sender.clone()));
+            }
+            Err(e) => {
+                warn!("failed to accept a control connection: {}", e);
+            }
+        }
+    }
+}
+
+async fn serve(stream: UnixStream, sender: mpsc::Sender<Request>) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = AsyncBufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let mut response = respond(&line, &sender).await.to_string();
+        response.push('\n');
+        if write.write_all(response.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn respond(line: &str, sender: &mpsc::Sender<Request>) -> Value {
+    let request: Value = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => return error(Value::Null, PARSE_ERROR, e),
+    };
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let Some(method) = request.get("method").and_then(Value::as_str) else {
+        return error(id, INVALID_REQUEST, "no method");
+    };
+    let (reply, result) = oneshot::channel();
+    let request = Request {
+        method: method.to_owned(),
+        params: request.get("params").cloned().unwrap_or(Value::Null),
+        reply,
+    };
+    if sender.send(request).await.is_err() {
+        return error(id, INTERNAL_ERROR, "the tracer is exiting");
+    }
+    match result.await {
+        Ok(Ok(result)) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Ok(Err(e)) => {
+            let code = e.downcast_ref::<RpcError>().map_or(INTERNAL_ERROR, |e| e.code);
+            error(id, code, format!("{:#}", e))
+        }
+        Err(_) => error(id, INTERNAL_ERROR, "the tracer is exiting"),
+    }
+}
+
+fn error(id: Value, code: i64, message: impl fmt::Display) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message.to_string() } })
+}
+
+/// Sends one request to the control socket at `path` and prints the result.
+pub fn cli(path: &Path, method: &str, params: Option<&str>) -> Result<(), anyhow::Error> {
+    let mut request = json!({ "jsonrpc": "2.0", "id": 1, "method": method });
+    if let Some(params) = params {
+        request["params"] = serde_json::from_str(params).context("params are not valid JSON")?;
+    }
+    let mut stream = StdUnixStream::connect(path).with_context(|| format!("failed to connect to {}", path.display()))?;
+    writeln!(stream, "{}", request)?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let response: Value = serde_json::from_str(&line).context("malformed response")?;
+    if let Some(error) = response.get("error") {
+        bail!("{}", error["message"].as_str().unwrap_or("request failed"));
+    }
+    println!("{}", serde_json::to_string_pretty(&response["result"])?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct EchoParams {
+        text: String,
+    }
+
+    /// A main loop with an `echo` and a `fail` method, answering until the
+    /// channel closes.
+    fn tracer() -> mpsc::Sender<Request> {
+        let (sender, mut requests) = mpsc::channel::<Request>(16);
+        tokio::spawn(async move {
+            while let Some(request) = requests.recv().await {
+                let result = match request.method.as_str() {
+                    "echo" => request.params().map(|EchoParams { text }| json!(text)),
+                    "fail" => Err(anyhow::anyhow!("no map").context("dump failed")),
+                    method => Err(RpcError::method_not_found(method).into()),
+                };
+                request.reply(result);
+            }
+        });
+        sender
+    }
+
+    async fn ask(sender: &mpsc::Sender<Request>, line: &str) -> Value {
+        respond(line, sender).await
+    }
+
+    #[tokio::test]
+    async fn answers_requests_with_their_id() {
+        let sender = tracer();
+        assert_eq!(
+            ask(&sender, r#"{"jsonrpc": "2.0", "id": 7, "method": "echo", "params": {"text": "hi"}}"#).await,
+            json!({ "jsonrpc": "2.0", "id": 7, "result": "hi" })
+        );
+        assert_eq!(
+            ask(&sender, r#"{"jsonrpc": "2.0", "id": "a", "method": "echo", "params": {"text": ""}}"#).await["id"],
+            "a"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_errors_with_their_codes() {
+        let sender = tracer();
+        let code = |response: Value| response["error"]["code"].as_i64().unwrap();
+        assert_eq!(code(ask(&sender, "{").await), PARSE_ERROR);
+        assert_eq!(ask(&sender, "{").await["id"], Value::Null);
+        assert_eq!(code(ask(&sender, r#"{"id": 1}"#).await), INVALID_REQUEST);
+        assert_eq!(code(ask(&sender, r#"{"id": 1, "method": 3}"#).await), INVALID_REQUEST);
+        assert_eq!(code(ask(&sender, r#"{"id": 1, "method": "reset"}"#).await), METHOD_NOT_FOUND);
+        // Params that are missing or of the wrong shape.
+        assert_eq!(code(ask(&sender, r#"{"id": 1, "method": "echo"}"#).await), INVALID_PARAMS);
+        assert_eq!(code(ask(&sender, r#"{"id": 1, "method": "echo", "params": {"text": 1}}"#).await), INVALID_PARAMS);
+
+        let response = ask(&sender, r#"{"id": 2, "method": "fail"}"#).await;
+        assert_eq!(
+            response,
+            json!({ "jsonrpc": "2.0", "id": 2, "error": { "code": INTERNAL_ERROR, "message": "dump failed: no map" } })
+        );
+        let response = ask(&sender, r#"{"id": 3, "method": "reset"}"#).await;
+        assert_eq!(response["error"]["message"], "unknown method 'reset'");
+    }
+
+    #[tokio::test]
+    async fn reports_a_tracer_that_is_exiting() {
+        let (sender, requests) = mpsc::channel(1);
+        drop(requests);
+        let response = ask(&sender, r#"{"id": 1, "method": "echo"}"#).await;
+        assert_eq!(response["error"]["message"], "the tracer is exiting");
+
+        // Taken, but dropped unanswered.
+        let (sender, mut requests) = mpsc::channel::<Request>(1);
+        tokio::spawn(async move { drop(requests.recv().await) });
+        let response = ask(&sender, r#"{"id": 1, "method": "echo"}"#).await;
+        assert_eq!(response["error"]["message"], "the tracer is exiting");
+    }
+
+    #[tokio::test]
+    async fn serves_a_socket_only_its_user_can_reach() {
+        let path = std::env::temp_dir().join(format!("control-{}.sock", std::process::id()));
+        let mut socket = Some(ControlSocket::bind(&path).unwrap());
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(ControlSocket::bind(&path).err().unwrap().to_string().contains("in use"));
+
+        let client = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || {
+                let mut stream = StdUnixStream::connect(&path).unwrap();
+                writeln!(stream, r#"{{"jsonrpc": "2.0", "id": 1, "method": "programs"}}"#).unwrap();
+                let mut line = String::new();
+                BufReader::new(&stream).read_line(&mut line).unwrap();
+                serde_json::from_str::<Value>(&line).unwrap()
+            }
+        });
+        let request = ControlSocket::next(&mut socket).await;
+        assert_eq!(request.method, "programs");
+        request.reply(Ok(json!(["array"])));
+        assert_eq!(client.await.unwrap(), json!({ "jsonrpc": "2.0", "id": 1, "result": ["array"] }));
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn replaces_a_socket_left_behind() {
+        let path = std::env::temp_dir().join(format!("control-stale-{}.sock", std::process::id()));
+        // Bound and never accepted on, as after a crash.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let socket = ControlSocket::bind(&path).unwrap();
+        assert!(StdUnixStream::connect(&path).is_ok());
+        drop(socket);
+    }
+
+    #[test]
+    fn counts_what_is_suppressed_while_paused() {
+        let emission = Emission::new();
+        assert!(!emission.suppress());
+        emission.set_paused(true);
+        assert!(emission.suppress() && emission.suppress());
+        emission.set_paused(false);
+        assert!(!emission.suppress());
+        assert_eq!(emission.suppressed(), 2);
+    }
+}
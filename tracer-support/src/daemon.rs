@@ -0,0 +1,538 @@
+//! Running as a system service: signals, JSON logs, `sd_notify` and a
+//! self-check that feeds the systemd watchdog.
+//!
+//! ```text
+//! [Service]
+//! Type=notify
+//! ExecStart=/usr/local/bin/perf --daemon --max-idle 300
+//! ExecReload=/bin/kill -HUP $MAINPID
+//! WatchdogSec=60
+//! ```
+//!
+//! The self-check counts the links that attach each program and compares
+//! that with how many there were at startup, so a program someone detached,
+//! e.g. with `bpftool link detach`, fails it. With a `max_idle` it also
+//! fails once no event came in for that long. The watchdog is only fed while
+//! the check passes, so systemd restarts a tracer that stopped tracing.
+
+use std::collections::HashMap;
+use std::env;
+use std::future;
+use std::io::{self, Write as _};
+use std::os::linux::net::SocketAddrExt as _;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context as _;
+use aya::programs::loaded_links;
+use aya::Bpf;
+use log::{error, info, warn, LevelFilter, Log, Metadata, Record};
+use serde_json::{json, Value};
+use tokio::signal::unix::{signal, Signal as UnixSignal, SignalKind};
+use tokio::time::{self, Interval, MissedTickBehavior};
+
+/// What a signal asks the tracer to do.
+pub enum Signal {
+    /// SIGINT or SIGTERM.
+    Shutdown,
+    /// SIGHUP.
+    Reload,
+}
+
+pub struct Signals {
+    interrupt: UnixSignal,
+    terminate: UnixSignal,
+    hangup: UnixSignal,
+}
+
+impl Signals {
+    pub fn new() -> Result<Self, anyhow::Error> {
+        Ok(Signals {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    pub async fn next(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.interrupt.recv() => Signal::Shutdown,
+            _ = self.terminate.recv() => Signal::Shutdown,
+            _ = self.hangup.recv() => Signal::Reload,
+        }
+    }
+}
+
+/// Logs through `env_logger` or, with `json`, as one JSON object per line on
+/// stderr. Either way `RUST_LOG` picks what is logged; without it the JSON
+/// logger logs `info` and up.
+pub fn init_logger(json: bool) {
+    if !json {
+        env_logger::init();
+        return;
+    }
+    let mut builder = env_logger::filter::Builder::new();
+    match env::var("RUST_LOG") {
+        Ok(filters) => builder.parse(&filters),
+        Err(_) => builder.filter_level(LevelFilter::Info),
+    };
+    let filter = builder.build();
+    log::set_max_level(filter.filter());
+    // Only fails if a logger is set already.
+    let _ = log::set_boxed_logger(Box::new(JsonLogger { filter }));
+}
+
+struct JsonLogger {
+    filter: env_logger::filter::Filter,
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let _ = writeln!(io::stderr().lock(), "{}", json_line(record, SystemTime::now()));
+    }
+
+    fn flush(&self) {}
+}
+
+fn json_line(record: &Record, now: SystemTime) -> Value {
+    json!({
+        "timestamp": humantime::format_rfc3339_micros(now).to_string(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    })
+}
+
+/// The socket systemd passes in `NOTIFY_SOCKET` for `Type=notify` services.
+struct Notify {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+}
+
+impl Notify {
+    fn from_env() -> Result<Option<Self>, anyhow::Error> {
+        match env::var_os("NOTIFY_SOCKET") {
+            Some(path) => Notify::new(&path.to_string_lossy()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn new(path: &str) -> Result<Self, anyhow::Error> {
+        // `@` stands for the abstract namespace.
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+            None => SocketAddr::from_pathname(path),
+        }
+        .with_context(|| format!("bad NOTIFY_SOCKET {}", path))?;
+        let socket = UnixDatagram::unbound()?;
+        Ok(Notify { socket, addr })
+    }
+
+    fn send(&self, state: &str) {
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            warn!("failed to notify systemd: {}", e);
+        }
+    }
+}
+
+/// The watchdog timeout systemd asks for with `WatchdogSec=`, if it is meant
+/// for this process.
+fn watchdog_timeout() -> Option<Duration> {
+    watchdog_for(&env::var("WATCHDOG_USEC").ok()?, env::var("WATCHDOG_PID").ok().as_deref(), std::process::id())
+}
+
+fn watchdog_for(usec: &str, pid: Option<&str>, this: u32) -> Option<Duration> {
+    let usec = usec.parse().ok()?;
+    match pid {
+        Some(pid) if pid.parse() != Ok(this) => None,
+        _ => Some(Duration::from_micros(usec)),
+    }
+}
+
+/// The state `sd_notify` sends before a reload that started at `monotonic`,
+/// on `CLOCK_MONOTONIC`.
+fn reloading_state(monotonic: Duration) -> String {
+    format!("RELOADING=1\nMONOTONIC_USEC={}", monotonic.as_micros())
+}
+
+fn monotonic_now() -> Duration {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    Duration::new(now.tv_sec as u64, now.tv_nsec as u32)
+}
+
+/// How many links attach each program, by program id, or `None` if this
+/// process may no longer list them, as after `--user`. Kernels before 5.8
+/// cannot list links; then none are found.
+fn links() -> Option<HashMap<u32, usize>> {
+    let mut links = HashMap::new();
+    for link in loaded_links() {
+        match link {
+            Ok(link) => *links.entry(link.prog_id).or_default() += 1,
+            Err(e) if is_permission_denied(&e) => return None,
+            // A link may go away while they are listed.
+            Err(_) => {}
+        }
+    }
+    Some(links)
+}
+
+fn is_permission_denied(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<io::Error>() {
+            return error.kind() == io::ErrorKind::PermissionDenied;
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// The ids of the programs called `names`, once each, for [`Daemon::watch`].
+pub fn program_ids<'a>(bpf: &Bpf, names: impl IntoIterator<Item = &'a str>) -> Result<Vec<(&'a str, u32)>, anyhow::Error> {
+    let mut ids = Vec::new();
+    for name in names {
+        if !ids.iter().any(|(seen, _)| *seen == name) {
+            let program = bpf.program(name).with_context(|| format!("the object has no program {}", name))?;
+            ids.push((name, program.info()?.id()));
+        }
+    }
+    Ok(ids)
+}
+
+/// A program and the links it had when it was attached.
+struct Watched {
+    name: String,
+    id: u32,
+    links: usize,
+}
+
+pub struct Daemon {
+    notify: Option<Notify>,
+    watchdog: bool,
+    interval: Interval,
+    programs: Vec<Watched>,
+    max_idle: Option<Duration>,
+    events: u64,
+    last_event: Instant,
+    healthy: bool,
+    /// Set once the links it watched can no longer be listed.
+    blind: bool,
+}
+
+impl Daemon {
+    /// Checks every `check_interval`, or twice per watchdog timeout if that
+    /// is shorter, and fails the check after `max_idle` without events.
+    pub fn new(check_interval: Duration, max_idle: Option<Duration>) -> Result<Self, anyhow::Error> {
+        let notify = Notify::from_env()?;
+        let watchdog = notify.as_ref().and(watchdog_timeout());
+        Ok(Daemon::with(notify, watchdog, check_interval, max_idle))
+    }
+
+    fn with(notify: Option<Notify>, watchdog: Option<Duration>, check_interval: Duration, max_idle: Option<Duration>) -> Self {
+        let period = watchdog.map_or(check_interval, |timeout| check_interval.min(timeout / 2));
+        let mut interval = time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        if let Some(timeout) = watchdog {
+            info!("feeding the watchdog every {:?} of its {:?}", period, timeout);
+        }
+        Daemon {
+            watchdog: watchdog.is_some(),
+            notify,
+            interval,
+            programs: Vec::new(),
+            max_idle,
+            events: 0,
+            last_event: Instant::now(),
+            healthy: true,
+            blind: false,
+        }
+    }
+
+    /// Has the check count the links of `programs`, by name and id, from now
+    /// on. Programs the kernel attached without a link, as it does for
+    /// probes before 5.15, cannot be checked.
+    pub fn watch(&mut self, programs: &[(&str, u32)]) {
+        self.watch_links(programs, links());
+    }
+
+    fn watch_links(&mut self, programs: &[(&str, u32)], links: Option<HashMap<u32, usize>>) {
+        self.programs.clear();
+        let Some(links) = links else {
+            warn!("links cannot be listed; the self-check cannot see programs detached");
+            self.blind = true;
+            return;
+        };
+        for (name, id) in programs {
+            match links.get(id) {
+                Some(&count) => self.programs.push(Watched {
+                    name: name.to_string(),
+                    id: *id,
+                    links: count,
+                }),
+                None => info!("{} is attached without a link; the self-check cannot see it detached", name),
+            }
+        }
+    }
+
+    pub fn ready(&self) {
+        self.notify(&format!("READY=1\nSTATUS={}", self.status()));
+    }
+
+    /// Before a reload; [`Daemon::ready`] says when it is done.
+    pub fn reloading(&self) {
+        self.notify(&reloading_state(monotonic_now()));
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    /// Waits for the next check. Without a daemon this never returns, so it
+    /// can sit in a `select!` either way.
+    pub async fn tick(daemon: &mut Option<Daemon>) {
+        match daemon {
+            Some(daemon) => {
+                daemon.interval.tick().await;
+            }
+            None => future::pending().await,
+        }
+    }
+
+    /// Runs the check with `events` seen so far, and feeds the watchdog if
+    /// it passes. Without a `max_idle` the events are not looked at.
+    pub fn check(&mut self, events: u64) {
+        self.check_links(links(), events);
+    }
+
+    fn check_links(&mut self, links: Option<HashMap<u32, usize>>, events: u64) {
+        let problems = self.problems(links, events);
+        if problems.is_empty() {
+            if !self.healthy {
+                info!("self-check passed again");
+                self.notify(&format!("STATUS={}", self.status()));
+            }
+            if self.watchdog {
+                self.notify("WATCHDOG=1");
+            }
+        } else {
+            let problems = problems.join(", ");
+            error!("self-check failed: {}", problems);
+            self.notify(&format!("STATUS=unhealthy: {}", problems));
+        }
+        self.healthy = problems.is_empty();
+    }
+
+    fn problems(&mut self, links: Option<HashMap<u32, usize>>, events: u64) -> Vec<String> {
+        let mut problems = Vec::new();
+        match links {
+            Some(links) => {
+                for program in &self.programs {
+                    let count = links.get(&program.id).copied().unwrap_or(0);
+                    if count < program.links {
+                        problems.push(format!("{} has {} of its {} links", program.name, count, program.links));
+                    }
+                }
+            }
+            None if !self.programs.is_empty() => {
+                // Listing them needs CAP_SYS_ADMIN, which --user drops.
+                warn!("links can no longer be listed, e.g. after --user; the self-check cannot see programs detached any more");
+                self.programs.clear();
+                self.blind = true;
+                self.notify(&format!("STATUS={}", self.status()));
+            }
+            None => {}
+        }
+        if events != self.events {
+            self.events = events;
+            self.last_event = Instant::now();
+        } else if let Some(max_idle) = self.max_idle {
+            let idle = self.last_event.elapsed();
+            if idle >= max_idle {
+                problems.push(format!("no events for {} seconds", idle.as_secs()));
+            }
+        }
+        problems
+    }
+
+    /// The status while the check passes.
+    fn status(&self) -> &'static str {
+        if self.blind {
+            "tracing, but the self-check cannot count links"
+        } else {
+            "tracing"
+        }
+    }
+
+    fn notify(&self, state: &str) {
+        if let Some(notify) = &self.notify {
+            notify.send(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    /// Stands in for systemd's notify socket.
+    struct FakeSystemd {
+        socket: UnixDatagram,
+        path: PathBuf,
+    }
+
+    impl FakeSystemd {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("daemon-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let socket = UnixDatagram::bind(&path).unwrap();
+            socket.set_nonblocking(true).unwrap();
+            FakeSystemd { socket, path }
+        }
+
+        fn notify(&self) -> Notify {
+            Notify::new(self.path.to_str().unwrap()).unwrap()
+        }
+
+        /// What was sent since the last call.
+        fn received(&self) -> Vec<String> {
+            let mut states = Vec::new();
+            let mut buf = [0; 512];
+            while let Ok(len) = self.socket.recv(&mut buf) {
+                states.push(String::from_utf8_lossy(&buf[..len]).into_owned());
+            }
+            states
+        }
+    }
+
+    impl Drop for FakeSystemd {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn links(counts: &[(u32, usize)]) -> Option<HashMap<u32, usize>> {
+        Some(counts.iter().copied().collect())
+    }
+
+    #[test]
+    fn formats_the_states() {
+        assert_eq!(reloading_state(Duration::new(12, 345_678_999)), "RELOADING=1\nMONOTONIC_USEC=12345678");
+        let now = monotonic_now();
+        assert!(now > Duration::ZERO);
+        assert!(monotonic_now() >= now);
+    }
+
+    #[test]
+    fn reads_the_watchdog_meant_for_this_process() {
+        assert_eq!(watchdog_for("60000000", None, 42), Some(Duration::from_secs(60)));
+        assert_eq!(watchdog_for("60000000", Some("42"), 42), Some(Duration::from_secs(60)));
+        assert_eq!(watchdog_for("60000000", Some("43"), 42), None);
+        assert_eq!(watchdog_for("sixty", None, 42), None);
+    }
+
+    #[test]
+    fn parses_the_notify_socket() {
+        assert!(Notify::new("/run/systemd/notify").unwrap().addr.as_pathname().is_some());
+        let abstract_name = Notify::new("@/org/freedesktop/systemd1/notify").unwrap();
+        assert_eq!(abstract_name.addr.as_abstract_name(), Some(&b"/org/freedesktop/systemd1/notify"[..]));
+        let too_long = format!("/{}", "x".repeat(200));
+        let e = Notify::new(&too_long).err().unwrap();
+        assert!(e.to_string().starts_with("bad NOTIFY_SOCKET /xxx"), "{}", e);
+    }
+
+    #[test]
+    fn logs_one_json_object_per_line() {
+        let line = json_line(
+            &Record::builder().level(log::Level::Warn).target("perf").args(format_args!("attached {} programs", 2)).build(),
+            SystemTime::UNIX_EPOCH + Duration::from_micros(1_500_000),
+        );
+        assert_eq!(
+            line,
+            json!({
+                "timestamp": "1970-01-01T00:00:01.500000Z",
+                "level": "WARN",
+                "target": "perf",
+                "message": "attached 2 programs",
+            })
+        );
+        assert!(!line.to_string().contains('\n'));
+    }
+
+    #[tokio::test]
+    async fn sends_the_lifecycle_states() {
+        let systemd = FakeSystemd::new("lifecycle");
+        let daemon = Daemon::with(Some(systemd.notify()), None, Duration::from_secs(30), None);
+        daemon.ready();
+        daemon.reloading();
+        daemon.stopping();
+        let states = systemd.received();
+        assert_eq!(states.len(), 3);
+        assert_eq!(states[0], "READY=1\nSTATUS=tracing");
+        assert!(states[1].starts_with("RELOADING=1\nMONOTONIC_USEC="), "{}", states[1]);
+        assert_eq!(states[2], "STOPPING=1");
+    }
+
+    #[tokio::test]
+    async fn feeds_the_watchdog_only_while_the_links_are_there() {
+        let systemd = FakeSystemd::new("links");
+        let mut daemon = Daemon::with(Some(systemd.notify()), Some(Duration::from_secs(60)), Duration::from_secs(30), None);
+        // One program with two links, one attached without a link.
+        daemon.watch_links(&[("openat", 7), ("execve", 8)], links(&[(7, 2)]));
+        assert_eq!(daemon.programs.len(), 1);
+
+        daemon.check_links(links(&[(7, 2)]), 0);
+        assert_eq!(systemd.received(), ["WATCHDOG=1"]);
+
+        daemon.check_links(links(&[(7, 1)]), 0);
+        assert_eq!(systemd.received(), ["STATUS=unhealthy: openat has 1 of its 2 links"]);
+        daemon.check_links(links(&[]), 0);
+        assert_eq!(systemd.received(), ["STATUS=unhealthy: openat has 0 of its 2 links"]);
+
+        daemon.check_links(links(&[(7, 2)]), 0);
+        assert_eq!(systemd.received(), ["STATUS=tracing", "WATCHDOG=1"]);
+    }
+
+    #[tokio::test]
+    async fn skips_the_links_once_they_cannot_be_listed() {
+        let systemd = FakeSystemd::new("unlisted");
+        let mut daemon = Daemon::with(Some(systemd.notify()), Some(Duration::from_secs(60)), Duration::from_secs(30), None);
+        daemon.watch_links(&[("openat", 7)], links(&[(7, 1)]));
+        daemon.check_links(None, 0);
+        assert!(daemon.programs.is_empty());
+        assert_eq!(systemd.received(), ["STATUS=tracing, but the self-check cannot count links", "WATCHDOG=1"]);
+        daemon.ready();
+        assert_eq!(systemd.received(), ["READY=1\nSTATUS=tracing, but the self-check cannot count links"]);
+
+        let mut daemon = Daemon::with(None, None, Duration::from_secs(30), None);
+        daemon.watch_links(&[("openat", 7)], None);
+        assert!(daemon.programs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fails_after_max_idle_without_events() {
+        let systemd = FakeSystemd::new("idle");
+        let mut daemon = Daemon::with(Some(systemd.notify()), None, Duration::from_secs(30), Some(Duration::ZERO));
+        daemon.check_links(links(&[]), 1);
+        assert_eq!(systemd.received(), Vec::<String>::new());
+        daemon.check_links(links(&[]), 1);
+        assert_eq!(systemd.received(), ["STATUS=unhealthy: no events for 0 seconds"]);
+        daemon.check_links(links(&[]), 2);
+        assert_eq!(systemd.received(), ["STATUS=tracing"]);
+
+        // Without a max_idle the events are not looked at.
+        let mut daemon = Daemon::with(Some(systemd.notify()), Some(Duration::from_secs(60)), Duration::from_secs(30), None);
+        daemon.check_links(links(&[]), 0);
+        daemon.check_links(links(&[]), 0);
+        assert_eq!(systemd.received(), ["WATCHDOG=1", "WATCHDOG=1"]);
+    }
+}
@@ -0,0 +1,376 @@
+//! What decoding the records of every tracer shares: the header they start
+//! with, the checks on it and on their size, the errors, the counts of
+//! decoded and malformed records and how kernel strings are shown. Each
+//! `-common` crate keeps the views of its own records and builds them on
+//! this.
+//!
+//! Only `core` is used, so the `-common` crates stay `no_std`.
+
+use core::fmt;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Size of the `EventHeader` every record starts with: `kind: u16`,
+/// `version: u16`, `size: u32`, `timestamp: u64`, `cpu: u32` and padding.
+/// Each `-common` crate asserts its own matches.
+pub const HEADER_LEN: usize = 24;
+pub const KIND_OFFSET: usize = 0;
+pub const VERSION_OFFSET: usize = 2;
+pub const SIZE_OFFSET: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The record is shorter than its header says, or than its fixed part.
+    Truncated { len: usize, expected: usize },
+    /// The record was produced against another `SCHEMA_VERSION`.
+    UnsupportedVersion { version: u16, expected: u16 },
+    /// The record is not a kind this decoder handles.
+    UnknownKind(u16),
+    /// The header's size does not match the fixed part plus the names.
+    SizeMismatch { size: u32, expected: usize },
+    /// A name is longer than the kernel side ever sends.
+    NameTooLong { len: usize, max: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated { len, expected } => {
+                write!(f, "truncated record: {} bytes, expected at least {}", len, expected)
+            }
+            DecodeError::UnsupportedVersion { version, expected } => {
+                write!(f, "schema version {} is not supported (expected {})", version, expected)
+            }
+            DecodeError::UnknownKind(kind) => write!(f, "unknown event kind {}", kind),
+            DecodeError::SizeMismatch { size, expected } => {
+                write!(f, "record size {} does not match event size {}", size, expected)
+            }
+            DecodeError::NameTooLong { len, max } => {
+                write!(f, "name length {} exceeds the maximum of {}", len, max)
+            }
+        }
+    }
+}
+
+/// Checks the header every record starts with against `version`, the
+/// `SCHEMA_VERSION` of this build, and returns the record's kind.
+pub fn check_header(buf: &[u8], version: u16) -> Result<u16, DecodeError> {
+    if buf.len() < HEADER_LEN {
+        return Err(DecodeError::Truncated {
+            len: buf.len(),
+            expected: HEADER_LEN,
+        });
+    }
+    let found = u16::from_ne_bytes(bytes(buf, VERSION_OFFSET));
+    if found != version {
+        return Err(DecodeError::UnsupportedVersion { version: found, expected: version });
+    }
+    Ok(u16::from_ne_bytes(bytes(buf, KIND_OFFSET)))
+}
+
+/// Checks that `buf` holds the `fixed` part of a record and the names that
+/// follow it, none longer than `max_name`, and that the size in the header
+/// covers exactly those. The name lengths are only read once the fixed part
+/// is known to be there. A record without names passes `|| []`; what
+/// follows it in `buf`, like the padding of a perf sample, is not checked.
+pub fn check_size<const N: usize>(
+    buf: &[u8],
+    fixed: usize,
+    max_name: usize,
+    name_lens: impl FnOnce() -> [usize; N],
+) -> Result<(), DecodeError> {
+    if buf.len() < fixed {
+        return Err(DecodeError::Truncated {
+            len: buf.len(),
+            expected: fixed,
+        });
+    }
+    let name_lens = name_lens();
+    if let Some(&len) = name_lens.iter().find(|&&len| len > max_name) {
+        return Err(DecodeError::NameTooLong { len, max: max_name });
+    }
+    let expected = fixed + name_lens.iter().sum::<usize>();
+    let size = u32::from_ne_bytes(bytes(buf, SIZE_OFFSET));
+    if size as usize != expected {
+        return Err(DecodeError::SizeMismatch { size, expected });
+    }
+    if buf.len() < expected {
+        return Err(DecodeError::Truncated {
+            len: buf.len(),
+            expected,
+        });
+    }
+    Ok(())
+}
+
+/// The `N` bytes at `offset`, for `from_ne_bytes`; `buf` need not be aligned.
+pub fn bytes<const N: usize>(buf: &[u8], offset: usize) -> [u8; N] {
+    let mut out = [0u8; N];
+    out.copy_from_slice(&buf[offset..offset + N]);
+    out
+}
+
+/// The string in the `len` byte field at `offset`, up to its first NUL.
+pub fn c_str(buf: &[u8], offset: usize, len: usize) -> &[u8] {
+    let field = &buf[offset..offset + len];
+    match field.iter().position(|&b| b == 0) {
+        Some(end) => &field[..end],
+        None => field,
+    }
+}
+
+/// Displays a kernel string, escaping invalid UTF-8 as `\xNN` and control
+/// characters the way `str::escape_default` does.
+pub struct Escaped<'a>(pub &'a [u8]);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for chunk in self.0.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                if c.is_control() {
+                    write!(f, "{}", c.escape_default())?;
+                } else {
+                    write!(f, "{}", c)?;
+                }
+            }
+            for b in chunk.invalid() {
+                write!(f, "\\x{:02x}", b)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How the records of one tracer are decoded, for `DecodeStats`.
+pub trait Decode {
+    type Record<'a>;
+
+    fn parse(buf: &[u8]) -> Result<Self::Record<'_>, DecodeError>;
+
+    /// The size the header of `record` gives.
+    fn size(record: &Self::Record<'_>) -> u32;
+}
+
+/// Counts decoded and rejected records so malformed input shows up in the
+/// totals instead of taking down the reader.
+pub struct DecodeStats<D> {
+    decoded: AtomicU64,
+    malformed: AtomicU64,
+    bytes: AtomicU64,
+    decoder: PhantomData<fn() -> D>,
+}
+
+impl<D: Decode> DecodeStats<D> {
+    pub const fn new() -> Self {
+        DecodeStats {
+            decoded: AtomicU64::new(0),
+            malformed: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            decoder: PhantomData,
+        }
+    }
+
+    /// Decodes `buf`, counting the outcome.
+    pub fn decode<'a>(&self, buf: &'a [u8]) -> Result<D::Record<'a>, DecodeError> {
+        let ret = D::parse(buf);
+        match &ret {
+            Ok(record) => {
+                self.decoded.fetch_add(1, Ordering::Relaxed);
+                self.bytes.fetch_add(D::size(record) as u64, Ordering::Relaxed);
+            }
+            Err(_) => {
+                self.malformed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        ret
+    }
+
+    pub fn decoded(&self) -> u64 {
+        self.decoded.load(Ordering::Relaxed)
+    }
+
+    pub fn malformed(&self) -> u64 {
+        self.malformed.load(Ordering::Relaxed)
+    }
+
+    /// Total size of the decoded records.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+impl<D: Decode> Default for DecodeStats<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D> fmt::Debug for DecodeStats<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DecodeStats")
+            .field("decoded", &self.decoded)
+            .field("malformed", &self.malformed)
+            .field("bytes", &self.bytes)
+            .finish()
+    }
+}
+
+/// For the tests of the `-common` crates: building records and feeding
+/// their decoders broken ones.
+#[cfg(feature = "testing")]
+pub mod testing {
+    use core::{mem, slice};
+    use std::vec::Vec;
+
+    use super::{Decode, DecodeError, SIZE_OFFSET};
+
+    /// The bytes of a record as the kernel side writes it.
+    pub fn as_bytes<T>(value: &T) -> &[u8] {
+        unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+    }
+
+    pub fn set_u16(buf: &mut [u8], offset: usize, value: u16) {
+        buf[offset..offset + 2].copy_from_slice(&value.to_ne_bytes());
+    }
+
+    pub fn set_u32(buf: &mut [u8], offset: usize, value: u32) {
+        buf[offset..offset + 4].copy_from_slice(&value.to_ne_bytes());
+    }
+
+    /// Sets a name length at `offset` and the header size to match, as
+    /// `fixed` plus that one name.
+    pub fn set_name_len(buf: &mut [u8], offset: usize, fixed: usize, len: u16) {
+        set_u16(buf, offset, len);
+        set_u32(buf, SIZE_OFFSET, (fixed + len as usize) as u32);
+    }
+
+    /// xorshift64, so every run checks the same inputs.
+    pub struct Rng(pub u64);
+
+    impl Rng {
+        pub fn next_u64(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        pub fn below(&mut self, n: usize) -> usize {
+            (self.next_u64() % n as u64) as usize
+        }
+    }
+
+    /// Asserts that `parse` finds every prefix of the record in `buf`
+    /// truncated.
+    pub fn assert_refuses_prefixes<'a, T>(buf: &'a [u8], parse: impl Fn(&'a [u8]) -> Result<T, DecodeError>) {
+        for len in 0..buf.len() {
+            assert!(
+                matches!(parse(&buf[..len]), Err(DecodeError::Truncated { .. })),
+                "{} of {} bytes",
+                len,
+                buf.len()
+            );
+        }
+    }
+
+    /// Hands the decoder `D` 20 000 broken copies of the `valid` records:
+    /// a few bytes overwritten, then whatever `tweak` does, then cut short
+    /// at random. `tweak` can put small values in the length fields, where a
+    /// flip is likeliest to get past the size check. Nothing may panic, and
+    /// whatever is accepted must lie within the bytes given and is handed to
+    /// `read`, which should read all of it.
+    pub fn corrupt_records<D: Decode>(
+        valid: &[Vec<u8>],
+        mut tweak: impl FnMut(&mut Rng, &mut Vec<u8>),
+        mut read: impl FnMut(&D::Record<'_>),
+    ) {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..20_000 {
+            let mut buf = valid[rng.below(valid.len())].clone();
+            for _ in 0..1 + rng.below(4) {
+                let i = rng.below(buf.len());
+                buf[i] = rng.next_u64() as u8;
+            }
+            tweak(&mut rng, &mut buf);
+            buf.truncate(rng.below(buf.len() + 1));
+            let Ok(record) = D::parse(&buf) else { continue };
+            assert!(D::size(&record) as usize <= buf.len());
+            read(&record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(kind: u16, version: u16, size: u32, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        buf[KIND_OFFSET..][..2].copy_from_slice(&kind.to_ne_bytes());
+        buf[VERSION_OFFSET..][..2].copy_from_slice(&version.to_ne_bytes());
+        buf[SIZE_OFFSET..][..4].copy_from_slice(&size.to_ne_bytes());
+        buf
+    }
+
+    #[test]
+    fn checks_the_header() {
+        assert_eq!(check_header(&record(7, 3, 24, 24), 3), Ok(7));
+        assert_eq!(check_header(&record(7, 4, 24, 24), 3), Err(DecodeError::UnsupportedVersion { version: 4, expected: 3 }));
+        assert_eq!(check_header(&record(7, 3, 24, 24)[..23], 3), Err(DecodeError::Truncated { len: 23, expected: HEADER_LEN }));
+    }
+
+    #[test]
+    fn checks_the_size_against_the_names() {
+        // 32 fixed bytes, then names of 5 and 3.
+        let buf = record(1, 1, 40, 40);
+        assert_eq!(check_size(&buf, 32, 8, || [5, 3]), Ok(()));
+        assert_eq!(check_size(&buf[..31], 32, 8, || [5, 3]), Err(DecodeError::Truncated { len: 31, expected: 32 }));
+        assert_eq!(check_size(&buf[..39], 32, 8, || [5, 3]), Err(DecodeError::Truncated { len: 39, expected: 40 }));
+        assert_eq!(check_size(&buf, 32, 8, || [5, 4]), Err(DecodeError::SizeMismatch { size: 40, expected: 41 }));
+        assert_eq!(check_size(&buf, 32, 4, || [5, 3]), Err(DecodeError::NameTooLong { len: 5, max: 4 }));
+        // Without names the size is the fixed part, and padding may follow.
+        let padded = record(1, 1, 32, 36);
+        assert_eq!(check_size(&padded, 32, 0, || []), Ok(()));
+        assert_eq!(check_size(&padded, 36, 0, || []), Err(DecodeError::SizeMismatch { size: 32, expected: 36 }));
+    }
+
+    #[test]
+    fn reads_c_strings() {
+        assert_eq!(c_str(b"xxab\0cd", 2, 5), b"ab");
+        // No NUL within the field: all of it.
+        assert_eq!(c_str(b"xxabcd", 2, 4), b"abcd");
+    }
+
+    #[test]
+    fn escapes_names_that_are_not_utf8() {
+        assert_eq!(format!("{}", Escaped(b"/tmp/\xff\xfeok")), "/tmp/\\xff\\xfeok");
+        assert_eq!(format!("{}", Escaped(b"a\nb\x1b")), "a\\nb\\u{1b}");
+        assert_eq!(format!("{}", Escaped("/tmp/é".as_bytes())), "/tmp/é");
+    }
+
+    enum Headers {}
+
+    impl Decode for Headers {
+        type Record<'a> = &'a [u8];
+
+        fn parse(buf: &[u8]) -> Result<&[u8], DecodeError> {
+            check_header(buf, 1)?;
+            check_size(buf, HEADER_LEN, 0, || [])?;
+            Ok(buf)
+        }
+
+        fn size(record: &&[u8]) -> u32 {
+            u32::from_ne_bytes(bytes(record, SIZE_OFFSET))
+        }
+    }
+
+    #[test]
+    fn counts_decoded_and_malformed_records() {
+        static STATS: DecodeStats<Headers> = DecodeStats::new();
+        let buf = record(1, 1, 24, 24);
+        assert!(STATS.decode(&buf).is_ok());
+        assert!(STATS.decode(&buf[..8]).is_err());
+        assert!(STATS.decode(&record(1, 2, 24, 24)).is_err());
+        assert_eq!((STATS.decoded(), STATS.malformed(), STATS.bytes()), (1, 2, 24));
+    }
+}
@@ -0,0 +1,324 @@
+//! Resolves the raw ids in events into names people can read.
+//!
+//! uids and gids are looked up in the user and group databases, and pids in
+//! `/proc/<pid>`. Every answer is cached for a while, including misses, so a
+//! busy process costs one lookup per TTL rather than one per event. A process
+//! that has exited before it is looked up simply has no details; that is
+//! common for short-lived processes and never an error.
+//!
+//! Those lookups block, on `/proc` and NSS, so tracers reading events on
+//! async tasks hand enriched records to a `Worker` thread.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, Once};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+#[cfg(feature = "otlp")]
+use crate::otlp::AttrValue;
+use crate::users::{groupname, username};
+
+/// Entries kept per cache before expired ones are swept out.
+const MAX_ENTRIES: usize = 4096;
+/// Records waiting for a `Worker` before more are dropped.
+const QUEUE_LEN: usize = 16384;
+
+/// So a process that may not be looked at is only warned about once.
+static DENIED: Once = Once::new();
+
+struct TtlCache<K, V> {
+    ttl: Duration,
+    entries: HashMap<K, (Instant, V)>,
+}
+
+impl<K: Eq + Hash + Copy, V: Clone> TtlCache<K, V> {
+    fn new(ttl: Duration) -> Self {
+        TtlCache {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn get_or_insert_with(&mut self, key: K, f: impl FnOnce() -> V) -> V {
+        let now = Instant::now();
+        if let Some((inserted, value)) = self.entries.get(&key) {
+            if now.duration_since(*inserted) < self.ttl {
+                return value.clone();
+            }
+        }
+        if self.entries.len() >= MAX_ENTRIES {
+            let ttl = self.ttl;
+            self.entries.retain(|_, (inserted, _)| now.duration_since(*inserted) < ttl);
+            if self.entries.len() >= MAX_ENTRIES {
+                self.entries.clear();
+            }
+        }
+        let value = f();
+        self.entries.insert(key, (now, value.clone()));
+        value
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.entries.remove(key);
+    }
+}
+
+/// What `/proc/<pid>` had to say about a process; fields are `None` once the
+/// process is gone or when we may not look.
+#[derive(Debug, Default, Clone)]
+pub struct ProcessInfo {
+    pub exe: Option<String>,
+    /// Arguments joined by spaces.
+    pub cmdline: Option<String>,
+    pub cwd: Option<String>,
+    /// Real gid of the process.
+    pub gid: Option<u32>,
+}
+
+impl ProcessInfo {
+    fn read(pid: u32) -> Self {
+        let proc = format!("/proc/{}", pid);
+        let link = |name: &str| match fs::read_link(format!("{}/{}", proc, name)) {
+            Ok(path) => Some(path.to_string_lossy().into_owned()),
+            Err(e) => {
+                // Other users' processes need CAP_SYS_PTRACE, which --user drops.
+                if e.kind() == io::ErrorKind::PermissionDenied {
+                    DENIED.call_once(|| {
+                        warn!("may not read {}/{}, e.g. of another user's process after --user; such processes go without their executable and directory", proc, name)
+                    });
+                }
+                None
+            }
+        };
+        let cmdline = fs::read(format!("{}/cmdline", proc))
+            .ok()
+            .filter(|args| !args.is_empty())
+            .map(|args| {
+                args.strip_suffix(b"\0")
+                    .unwrap_or(&args)
+                    .split(|&b| b == 0)
+                    .map(String::from_utf8_lossy)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            });
+        let gid = fs::read_to_string(format!("{}/status", proc)).ok().and_then(|status| {
+            let line = status.lines().find(|line| line.starts_with("Gid:"))?;
+            line.split_whitespace().nth(1)?.parse().ok()
+        });
+        ProcessInfo {
+            exe: link("exe"),
+            cmdline,
+            cwd: link("cwd"),
+            gid,
+        }
+    }
+}
+
+/// Everything known about the process behind one event.
+pub struct Context {
+    pub uid: u32,
+    pub user: Option<Arc<str>>,
+    pub group: Option<Arc<str>>,
+    pub process: Arc<ProcessInfo>,
+}
+
+impl Context {
+    /// The details that do not fit in the `alice (1000) /usr/bin/vim` summary.
+    pub fn details(&self) -> Details<'_> {
+        Details(self)
+    }
+
+    /// Takes the executable from `known`, e.g. the process table, when
+    /// `/proc` had none because the process is already gone.
+    pub fn with_known_exe(mut self, known: Option<&str>) -> Self {
+        if self.process.exe.is_none() {
+            if let Some(exe) = known {
+                Arc::make_mut(&mut self.process).exe = Some(exe.to_owned());
+            }
+        }
+        self
+    }
+
+    /// The resolved values as OTLP attributes, leaving out what is unknown.
+    #[cfg(feature = "otlp")]
+    pub fn attributes(&self) -> Vec<(&'static str, AttrValue)> {
+        let process = &self.process;
+        [
+            ("process.owner", self.user.as_deref()),
+            ("process.group.name", self.group.as_deref()),
+            ("process.executable.path", process.exe.as_deref()),
+            ("process.command_line", process.cmdline.as_deref()),
+            ("process.working_directory", process.cwd.as_deref()),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, AttrValue::Str(value?.to_owned()))))
+        .collect()
+    }
+}
+
+/// Formats as `alice (1000) /usr/bin/vim`.
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.user {
+            Some(user) => write!(f, "{} ({})", user, self.uid)?,
+            None => write!(f, "{}", self.uid)?,
+        }
+        write!(f, " {}", self.process.exe.as_deref().unwrap_or("?"))
+    }
+}
+
+pub struct Details<'a>(&'a Context);
+
+impl fmt::Display for Details<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let process = &self.0.process;
+        match (process.gid, &self.0.group) {
+            (Some(gid), Some(group)) => write!(f, "group : {} ({})", group, gid)?,
+            (Some(gid), None) => write!(f, "group : {}", gid)?,
+            (None, _) => write!(f, "group : ?")?,
+        }
+        write!(f, ", cwd : {}", process.cwd.as_deref().unwrap_or("?"))?;
+        write!(f, ", cmdline : {}", process.cmdline.as_deref().unwrap_or("?"))
+    }
+}
+
+pub struct Enricher {
+    users: Mutex<TtlCache<u32, Option<Arc<str>>>>,
+    groups: Mutex<TtlCache<u32, Option<Arc<str>>>>,
+    processes: Mutex<TtlCache<u32, Arc<ProcessInfo>>>,
+}
+
+impl Enricher {
+    pub fn new(ttl: Duration) -> Self {
+        Enricher {
+            users: Mutex::new(TtlCache::new(ttl)),
+            groups: Mutex::new(TtlCache::new(ttl)),
+            processes: Mutex::new(TtlCache::new(ttl)),
+        }
+    }
+
+    pub fn lookup(&self, uid: u32, pid: u32) -> Context {
+        let process = self
+            .processes
+            .lock()
+            .unwrap()
+            .get_or_insert_with(pid, || Arc::new(ProcessInfo::read(pid)));
+        let user = self
+            .users
+            .lock()
+            .unwrap()
+            .get_or_insert_with(uid, || username(uid).map(Arc::from));
+        let group = process.gid.and_then(|gid| {
+            self.groups
+                .lock()
+                .unwrap()
+                .get_or_insert_with(gid, || groupname(gid).map(Arc::from))
+        });
+        Context {
+            uid,
+            user,
+            group,
+            process,
+        }
+    }
+
+    /// Drops what is cached for `pid`, e.g. after it exec'd a new image.
+    pub fn forget(&self, pid: u32) {
+        self.processes.lock().unwrap().remove(&pid);
+    }
+}
+
+enum Message<T> {
+    Record(T),
+    Flush(SyncSender<()>),
+}
+
+/// Handles records on a thread of its own, in the order they were queued.
+pub struct Worker<T = Vec<u8>> {
+    tx: SyncSender<Message<T>>,
+    dropped: AtomicU64,
+    _thread: JoinHandle<()>,
+}
+
+impl<T: Send + 'static> Worker<T> {
+    pub fn spawn(mut handle: impl FnMut(T) + Send + 'static) -> Self {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+        let thread = thread::spawn(move || {
+            for message in rx {
+                match message {
+                    Message::Record(record) => handle(record),
+                    Message::Flush(ack) => {
+                        let _ = ack.send(());
+                    }
+                }
+            }
+        });
+        Worker {
+            tx,
+            dropped: AtomicU64::new(0),
+            _thread: thread,
+        }
+    }
+
+    /// Queues `record` without blocking; records are dropped and counted
+    /// while the queue is full.
+    pub fn submit(&self, record: T) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(Message::Record(record)) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records dropped so far because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the records queued so far to be handled.
+    pub fn flush(&self, timeout: Duration) {
+        let (ack_tx, ack_rx) = mpsc::sync_channel(1);
+        if self.tx.send(Message::Flush(ack_tx)).is_ok() && ack_rx.recv_timeout(timeout).is_err() {
+            warn!("timed out handling the queued events");
+        }
+        let dropped = self.dropped();
+        if dropped > 0 {
+            warn!("{} events dropped because looking up their processes fell behind", dropped);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn worker_handles_records_in_order() {
+        let (tx, rx) = mpsc::channel();
+        let worker = Worker::spawn(move |record| tx.send(record).unwrap());
+        for i in 0..100u8 {
+            worker.submit(vec![i, i]);
+        }
+        worker.flush(Duration::from_secs(10));
+        let handled: Vec<_> = rx.try_iter().collect();
+        assert_eq!(handled, (0..100u8).map(|i| vec![i, i]).collect::<Vec<_>>());
+        assert_eq!(worker.dropped(), 0);
+    }
+
+    #[test]
+    fn looks_up_this_process() {
+        let enricher = Enricher::new(Duration::from_secs(60));
+        let context = enricher.lookup(unsafe { libc::getuid() }, std::process::id());
+        let exe = std::env::current_exe().unwrap();
+        assert_eq!(context.process.exe.as_deref(), exe.to_str());
+        assert_eq!(context.process.gid, Some(unsafe { libc::getgid() }));
+        // Gone, or never there.
+        assert!(enricher.lookup(0, u32::MAX).process.exe.is_none());
+    }
+}
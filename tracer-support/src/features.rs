@@ -0,0 +1,459 @@
+//! What the running kernel supports, probed once at startup so a missing
+//! feature is named rather than surfacing as an error from deep inside the
+//! load or attach.
+//!
+//! Map and program types are probed by creating the smallest one of each.
+//! Attach points are looked up before attaching: kernel functions in
+//! `/proc/kallsyms`, where one the compiler inlined everywhere, or one a
+//! kernel renamed, is missing and kprobes cannot attach to it; tracepoints
+//! under `events` in tracefs, where those of newer system calls are missing
+//! on older kernels.
+
+use std::collections::HashSet;
+use std::ffi::CStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::fd::{FromRawFd as _, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::bail;
+use log::{info, warn};
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_MAP_TYPE_PERF_EVENT_ARRAY: u32 = 4;
+const BPF_MAP_TYPE_RINGBUF: u32 = 27;
+const BPF_PROG_TYPE_KPROBE: u32 = 2;
+const BPF_PROG_TYPE_TRACEPOINT: u32 = 5;
+
+const VMLINUX_BTF: &str = "/sys/kernel/btf/vmlinux";
+const LSMS: &str = "/sys/kernel/security/lsm";
+const TRACEFS: [&str; 2] = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+/// How records get from the probes to user space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum Transport {
+    /// One BPF ring buffer shared by all CPUs, `RINGBUF`
+    Ringbuf,
+    /// A perf buffer per CPU, `EVENTS`
+    Perf,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Transport::Ringbuf => "ring buffer",
+            Transport::Perf => "perf event array",
+        })
+    }
+}
+
+impl FromStr for Transport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ringbuf" => Ok(Transport::Ringbuf),
+            "perf" => Ok(Transport::Perf),
+            _ => bail!("no transport {}; ringbuf or perf", s),
+        }
+    }
+}
+
+/// Something a tracer cannot run without.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Need {
+    Kprobes,
+    Tracepoints,
+    /// `tp_btf` programs, which take their arguments typed from kernel BTF.
+    BtfTracepoints,
+    GlobalData,
+    RingBuffers,
+    PerfArrays,
+    /// Ring buffers or, failing those, perf event arrays.
+    AnyTransport,
+}
+
+impl fmt::Display for Need {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Need::Kprobes => "kprobe programs",
+            Need::Tracepoints => "tracepoint programs",
+            Need::BtfTracepoints => "BTF tracepoints (Linux 5.5 with kernel BTF)",
+            Need::GlobalData => "global variables in programs (Linux 5.2)",
+            Need::RingBuffers => "BPF ring buffers (Linux 5.8)",
+            Need::PerfArrays => "perf event arrays",
+            Need::AnyTransport => "ring buffers and perf event arrays",
+        })
+    }
+}
+
+/// Where some programs attach: kernel functions for kprobes, or
+/// `category/name` tracepoints, in the order they are tried.
+pub struct AttachPoint {
+    pub targets: &'static [&'static str],
+    pub programs: &'static [&'static str],
+    /// What goes untraced if none of `targets` exists; empty if the tracer
+    /// cannot run without them.
+    pub untraced: &'static str,
+}
+
+pub struct Features {
+    release: String,
+    version: Option<(u32, u32, u32)>,
+    vmlinux_btf: bool,
+    btf: bool,
+    global_data: bool,
+    kprobes: bool,
+    tracepoints: bool,
+    ring_buffers: bool,
+    perf_arrays: bool,
+    bpf_lsm: bool,
+    symbols: Option<HashSet<String>>,
+    tracefs: Option<PathBuf>,
+}
+
+impl Features {
+    pub fn probe() -> Self {
+        let aya = aya::features();
+        let release = release();
+        let version = parse_release(&release);
+        Features {
+            vmlinux_btf: Path::new(VMLINUX_BTF).exists(),
+            btf: aya.btf().is_some(),
+            global_data: aya.bpf_global_data(),
+            kprobes: program_supported(BPF_PROG_TYPE_KPROBE, version),
+            tracepoints: program_supported(BPF_PROG_TYPE_TRACEPOINT, version),
+            ring_buffers: map_supported(BPF_MAP_TYPE_RINGBUF, 0, 0, page_size()),
+            perf_arrays: map_supported(BPF_MAP_TYPE_PERF_EVENT_ARRAY, 4, 4, 1),
+            bpf_lsm: fs::read_to_string(LSMS).is_ok_and(|lsms| lsms.trim().split(',').any(|lsm| lsm == "bpf")),
+            symbols: fs::read_to_string("/proc/kallsyms").ok().map(|kallsyms| parse_kallsyms(&kallsyms)),
+            tracefs: TRACEFS.iter().map(PathBuf::from).find(|dir| dir.join("events").is_dir()),
+            release,
+            version,
+        }
+    }
+
+    /// Whether the kernel is `major.minor` or later. A release that cannot
+    /// be read counts as older, so layouts that changed are not guessed at.
+    pub fn at_least(&self, major: u32, minor: u32) -> bool {
+        self.version.is_some_and(|(have_major, have_minor, _)| (have_major, have_minor) >= (major, minor))
+    }
+
+    /// Fails, naming what is missing of `needs`, if `tracer` cannot trace
+    /// anything here.
+    pub fn check(&self, tracer: &str, needs: &[Need]) -> Result<(), anyhow::Error> {
+        let missing = needs.iter().filter(|need| !self.has(**need)).map(Need::to_string).collect::<Vec<_>>();
+        if !missing.is_empty() {
+            bail!("this kernel lacks {}; `{} features` tells what it has", missing.join(", "), tracer);
+        }
+        Ok(())
+    }
+
+    fn has(&self, need: Need) -> bool {
+        match need {
+            Need::Kprobes => self.kprobes,
+            Need::Tracepoints => self.tracepoints,
+            Need::BtfTracepoints => self.vmlinux_btf && self.at_least(5, 5),
+            Need::GlobalData => self.global_data,
+            Need::RingBuffers => self.ring_buffers,
+            Need::PerfArrays => self.perf_arrays,
+            Need::AnyTransport => self.ring_buffers || self.perf_arrays,
+        }
+    }
+
+    /// The transport to use: `wanted` if given and supported, otherwise ring
+    /// buffers where there are any.
+    pub fn transport(&self, wanted: Option<Transport>) -> Result<Transport, anyhow::Error> {
+        match wanted {
+            Some(Transport::Ringbuf) if !self.ring_buffers => bail!("this kernel has no BPF ring buffers, which need Linux 5.8"),
+            Some(Transport::Perf) if !self.perf_arrays => bail!("this kernel has no perf event arrays"),
+            Some(transport) => Ok(transport),
+            None if self.ring_buffers => Ok(Transport::Ringbuf),
+            None => Ok(Transport::Perf),
+        }
+    }
+
+    /// The first of `targets` there is to attach to. Without a readable
+    /// `/proc/kallsyms` or tracefs, the first is taken on trust.
+    pub fn attach_point<'a>(&self, targets: &[&'a str]) -> Option<&'a str> {
+        targets.iter().copied().find(|target| self.exists(target))
+    }
+
+    fn exists(&self, target: &str) -> bool {
+        if target.contains('/') {
+            self.tracefs.as_ref().is_none_or(|dir| dir.join("events").join(target).is_dir())
+        } else {
+            self.symbols.as_ref().is_none_or(|symbols| symbols.contains(target))
+        }
+    }
+
+    /// Where the programs of `point` attach, or `None`, after a warning, if
+    /// that only leaves something untraced. Fails if the tracer cannot run
+    /// without them.
+    pub fn resolve(&self, point: &AttachPoint) -> Result<Option<&'static str>, anyhow::Error> {
+        let target = match self.attach_point(point.targets) {
+            Some(target) => target,
+            None if point.untraced.is_empty() => {
+                bail!("cannot attach to {}: not in this kernel, or inlined or renamed in it", point.targets.join(" or "));
+            }
+            None => {
+                warn!("cannot attach to {}, so {} are not traced: not in this kernel", point.targets[0], point.untraced);
+                return Ok(None);
+            }
+        };
+        if target != point.targets[0] {
+            info!("attaching to {} in place of {}, which is not in this kernel", target, point.targets[0]);
+        }
+        Ok(Some(target))
+    }
+
+    /// One line per feature, then where each of `points` would attach, for
+    /// the `features` command and the startup log.
+    pub fn report(&self, points: &[AttachPoint]) -> Vec<String> {
+        let rows = [
+            (self.vmlinux_btf, "kernel BTF", VMLINUX_BTF),
+            (self.btf, "BTF for programs", "names and types in the verifier log"),
+            (self.global_data, "global variables", "Linux 5.2"),
+            (self.kprobes, "kprobe programs", ""),
+            (self.tracepoints, "tracepoint programs", ""),
+            (self.ring_buffers, "ring buffers", "Linux 5.8"),
+            (self.perf_arrays, "perf event arrays", "where there are no ring buffers"),
+            (self.bpf_lsm, "BPF LSM", "not used; needs lsm=bpf on the kernel command line"),
+            (self.symbols.is_some(), "/proc/kallsyms", "to check kernel functions"),
+            (self.tracefs.is_some(), "tracefs", "to check tracepoints"),
+        ];
+        let mut lines = vec![format!("kernel {}", self.release)];
+        for (available, feature, note) in rows {
+            lines.push(format!("{:<3} {:<19} {}", if available { "yes" } else { "no" }, feature, note).trim_end().to_owned());
+        }
+        for point in points {
+            let attach = match self.attach_point(point.targets) {
+                Some(target) => target.to_owned(),
+                None if point.untraced.is_empty() => "nothing, so the tracer cannot run".to_owned(),
+                None => format!("nothing, so {} are not traced", point.untraced),
+            };
+            lines.push(format!("{} attach to {}", point.programs.join(", "), attach));
+        }
+        lines
+    }
+}
+
+fn release() -> String {
+    let mut uname: libc::utsname = unsafe { mem::zeroed() };
+    if unsafe { libc::uname(&mut uname) } != 0 {
+        return "unknown".to_owned();
+    }
+    unsafe { CStr::from_ptr(uname.release.as_ptr()) }.to_string_lossy().into_owned()
+}
+
+/// The major, minor and patch numbers at the start of a release such as
+/// `5.15.0-91-generic`; a missing patch is 0.
+fn parse_release(release: &str) -> Option<(u32, u32, u32)> {
+    let end = release.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(release.len());
+    let mut numbers = release[..end].split('.').map(|number| number.parse().ok());
+    let major = numbers.next()??;
+    let minor = numbers.next()??;
+    let patch = numbers.next().flatten().unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+/// `LINUX_VERSION_CODE` of `version`, with the patch capped as the kernel
+/// caps it.
+fn version_code((major, minor, patch): (u32, u32, u32)) -> u32 {
+    major << 16 | minor << 8 | patch.min(255)
+}
+
+fn page_size() -> u32 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u32 }
+}
+
+/// Runs the bpf() command `cmd` with `attr`, and closes the fd it returns.
+fn bpf(cmd: libc::c_long, attr: &[u32]) -> Result<(), io::Error> {
+    let fd = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr.as_ptr(), mem::size_of_val(attr)) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    drop(unsafe { OwnedFd::from_raw_fd(fd as i32) });
+    Ok(())
+}
+
+fn map_supported(map_type: u32, key_size: u32, value_size: u32, max_entries: u32) -> bool {
+    // `bpf_attr` for BPF_MAP_CREATE, up to `map_flags`.
+    bpf(BPF_MAP_CREATE, &[map_type, key_size, value_size, max_entries, 0]).is_ok()
+}
+
+fn program_supported(prog_type: u32, version: Option<(u32, u32, u32)>) -> bool {
+    // r0 = 0; exit
+    let insns: [u64; 2] = [0x0000_0000_0000_00b7, 0x0000_0000_0000_0095];
+    let license = c"GPL";
+    // Kernels before 5.0 check it against their own for kprobes.
+    let kern_version = version.map_or(0, version_code);
+    // `bpf_attr` for BPF_PROG_LOAD: prog_type, insn_cnt, insns, license,
+    // log_level, log_size, log_buf and kern_version.
+    let insns = insns.as_ptr() as u64;
+    let license = license.as_ptr() as u64;
+    let attr = [
+        prog_type,
+        2,
+        insns as u32,
+        (insns >> 32) as u32,
+        license as u32,
+        (license >> 32) as u32,
+        0,
+        0,
+        0,
+        0,
+        kern_version,
+        0,
+    ];
+    bpf(BPF_PROG_LOAD, &attr).is_ok()
+}
+
+/// The names of the kernel's functions in `/proc/kallsyms`, modules'
+/// included.
+fn parse_kallsyms(kallsyms: &str) -> HashSet<String> {
+    kallsyms
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let kind = fields.nth(1)?;
+            let name = fields.next()?;
+            matches!(kind, "t" | "T").then(|| name.to_owned())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(ring_buffers: bool, perf_arrays: bool) -> Features {
+        Features {
+            release: "5.4.0-150-generic".to_owned(),
+            version: Some((5, 4, 0)),
+            vmlinux_btf: false,
+            btf: false,
+            global_data: true,
+            kprobes: true,
+            tracepoints: true,
+            ring_buffers,
+            perf_arrays,
+            bpf_lsm: false,
+            symbols: None,
+            tracefs: None,
+        }
+    }
+
+    #[test]
+    fn parses_releases() {
+        assert_eq!(parse_release("5.15.0-91-generic"), Some((5, 15, 0)));
+        assert_eq!(parse_release("6.10.3-arch1-1"), Some((6, 10, 3)));
+        assert_eq!(parse_release("4.19.282-1.el8"), Some((4, 19, 282)));
+        assert_eq!(parse_release("6.1.0-rc3+"), Some((6, 1, 0)));
+        assert_eq!(parse_release("6.8"), Some((6, 8, 0)));
+        assert_eq!(parse_release("6.8-rc1"), Some((6, 8, 0)));
+        assert_eq!(parse_release("6"), None);
+        assert_eq!(parse_release("unknown"), None);
+        assert_eq!(version_code((4, 19, 282)), 0x0004_13ff);
+        assert_eq!(version_code((5, 15, 0)), 0x0005_0f00);
+    }
+
+    #[test]
+    fn compares_versions() {
+        let mut features = features(false, true);
+        assert!(features.at_least(5, 4));
+        assert!(features.at_least(4, 19));
+        assert!(!features.at_least(5, 12));
+        features.version = Some((6, 1, 0));
+        assert!(features.at_least(5, 12));
+        features.version = None;
+        assert!(!features.at_least(3, 0));
+    }
+
+    #[test]
+    fn parses_kallsyms() {
+        let kallsyms = "\
+ffffffff81000000 T _stext
+ffffffff812a4c10 T security_file_open
+ffffffff812a5000 t do_dentry_open
+ffffffff82600000 D init_task
+ffffffff82a00000 b dentry_hashtable
+ffffffff812a6000 W weak_function
+ffffffffc0a01000 t ext4_file_open\t[ext4]
+truncated";
+        let symbols = parse_kallsyms(kallsyms);
+        let mut names = symbols.iter().map(String::as_str).collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, ["_stext", "do_dentry_open", "ext4_file_open", "security_file_open"]);
+    }
+
+    #[test]
+    fn chooses_the_transport() {
+        let both = features(true, true);
+        assert_eq!(both.transport(None).unwrap(), Transport::Ringbuf);
+        assert_eq!(both.transport(Some(Transport::Perf)).unwrap(), Transport::Perf);
+
+        let old = features(false, true);
+        assert_eq!(old.transport(None).unwrap(), Transport::Perf);
+        let e = old.transport(Some(Transport::Ringbuf)).unwrap_err();
+        assert_eq!(e.to_string(), "this kernel has no BPF ring buffers, which need Linux 5.8");
+
+        let e = features(true, false).transport(Some(Transport::Perf)).unwrap_err();
+        assert_eq!(e.to_string(), "this kernel has no perf event arrays");
+        assert_eq!("perf".parse::<Transport>().unwrap(), Transport::Perf);
+        assert!("kafka".parse::<Transport>().is_err());
+    }
+
+    #[test]
+    fn names_what_is_missing() {
+        let old = features(false, true);
+        old.check("ringbuf", &[Need::Kprobes, Need::AnyTransport]).unwrap();
+        let e = old.check("network", &[Need::Kprobes, Need::RingBuffers, Need::BtfTracepoints]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "this kernel lacks BPF ring buffers (Linux 5.8), BTF tracepoints (Linux 5.5 with kernel BTF); `network features` tells what it has"
+        );
+        let e = features(false, false).check("ringbuf", &[Need::AnyTransport]).unwrap_err();
+        assert!(e.to_string().starts_with("this kernel lacks ring buffers and perf event arrays;"), "{}", e);
+    }
+
+    #[test]
+    fn falls_back_to_the_next_attach_point() {
+        let dir = std::env::temp_dir().join(format!("features-{}", std::process::id()));
+        fs::create_dir_all(dir.join("events/syscalls/sys_enter_openat")).unwrap();
+        let mut features = features(true, true);
+        features.symbols = Some(parse_kallsyms("0 t do_dentry_open\n0 T vfs_write"));
+        features.tracefs = Some(dir.clone());
+
+        let open = AttachPoint { targets: &["security_file_open", "do_dentry_open"], programs: &["open"], untraced: "" };
+        let rename = AttachPoint { targets: &["vfs_rename"], programs: &["rename"], untraced: "renames" };
+        let openat = AttachPoint { targets: &["syscalls/sys_enter_openat"], programs: &["openat"], untraced: "" };
+        let openat2 = AttachPoint { targets: &["syscalls/sys_enter_openat2"], programs: &["openat2"], untraced: "openat2 calls" };
+        let write = AttachPoint { targets: &["vfs_write"], programs: &["write"], untraced: "" };
+        assert_eq!(features.resolve(&open).unwrap(), Some("do_dentry_open"));
+        assert_eq!(features.resolve(&rename).unwrap(), None);
+        assert_eq!(features.resolve(&openat).unwrap(), Some("syscalls/sys_enter_openat"));
+        assert_eq!(features.resolve(&openat2).unwrap(), None);
+        let missing = AttachPoint { untraced: "", ..openat2 };
+        let e = features.resolve(&missing).unwrap_err();
+        assert_eq!(e.to_string(), "cannot attach to syscalls/sys_enter_openat2: not in this kernel, or inlined or renamed in it");
+
+        let report = features.report(&[open, rename, write]);
+        assert_eq!(report[0], "kernel 5.4.0-150-generic");
+        assert_eq!(report[4], "yes kprobe programs");
+        assert_eq!(report[6], "yes ring buffers        Linux 5.8");
+        assert_eq!(&report[report.len() - 3..], ["open attach to do_dentry_open", "rename attach to nothing, so renames are not traced", "write attach to vfs_write"]);
+
+        // Without kallsyms or tracefs, the first is taken on trust.
+        features.symbols = None;
+        features.tracefs = None;
+        assert_eq!(features.attach_point(&["security_file_open", "do_dentry_open"]), Some("security_file_open"));
+        assert_eq!(features.attach_point(&["syscalls/sys_enter_openat2"]), Some("syscalls/sys_enter_openat2"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
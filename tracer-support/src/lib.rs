@@ -0,0 +1,25 @@
+//! The user space parts the tracers share, each behind a feature of its own
+//! so a tracer only builds what it uses.
+
+#[cfg(feature = "control")]
+pub mod control;
+#[cfg(feature = "daemon")]
+pub mod daemon;
+#[cfg(feature = "decode")]
+pub mod decode;
+#[cfg(feature = "enrich")]
+pub mod enrich;
+#[cfg(feature = "features")]
+pub mod features;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+#[cfg(feature = "rules")]
+pub mod rules;
+#[cfg(feature = "sandbox")]
+pub mod sandbox;
+#[cfg(feature = "transport")]
+pub mod transport;
+#[cfg(feature = "users")]
+pub mod users;
+#[cfg(feature = "verifier")]
+pub mod verifier;
@@ -0,0 +1,434 @@
+//! Optional export of events as OTLP log records over HTTP/JSON.
+//!
+//! Events are handed to a background thread that batches them, posts each
+//! batch to the collector with retries, and appends batches it could not
+//! deliver to a local fallback file, one `ExportLogsServiceRequest` per line.
+//! Records are grouped by process, so host, process and cgroup end up as
+//! resource attributes.
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+use serde_json::{json, Value};
+
+const QUEUE_LEN: usize = 16384;
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Config {
+    /// Name of the instrumentation scope the records are sent under, the
+    /// tracer's.
+    pub scope: &'static str,
+    /// Full URL of the collector's logs endpoint, e.g. `http://localhost:4318/v1/logs`.
+    pub endpoint: String,
+    /// Where batches go when the collector cannot be reached.
+    pub fallback: Option<PathBuf>,
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+}
+
+pub enum AttrValue {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+pub struct LogRecord {
+    /// `bpf_ktime_get_ns()` of the event.
+    pub timestamp: u64,
+    pub pid: u32,
+    /// Task name, if the event carries one.
+    pub comm: Option<String>,
+    pub body: String,
+    pub attributes: Vec<(&'static str, AttrValue)>,
+}
+
+enum Message {
+    Record(LogRecord),
+    Flush(SyncSender<()>),
+}
+
+pub struct Exporter {
+    tx: SyncSender<Message>,
+    dropped: AtomicU64,
+    _worker: JoinHandle<()>,
+}
+
+impl Exporter {
+    pub fn spawn(config: Config) -> Self {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+        let worker = thread::spawn(move || run(config, rx));
+        Exporter {
+            tx,
+            dropped: AtomicU64::new(0),
+            _worker: worker,
+        }
+    }
+
+    /// Queues `record` without blocking; records are dropped and counted
+    /// while the queue is full.
+    pub fn export(&self, record: LogRecord) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(Message::Record(record)) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Sends everything queued so far and waits for it to be delivered or
+    /// written to the fallback file.
+    pub fn flush(&self, timeout: Duration) {
+        let (ack_tx, ack_rx) = mpsc::sync_channel(1);
+        if self.tx.send(Message::Flush(ack_tx)).is_ok() && ack_rx.recv_timeout(timeout).is_err() {
+            warn!("timed out flushing OTLP exporter");
+        }
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("{} OTLP log records dropped because the export queue was full", dropped);
+        }
+    }
+}
+
+fn run(config: Config, rx: Receiver<Message>) {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(REQUEST_TIMEOUT))
+        .build()
+        .into();
+    let host = hostname();
+    let boot_time = boot_time_ns();
+    let mut batch = Vec::with_capacity(config.batch_size);
+    let mut deadline = Instant::now() + config.flush_interval;
+    loop {
+        let ack = match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Message::Record(record)) => {
+                batch.push(record);
+                if batch.len() < config.batch_size {
+                    continue;
+                }
+                None
+            }
+            Ok(Message::Flush(ack)) => Some(ack),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                send_batch(&agent, &config, &host, boot_time, &mut batch);
+                return;
+            }
+        };
+        send_batch(&agent, &config, &host, boot_time, &mut batch);
+        deadline = Instant::now() + config.flush_interval;
+        if let Some(ack) = ack {
+            let _ = ack.send(());
+        }
+    }
+}
+
+fn send_batch(agent: &ureq::Agent, config: &Config, host: &str, boot_time: u64, batch: &mut Vec<LogRecord>) {
+    if batch.is_empty() {
+        return;
+    }
+    let request = build_request(config.scope, host, boot_time, batch);
+    let count = batch.len();
+    batch.clear();
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        match agent.post(&config.endpoint).send_json(&request) {
+            Ok(_) => {
+                debug!("exported {} log records", count);
+                return;
+            }
+            // Anything but throttling or a server error will fail the same way again.
+            Err(ureq::Error::StatusCode(code)) if code != 429 && code < 500 => {
+                warn!("collector rejected {} log records with status {}", count, code);
+                break;
+            }
+            Err(e) => {
+                debug!("export attempt {} of {} failed: {}", attempt, MAX_ATTEMPTS, e);
+                if attempt < MAX_ATTEMPTS {
+                    thread::sleep(RETRY_BACKOFF * attempt);
+                }
+            }
+        }
+    }
+
+    match &config.fallback {
+        Some(path) => {
+            let written = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", request));
+            match written {
+                Ok(()) => warn!("collector unreachable, wrote {} log records to {}", count, path.display()),
+                Err(e) => warn!("failed to write {} log records to {}: {}", count, path.display(), e),
+            }
+        }
+        None => warn!("collector unreachable, dropped {} log records", count),
+    }
+}
+
+fn build_request(scope: &str, host: &str, boot_time: u64, batch: &[LogRecord]) -> Value {
+    let observed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string();
+
+    let mut by_process: BTreeMap<u32, Vec<&LogRecord>> = BTreeMap::new();
+    for record in batch {
+        by_process.entry(record.pid).or_default().push(record);
+    }
+
+    let resource_logs: Vec<Value> = by_process
+        .into_iter()
+        .map(|(pid, records)| {
+            let mut resource = vec![
+                attribute("host.name", &AttrValue::Str(host.to_owned())),
+                attribute("process.pid", &AttrValue::Int(pid as i64)),
+            ];
+            let comm = records[0].comm.clone().or_else(|| read_comm(pid));
+            if let Some(comm) = comm {
+                resource.push(attribute("process.executable.name", &AttrValue::Str(comm)));
+            }
+            // The process may already be gone; the record is still worth sending.
+            if let Some(cgroup) = read_cgroup(pid) {
+                resource.push(attribute("cgroup.path", &AttrValue::Str(cgroup)));
+            }
+            let log_records: Vec<Value> = records
+                .iter()
+                .map(|record| {
+                    json!({
+                        "timeUnixNano": (boot_time + record.timestamp).to_string(),
+                        "observedTimeUnixNano": observed,
+                        "severityNumber": 9,
+                        "severityText": "INFO",
+                        "body": { "stringValue": record.body },
+                        "attributes": record
+                            .attributes
+                            .iter()
+                            .map(|(key, value)| attribute(key, value))
+                            .collect::<Vec<_>>(),
+                    })
+                })
+                .collect();
+            json!({
+                "resource": { "attributes": resource },
+                "scopeLogs": [{
+                    "scope": { "name": scope },
+                    "logRecords": log_records,
+                }],
+            })
+        })
+        .collect();
+
+    json!({ "resourceLogs": resource_logs })
+}
+
+fn attribute(key: &str, value: &AttrValue) -> Value {
+    let value = match value {
+        AttrValue::Str(s) => json!({ "stringValue": s }),
+        // OTLP/JSON encodes 64-bit integers as strings.
+        AttrValue::Int(i) => json!({ "intValue": i.to_string() }),
+        AttrValue::Bool(b) => json!({ "boolValue": b }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+fn read_comm(pid: u32) -> Option<String> {
+    let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    Some(comm.trim_end().to_owned())
+}
+
+/// The unified (v2) cgroup path of `pid`, or the first hierarchy on v1.
+fn read_cgroup(pid: u32) -> Option<String> {
+    let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    let line = cgroups
+        .lines()
+        .find(|line| line.starts_with("0::"))
+        .or_else(|| cgroups.lines().next())?;
+    Some(line.splitn(3, ':').nth(2)?.to_owned())
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return "unknown".to_owned();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Wall-clock time of boot in nanoseconds, to turn `bpf_ktime_get_ns()`
+/// (CLOCK_MONOTONIC) into a Unix timestamp.
+fn boot_time_ns() -> u64 {
+    clock_ns(libc::CLOCK_REALTIME).saturating_sub(ktime_now())
+}
+
+/// What `bpf_ktime_get_ns()` would return now, to timestamp records that do
+/// not come from the kernel.
+pub fn ktime_now() -> u64 {
+    clock_ns(libc::CLOCK_MONOTONIC)
+}
+
+fn clock_ns(clock: libc::clockid_t) -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(clock, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+
+    use super::*;
+
+    /// A collector answering one request with each of `statuses`, in turn,
+    /// and handing over the bodies it got.
+    fn collector(statuses: Vec<u16>) -> (String, Receiver<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/logs", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut len = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            len = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                tx.send(serde_json::from_slice(&body).unwrap()).unwrap();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+        (endpoint, rx)
+    }
+
+    fn exporter(endpoint: String, fallback: Option<PathBuf>) -> Exporter {
+        Exporter::spawn(Config {
+            scope: "test",
+            endpoint,
+            fallback,
+            batch_size: 100,
+            // Only `flush` sends anything.
+            flush_interval: Duration::from_secs(3600),
+        })
+    }
+
+    fn record(pid: u32, body: &str) -> LogRecord {
+        LogRecord {
+            timestamp: 1_000,
+            pid,
+            comm: Some("cat".to_owned()),
+            body: body.to_owned(),
+            attributes: vec![
+                ("file.path", AttrValue::Str("/etc/shadow".to_owned())),
+                ("ret", AttrValue::Int(-13)),
+                ("denied", AttrValue::Bool(true)),
+            ],
+        }
+    }
+
+    fn fallback_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("otlp-{}-{}.jsonl", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn attributes(value: &Value) -> BTreeMap<String, Value> {
+        value
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|attribute| (attribute["key"].as_str().unwrap().to_owned(), attribute["value"].clone()))
+            .collect()
+    }
+
+    #[test]
+    fn exports_records_by_process() {
+        let (endpoint, requests) = collector(vec![200]);
+        let exporter = exporter(endpoint, None);
+        // Pids no process has, so nothing is read from /proc.
+        exporter.export(record(u32::MAX, "first"));
+        exporter.export(record(u32::MAX - 1, "second"));
+        exporter.export(record(u32::MAX, "third"));
+        exporter.flush(Duration::from_secs(10));
+
+        let request = requests.recv_timeout(Duration::from_secs(10)).unwrap();
+        let resource_logs = request["resourceLogs"].as_array().unwrap();
+        assert_eq!(resource_logs.len(), 2);
+        let resource = attributes(&resource_logs[0]["resource"]["attributes"]);
+        assert_eq!(resource["process.pid"], json!({ "intValue": (u32::MAX - 1).to_string() }));
+        assert_eq!(resource["process.executable.name"], json!({ "stringValue": "cat" }));
+        assert!(resource.contains_key("host.name"));
+        assert!(!resource.contains_key("cgroup.path"));
+
+        let scope_logs = &resource_logs[1]["scopeLogs"][0];
+        assert_eq!(scope_logs["scope"]["name"], "test");
+        let records = scope_logs["logRecords"].as_array().unwrap();
+        let bodies: Vec<_> = records.iter().map(|record| record["body"]["stringValue"].as_str().unwrap()).collect();
+        assert_eq!(bodies, ["first", "third"]);
+        let record = &records[0];
+        assert_eq!(record["severityText"], "INFO");
+        // A Unix timestamp, not the time since boot.
+        let time: u64 = record["timeUnixNano"].as_str().unwrap().parse().unwrap();
+        assert!(time > 1_000_000_000 * 1_000_000_000);
+        let attributes = attributes(&record["attributes"]);
+        assert_eq!(attributes["file.path"], json!({ "stringValue": "/etc/shadow" }));
+        assert_eq!(attributes["ret"], json!({ "intValue": "-13" }));
+        assert_eq!(attributes["denied"], json!({ "boolValue": true }));
+    }
+
+    #[test]
+    fn retries_and_falls_back_to_a_file() {
+        let (endpoint, requests) = collector(vec![503; MAX_ATTEMPTS as usize]);
+        let fallback = fallback_file("retries");
+        let exporter = exporter(endpoint, Some(fallback.clone()));
+        exporter.export(record(u32::MAX, "lost"));
+        exporter.flush(Duration::from_secs(30));
+
+        let sent: Vec<_> = requests.try_iter().collect();
+        assert_eq!(sent.len(), MAX_ATTEMPTS as usize);
+        let written = fs::read_to_string(&fallback).unwrap();
+        fs::remove_file(&fallback).unwrap();
+        let lines: Vec<Value> = written.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines, [sent[0].clone()]);
+    }
+
+    #[test]
+    fn does_not_retry_rejected_batches() {
+        let (endpoint, requests) = collector(vec![400]);
+        let fallback = fallback_file("rejected");
+        let exporter = exporter(endpoint, Some(fallback.clone()));
+        exporter.export(record(u32::MAX, "rejected"));
+        exporter.flush(Duration::from_secs(10));
+
+        assert_eq!(requests.try_iter().count(), 1);
+        let written = fs::read_to_string(&fallback).unwrap();
+        fs::remove_file(&fallback).unwrap();
+        assert_eq!(written.lines().count(), 1);
+    }
+}
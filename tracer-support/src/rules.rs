@@ -0,0 +1,633 @@
+//! Alert rules: a TOML file of conditions on event fields, each rule with a
+//! name and a priority.
+//!
+//! ```toml
+//! [[rule]]
+//! name = "non-root opened a shadow file for write"
+//! priority = "critical"
+//! when = { event = "open", name = ["shadow", "gshadow"], flags = ["O_WRONLY", "O_RDWR"] }
+//! unless = { uid = 0 }
+//! ```
+//!
+//! A rule matches an event when every condition under `when` holds and none
+//! under `unless` does. A condition is a value or a list of values, any of
+//! which may match; strings are glob patterns, where `*` does not cross a
+//! `/`. A field the event does not have never matches, so one file can be
+//! shared by tools that report different events: its rules about fields a
+//! tool never reports are skipped there.
+//!
+//! The file is looked at again every `RELOAD_INTERVAL` and reloaded when it
+//! changed. A file that no longer parses is reported and the rules loaded
+//! before stay in force.
+//!
+//! Each tracer implements `Fields` for its events and passes the names of
+//! the fields it knows to `Rules::load`.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context as _};
+use glob::{MatchOptions, Pattern};
+use log::{info, warn};
+use serde::Deserialize;
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+/// How far up the process tree `ancestor` looks.
+pub const MAX_ANCESTORS: usize = 64;
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Every field a rule may test, across the tools that read rules files.
+const FIELDS: &[(&str, Type)] = &[
+    ("event", Type::Str),
+    ("comm", Type::Str),
+    ("pid", Type::Int),
+    ("ppid", Type::Int),
+    ("ancestor", Type::Str),
+    ("uid", Type::Int),
+    ("gid", Type::Int),
+    ("euid", Type::Int),
+    ("fsuid", Type::Int),
+    ("elevated", Type::Bool),
+    ("path", Type::Str),
+    ("name", Type::Str),
+    ("new_name", Type::Str),
+    ("exe", Type::Str),
+    ("interpreted", Type::Bool),
+    ("f_flag", Type::Int),
+    ("flags", Type::Str),
+    ("ret", Type::Int),
+    ("denied", Type::Bool),
+    ("type", Type::Str),
+    ("fs", Type::Str),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Info,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Priority::Info => "info",
+            Priority::Low => "low",
+            Priority::Medium => "medium",
+            Priority::High => "high",
+            Priority::Critical => "critical",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    Int,
+    Str,
+    Bool,
+}
+
+/// A field of an event, as rules see it.
+pub enum Value {
+    Int(i64),
+    Str(String),
+    Bool(bool),
+    /// Matches when any of the strings does.
+    List(Vec<String>),
+}
+
+/// An event whose fields rules can test. `get` is only asked for the fields
+/// the rules use, so fields that are costly to work out should be worked out
+/// there.
+pub trait Fields {
+    fn get(&self, field: &str) -> Option<Value>;
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<RuleSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    name: String,
+    priority: Priority,
+    #[serde(default)]
+    when: BTreeMap<String, OneOrMany>,
+    #[serde(default)]
+    unless: BTreeMap<String, OneOrMany>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(Literal),
+    Many(Vec<Literal>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Literal {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+}
+
+enum Matcher {
+    Ints(Vec<i64>),
+    Globs(Vec<Pattern>),
+    Bool(bool),
+}
+
+impl Matcher {
+    fn new(field: &str, ty: Type, values: OneOrMany) -> Result<Self, anyhow::Error> {
+        let values = match values {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        };
+        if values.is_empty() {
+            bail!("`{}` has no values", field);
+        }
+        let mut ints = Vec::new();
+        let mut globs = Vec::new();
+        let mut bools = Vec::new();
+        for value in values {
+            match (ty, value) {
+                (Type::Int, Literal::Int(n)) => ints.push(n),
+                (Type::Str, Literal::Str(s)) => {
+                    globs.push(Pattern::new(&s).with_context(|| format!("`{}` has a bad pattern '{}'", field, s))?)
+                }
+                (Type::Bool, Literal::Bool(b)) => bools.push(b),
+                (Type::Int, _) => bail!("`{}` takes integers", field),
+                (Type::Str, _) => bail!("`{}` takes strings", field),
+                (Type::Bool, _) => bail!("`{}` takes true or false", field),
+            }
+        }
+        Ok(match ty {
+            Type::Int => Matcher::Ints(ints),
+            Type::Str => Matcher::Globs(globs),
+            Type::Bool if bools.len() == 1 => Matcher::Bool(bools[0]),
+            Type::Bool => bail!("`{}` takes a single true or false", field),
+        })
+    }
+
+    fn matches(&self, value: &Value) -> bool {
+        match (self, value) {
+            (Matcher::Ints(ints), Value::Int(n)) => ints.contains(n),
+            (Matcher::Globs(globs), Value::Str(s)) => globs.iter().any(|glob| glob.matches_with(s, MATCH_OPTIONS)),
+            (Matcher::Globs(globs), Value::List(list)) => {
+                list.iter().any(|s| globs.iter().any(|glob| glob.matches_with(s, MATCH_OPTIONS)))
+            }
+            (Matcher::Bool(b), Value::Bool(value)) => b == value,
+            _ => false,
+        }
+    }
+}
+
+struct Rule {
+    name: String,
+    priority: Priority,
+    when: Vec<(String, Matcher)>,
+    unless: Vec<(String, Matcher)>,
+}
+
+impl Rule {
+    fn matches(&self, event: &dyn Fields) -> bool {
+        let holds = |(field, matcher): &(String, Matcher)| event.get(field).is_some_and(|value| matcher.matches(&value));
+        self.when.iter().all(holds) && !self.unless.iter().any(holds)
+    }
+}
+
+/// The rules of one version of the file.
+pub struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    /// Parses a rules file, keeping the rules whose `when` and `unless`
+    /// fields are all in `supported`. Without the field, an `unless` could
+    /// never hold and the rule would fire on more than it was written for.
+    pub fn parse(text: &str, supported: &[&str]) -> Result<Self, anyhow::Error> {
+        let file: RulesFile = toml::from_str(text)?;
+        let mut rules = Vec::new();
+        for spec in file.rule {
+            let compile = |conditions: BTreeMap<String, OneOrMany>| {
+                conditions
+                    .into_iter()
+                    .map(|(field, values)| {
+                        let &(_, ty) = FIELDS
+                            .iter()
+                            .find(|(name, _)| *name == field)
+                            .ok_or_else(|| anyhow!("unknown field `{}`", field))?;
+                        let matcher = Matcher::new(&field, ty, values)?;
+                        Ok((field, matcher))
+                    })
+                    .collect::<Result<Vec<_>, anyhow::Error>>()
+                    .with_context(|| format!("in rule '{}'", spec.name))
+            };
+            let when = compile(spec.when)?;
+            let unless = compile(spec.unless)?;
+            if let Some((field, _)) = when.iter().chain(&unless).find(|(field, _)| !supported.contains(&field.as_str())) {
+                info!("skipping rule '{}': these events have no `{}`", spec.name, field);
+                continue;
+            }
+            rules.push(Rule {
+                name: spec.name,
+                priority: spec.priority,
+                when,
+                unless,
+            });
+        }
+        Ok(RuleSet { rules })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The rules `event` matches, the highest priority first.
+    pub fn evaluate(&self, event: &dyn Fields) -> Alerts {
+        let mut alerts: Vec<_> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(event))
+            .map(|rule| Alert {
+                priority: rule.priority,
+                rule: rule.name.clone(),
+            })
+            .collect();
+        alerts.sort_by_key(|alert| Reverse(alert.priority));
+        Alerts(alerts)
+    }
+}
+
+pub struct Alert {
+    pub priority: Priority,
+    pub rule: String,
+}
+
+/// The rules an event matched. Displays as a line prefix such as
+/// `[critical: rule] [low: other rule] `, or as nothing if there are none.
+#[derive(Default)]
+pub struct Alerts(Vec<Alert>);
+
+impl Alerts {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn highest(&self) -> Option<&Alert> {
+        self.0.first()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Alert> {
+        self.0.iter()
+    }
+}
+
+impl fmt::Display for Alerts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for alert in &self.0 {
+            write!(f, "[{}: {}] ", alert.priority, alert.rule)?;
+        }
+        Ok(())
+    }
+}
+
+/// A rules file and the rules it held when last read.
+pub struct Rules {
+    path: PathBuf,
+    supported: &'static [&'static str],
+    current: RwLock<Arc<RuleSet>>,
+}
+
+impl Rules {
+    pub fn load(path: &Path, supported: &'static [&'static str]) -> Result<Self, anyhow::Error> {
+        let rules = read(path, supported)?;
+        info!("loaded {} rules from {}", rules.len(), path.display());
+        Ok(Rules {
+            path: path.to_owned(),
+            supported,
+            current: RwLock::new(Arc::new(rules)),
+        })
+    }
+
+    pub fn current(&self) -> Arc<RuleSet> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Starts a thread that reloads the file whenever it changes.
+    pub fn watch(self: &Arc<Self>) {
+        let rules = self.clone();
+        let mut last = modified(&rules.path);
+        thread::spawn(move || {
+            loop {
+                thread::sleep(RELOAD_INTERVAL);
+                // Missing while an editor replaces it; wait for the new one.
+                let Some(now) = modified(&rules.path) else {
+                    continue;
+                };
+                if last == Some(now) {
+                    continue;
+                }
+                last = Some(now);
+                rules.reload();
+            }
+        });
+    }
+
+    /// Reads the file again now. If it does not parse, the rules stay.
+    pub fn reload(&self) {
+        match read(&self.path, self.supported) {
+            Ok(set) => {
+                info!("reloaded {} rules from {}", set.len(), self.path.display());
+                *self.current.write().unwrap() = Arc::new(set);
+            }
+            Err(e) => warn!("keeping the previous rules: {:#}", e),
+        }
+    }
+}
+
+fn read(path: &Path, supported: &[&str]) -> Result<RuleSet, anyhow::Error> {
+    let text = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    RuleSet::parse(&text, supported).with_context(|| format!("bad rules file {}", path.display()))
+}
+
+/// The file's mtime and size, which together tell a rewrite apart.
+fn modified(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// The parent pid and task name of each of `pid`'s ancestors, nearest first,
+/// as `/proc` has them now.
+pub fn proc_ancestors(pid: u32) -> Vec<(u32, String)> {
+    let mut ancestors = Vec::new();
+    let mut ppid = proc_stat(pid).map_or(0, |(ppid, _)| ppid);
+    while ppid != 0 && ancestors.len() < MAX_ANCESTORS {
+        let Some((next, task_name)) = proc_stat(ppid) else {
+            break;
+        };
+        ancestors.push((ppid, task_name));
+        ppid = next;
+    }
+    ancestors
+}
+
+/// `pid`'s parent pid and task name.
+fn proc_stat(pid: u32) -> Option<(u32, String)> {
+    // `pid (comm) state ppid ...`; comm may itself contain spaces and parens.
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let (head, tail) = stat.rsplit_once(')')?;
+    let task_name = head.split_once('(')?.1.to_owned();
+    let ppid = tail.split_whitespace().nth(1)?.parse().ok()?;
+    Some((ppid, task_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    use super::*;
+
+    /// An event with the fields it is given, and no others.
+    struct Event(HashMap<&'static str, Value>);
+
+    impl Event {
+        fn new(fields: impl IntoIterator<Item = (&'static str, Value)>) -> Self {
+            Event(fields.into_iter().collect())
+        }
+    }
+
+    impl Fields for Event {
+        fn get(&self, field: &str) -> Option<Value> {
+            Some(match self.0.get(field)? {
+                Value::Int(n) => Value::Int(*n),
+                Value::Str(s) => Value::Str(s.clone()),
+                Value::Bool(b) => Value::Bool(*b),
+                Value::List(list) => Value::List(list.clone()),
+            })
+        }
+    }
+
+    fn str(s: &str) -> Value {
+        Value::Str(s.to_owned())
+    }
+
+    fn open(comm: &str, uid: i64, name: &str) -> Event {
+        Event::new([("event", str("open")), ("comm", str(comm)), ("uid", Value::Int(uid)), ("name", str(name))])
+    }
+
+    const ALL: &[&str] = &["event", "comm", "pid", "ppid", "ancestor", "uid", "name", "path", "elevated", "flags"];
+
+    fn names(alerts: &Alerts) -> Vec<String> {
+        alerts.iter().map(|alert| format!("{}: {}", alert.priority, alert.rule)).collect()
+    }
+
+    /// A scratch rules file, removed on drop.
+    struct RulesFile(PathBuf);
+
+    impl RulesFile {
+        fn new(name: &str, text: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("rules-{}-{}.toml", name, std::process::id()));
+            fs::write(&path, text).unwrap();
+            RulesFile(path)
+        }
+    }
+
+    impl Drop for RulesFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    const SHADOW: &str = r#"
+        [[rule]]
+        name = "shadow read"
+        priority = "high"
+        when = { event = "open", name = ["shadow", "gshadow"] }
+        unless = { uid = 0 }
+    "#;
+
+    #[test]
+    fn matches_when_and_not_unless() {
+        let rules = RuleSet::parse(SHADOW, ALL).unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(names(&rules.evaluate(&open("cat", 1000, "gshadow"))), ["high: shadow read"]);
+        assert!(rules.evaluate(&open("cat", 0, "shadow")).is_empty());
+        assert!(rules.evaluate(&open("cat", 1000, "passwd")).is_empty());
+        // A field the event lacks never matches.
+        assert!(rules.evaluate(&Event::new([("event", str("open")), ("uid", Value::Int(1000))])).is_empty());
+    }
+
+    #[test]
+    fn matches_globs_within_a_path_component() {
+        let text = r#"
+            [[rule]]
+            name = "etc"
+            priority = "low"
+            when = { path = "/etc/*" }
+        "#;
+        let rules = RuleSet::parse(text, ALL).unwrap();
+        let path = |path| Event::new([("path", str(path))]);
+        assert!(!rules.evaluate(&path("/etc/shadow")).is_empty());
+        assert!(!rules.evaluate(&path("/etc/.hidden")).is_empty());
+        assert!(rules.evaluate(&path("/etc/ssh/sshd_config")).is_empty());
+        assert!(rules.evaluate(&path("/ETC/shadow")).is_empty());
+    }
+
+    #[test]
+    fn matches_any_of_a_list() {
+        let text = r#"
+            [[rule]]
+            name = "shell under nginx"
+            priority = "high"
+            when = { comm = ["sh", "bash"], ancestor = "nginx*", flags = "O_WRONLY" }
+        "#;
+        let rules = RuleSet::parse(text, ALL).unwrap();
+        let event = |comm, ancestors: &[&str], flags: &[&str]| {
+            let list = |items: &[&str]| Value::List(items.iter().map(|item| item.to_string()).collect());
+            Event::new([("comm", str(comm)), ("ancestor", list(ancestors)), ("flags", list(flags))])
+        };
+        assert!(!rules.evaluate(&event("sh", &["bash", "nginx: worker", "systemd"], &["O_WRONLY", "O_CREAT"])).is_empty());
+        assert!(rules.evaluate(&event("sh", &["bash", "systemd"], &["O_WRONLY"])).is_empty());
+        assert!(rules.evaluate(&event("sh", &["nginx"], &["O_RDONLY"])).is_empty());
+        assert!(rules.evaluate(&event("sh", &[], &["O_WRONLY"])).is_empty());
+        assert!(rules.evaluate(&event("zsh", &["nginx"], &["O_WRONLY"])).is_empty());
+    }
+
+    #[test]
+    fn orders_alerts_by_priority() {
+        let text = r#"
+            [[rule]]
+            name = "any open"
+            priority = "info"
+            when = { event = "open" }
+
+            [[rule]]
+            name = "shadow"
+            priority = "critical"
+            when = { name = "shadow" }
+
+            [[rule]]
+            name = "non-root"
+            priority = "medium"
+            unless = { uid = 0 }
+        "#;
+        let rules = RuleSet::parse(text, ALL).unwrap();
+        let alerts = rules.evaluate(&open("cat", 1000, "shadow"));
+        assert_eq!(names(&alerts), ["critical: shadow", "medium: non-root", "info: any open"]);
+        assert_eq!(alerts.highest().map(|alert| alert.priority), Some(Priority::Critical));
+        assert_eq!(alerts.to_string(), "[critical: shadow] [medium: non-root] [info: any open] ");
+        assert_eq!(Alerts::default().to_string(), "");
+    }
+
+    #[test]
+    fn skips_rules_on_fields_these_events_lack() {
+        let text = r#"
+            [[rule]]
+            name = "needs exe"
+            priority = "low"
+            when = { exe = "/tmp/*" }
+
+            [[rule]]
+            name = "excludes by exe"
+            priority = "low"
+            when = { event = "open" }
+            unless = { exe = "/usr/bin/*" }
+
+            [[rule]]
+            name = "kept"
+            priority = "low"
+            when = { event = "open" }
+        "#;
+        let rules = RuleSet::parse(text, ALL).unwrap();
+        // Without `exe`, the second rule would fire on every open.
+        assert_eq!(names(&rules.evaluate(&open("cat", 0, "x"))), ["low: kept"]);
+    }
+
+    #[test]
+    fn refuses_bad_rules() {
+        for (text, error) in [
+            ("[[rule]]\nname = \"x\"\npriority = \"low\"\nwhen = { nonsense = 1 }", "unknown field `nonsense`"),
+            ("[[rule]]\nname = \"x\"\npriority = \"low\"\nunless = { uid = \"root\" }", "`uid` takes integers"),
+            ("[[rule]]\nname = \"x\"\npriority = \"low\"\nwhen = { comm = 1 }", "`comm` takes strings"),
+            ("[[rule]]\nname = \"x\"\npriority = \"low\"\nwhen = { elevated = [true, false] }", "single true or false"),
+            ("[[rule]]\nname = \"x\"\npriority = \"low\"\nwhen = { comm = [] }", "`comm` has no values"),
+            ("[[rule]]\nname = \"x\"\npriority = \"low\"\nwhen = { comm = \"[\" }", "bad pattern"),
+            ("[[rule]]\nname = \"x\"\npriority = \"urgent\"", "urgent"),
+            ("[[rule]]\nname = \"x\"\npriority = \"low\"\nif = {}", "if"),
+        ] {
+            let e = RuleSet::parse(text, ALL).err().unwrap_or_else(|| panic!("accepted {:?}", text));
+            assert!(format!("{:#}", e).contains(error), "{:#} does not mention {}", e, error);
+        }
+        assert_eq!(RuleSet::parse("", ALL).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn finds_the_ancestors_of_this_process() {
+        let ancestors = proc_ancestors(std::process::id());
+        assert_eq!(ancestors.first().map(|(ppid, _)| *ppid), Some(std::os::unix::process::parent_id()));
+        assert!(ancestors.len() <= MAX_ANCESTORS);
+        assert!(proc_ancestors(u32::MAX).is_empty());
+    }
+
+    #[test]
+    fn reloads_and_keeps_the_rules_when_the_file_breaks() {
+        let file = RulesFile::new("reload", SHADOW);
+        let rules = Rules::load(&file.0, ALL).unwrap();
+        assert_eq!(rules.current().len(), 1);
+        let before = rules.current();
+
+        fs::write(&file.0, format!("{}\n[[rule]]\nname = \"all\"\npriority = \"info\"\n", SHADOW)).unwrap();
+        rules.reload();
+        assert_eq!(rules.current().len(), 2);
+        // Whoever holds the old set keeps it.
+        assert_eq!(before.len(), 1);
+
+        fs::write(&file.0, "[[rule]]\nname = ").unwrap();
+        rules.reload();
+        assert_eq!(rules.current().len(), 2);
+
+        fs::remove_file(&file.0).unwrap();
+        rules.reload();
+        assert_eq!(rules.current().len(), 2);
+        assert!(Rules::load(&file.0, ALL).is_err());
+    }
+
+    #[test]
+    fn watches_the_file_for_changes() {
+        let file = RulesFile::new("watch", SHADOW);
+        let rules = Arc::new(Rules::load(&file.0, ALL).unwrap());
+        rules.watch();
+        // A different size, so the change shows even within one mtime tick.
+        fs::write(&file.0, "").unwrap();
+        let deadline = Instant::now() + 10 * RELOAD_INTERVAL;
+        while !rules.current().is_empty() {
+            assert!(Instant::now() < deadline, "the change was not picked up");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
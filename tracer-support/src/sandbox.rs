@@ -0,0 +1,504 @@
+//! Dropping root once the programs are attached, and a seccomp filter over
+//! what is left.
+//!
+//! Loading and attaching need root, or CAP_BPF and CAP_PERFMON, but reading
+//! events does not: the ring and perf buffers are open and mapped by then.
+//! So the tracer moves to an unprivileged user and keeps no capabilities,
+//! except CAP_BPF if it still reads or writes maps and the kernel wants it
+//! for that. Before 6.5, with `kernel.unprivileged_bpf_disabled` set, every
+//! bpf() command needs it; since, only loading and creating do.
+//!
+//! Credentials belong to threads. glibc moves all of them to the new user,
+//! but only the thread that calls [`enter`], and threads it starts later,
+//! keep CAP_BPF, so maps must be used from there.
+//!
+//! The seccomp filter covers every thread and allows only the system calls
+//! the tracer makes from then on: files, sockets, polling, memory and
+//! threads. Anything else, e.g. execve, ptrace or mount, fails with EPERM.
+//! It lists x86_64 and aarch64 system calls; elsewhere `--user` is refused.
+//!
+//! What the new user may not do any more also limits the tracer: listing
+//! links and programs needs CAP_SYS_ADMIN, so the daemon self-check stops
+//! counting links, and `/proc/<pid>/exe` of other users' processes is out of
+//! reach, so enrichment leaves their executables out. Both say so in a
+//! warning rather than quietly doing less.
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::ptr;
+
+use anyhow::{bail, Context as _};
+use log::info;
+
+const CAP_BPF: u32 = 39;
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+const BPF_OBJ_GET_INFO_BY_FD: libc::c_long = 15;
+
+/// The architecture the filter lets system calls through for, or `None`
+/// where it does not know the system calls.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// Switches every thread to `user` and installs the seccomp filter. With
+/// `maps`, the tracer goes on using bpf() on the maps it holds, so it stays
+/// allowed, along with CAP_BPF if this kernel needs it for that.
+pub fn enter(user: &str, maps: bool) -> Result<(), anyhow::Error> {
+    let Some(arch) = AUDIT_ARCH else {
+        bail!("--user is not supported on {}: the seccomp filter only knows x86_64 and aarch64", std::env::consts::ARCH);
+    };
+    let (uid, gid) = lookup(user)?;
+    if uid == 0 {
+        bail!("--user {} is root", user);
+    }
+    // This thread keeps its capabilities across the switch, to pick
+    // CAP_BPF from; they are all gone from the effective set meanwhile.
+    check(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) }).context("failed to keep capabilities")?;
+    check(unsafe { libc::setgroups(0, ptr::null()) }).context("failed to drop supplementary groups")?;
+    check(unsafe { libc::setresgid(gid, gid, gid) }).with_context(|| format!("failed to switch to gid {}", gid))?;
+    check(unsafe { libc::setresuid(uid, uid, uid) }).with_context(|| format!("failed to switch to uid {}", uid))?;
+    check(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0) })?;
+
+    let keep_bpf = maps && bpf_needs_capability();
+    if keep_bpf {
+        set_capabilities(1 << CAP_BPF).context("keeping CAP_BPF needs Linux 5.8 or later")?;
+    } else {
+        set_capabilities(0)?;
+    }
+    install_filter(arch, maps)?;
+    info!(
+        "running as {} (uid {}, gid {}){} under a seccomp filter",
+        user,
+        uid,
+        gid,
+        if keep_bpf { " with CAP_BPF" } else { "" }
+    );
+    Ok(())
+}
+
+fn check(ret: libc::c_int) -> Result<(), io::Error> {
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The uid and gid of `user`, from the user database.
+fn lookup(user: &str) -> Result<(u32, u32), anyhow::Error> {
+    let name = CString::new(user)?;
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let ret = unsafe { libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+        if ret == libc::ERANGE && buf.len() < 1 << 20 {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret)).with_context(|| format!("failed to look up user {}", user));
+        }
+        if result.is_null() {
+            bail!("no user {}", user);
+        }
+        return Ok((pwd.pw_uid, pwd.pw_gid));
+    }
+}
+
+/// Whether bpf() on an open map still needs CAP_BPF. An info query on an
+/// fd that is not open tells: the kernels that want it refuse the command
+/// up front with EPERM, the others look at the fd and fail with EBADF.
+fn bpf_needs_capability() -> bool {
+    // `bpf_attr.info`: bpf_fd, info_len and info.
+    let attr: [u32; 4] = [u32::MAX, 0, 0, 0];
+    let ret = unsafe { libc::syscall(libc::SYS_bpf, BPF_OBJ_GET_INFO_BY_FD, attr.as_ptr(), mem::size_of_val(&attr)) };
+    ret < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Makes `caps`, a mask of capability numbers, the permitted and effective
+/// sets of this thread, and clears the inheritable one.
+fn set_capabilities(caps: u64) -> Result<(), io::Error> {
+    let header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    for (i, data) in data.iter_mut().enumerate() {
+        let word = (caps >> (32 * i)) as u32;
+        data.effective = word;
+        data.permitted = word;
+    }
+    if unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// System calls allowed whatever their arguments.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const ALLOWED: &[libc::c_long] = &[
+    // Files, including /proc and the user database.
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_lseek,
+    libc::SYS_close,
+    libc::SYS_openat,
+    libc::SYS_newfstatat,
+    libc::SYS_fstat,
+    libc::SYS_statx,
+    libc::SYS_readlinkat,
+    libc::SYS_getdents64,
+    libc::SYS_fcntl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_renameat2,
+    libc::SYS_unlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_getcwd,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    // Memory.
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    // Threads and signals.
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_getrandom,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    libc::SYS_tgkill,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    // Time.
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    // Polling.
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_eventfd2,
+    libc::SYS_pipe2,
+    // Sockets: the control socket, exporters and systemd.
+    libc::SYS_socket,
+    libc::SYS_connect,
+    libc::SYS_accept4,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_shutdown,
+    // Identity.
+    libc::SYS_uname,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_prlimit64,
+];
+
+/// The older calls x86_64 still has and glibc still makes there.
+#[cfg(target_arch = "x86_64")]
+const ALLOWED_LEGACY: &[libc::c_long] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_readlink,
+    libc::SYS_rename,
+    libc::SYS_renameat,
+    libc::SYS_unlink,
+    libc::SYS_access,
+    libc::SYS_poll,
+    libc::SYS_select,
+    libc::SYS_epoll_wait,
+    libc::SYS_pipe,
+    libc::SYS_dup2,
+    libc::SYS_accept,
+];
+#[cfg(target_arch = "aarch64")]
+const ALLOWED_LEGACY: &[libc::c_long] = &[];
+
+/// ioctl requests allowed: non-blocking mode, bytes pending, and the
+/// terminal queries behind `isatty`.
+const IOCTLS: &[u32] = &[libc::FIONBIO as u32, libc::FIONREAD as u32, libc::TCGETS as u32, libc::TIOCGWINSZ as u32];
+/// prctl options allowed: thread names.
+const PRCTLS: &[u32] = &[libc::PR_SET_NAME as u32, libc::PR_GET_NAME as u32];
+
+const DENY: u32 = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+// Offsets into `struct seccomp_data`. Only the low half of an argument is
+// looked at, which is all the kernel reads of these.
+const NR: u32 = 0;
+const ARCH: u32 = 4;
+const fn arg(i: u32) -> u32 {
+    16 + 8 * i
+}
+
+fn load(offset: u32) -> libc::sock_filter {
+    statement((libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16, offset)
+}
+
+fn ret(action: u32) -> libc::sock_filter {
+    statement((libc::BPF_RET | libc::BPF_K) as u16, action)
+}
+
+fn statement(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code, jt: 0, jf: 0, k }
+}
+
+fn jump(op: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_JMP | op | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// Allows `nr` only if its argument `i` is one of `values`.
+fn allow_if_arg(filter: &mut Vec<libc::sock_filter>, nr: libc::c_long, i: u32, values: &[u32]) {
+    let len = u8::try_from(2 + 2 * values.len()).expect("too many values to jump over");
+    filter.push(jump(libc::BPF_JEQ, nr as u32, 0, len));
+    filter.push(load(arg(i)));
+    for value in values {
+        filter.push(jump(libc::BPF_JEQ, *value, 0, 1));
+        filter.push(ret(libc::SECCOMP_RET_ALLOW));
+    }
+    filter.push(ret(DENY));
+    // A no longer holds the number; only reached after returning.
+    filter.push(load(NR));
+}
+
+/// The filter for `arch`, which allows bpf() with `maps`.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn filter(arch: u32, maps: bool) -> Vec<libc::sock_filter> {
+    let mut filter = vec![
+        load(ARCH),
+        jump(libc::BPF_JEQ, arch, 1, 0),
+        ret(libc::SECCOMP_RET_KILL_PROCESS),
+        load(NR),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    {
+        // x32 system calls, which have their own numbers.
+        filter.push(jump(libc::BPF_JGE, 0x4000_0000, 0, 1));
+        filter.push(ret(DENY));
+    }
+    let bpf: &[libc::c_long] = if maps { &[libc::SYS_bpf] } else { &[] };
+    for nr in ALLOWED.iter().chain(ALLOWED_LEGACY).chain(bpf) {
+        filter.push(jump(libc::BPF_JEQ, *nr as u32, 0, 1));
+        filter.push(ret(libc::SECCOMP_RET_ALLOW));
+    }
+    // New threads only, not processes or namespaces.
+    filter.push(jump(libc::BPF_JEQ, libc::SYS_clone as u32, 0, 3));
+    filter.push(load(arg(0)));
+    filter.push(jump(libc::BPF_JSET, libc::CLONE_THREAD as u32, 0, 1));
+    filter.push(ret(libc::SECCOMP_RET_ALLOW));
+    filter.push(load(NR));
+    // clone3 hides its flags behind a pointer; glibc falls back to clone.
+    filter.push(jump(libc::BPF_JEQ, libc::SYS_clone3 as u32, 0, 1));
+    filter.push(ret(libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32));
+    allow_if_arg(&mut filter, libc::SYS_ioctl, 1, IOCTLS);
+    allow_if_arg(&mut filter, libc::SYS_prctl, 0, PRCTLS);
+    filter.push(ret(DENY));
+    filter
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn filter(_arch: u32, _maps: bool) -> Vec<libc::sock_filter> {
+    unreachable!("there is no AUDIT_ARCH to build a filter for")
+}
+
+fn install_filter(arch: u32, maps: bool) -> Result<(), anyhow::Error> {
+    let mut filter = filter(arch, maps);
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) }).context("failed to set no_new_privs")?;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &program,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        -1 => Err(io::Error::last_os_error()).context("failed to install the seccomp filter"),
+        tid => bail!("failed to install the seccomp filter: thread {} has one of its own", tid),
+    }
+}
+
+#[cfg(all(test, any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod tests {
+    use super::*;
+
+    const ARCH_HERE: u32 = match AUDIT_ARCH {
+        Some(arch) => arch,
+        None => 0,
+    };
+
+    /// Runs `filter` the way the kernel would on a system call `nr` with
+    /// `args`, and returns the action.
+    fn run(filter: &[libc::sock_filter], arch: u32, nr: libc::c_long, args: [u64; 6]) -> u32 {
+        let word = |offset: u32| match offset {
+            NR => nr as u32,
+            ARCH => arch,
+            offset => args[((offset - arg(0)) / 8) as usize] as u32,
+        };
+        let mut a = 0;
+        let mut pc = 0;
+        loop {
+            let insn = filter[pc];
+            pc += 1;
+            let code = insn.code as u32;
+            match code {
+                _ if code == libc::BPF_LD | libc::BPF_W | libc::BPF_ABS => a = word(insn.k),
+                _ if code == libc::BPF_RET | libc::BPF_K => return insn.k,
+                _ if code & 0x07 == libc::BPF_JMP => {
+                    let taken = match code & 0xf0 {
+                        op if op == libc::BPF_JEQ => a == insn.k,
+                        op if op == libc::BPF_JGE => a >= insn.k,
+                        op if op == libc::BPF_JSET => a & insn.k != 0,
+                        op => panic!("jump op {:#x}", op),
+                    };
+                    pc += if taken { insn.jt } else { insn.jf } as usize;
+                }
+                code => panic!("instruction {:#x}", code),
+            }
+        }
+    }
+
+    fn call(filter: &[libc::sock_filter], nr: libc::c_long, args: [u64; 6]) -> u32 {
+        run(filter, ARCH_HERE, nr, args)
+    }
+
+    #[test]
+    fn allow_if_arg_jumps_over_its_block() {
+        let mut filter = Vec::new();
+        allow_if_arg(&mut filter, libc::SYS_ioctl, 1, &[7, 9]);
+        // The number check, the load, two tests with their returns, the
+        // denial and the reload of the number.
+        assert_eq!(filter.len(), 8);
+        assert_eq!((filter[0].k, filter[0].jt, filter[0].jf), (libc::SYS_ioctl as u32, 0, 6));
+        // Not ioctl: on to the reload, the last of the block.
+        assert_eq!((filter[1 + 6].code, filter[1 + 6].k), (load(NR).code, NR));
+        assert_eq!(filter[1].k, arg(1));
+        for (i, value) in [7, 9].into_iter().enumerate() {
+            let test = filter[2 + 2 * i];
+            assert_eq!((test.k, test.jt, test.jf), (value, 0, 1));
+            assert_eq!(filter[3 + 2 * i].k, libc::SECCOMP_RET_ALLOW);
+        }
+        assert_eq!(filter[6].k, DENY);
+
+        filter.insert(0, load(NR));
+        filter.push(ret(libc::SECCOMP_RET_KILL_PROCESS));
+        assert_eq!(call(&filter, libc::SYS_ioctl, [0, 9, 0, 0, 0, 0]), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(&filter, libc::SYS_ioctl, [0, 8, 0, 0, 0, 0]), DENY);
+        assert_eq!(call(&filter, libc::SYS_read, [0, 9, 0, 0, 0, 0]), libc::SECCOMP_RET_KILL_PROCESS);
+    }
+
+    #[test]
+    fn every_jump_lands_in_the_filter() {
+        for maps in [false, true] {
+            let filter = filter(ARCH_HERE, maps);
+            assert!(filter.len() <= u16::MAX as usize);
+            for (pc, insn) in filter.iter().enumerate() {
+                if insn.code as u32 & 0x07 == libc::BPF_JMP {
+                    assert!(pc + 1 + (insn.jt.max(insn.jf) as usize) < filter.len(), "jump at {} leaves the filter", pc);
+                }
+            }
+            assert_eq!(filter.last().unwrap().k, DENY);
+        }
+    }
+
+    #[test]
+    fn allows_the_listed_system_calls() {
+        let filter = filter(ARCH_HERE, false);
+        for nr in ALLOWED.iter().chain(ALLOWED_LEGACY) {
+            assert_eq!(call(&filter, *nr, [0; 6]), libc::SECCOMP_RET_ALLOW, "system call {}", nr);
+        }
+        for nr in [libc::SYS_execve, libc::SYS_ptrace, libc::SYS_mount, libc::SYS_setuid, libc::SYS_bpf] {
+            assert_eq!(call(&filter, nr, [0; 6]), DENY, "system call {}", nr);
+        }
+        assert_eq!(call(&super::filter(ARCH_HERE, true), libc::SYS_bpf, [0; 6]), libc::SECCOMP_RET_ALLOW);
+    }
+
+    #[test]
+    fn checks_the_arguments_it_cares_about() {
+        let filter = filter(ARCH_HERE, false);
+        let thread = (libc::CLONE_VM | libc::CLONE_THREAD) as u64;
+        assert_eq!(call(&filter, libc::SYS_clone, [thread, 0, 0, 0, 0, 0]), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(&filter, libc::SYS_clone, [libc::SIGCHLD as u64, 0, 0, 0, 0, 0]), DENY);
+        assert_eq!(call(&filter, libc::SYS_clone3, [0; 6]), libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32);
+        for request in IOCTLS {
+            assert_eq!(call(&filter, libc::SYS_ioctl, [0, *request as u64, 0, 0, 0, 0]), libc::SECCOMP_RET_ALLOW);
+        }
+        assert_eq!(call(&filter, libc::SYS_ioctl, [0, libc::TIOCSTI, 0, 0, 0, 0]), DENY);
+        // prctl is looked at after ioctl, so the number must be back in A.
+        assert_eq!(call(&filter, libc::SYS_prctl, [libc::PR_SET_NAME as u64, 0, 0, 0, 0, 0]), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(call(&filter, libc::SYS_prctl, [libc::PR_SET_DUMPABLE as u64, 0, 0, 0, 0, 0]), DENY);
+        // Only the low half of an argument counts.
+        assert_eq!(call(&filter, libc::SYS_prctl, [1 << 32 | libc::PR_GET_NAME as u64, 0, 0, 0, 0, 0]), libc::SECCOMP_RET_ALLOW);
+    }
+
+    #[test]
+    fn kills_other_architectures() {
+        let filter = filter(ARCH_HERE, true);
+        assert_eq!(run(&filter, 0x4000_0003, libc::SYS_read, [0; 6]), libc::SECCOMP_RET_KILL_PROCESS);
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(call(&filter, libc::SYS_read | 0x4000_0000, [0; 6]), DENY);
+    }
+}
@@ -0,0 +1,176 @@
+//! The two ways records reach us. BPF ring buffers are the default; kernels
+//! before 5.8 have none, and there the probes are loaded from a second
+//! object that writes to a perf event array instead.
+//!
+//! The perf buffers pad every sample to 8 bytes, so the tracer says how to
+//! read the length of a record off its start, and the padding is dropped to
+//! hand on records as long as they come from the ring buffer.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use aya::maps::{AsyncPerfEventArray, MapData, RingBuf};
+use aya::util::online_cpus;
+use anyhow::Context as _;
+use aya::Bpf;
+use bytes::BytesMut;
+use log::warn;
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc;
+
+pub use crate::features::Transport;
+
+/// How long the record at the start of a buffer says it is, if it says.
+pub type RecordLen = fn(&[u8]) -> Option<usize>;
+
+pub enum Reader {
+    Ring(AsyncFd<RingBuf<MapData>>),
+    /// Filled by a task per CPU.
+    Perf {
+        records: mpsc::Receiver<BytesMut>,
+        lost: Arc<AtomicU64>,
+    },
+}
+
+impl Reader {
+    /// Takes the map of `transport` from `bpf`. The perf buffers share
+    /// `size` bytes between them, as evenly as powers of two allow, and
+    /// their records are cut to `record_len`.
+    pub fn open(bpf: &mut Bpf, transport: Transport, size: u32, record_len: RecordLen) -> Result<Self, anyhow::Error> {
+        match transport {
+            Transport::Ringbuf => {
+                let map = bpf.take_map("RINGBUF").context("the object has no map RINGBUF")?;
+                Ok(Reader::Ring(AsyncFd::new(RingBuf::try_from(map)?)?))
+            }
+            Transport::Perf => {
+                let map = bpf.take_map("EVENTS").context("the object has no map EVENTS")?;
+                let mut array = AsyncPerfEventArray::try_from(map)?;
+                let cpus = online_cpus()?;
+                let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+                let pages = (size as usize / page_size / cpus.len()).max(1);
+                // Rounded down to a power of two.
+                let pages = 1 << pages.ilog2();
+                let (sender, records) = mpsc::channel(1024);
+                let lost = Arc::new(AtomicU64::new(0));
+                for cpu in cpus {
+                    let mut buf = array.open(cpu, Some(pages))?;
+                    let sender = sender.clone();
+                    let lost = lost.clone();
+                    tokio::spawn(async move {
+                        let mut buffers = (0..10).map(|_| BytesMut::with_capacity(1024)).collect::<Vec<_>>();
+                        loop {
+                            let events = match buf.read_events(&mut buffers).await {
+                                Ok(events) => events,
+                                Err(e) => {
+                                    warn!("stopped reading the perf buffer of CPU {}: {}", cpu, e);
+                                    return;
+                                }
+                            };
+                            lost.fetch_add(events.lost as u64, Ordering::Relaxed);
+                            for buf in buffers.iter_mut().take(events.read) {
+                                trim(buf, record_len);
+                                // Only fails once we stopped reading.
+                                if sender.send(buf.split()).await.is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                    });
+                }
+                Ok(Reader::Perf { records, lost })
+            }
+        }
+    }
+
+    /// Waits for records and passes each of them to `handle`.
+    pub async fn read(&mut self, mut handle: impl FnMut(&[u8])) -> Result<(), anyhow::Error> {
+        match self {
+            Reader::Ring(ring_buf) => {
+                let mut guard = ring_buf.readable_mut().await?;
+                drain_ring(guard.get_inner_mut(), handle);
+                guard.clear_ready();
+            }
+            Reader::Perf { records, .. } => {
+                // Closed once every task stopped; nothing comes any more.
+                let Some(buf) = records.recv().await else {
+                    return std::future::pending().await;
+                };
+                handle(&buf);
+                while let Ok(buf) = records.try_recv() {
+                    handle(&buf);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes the records already there to `handle`, without waiting.
+    pub fn drain(&mut self, mut handle: impl FnMut(&[u8])) {
+        match self {
+            Reader::Ring(ring_buf) => drain_ring(ring_buf.get_mut(), handle),
+            Reader::Perf { records, .. } => {
+                while let Ok(buf) = records.try_recv() {
+                    handle(&buf);
+                }
+            }
+        }
+    }
+
+    /// Records the kernel dropped so far because a perf buffer was full. The
+    /// probes count failed ring buffer reservations in `STATS` instead.
+    pub fn lost(&self) -> Option<u64> {
+        match self {
+            Reader::Ring(_) => None,
+            Reader::Perf { lost, .. } => Some(lost.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+fn drain_ring(ring_buf: &mut RingBuf<MapData>, mut handle: impl FnMut(&[u8])) {
+    while let Some(item) = ring_buf.next() {
+        handle(&item);
+    }
+}
+
+/// Drops the padding the kernel adds after a perf sample, so the record is
+/// as long as it says, as it is from the ring buffer.
+fn trim(buf: &mut BytesMut, record_len: RecordLen) {
+    if let Some(len) = record_len(buf) {
+        if len <= buf.len() {
+            buf.truncate(len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `u32` length after a `u16` kind and version, as in ringbuf's header.
+    fn header_len(buf: &[u8]) -> Option<usize> {
+        Some(u32::from_ne_bytes(buf.get(4..8)?.try_into().unwrap()) as usize)
+    }
+
+    #[test]
+    fn trims_the_perf_padding() {
+        let mut record = BytesMut::new();
+        record.extend_from_slice(&1u16.to_ne_bytes());
+        record.extend_from_slice(&[0; 2]);
+        record.extend_from_slice(&10u32.to_ne_bytes());
+        record.extend_from_slice(&[7, 7]);
+        let padded = [&record[..], &[0; 6]].concat();
+
+        let mut buf = BytesMut::from(&padded[..]);
+        trim(&mut buf, header_len);
+        assert_eq!(buf, record);
+
+        // A length past the end, or none at all, leaves the buffer alone.
+        let mut buf = BytesMut::from(&padded[..]);
+        buf[4..8].copy_from_slice(&64u32.to_ne_bytes());
+        trim(&mut buf, header_len);
+        assert_eq!(buf.len(), 16);
+        let mut buf = BytesMut::from(&[1, 2, 3][..]);
+        trim(&mut buf, header_len);
+        assert_eq!(&buf[..], [1, 2, 3]);
+    }
+}
@@ -0,0 +1,346 @@
+//! Explains why the kernel refused to load a program. The verifier's log is
+//! a trace of every instruction it walked, ending with the one it stopped at
+//! and why; this picks those out, names the program they belong to and adds
+//! a hint for the usual causes.
+//!
+//! The objects carry BTF line info, so the log quotes the source line of
+//! what it walks. Functions the program calls, like a loop over a map, are
+//! inlined into it and show up as its own instructions; the source line is
+//! looked up in the eBPF sources to name the function it is really in.
+
+use std::fs;
+use std::path::Path;
+
+use aya::programs::ProgramError;
+
+/// Instructions shown before the one the verifier stopped at.
+const CONTEXT: usize = 4;
+
+/// Hints for the messages rejections end with, by the words they contain.
+const HINTS: [(&[&str], &str); 7] = [
+    (
+        &["stack size", "invalid write to stack", "invalid read from stack", "invalid indirect read from stack"],
+        "programs get 512 bytes of stack, the functions inlined into them included; keep large buffers in a per-CPU array map",
+    ),
+    (
+        &["back-edge", "infinite loop", "program is too large", "jumps is too complex", "too many states"],
+        "the verifier walks a loop once per iteration, up to a million instructions in all, and kernels before 5.3 refuse loops altogether; lower the bound of the loop, e.g. one over every entry of a map",
+    ),
+    (
+        &["_or_null"],
+        "a map lookup can find nothing; check the pointer it returns before reading through it",
+    ),
+    (
+        &["invalid mem access 'scalar'", "invalid mem access 'inv'"],
+        "kernel memory cannot be read through a plain pointer; copy it with bpf_probe_read_kernel",
+    ),
+    (
+        &["outside of the allowed memory range", "unbounded memory access", "min value is negative", "invalid access to map value"],
+        "an offset or length the verifier cannot bound; compare it with the size of the buffer right before using it",
+    ),
+    (
+        &["unknown func", "cannot call helper", "helper call is not allowed", "program of this type cannot use helper"],
+        "this kernel lacks the helper, or this program type may not call it",
+    ),
+    (
+        &["GPL-restricted"],
+        "the helper is only for programs whose license is GPL compatible",
+    ),
+];
+
+/// The eBPF sources of a tracer, by file name, e.g.
+/// `("main.rs", include_str!("../../array-ebpf/src/main.rs"))`.
+pub type Sources<'a> = &'a [(&'a str, &'a str)];
+
+/// Turns a failure to load `program` into an error saying where and why the
+/// verifier refused it, and writes the whole log to `dump` if given.
+pub fn explain(program: &str, error: ProgramError, dump: Option<&Path>, sources: Sources) -> anyhow::Error {
+    let ProgramError::LoadError { io_error, verifier_log } = error else {
+        return anyhow::Error::new(error).context(format!("failed to load {}", program));
+    };
+    let log = verifier_log.to_string();
+    let rejection = Rejection::parse(&log);
+    let mut message = if rejection.reason.is_empty() {
+        format!("failed to load {}", program)
+    } else {
+        format!("the verifier rejected {}: {}", program, rejection.reason.join("; "))
+    };
+    if !rejection.instructions.is_empty() {
+        message.push_str(&format!("\n  in {}, with the functions it calls inlined:", program));
+        let failed = rejection.instructions.len() - 1;
+        for (i, (index, instruction)) in rejection.instructions.iter().enumerate() {
+            let marker = if i == failed { '>' } else { ' ' };
+            message.push_str(&format!("\n  {} {}: {}", marker, index, instruction));
+        }
+    }
+    if let Some(source) = &rejection.source {
+        message.push_str(&format!("\n  at {}", source.describe(sources)));
+    }
+    let reason = rejection.reason.join("\n");
+    for (words, hint) in HINTS {
+        if words.iter().any(|word| reason.contains(word)) {
+            message.push_str(&format!("\n  hint: {}", hint));
+        }
+    }
+    if log.is_empty() && io_error.raw_os_error() == Some(libc::EPERM) {
+        message.push_str("\n  hint: loading programs needs root, or CAP_BPF and CAP_PERFMON");
+    }
+    match dump {
+        Some(path) => match fs::write(path, &log) {
+            Ok(()) => message.push_str(&format!("\n  the whole verifier log is in {}", path.display())),
+            Err(e) => message.push_str(&format!("\n  failed to write the verifier log to {}: {}", path.display(), e)),
+        },
+        None if !log.is_empty() => {
+            message.push_str(&format!("\n  --verifier-log <file> writes the whole log, {} lines", log.lines().count()));
+        }
+        None => {}
+    }
+    anyhow::Error::new(io_error).context(message)
+}
+
+/// The end of a verifier log.
+struct Rejection<'a> {
+    /// Why the verifier stopped, usually one line.
+    reason: Vec<&'a str>,
+    /// The last instructions walked, by index, the failing one last.
+    instructions: Vec<(&'a str, &'a str)>,
+    /// The source line of the failing instruction, with BTF line info only.
+    source: Option<SourceLine<'a>>,
+}
+
+/// A source line the log quotes, `; for i in 0..N { @ main.rs:43`. Older
+/// kernels leave out the file and line.
+#[derive(Debug, PartialEq)]
+struct SourceLine<'a> {
+    text: &'a str,
+    location: Option<(&'a str, usize)>,
+}
+
+impl<'a> SourceLine<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let quoted = line.strip_prefix("; ")?;
+        let location = quoted.rsplit_once(" @ ").and_then(|(text, location)| {
+            let (file, line) = location.rsplit_once(':')?;
+            Some((text, (file, line.parse().ok()?)))
+        });
+        Some(match location {
+            Some((text, location)) => SourceLine { text: text.trim(), location: Some(location) },
+            None => SourceLine { text: quoted.trim(), location: None },
+        })
+    }
+
+    /// File, line and function of the source line, as far as the log and
+    /// `sources` tell, then its text.
+    fn describe(&self, sources: Sources) -> String {
+        // Without a location, the first line with the same text will do.
+        let location = self.location.or_else(|| {
+            sources.iter().find_map(|(file, source)| Some((*file, source.lines().position(|line| line.trim() == self.text)? + 1)))
+        });
+        let Some((file, line)) = location else {
+            return self.text.to_owned();
+        };
+        let function = sources
+            .iter()
+            .find(|(name, _)| Path::new(file).file_name().is_some_and(|file| file == *name))
+            .and_then(|(_, source)| source.lines().take(line).collect::<Vec<_>>().into_iter().rev().find_map(function_name));
+        match function {
+            Some(function) => format!("{}:{}, in {}: {}", file, line, function, self.text),
+            None => format!("{}:{}: {}", file, line, self.text),
+        }
+    }
+}
+
+impl<'a> Rejection<'a> {
+    fn parse(log: &'a str) -> Self {
+        let lines = log.lines().map(str::trim_end).filter(|line| !line.is_empty()).collect::<Vec<_>>();
+        // Statistics follow the verdict.
+        let end = lines.iter().rposition(|line| !is_statistics(line)).map_or(0, |i| i + 1);
+        let lines = &lines[..end];
+        let Some(failed) = lines.iter().rposition(|line| instruction(line).is_some()) else {
+            // Refused before walking any instruction, e.g. for a loop.
+            return Rejection { reason: lines.last().copied().into_iter().collect(), instructions: Vec::new(), source: None };
+        };
+        let mut instructions = lines[..=failed].iter().rev().filter_map(|line| instruction(line)).take(CONTEXT + 1).collect::<Vec<_>>();
+        instructions.reverse();
+        // The verdict on a jump can follow it on the same line.
+        let glued = instructions.last_mut().and_then(|(_, text)| {
+            let (jump, verdict) = split_jump(text)?;
+            *text = jump;
+            Some(verdict)
+        });
+        let mut reason = glued.into_iter().chain(lines[failed + 1..].iter().copied().filter(|line| !is_state(line))).collect::<Vec<_>>();
+        if reason.is_empty() {
+            reason.extend(lines.last());
+        }
+        // A source line is quoted where it changes, so it may be a few
+        // instructions back.
+        let source = lines[..failed].iter().rev().find_map(|line| SourceLine::parse(line));
+        Rejection { reason, instructions, source }
+    }
+}
+
+/// Index and text of an instruction line, `12: (bf) r6 = r1`.
+fn instruction(line: &str) -> Option<(&str, &str)> {
+    let (index, rest) = line.split_once(": (")?;
+    if index.is_empty() || !index.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let (_, text) = rest.split_once(") ")?;
+    // Newer kernels add the registers it changed, after a jump without `;`.
+    let text = text.split_once(" ; ").map_or(text, |(text, _)| text).trim();
+    match split_jump(text) {
+        Some((jump, rest)) if is_state(rest) => Some((index, jump)),
+        _ => Some((index, text)),
+    }
+}
+
+/// Splits what follows the target of a jump, `if r0 != 0x5 goto pc-2`, from
+/// it.
+fn split_jump(text: &str) -> Option<(&str, &str)> {
+    let target = text.find("goto pc")?;
+    let end = text[target..].find(' ').and_then(|i| text[target + i + 1..].find(' ').map(|j| target + i + 1 + j))?;
+    Some((&text[..end], text[end..].trim()))
+}
+
+/// Registers at an instruction, where a branch was taken, or the states an
+/// infinite loop was found between.
+fn is_state(line: &str) -> bool {
+    if ["from ", "cur state:", "old state:"].iter().any(|prefix| line.starts_with(prefix)) {
+        return true;
+    }
+    line.split_once(": ").is_some_and(|(index, rest)| {
+        !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()) && (rest.starts_with('R') || rest.starts_with("frame"))
+    })
+}
+
+/// The name `line` declares a function by, `pub unsafe fn name(...)`.
+fn function_name(line: &str) -> Option<&str> {
+    let mut words = line.split_whitespace();
+    loop {
+        match words.next()? {
+            "fn" => return words.next()?.split(['(', '<']).next().filter(|name| !name.is_empty()),
+            "pub" | "unsafe" | "const" | "async" | "extern" | "\"C\"" => {}
+            word if word.starts_with("pub(") => {}
+            _ => return None,
+        }
+    }
+}
+
+fn is_statistics(line: &str) -> bool {
+    ["processed ", "verification time ", "stack depth "].iter().any(|prefix| line.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The start of array-ebpf's main.rs.
+    const SOURCE: &str = "\
+#[kprobe]
+pub fn array(ctx: ProbeContext) -> u32 {
+    match try_array(ctx) {
+        Ok(ret) => ret,
+        Err(_) => 1,
+    }
+}
+fn check_valid_user(uid: u32) -> bool {
+    let base = match unsafe { ACTIVE.get(0) } {
+        Some(&1) => USER_LIST_MAX_ENTRIES,
+        _ => 0,
+    };
+    for i in 0..USER_LIST_MAX_ENTRIES {
+        match unsafe {USER_LIST.get(base + i) } {
+";
+
+    // A 6.x kernel walking the loop of check_valid_user to the limit.
+    const TOO_LARGE: &str = "\
+func#0 @0
+0: R1=ctx() R10=fp0
+; pub fn array(ctx: ProbeContext) -> u32 { @ main.rs:2
+0: (bf) r6 = r1                       ; R1=ctx() R6_w=ctx()
+; let base = match unsafe { ACTIVE.get(0) } { @ main.rs:9
+1: (b7) r1 = 0                        ; R1_w=0
+2: (63) *(u32 *)(r10 -4) = r1         ; R1_w=0 R10=fp0 fp-8=0000????
+from 18 to 9: R0=map_value(ks=4,vs=4) R6=ctx() R8=41 R10=fp0
+; match unsafe {USER_LIST.get(base + i) } { @ main.rs:14
+9: (63) *(u32 *)(r10 -8) = r8         ; R8=41 R10=fp0 fp-8=mmmm0000
+10: (bf) r2 = r10                     ; R2_w=fp0 R10=fp0
+11: (07) r2 += -8                     ; R2_w=fp-8
+12: (18) r1 = 0xffff8881036a5c00      ; R1_w=map_ptr(ks=4,vs=4)
+14: (85) call bpf_map_lookup_elem#1   ; R0=map_value_or_null(id=42,ks=4,vs=4)
+; for i in 0..USER_LIST_MAX_ENTRIES { @ main.rs:13
+15: (07) r8 += 1                      ; R8_w=42
+16: (55) if r8 != 0x400 goto pc-8     ; R8_w=42
+BPF program is too large. Processed 1000001 insn
+processed 1000001 insns (limit 1000000) max_states_per_insn 4 total_states 9802 peak_states 9802 mark_read 3
+";
+
+    // A 5.4 kernel, which quotes no file and line and no registers.
+    const NULL_VALUE: &str = "\
+0: (b7) r1 = 0
+1: (63) *(u32 *)(r10 -4) = r1
+2: (bf) r2 = r10
+3: (07) r2 += -4
+4: (18) r1 = 0xffff9c5e3a1c3c00
+; let base = match unsafe { ACTIVE.get(0) } {
+6: (85) call bpf_map_lookup_elem#1
+7: (61) r1 = *(u32 *)(r0 +0)
+R0 invalid mem access 'map_value_or_null'
+processed 7 insns (limit 1000000) max_states_per_insn 0 total_states 0 peak_states 0 mark_read 0
+";
+
+    // A 5.2 kernel, which refuses any loop before walking the program.
+    const BACK_EDGE: &str = "back-edge from insn 16 to 9\n";
+
+    #[test]
+    fn names_the_function_of_a_loop() {
+        let rejection = Rejection::parse(TOO_LARGE);
+        assert_eq!(rejection.reason, ["BPF program is too large. Processed 1000001 insn"]);
+        assert_eq!(
+            rejection.instructions,
+            [
+                ("11", "r2 += -8"),
+                ("12", "r1 = 0xffff8881036a5c00"),
+                ("14", "call bpf_map_lookup_elem#1"),
+                ("15", "r8 += 1"),
+                ("16", "if r8 != 0x400 goto pc-8"),
+            ]
+        );
+        let source = rejection.source.unwrap();
+        assert_eq!(source, SourceLine { text: "for i in 0..USER_LIST_MAX_ENTRIES {", location: Some(("main.rs", 13)) });
+        assert_eq!(source.describe(&[("main.rs", SOURCE)]), "main.rs:13, in check_valid_user: for i in 0..USER_LIST_MAX_ENTRIES {");
+        assert_eq!(source.describe(&[]), "main.rs:13: for i in 0..USER_LIST_MAX_ENTRIES {");
+    }
+
+    #[test]
+    fn finds_a_line_without_a_location() {
+        let rejection = Rejection::parse(NULL_VALUE);
+        assert_eq!(rejection.reason, ["R0 invalid mem access 'map_value_or_null'"]);
+        assert_eq!(rejection.instructions.last(), Some(&("7", "r1 = *(u32 *)(r0 +0)")));
+        assert_eq!(rejection.instructions.len(), CONTEXT + 1);
+        let source = rejection.source.unwrap();
+        assert_eq!(source.location, None);
+        assert_eq!(
+            source.describe(&[("main.rs", SOURCE)]),
+            "main.rs:9, in check_valid_user: let base = match unsafe { ACTIVE.get(0) } {"
+        );
+        assert_eq!(source.describe(&[]), "let base = match unsafe { ACTIVE.get(0) } {");
+    }
+
+    #[test]
+    fn reads_a_refusal_before_any_instruction() {
+        let rejection = Rejection::parse(BACK_EDGE);
+        assert_eq!(rejection.reason, ["back-edge from insn 16 to 9"]);
+        assert!(rejection.instructions.is_empty());
+        assert_eq!(rejection.source, None);
+    }
+
+    #[test]
+    fn names_functions() {
+        assert_eq!(function_name("fn check_valid_user(uid: u32) -> bool {"), Some("check_valid_user"));
+        assert_eq!(function_name("pub unsafe fn try_array<C>(ctx: C) {"), Some("try_array"));
+        assert_eq!(function_name("pub(crate) fn output(event: &Event) {"), Some("output"));
+        assert_eq!(function_name("    let f = fn_pointer;"), None);
+        assert_eq!(function_name("// fn commented(out)"), None);
+    }
+}